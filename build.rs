@@ -0,0 +1,4 @@
+fn main() {
+    // Migrations are embedded, rebuild when one is added
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,9 @@
+-- AlterTable
+ALTER TABLE "CloudUser" ALTER COLUMN "id" DROP DEFAULT,
+    ALTER COLUMN "updatedAt" DROP DEFAULT;
+
+-- DropForeignKey
+ALTER TABLE "SshKey" DROP CONSTRAINT "SshKey_userId_fkey";
+
+-- DropTable
+DROP TABLE "SshKey";
@@ -0,0 +1,27 @@
+-- CreateTable
+CREATE TABLE "SshKey" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    "userId" TEXT NOT NULL,
+    "name" TEXT NOT NULL,
+    "keyType" TEXT NOT NULL,
+    "publicKey" TEXT NOT NULL,
+    "fingerprint" TEXT NOT NULL,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updatedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "SshKey_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "SshKey_userId_name_key" ON "SshKey"("userId", "name");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "SshKey_userId_fingerprint_key" ON "SshKey"("userId", "fingerprint");
+
+-- AddForeignKey
+ALTER TABLE "SshKey" ADD CONSTRAINT "SshKey_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User"("id") ON DELETE CASCADE ON UPDATE CASCADE;
+
+-- AlterTable
+-- Cloud accounts are inserted without an id or timestamps
+ALTER TABLE "CloudUser" ALTER COLUMN "id" SET DEFAULT gen_random_uuid()::text,
+    ALTER COLUMN "updatedAt" SET DEFAULT CURRENT_TIMESTAMP;
@@ -0,0 +1,5 @@
+-- DropForeignKey
+ALTER TABLE "RoleQuota" DROP CONSTRAINT "RoleQuota_roleId_fkey";
+
+-- DropTable
+DROP TABLE "RoleQuota";
@@ -0,0 +1,20 @@
+-- CreateTable
+CREATE TABLE "RoleQuota" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    "roleId" TEXT NOT NULL,
+    "vcpus" INTEGER NOT NULL,
+    "ramMb" INTEGER NOT NULL,
+    "instances" INTEGER NOT NULL,
+    "volumes" INTEGER NOT NULL,
+    "floatingIps" INTEGER NOT NULL,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updatedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "RoleQuota_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "RoleQuota_roleId_key" ON "RoleQuota"("roleId");
+
+-- AddForeignKey
+ALTER TABLE "RoleQuota" ADD CONSTRAINT "RoleQuota_roleId_fkey" FOREIGN KEY ("roleId") REFERENCES "Role"("id") ON DELETE CASCADE ON UPDATE CASCADE;
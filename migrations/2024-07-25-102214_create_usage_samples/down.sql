@@ -0,0 +1,5 @@
+-- DropForeignKey
+ALTER TABLE "UsageSample" DROP CONSTRAINT "UsageSample_userId_fkey";
+
+-- DropTable
+DROP TABLE "UsageSample";
@@ -0,0 +1,24 @@
+-- CreateTable
+CREATE TABLE "UsageSample" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    "userId" TEXT NOT NULL,
+    "cloudProvider" "CloudProvider" NOT NULL,
+    "resourceType" TEXT NOT NULL,
+    "resourceId" TEXT NOT NULL,
+    "resourceName" TEXT NOT NULL,
+    "status" TEXT NOT NULL,
+    "flavorId" TEXT,
+    "vcpus" INTEGER NOT NULL,
+    "ramMb" INTEGER NOT NULL,
+    "storageGb" INTEGER NOT NULL,
+    "intervalSeconds" INTEGER NOT NULL,
+    "sampledAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "UsageSample_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE INDEX "UsageSample_userId_sampledAt_idx" ON "UsageSample"("userId", "sampledAt");
+
+-- AddForeignKey
+ALTER TABLE "UsageSample" ADD CONSTRAINT "UsageSample_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User"("id") ON DELETE CASCADE ON UPDATE CASCADE;
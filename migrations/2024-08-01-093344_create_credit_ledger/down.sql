@@ -0,0 +1,15 @@
+-- DropTrigger
+DROP TRIGGER "CreditLedger_append_only" ON "CreditLedger";
+DROP FUNCTION "CreditLedger_append_only"();
+
+-- DropForeignKey
+ALTER TABLE "CreditLedger" DROP CONSTRAINT "CreditLedger_userId_fkey";
+
+-- DropTable
+DROP TABLE "Price";
+
+-- DropTable
+DROP TABLE "CreditLedger";
+
+-- DropEnum
+DROP TYPE "LedgerEntryType";
@@ -0,0 +1,52 @@
+-- CreateEnum
+CREATE TYPE "LedgerEntryType" AS ENUM ('GRANT', 'CHARGE', 'REFUND');
+
+-- CreateTable
+CREATE TABLE "CreditLedger" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    "userId" TEXT NOT NULL,
+    "entryType" "LedgerEntryType" NOT NULL,
+    -- Milli-credits, negative for charges
+    "amount" BIGINT NOT NULL,
+    "description" TEXT NOT NULL,
+    -- Start of the usage period a usage charge is for
+    "usagePeriod" TIMESTAMP(3),
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "CreditLedger_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateTable
+CREATE TABLE "Price" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    -- 'flavor:<flavor id>' per running instance, or 'storage' per volume GB
+    "resource" TEXT NOT NULL,
+    "milliCreditsPerHour" BIGINT NOT NULL,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updatedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "Price_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE INDEX "CreditLedger_userId_createdAt_idx" ON "CreditLedger"("userId", "createdAt");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "CreditLedger_userId_usagePeriod_key" ON "CreditLedger"("userId", "usagePeriod");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "Price_resource_key" ON "Price"("resource");
+
+-- AddForeignKey
+ALTER TABLE "CreditLedger" ADD CONSTRAINT "CreditLedger_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User"("id") ON DELETE RESTRICT ON UPDATE CASCADE;
+
+-- The ledger is append-only, corrections are made with new entries
+CREATE FUNCTION "CreditLedger_append_only"() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'CreditLedger is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "CreditLedger_append_only"
+    BEFORE UPDATE OR DELETE ON "CreditLedger"
+    FOR EACH ROW EXECUTE FUNCTION "CreditLedger_append_only"();
@@ -0,0 +1,10 @@
+-- DeleteRows
+DELETE FROM "UsageSample" WHERE "cloudProvider" = 'FAKE';
+DELETE FROM "CloudUser" WHERE "cloudProvider" = 'FAKE';
+
+-- AlterEnum
+ALTER TYPE "CloudProvider" RENAME TO "CloudProvider_old";
+CREATE TYPE "CloudProvider" AS ENUM ('OPENSTACK', 'PIKACLOUD');
+ALTER TABLE "CloudUser" ALTER COLUMN "cloudProvider" TYPE "CloudProvider" USING "cloudProvider"::text::"CloudProvider";
+ALTER TABLE "UsageSample" ALTER COLUMN "cloudProvider" TYPE "CloudProvider" USING "cloudProvider"::text::"CloudProvider";
+DROP TYPE "CloudProvider_old";
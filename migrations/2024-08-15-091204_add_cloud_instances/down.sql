@@ -0,0 +1,14 @@
+-- DropForeignKey
+ALTER TABLE "CloudPlacement" DROP CONSTRAINT "CloudPlacement_roleId_fkey";
+
+-- DropForeignKey
+ALTER TABLE "CloudPlacement" DROP CONSTRAINT "CloudPlacement_userId_fkey";
+
+-- DropTable
+DROP TABLE "CloudPlacement";
+
+-- DropIndex
+DROP INDEX "CloudUser_userId_cloudProvider_key";
+
+-- AlterTable
+ALTER TABLE "CloudUser" DROP COLUMN "cloudInstance";
@@ -0,0 +1,33 @@
+-- AlterTable
+-- Rows predate named instances and belong to the instance named after their provider
+ALTER TABLE "CloudUser" ADD COLUMN "cloudInstance" TEXT;
+UPDATE "CloudUser" SET "cloudInstance" = lower("cloudProvider"::text);
+ALTER TABLE "CloudUser" ALTER COLUMN "cloudInstance" SET NOT NULL;
+
+-- CreateIndex
+-- One account per provider, a second PROVISION_ACCOUNT job racing the first fails here
+CREATE UNIQUE INDEX "CloudUser_userId_cloudProvider_key" ON "CloudUser"("userId", "cloudProvider");
+
+-- CreateTable
+CREATE TABLE "CloudPlacement" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    "cloudProvider" "CloudProvider" NOT NULL,
+    "cloudInstance" TEXT NOT NULL,
+    -- At most one of userId and roleId, a rule with neither applies to everyone
+    "userId" TEXT,
+    "roleId" TEXT,
+    "priority" INTEGER NOT NULL DEFAULT 0,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "CloudPlacement_pkey" PRIMARY KEY ("id"),
+    CONSTRAINT "CloudPlacement_target_check" CHECK ("userId" IS NULL OR "roleId" IS NULL)
+);
+
+-- CreateIndex
+CREATE INDEX "CloudPlacement_cloudProvider_idx" ON "CloudPlacement"("cloudProvider");
+
+-- AddForeignKey
+ALTER TABLE "CloudPlacement" ADD CONSTRAINT "CloudPlacement_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User"("id") ON DELETE CASCADE ON UPDATE CASCADE;
+
+-- AddForeignKey
+ALTER TABLE "CloudPlacement" ADD CONSTRAINT "CloudPlacement_roleId_fkey" FOREIGN KEY ("roleId") REFERENCES "Role"("id") ON DELETE CASCADE ON UPDATE CASCADE;
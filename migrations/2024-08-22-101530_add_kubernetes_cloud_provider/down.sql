@@ -0,0 +1,12 @@
+-- DeleteRows
+DELETE FROM "CloudPlacement" WHERE "cloudProvider" = 'KUBERNETES';
+DELETE FROM "UsageSample" WHERE "cloudProvider" = 'KUBERNETES';
+DELETE FROM "CloudUser" WHERE "cloudProvider" = 'KUBERNETES';
+
+-- AlterEnum
+ALTER TYPE "CloudProvider" RENAME TO "CloudProvider_old";
+CREATE TYPE "CloudProvider" AS ENUM ('OPENSTACK', 'PIKACLOUD', 'FAKE');
+ALTER TABLE "CloudUser" ALTER COLUMN "cloudProvider" TYPE "CloudProvider" USING "cloudProvider"::text::"CloudProvider";
+ALTER TABLE "UsageSample" ALTER COLUMN "cloudProvider" TYPE "CloudProvider" USING "cloudProvider"::text::"CloudProvider";
+ALTER TABLE "CloudPlacement" ALTER COLUMN "cloudProvider" TYPE "CloudProvider" USING "cloudProvider"::text::"CloudProvider";
+DROP TYPE "CloudProvider_old";
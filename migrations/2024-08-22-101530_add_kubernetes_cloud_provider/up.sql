@@ -0,0 +1,2 @@
+-- AlterEnum
+ALTER TYPE "CloudProvider" ADD VALUE 'KUBERNETES';
@@ -0,0 +1,8 @@
+-- DropTable
+DROP TABLE "Job";
+
+-- DropEnum
+DROP TYPE "JobStatus";
+
+-- DropEnum
+DROP TYPE "JobKind";
@@ -0,0 +1,43 @@
+-- CreateEnum
+CREATE TYPE "JobKind" AS ENUM ('PROVISION_ACCOUNT', 'CREATE_INSTANCE', 'DELETE_INSTANCE');
+
+-- CreateEnum
+CREATE TYPE "JobStatus" AS ENUM ('PENDING', 'RUNNING', 'SUCCEEDED', 'FAILED', 'CANCELLED');
+
+-- CreateTable
+CREATE TABLE "Job" (
+    "id" TEXT NOT NULL DEFAULT gen_random_uuid()::text,
+    "userId" TEXT NOT NULL,
+    "kind" "JobKind" NOT NULL,
+    "cloudProvider" "CloudProvider" NOT NULL,
+    -- JSON arguments of the operation
+    "payload" TEXT NOT NULL,
+    "status" "JobStatus" NOT NULL DEFAULT 'PENDING',
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "maxAttempts" INTEGER NOT NULL,
+    -- Pending jobs are not picked up before this, retries are pushed back here
+    "runAfter" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "cancelRequested" BOOLEAN NOT NULL DEFAULT false,
+    -- JSON outcome of a succeeded job
+    "result" TEXT,
+    -- Last failure
+    "error" TEXT,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updatedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "finishedAt" TIMESTAMP(3),
+    -- Process running the job and when it last reported that it still does,
+    -- a running job without recent heartbeats lost its worker
+    "workerId" TEXT,
+    "heartbeatAt" TIMESTAMP(3),
+
+    CONSTRAINT "Job_pkey" PRIMARY KEY ("id")
+);
+
+-- CreateIndex
+CREATE INDEX "Job_userId_createdAt_idx" ON "Job"("userId", "createdAt");
+
+-- CreateIndex
+CREATE INDEX "Job_status_runAfter_idx" ON "Job"("status", "runAfter");
+
+-- AddForeignKey
+ALTER TABLE "Job" ADD CONSTRAINT "Job_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User"("id") ON DELETE CASCADE ON UPDATE CASCADE;
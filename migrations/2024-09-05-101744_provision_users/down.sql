@@ -0,0 +1,12 @@
+-- Seeded roles are kept, users may hold them
+
+-- DropIndex
+DROP INDEX "UserRole_userId_roleId_key";
+
+-- AlterTable
+ALTER TABLE "UserRole" ALTER COLUMN "id" DROP DEFAULT,
+    ALTER COLUMN "updatedAt" DROP DEFAULT;
+ALTER TABLE "Role" ALTER COLUMN "id" DROP DEFAULT,
+    ALTER COLUMN "updatedAt" DROP DEFAULT;
+ALTER TABLE "User" ALTER COLUMN "id" DROP DEFAULT,
+    ALTER COLUMN "updatedAt" DROP DEFAULT;
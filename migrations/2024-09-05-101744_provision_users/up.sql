@@ -0,0 +1,20 @@
+-- AlterTable
+-- Let users, roles and their links be created in a single statement each
+ALTER TABLE "User" ALTER COLUMN "id" SET DEFAULT gen_random_uuid()::text,
+    ALTER COLUMN "updatedAt" SET DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE "Role" ALTER COLUMN "id" SET DEFAULT gen_random_uuid()::text,
+    ALTER COLUMN "updatedAt" SET DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE "UserRole" ALTER COLUMN "id" SET DEFAULT gen_random_uuid()::text,
+    ALTER COLUMN "updatedAt" SET DEFAULT CURRENT_TIMESTAMP;
+
+-- Keep the oldest of duplicated role assignments
+DELETE FROM "UserRole" a USING "UserRole" b
+WHERE a."userId" = b."userId" AND a."roleId" = b."roleId"
+    AND (a."createdAt", a."id") > (b."createdAt", b."id");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "UserRole_userId_roleId_key" ON "UserRole"("userId", "roleId");
+
+-- Seed the roles logins rely on, new users get "member"
+INSERT INTO "Role" ("name") VALUES ('member'), ('admin')
+ON CONFLICT ("name") DO NOTHING;
@@ -0,0 +1,12 @@
+-- DropTrigger
+DROP TRIGGER "Job_set_updatedAt" ON "Job";
+DROP TRIGGER "Price_set_updatedAt" ON "Price";
+DROP TRIGGER "RoleQuota_set_updatedAt" ON "RoleQuota";
+DROP TRIGGER "SshKey_set_updatedAt" ON "SshKey";
+DROP TRIGGER "CloudUser_set_updatedAt" ON "CloudUser";
+DROP TRIGGER "UserRole_set_updatedAt" ON "UserRole";
+DROP TRIGGER "Role_set_updatedAt" ON "Role";
+DROP TRIGGER "User_set_updatedAt" ON "User";
+
+-- DropFunction
+DROP FUNCTION "set_updated_at"();
@@ -0,0 +1,26 @@
+-- CreateFunction
+-- Bump "updatedAt" on every update, so writers do not have to remember it
+CREATE FUNCTION "set_updated_at"() RETURNS TRIGGER AS $$
+BEGIN
+    NEW."updatedAt" = CURRENT_TIMESTAMP;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- CreateTrigger
+CREATE TRIGGER "User_set_updatedAt" BEFORE UPDATE ON "User"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "Role_set_updatedAt" BEFORE UPDATE ON "Role"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "UserRole_set_updatedAt" BEFORE UPDATE ON "UserRole"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "CloudUser_set_updatedAt" BEFORE UPDATE ON "CloudUser"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "SshKey_set_updatedAt" BEFORE UPDATE ON "SshKey"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "RoleQuota_set_updatedAt" BEFORE UPDATE ON "RoleQuota"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "Price_set_updatedAt" BEFORE UPDATE ON "Price"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
+CREATE TRIGGER "Job_set_updatedAt" BEFORE UPDATE ON "Job"
+    FOR EACH ROW EXECUTE FUNCTION "set_updated_at"();
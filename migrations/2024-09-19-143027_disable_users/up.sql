@@ -0,0 +1,3 @@
+-- AlterTable
+-- Disabled users cannot log in, set with `pikactl user disable`
+ALTER TABLE "User" ADD COLUMN "disabledAt" TIMESTAMP(3);
@@ -1,9 +1,13 @@
-use std::env;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use crate::{auth::{AuthError, get_result_from_resp}, db::DBClient};
+use crate::{
+    auth::{get_result_from_resp, AuthError},
+    db::DBClient,
+    repository::{PgRepository, Repository},
+};
 
 use super::BaseAuthProvider;
 
@@ -69,7 +73,7 @@ fn md5_hash(msg: &String) -> String {
     format!("{:x}", digest)
 }
 
-const VALIDATE_ENDPOINT: &'static str = "https://iaaa.pku.edu.cn/iaaa/svc/token/validate.do";
+const VALIDATE_ENDPOINT: &str = "https://iaaa.pku.edu.cn/iaaa/svc/token/validate.do";
 
 pub async fn validate(
     remote_addr: String,
@@ -86,7 +90,7 @@ pub async fn validate(
         .json::<IAAAValidateResponse>()
         .await
         .map_err(|e| IaaaError::Deserialize(e.to_string()))?;
-    return Ok(data);
+    Ok(data)
 }
 
 pub type IaaaResult<T> = std::result::Result<T, IaaaError>;
@@ -98,33 +102,36 @@ pub enum IaaaError {
     Deserialize(String),
 }
 
+/// Application registered with IAAA
+#[derive(Debug, Clone, PartialEq)]
+pub struct IaaaConfig {
+    /// `IAAA_ID`
+    pub id: String,
+    /// `IAAA_KEY`
+    pub key: String,
+}
+
 /// IAAA authentication provider
 pub struct IaaaAuthProvider {
-    client: DBClient,
+    repository: Arc<dyn Repository>,
     iaaa_id: String,
     iaaa_key: String,
     enable_mfa: bool,
 }
 
-#[async_trait]
-impl BaseAuthProvider for IaaaAuthProvider {
-    fn new(client: DBClient) -> Self {
-        let iaaa_id = env::var("IAAA_ID").expect("Must set IAAA_ID");
-        let iaaa_key = env::var("IAAA_KEY").expect("Must set IAAA_KEY");
-
-        let enable_mfa = env::var("PIKA_ENABLE_MFA")
-            .unwrap_or_else(|_| "false".to_string())
-            .parse::<bool>()
-            .unwrap_or(false);
-
+impl IaaaAuthProvider {
+    pub fn new(client: DBClient, config: IaaaConfig, enable_mfa: bool) -> Self {
         Self {
-            iaaa_id,
-            iaaa_key,
-            client,
+            repository: PgRepository::shared(client),
+            iaaa_id: config.id,
+            iaaa_key: config.key,
             enable_mfa,
         }
     }
+}
 
+#[async_trait]
+impl BaseAuthProvider for IaaaAuthProvider {
     fn enable_mfa(&self) -> bool {
         self.enable_mfa
     }
@@ -135,13 +142,10 @@ impl BaseAuthProvider for IaaaAuthProvider {
 
     /// Login with IAAA authentication
     async fn login(
-        &mut self,
+        &self,
         payload: serde_json::Value,
         ip_address: Option<String>,
     ) -> Result<(String, Vec<String>), AuthError> {
-        // Get connection to database
-        let conn = &mut self.client.get_conn()?;
-
         #[derive(Deserialize)]
         struct LoginPayLoad {
             token: String,
@@ -164,11 +168,11 @@ impl BaseAuthProvider for IaaaAuthProvider {
             return Err(AuthError::Unauthorized("Fail to authorize".into()));
         }
 
-        get_result_from_resp(conn, resp).await
+        get_result_from_resp(self.repository.as_ref(), resp).await
     }
 
     async fn register(
-        &mut self,
+        &self,
         _payload: serde_json::Value,
     ) -> Result<(String, Vec<String>), AuthError> {
         unreachable!("IAAA should not call register!")
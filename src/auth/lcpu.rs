@@ -1,16 +1,30 @@
-use std::env;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use serde::Deserialize;
 
 use crate::auth::{get_result_from_resp, iaaa::IAAAValidateResponse};
-use crate::{db::DBClient, utils::load_env_panic};
+use crate::{
+    db::DBClient,
+    repository::{PgRepository, Repository},
+};
 
 use super::{AuthError, BaseAuthProvider};
 
+/// Application registered with LCPU
+#[derive(Debug, Clone, PartialEq)]
+pub struct LcpuConfig {
+    /// `LCPU_APP_ROOT`
+    pub app_root: String,
+    /// `LCPU_APP_ID`
+    pub app_id: String,
+    /// `LCPU_APP_KEY`
+    pub app_key: String,
+}
+
 /// LCPU authentication provider
 pub struct LcpuAuthProvider {
-    client: DBClient,
+    repository: Arc<dyn Repository>,
     req_client: reqwest::Client,
     app_id: String,
     app_key: String,
@@ -18,29 +32,21 @@ pub struct LcpuAuthProvider {
     enable_mfa: bool,
 }
 
-#[async_trait]
-impl BaseAuthProvider for LcpuAuthProvider {
-    fn new(client: DBClient) -> Self
-    where
-        Self: Sized,
-    {
-        let app_id = load_env_panic("LCPU_APP_ID");
-        let app_key = load_env_panic("LCPU_APP_KEY");
-        let app_root = load_env_panic("LCPU_APP_ROOT");
-        let enable_mfa = env::var("PIKA_ENABLE_MFA")
-            .unwrap_or_else(|_| "false".to_string())
-            .parse::<bool>()
-            .unwrap_or(false);
+impl LcpuAuthProvider {
+    pub fn new(client: DBClient, config: LcpuConfig, enable_mfa: bool) -> Self {
         Self {
-            client,
+            repository: PgRepository::shared(client),
             req_client: reqwest::Client::new(),
-            app_id,
-            app_key,
-            app_root,
+            app_id: config.app_id,
+            app_key: config.app_key,
+            app_root: config.app_root,
             enable_mfa,
         }
     }
+}
 
+#[async_trait]
+impl BaseAuthProvider for LcpuAuthProvider {
     fn enable_mfa(&self) -> bool {
         self.enable_mfa
     }
@@ -50,12 +56,10 @@ impl BaseAuthProvider for LcpuAuthProvider {
     }
 
     async fn login(
-        &mut self,
+        &self,
         payload: serde_json::Value,
         ip_address: Option<String>,
     ) -> Result<(String, Vec<String>), AuthError> {
-        let conn = &mut self.client.get_conn()?;
-
         #[derive(Deserialize)]
         struct LoginPayLoad {
             token: String,
@@ -97,11 +101,11 @@ impl BaseAuthProvider for LcpuAuthProvider {
             return Err(AuthError::Unauthorized("Fail to send request".into()));
         };
 
-        get_result_from_resp(conn, resp).await
+        get_result_from_resp(self.repository.as_ref(), resp).await
     }
 
     async fn register(
-        &mut self,
+        &self,
         _payload: serde_json::Value,
     ) -> Result<(String, Vec<String>), AuthError> {
         unreachable!("LCPU should not call register!")
@@ -1,52 +1,64 @@
 use async_trait::async_trait;
 use serde::Deserialize;
-use std::env;
+use std::sync::Arc;
 
 use crate::{
     db::DBClient,
-    models::{self, NewUserRole, PasswordNewUser},
-    schema,
+    models::{self, NewUser},
+    repository::{PgRepository, Repository},
 };
 
-use super::{AuthError, BaseAuthProvider};
-use diesel::prelude::*;
+use super::{
+    ensure_enabled, provision_error, AuthError, AuthResult, BaseAuthProvider, DEFAULT_ROLE,
+};
+
+/// Whether users may sign up with a password. Logging in with one only needs
+/// the provider to be enabled.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PasswordConfig {
+    /// `PIKA_ALLOW_PASSWORD_REGISTER`
+    pub allow_register: bool,
+}
 
 pub struct PasswordAuthProvider {
-    client: DBClient,
+    repository: Arc<dyn Repository>,
     enable_mfa: bool,
-    allow_password_login: bool,
     allow_register: bool,
 }
 
-#[async_trait]
-impl BaseAuthProvider for PasswordAuthProvider {
-    fn new(client: DBClient) -> Self
-    where
-        Self: Sized,
-    {
-        let allow_password_login = env::var("PIKA_ALLOW_PASSWORD_LOGIN")
-            .unwrap_or_else(|_| "false".to_string())
-            .parse::<bool>()
-            .unwrap_or(false);
-
-        let allow_register = env::var("PIKA_ALLOW_PASSWORD_REGISTER")
-            .unwrap_or_else(|_| "false".to_string())
-            .parse::<bool>()
-            .unwrap_or(false);
-
-        let enable_mfa = env::var("PIKA_ENABLE_MFA")
-            .unwrap_or_else(|_| "false".to_string())
-            .parse::<bool>()
-            .unwrap_or(false);
-
+impl PasswordAuthProvider {
+    pub fn new(client: DBClient, config: &PasswordConfig, enable_mfa: bool) -> Self {
         Self {
-            client,
+            repository: PgRepository::shared(client),
             enable_mfa,
-            allow_password_login,
+            allow_register: config.allow_register,
+        }
+    }
+
+    /// A provider on top of `repository` rather than the database, with MFA
+    /// disabled
+    pub fn with_repository(repository: Arc<dyn Repository>, allow_register: bool) -> Self {
+        Self {
+            repository,
+            enable_mfa: false,
             allow_register,
         }
     }
+}
+
+/// bcrypt is slow on purpose, so it runs on the blocking pool
+async fn blocking<T, F>(f: F) -> AuthResult<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| AuthError::InternalServerError(e.to_string()))
+}
 
+#[async_trait]
+impl BaseAuthProvider for PasswordAuthProvider {
     fn enable_mfa(&self) -> bool {
         self.enable_mfa
     }
@@ -56,13 +68,10 @@ impl BaseAuthProvider for PasswordAuthProvider {
     }
 
     async fn login(
-        &mut self,
+        &self,
         payload: serde_json::Value,
         _ip_address: Option<String>,
     ) -> Result<(String, Vec<String>), AuthError> {
-        // Get connection to database
-        let mut conn = self.client.get_conn()?;
-
         #[derive(Deserialize)]
         struct LoginPayload {
             f_username: String,
@@ -81,70 +90,34 @@ impl BaseAuthProvider for PasswordAuthProvider {
             ));
         }
 
-        let user = schema::User::dsl::User
-            .filter(schema::User::username.eq(f_username))
-            .select(models::User::as_select())
-            .first(&mut conn);
-        let user = match user {
-            Err(e) => {
-                return Err(AuthError::Unauthorized(format!(
-                    "Invalid username or password: {e}"
-                )));
-            }
-            Ok(user) => user,
+        let Some(user) = self.repository.find_user_by_username(&f_username).await? else {
+            return Err(AuthError::Unauthorized(
+                "Invalid username or password".into(),
+            ));
         };
 
         // Crypt password and user's password, and compare them
-        let is_match = user.password.is_some()
-            && bcrypt::verify(&f_password, &user.password.unwrap())
-                .map_err(|_| AuthError::Unauthorized("Invalid username or password".into()))?;
-
+        let is_match = match user.password.clone() {
+            Some(hash) => blocking(move || bcrypt::verify(&f_password, &hash))
+                .await?
+                .map_err(|_| AuthError::Unauthorized("Invalid username or password".into()))?,
+            None => false,
+        };
         if !is_match {
             return Err(AuthError::Unauthorized(
                 "Invalid username or password".into(),
             ));
         }
 
-        // Find UserRoles by user id
-        // let roles = self.client.user_role().find_many("id", &user.id).await?;
-        let roles = schema::UserRole::dsl::UserRole
-            .filter(schema::UserRole::id.eq(&user.id))
-            .select(models::UserRole::as_select())
-            .load(&mut conn);
-        let roles = match roles {
-            Err(_) => {
-                return Err(AuthError::Unauthorized(
-                    "Invalid username or password".into(),
-                ))
-            }
-            Ok(roles) => roles,
-        };
-
-        let roles_id: Vec<String> = roles.into_iter().map(|r| r.roleId).collect();
-
-        // For each UserRole's role id, get correspond Role's name
-        // let role_names = self.client.role().find_many("id", roles_id).await?;
-        let role_names = schema::Role::dsl::Role
-            .filter(schema::Role::id.eq_any(roles_id))
-            .select(models::Role::as_select())
-            .load(&mut conn);
-        let role_names = match role_names {
-            Err(_) => {
-                return Err(AuthError::Unauthorized(
-                    "Invalid username or password".into(),
-                ))
-            }
-            Ok(role_names) => role_names,
-        };
-
-        Ok((user.id, role_names.into_iter().map(|r| r.name).collect()))
+        ensure_enabled(&user)?;
+        let roles = self.repository.role_names(&user.id).await?;
+        Ok((user.id, roles))
     }
 
     async fn register(
-        &mut self,
+        &self,
         payload: serde_json::Value,
     ) -> Result<(String, Vec<String>), AuthError> {
-        let mut conn = self.client.get_conn()?;
         #[derive(Deserialize)]
         struct RegisterPayload {
             f_username: String,
@@ -168,45 +141,34 @@ impl BaseAuthProvider for PasswordAuthProvider {
             ));
         }
 
-        // let existing_user = self.client.user().find_unique("username", &username).await;
-        let existing_user = schema::User::dsl::User
-            .filter(schema::User::username.eq(&f_username))
-            .select(models::User::as_select())
-            .first(&mut conn);
-        if existing_user.is_ok() {
+        if self
+            .repository
+            .find_user_by_username(&f_username)
+            .await?
+            .is_some()
+        {
             return Err(AuthError::Conflict("User already exists".into()));
         }
 
-        let hashed_password = bcrypt::hash(&f_password, 10)
+        let hashed_password = blocking(move || bcrypt::hash(&f_password, 10))
+            .await?
             .map_err(|_| AuthError::Unauthorized("bcrypt error".into()))?;
-        let new_user = PasswordNewUser {
+        let new_user = NewUser {
             username: f_username,
-            password: Some(hashed_password),
             loginProvider: models::LoginProvider::PASSWORD,
+            name: None,
+            password: Some(hashed_password),
         };
-        let new_user = diesel::insert_into(schema::User::table)
-            .values(&new_user)
-            .returning(models::User::as_returning())
-            .get_result(&mut conn)
-            .map_err(|_| AuthError::InternalServerError("Failed to create user".into()))?;
-
-        let default_role = schema::Role::dsl::Role
-            .filter(schema::Role::name.eq(&"member".to_string()))
-            .select(models::Role::as_select())
-            .first(&mut conn)
-            .map_err(|_| AuthError::InternalServerError("Default role not found".into()))?;
-
-        let new_user_role = NewUserRole {
-            userId: new_user.id.clone(),
-            roleId: default_role.id,
-        };
-
-        diesel::insert_into(schema::UserRole::table)
-            .values(&new_user_role)
-            .returning(models::UserRole::as_returning())
-            .get_result(&mut conn)
-            .map_err(|_| AuthError::InternalServerError("Failed to create new user role".into()))?;
+        let provisioned = self
+            .repository
+            .provision_user(new_user, DEFAULT_ROLE)
+            .await
+            .map_err(provision_error)?;
+        // Someone registered the name since the check above
+        if !provisioned.created {
+            return Err(AuthError::Conflict("User already exists".into()));
+        }
 
-        Ok((new_user.id, vec!["member".into()]))
+        Ok((provisioned.user.id, provisioned.roles))
     }
 }
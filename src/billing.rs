@@ -0,0 +1,337 @@
+//! Credit billing
+//!
+//! Every user has a balance, the sum of their `CreditLedger` entries. Admins
+//! grant credits, each usage collection charges the sampled resources at the
+//! prices in `Price`, and once a balance has stayed at or below zero for the
+//! grace period the user's running instances are stopped.
+
+use std::collections::HashMap;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::sql_types::{BigInt, Text, Timestamp};
+use serde::Serialize;
+
+use crate::{
+    clouds::CloudError,
+    models::{self, LedgerEntryType, NewCreditLedger, NewUsageSample},
+    schema,
+    server::AppState,
+};
+
+/// Ledger amounts and prices are stored in thousandths of a credit
+pub const MILLI_CREDITS: i64 = 1000;
+
+/// `Price.resource` of a running instance of the given flavor
+pub fn flavor_price_resource(flavor_id: &str) -> String {
+    format!("flavor:{flavor_id}")
+}
+
+/// `Price.resource` of one GB of volume storage
+pub const STORAGE_PRICE_RESOURCE: &str = "storage";
+
+/// Cache key marking an instance `enforce_balances` has stopped, so it is not
+/// stopped again on every collection while Nova is still powering it off
+pub fn stopped_instance_key(cloud_instance: &str, instance_id: &str) -> String {
+    format!("billing:stopped:{cloud_instance}:{instance_id}")
+}
+
+pub fn to_credits(milli_credits: i64) -> f64 {
+    milli_credits as f64 / MILLI_CREDITS as f64
+}
+
+pub fn to_milli_credits(credits: f64) -> i64 {
+    (credits * MILLI_CREDITS as f64).round() as i64
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BillingError {
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("Fail to fetch connection: {0}")]
+    Connection(#[from] crate::db::DBError),
+    #[error("Cloud error: {0}")]
+    Cloud(#[from] CloudError),
+}
+
+pub type BillingResult<T> = std::result::Result<T, BillingError>;
+
+pub fn balance(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: &str,
+) -> BillingResult<i64> {
+    let balance = schema::CreditLedger::table
+        .filter(schema::CreditLedger::userId.eq(user_id))
+        .select(diesel::dsl::sql::<BigInt>(
+            r#"COALESCE(SUM("amount"), 0)::bigint"#,
+        ))
+        .first(conn)?;
+    Ok(balance)
+}
+
+#[derive(Debug, Clone, QueryableByName, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceRow {
+    #[diesel(sql_type = Text)]
+    pub user_id: String,
+    #[diesel(sql_type = Text)]
+    pub username: String,
+    #[diesel(sql_type = BigInt)]
+    pub milli_credits: i64,
+}
+
+/// Balances of every user with at least one ledger entry
+pub fn balances(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+) -> BillingResult<Vec<BalanceRow>> {
+    let rows = diesel::sql_query(
+        r#"SELECT l."userId" AS user_id, u."username" AS username,
+            SUM(l."amount")::bigint AS milli_credits
+        FROM "CreditLedger" l
+        JOIN "User" u ON u."id" = l."userId"
+        GROUP BY l."userId", u."username"
+        ORDER BY u."username""#,
+    )
+    .load(conn)?;
+    Ok(rows)
+}
+
+/// Append one entry per user, all with the same type, amount and description
+pub fn append_entries(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_ids: &[String],
+    entry_type: LedgerEntryType,
+    milli_credits: i64,
+    description: &str,
+) -> BillingResult<usize> {
+    let entries: Vec<NewCreditLedger> = user_ids
+        .iter()
+        .map(|user_id| NewCreditLedger {
+            userId: user_id.clone(),
+            entryType: entry_type,
+            amount: milli_credits,
+            description: description.to_string(),
+            usagePeriod: None,
+        })
+        .collect();
+    let count = diesel::insert_into(schema::CreditLedger::table)
+        .values(&entries)
+        .execute(conn)?;
+    Ok(count)
+}
+
+/// Cost of the samples per user, in milli-credits. Only running instances
+/// and volumes are billed, resources without a price are free. Each user's
+/// total is rounded once, to the nearest milli-credit, so cheap resources
+/// sampled often still add up.
+pub fn price_samples(
+    prices: &HashMap<String, i64>,
+    samples: &[NewUsageSample],
+) -> HashMap<String, i64> {
+    // Milli-credit seconds per hour, i.e. milli-credits times 3600
+    let mut costs: HashMap<String, i64> = HashMap::new();
+    for sample in samples {
+        let hourly = match sample.resourceType.as_str() {
+            "instance" if sample.status == "ACTIVE" => sample
+                .flavorId
+                .as_deref()
+                .and_then(|flavor_id| prices.get(&flavor_price_resource(flavor_id)))
+                .copied()
+                .unwrap_or(0),
+            "volume" => {
+                prices.get(STORAGE_PRICE_RESOURCE).copied().unwrap_or(0) * sample.storageGb as i64
+            }
+            _ => 0,
+        };
+        *costs.entry(sample.userId.clone()).or_default() += hourly * sample.intervalSeconds as i64;
+    }
+    costs
+        .into_iter()
+        .map(|(user_id, cost)| (user_id, (cost + 1800) / 3600))
+        .collect()
+}
+
+/// Store the usage samples collected in the period starting at `period` and
+/// charge them to their owners' ledgers, in one transaction so no sample is
+/// stored without its charge. A period is charged at most once per user, the
+/// samples of a period that was already charged are dropped. Returns the
+/// number of ledger entries.
+pub fn charge_usage(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    samples: &[NewUsageSample],
+    period: NaiveDateTime,
+) -> BillingResult<usize> {
+    conn.transaction(|conn| {
+        let charged = diesel::select(diesel::dsl::exists(
+            schema::CreditLedger::table.filter(schema::CreditLedger::usagePeriod.eq(period)),
+        ))
+        .get_result::<bool>(conn)?;
+        if charged {
+            log::warn!("Usage of the period starting at {period} was already charged");
+            return Ok(0);
+        }
+        diesel::insert_into(schema::UsageSample::table)
+            .values(samples)
+            .execute(conn)?;
+        charge_samples(conn, samples, period)
+    })
+}
+
+fn charge_samples(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    samples: &[NewUsageSample],
+    period: NaiveDateTime,
+) -> BillingResult<usize> {
+    let prices: HashMap<String, i64> = schema::Price::dsl::Price
+        .select(models::Price::as_select())
+        .load(conn)?
+        .into_iter()
+        .map(|price| (price.resource, price.milliCreditsPerHour))
+        .collect();
+    if prices.is_empty() {
+        return Ok(0);
+    }
+
+    let interval = samples.first().map_or(0, |s| s.intervalSeconds);
+    let entries: Vec<NewCreditLedger> = price_samples(&prices, samples)
+        .into_iter()
+        .filter(|(_, cost)| *cost > 0)
+        .map(|(user_id, cost)| NewCreditLedger {
+            userId: user_id,
+            entryType: LedgerEntryType::CHARGE,
+            amount: -cost,
+            description: format!("Usage over {interval}s"),
+            usagePeriod: Some(period),
+        })
+        .collect();
+    // The unique key on user and period keeps concurrent charges of the same
+    // period from billing anyone twice
+    let count = diesel::insert_into(schema::CreditLedger::table)
+        .values(&entries)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(count)
+}
+
+#[derive(Debug, Clone, QueryableByName)]
+struct DepletedRow {
+    #[diesel(sql_type = Text)]
+    user_id: String,
+    #[diesel(sql_type = Timestamp)]
+    depleted_since: NaiveDateTime,
+}
+
+/// Users whose balance is at or below zero, with the time of the entry that
+/// took it there
+fn depleted_users(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+) -> BillingResult<Vec<DepletedRow>> {
+    let rows = diesel::sql_query(
+        r#"WITH running AS (
+            SELECT "userId", "createdAt",
+                SUM("amount") OVER (PARTITION BY "userId" ORDER BY "createdAt", "id") AS balance
+            FROM "CreditLedger"
+        ), last_positive AS (
+            SELECT "userId", MAX("createdAt") FILTER (WHERE balance > 0) AS at
+            FROM running
+            GROUP BY "userId"
+        )
+        SELECT r."userId" AS user_id, MIN(r."createdAt") AS depleted_since
+        FROM running r
+        JOIN last_positive p ON p."userId" = r."userId"
+        WHERE r.balance <= 0 AND (p.at IS NULL OR r."createdAt" > p.at)
+        GROUP BY r."userId""#,
+    )
+    .load(conn)?;
+    Ok(rows)
+}
+
+/// Stop the running instances of users whose balance stayed depleted longer
+/// than `grace`, leaving disabled users alone. An instance is stopped at most
+/// once per grace period, later collections skip it. The usage collector runs
+/// this under its advisory lock, so replicas never enforce at the same time.
+/// Returns the number of instances stopped.
+pub async fn enforce_balances(state: &AppState, grace: chrono::Duration) -> BillingResult<usize> {
+    let deadline = Utc::now().naive_utc() - grace;
+    let cloud_users = state
+        .db
+        .run(move |conn| {
+            let user_ids: Vec<String> = depleted_users(conn)?
+                .into_iter()
+                .filter(|row| row.depleted_since <= deadline)
+                .map(|row| row.user_id)
+                .collect();
+            if user_ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            Ok::<_, BillingError>(
+                schema::CloudUser::table
+                    .inner_join(schema::User::table)
+                    .filter(schema::CloudUser::userId.eq_any(&user_ids))
+                    .filter(schema::User::disabledAt.is_null())
+                    .select(models::CloudUser::as_select())
+                    .load(conn)?,
+            )
+        })
+        .await?;
+    if cloud_users.is_empty() {
+        return Ok(0);
+    }
+
+    let cloud_providers = state.cloud_providers.load();
+    let mut stopped = 0;
+    for cloud_user in cloud_users {
+        let Some(provider) = cloud_providers.get(&cloud_user.cloudInstance) else {
+            continue;
+        };
+        let instances = match provider
+            .list_instances(
+                cloud_user.cloudUsername.clone(),
+                cloud_user.cloudPassword.clone(),
+            )
+            .await
+        {
+            Ok(instances) => instances,
+            Err(CloudError::Unsupported(_)) => continue,
+            Err(e) => {
+                log::warn!(
+                    "Failed to list instances of {}: {e}",
+                    cloud_user.cloudUsername
+                );
+                continue;
+            }
+        };
+        for instance in instances.into_iter().filter(|i| i.status == "ACTIVE") {
+            let key = stopped_instance_key(&cloud_user.cloudInstance, &instance.id);
+            if state.cache.get(&key).await.is_some() {
+                continue;
+            }
+            match provider
+                .stop_instance(
+                    cloud_user.cloudUsername.clone(),
+                    cloud_user.cloudPassword.clone(),
+                    instance.id.clone(),
+                )
+                .await
+            {
+                Ok(()) => {
+                    stopped += 1;
+                    // An instance the user starts again is stopped once the
+                    // grace period has passed once more
+                    let ttl = grace.num_seconds().max(1) as u64;
+                    if let Err(e) = state.cache.set(&key, "1", ttl).await {
+                        log::warn!("Failed to remember stopped instance {}: {e}", instance.id);
+                    }
+                    log::info!(
+                        "Stopped instance {} of {}: credits depleted",
+                        instance.id,
+                        cloud_user.cloudUsername
+                    );
+                }
+                Err(e) => log::warn!("Failed to stop instance {}: {e}", instance.id),
+            }
+        }
+    }
+    Ok(stopped)
+}
@@ -0,0 +1,412 @@
+//! Administration of a PikaCloud deployment
+//!
+//! Reads the same configuration file and environment, or `.env`, as the server
+//! and works on the same database, Redis and cloud instances. Every command prints JSON with `--json`.
+
+use std::{io, process::ExitCode, sync::Arc};
+
+use chrono::NaiveDateTime;
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+
+use pikacloud_backend::{
+    auth::{ADMIN_ROLE, DEFAULT_ROLE},
+    cache::{CacheError, RedisClient},
+    config::{Config, ConfigError},
+    db::{DBClient, DBError},
+    jobs::{deprovision_account, provision_account, JobError},
+    migrations::{ensure_up_to_date, MigrationError},
+    models::{CloudAccountInfo, CloudProvider, LoginProvider, NewUser, User},
+    quota::sync_quotas,
+    reconcile::{reconcile, ReconcileError, RECONCILE_LOCK},
+    registry::ProviderRegistry,
+    reload::Reloadable,
+    repository::{list_cloud_users, PgRepository, Repository},
+    server::{load_cloud_providers, AppState},
+};
+
+#[derive(Debug, Parser)]
+#[command(name = "pikactl", about = "Administration of a PikaCloud deployment")]
+struct Cli {
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage role assignments
+    #[command(subcommand)]
+    Role(RoleCommand),
+    /// Manage cloud accounts, without going through the job queue
+    #[command(subcommand)]
+    Account(AccountCommand),
+    /// Manage cached cloud tokens
+    #[command(subcommand)]
+    Tokens(TokensCommand),
+    /// Look for cloud accounts without a user and rotate stale credentials
+    Reconcile {
+        /// Delete the orphaned accounts found
+        #[arg(long)]
+        delete: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// List users with their roles
+    List,
+    /// Create a password user with the admin role
+    CreateAdmin {
+        username: String,
+        /// Read from the first line of stdin when missing
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Forbid a user to log in
+    Disable { username: String },
+    /// Allow a disabled user to log in again
+    Enable { username: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum RoleCommand {
+    /// Give a user a role and apply its quota to their cloud accounts
+    Assign { username: String, role: String },
+}
+
+#[derive(Debug, Args)]
+struct AccountArgs {
+    username: String,
+    /// Provider type, e.g. openstack
+    provider: String,
+}
+
+#[derive(Debug, Subcommand)]
+enum AccountCommand {
+    /// Create the user's account on the instance they are placed on
+    Provision(AccountArgs),
+    /// Delete the user's account on the provider
+    Deprovision(AccountArgs),
+}
+
+#[derive(Debug, Subcommand)]
+enum TokensCommand {
+    /// Drop the cached tokens of every instance, or of one
+    Flush { instance: Option<String> },
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CtlError {
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("Database error: {0}")]
+    Database(#[from] DBError),
+    #[error("{0}")]
+    Migration(#[from] MigrationError),
+    #[error("Cache error: {0}")]
+    Cache(#[from] CacheError),
+    #[error("{0}")]
+    Job(#[from] JobError),
+    #[error("{0}")]
+    Reconcile(#[from] ReconcileError),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+type CtlResult<T> = std::result::Result<T, CtlError>;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserSummary {
+    id: String,
+    username: String,
+    login_provider: &'static str,
+    name: Option<String>,
+    roles: Vec<String>,
+    disabled: bool,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+struct FlushedTokens {
+    instance: String,
+    flushed: usize,
+}
+
+/// The database is all most commands need, the rest is connected on demand
+struct Context {
+    config: Arc<Config>,
+    db: DBClient,
+    repository: Arc<dyn Repository>,
+}
+
+impl Context {
+    fn connect() -> CtlResult<Self> {
+        let config = Arc::new(Config::load()?);
+        let db = DBClient::connect(&config.database_url, &config.db_pool)?;
+        ensure_up_to_date(&mut db.get_conn()?)?;
+        let repository = PgRepository::shared(db.clone());
+        Ok(Self {
+            config,
+            db,
+            repository,
+        })
+    }
+
+    async fn app_state(&self) -> CtlResult<AppState> {
+        let cache = RedisClient::new(&self.config.redis_url).await?;
+        let cloud_providers = load_cloud_providers(&self.config.clouds, cache.clone());
+        Ok(AppState {
+            cache,
+            db: self.db.clone(),
+            auth_providers: Reloadable::new(ProviderRegistry::new(Vec::new())),
+            cloud_providers: Reloadable::new(cloud_providers),
+            config: Reloadable::new(self.config.clone()),
+        })
+    }
+
+    async fn find_user(&self, username: &str) -> CtlResult<User> {
+        self.repository
+            .find_user_by_username(username)
+            .await?
+            .ok_or_else(|| CtlError::Invalid(format!("No user named {username}")))
+    }
+
+    async fn summary(&self, user: User) -> CtlResult<UserSummary> {
+        let roles = self.repository.role_names(&user.id).await?;
+        Ok(UserSummary {
+            id: user.id,
+            username: user.username,
+            login_provider: user.loginProvider.name(),
+            name: user.name,
+            roles,
+            disabled: user.disabledAt.is_some(),
+            created_at: user.createdAt,
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenvy::dotenv().ok();
+    env_logger::init();
+    let cli = Cli::parse();
+    match run(cli.command, cli.json).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if cli.json {
+                eprintln!("{}", serde_json::json!({ "error": e.to_string() }));
+            } else {
+                eprintln!("Error: {e}");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `value` as JSON, or as `human` writes it
+fn emit<T: Serialize>(json: bool, value: &T, human: impl FnOnce(&T)) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(value).expect("output is serializable")
+        );
+    } else {
+        human(value);
+    }
+}
+
+fn print_user(user: &UserSummary) {
+    println!(
+        "{}\t{}\t{}\t{}{}",
+        user.username,
+        user.login_provider,
+        user.id,
+        user.roles.join(","),
+        if user.disabled { "\tdisabled" } else { "" }
+    );
+}
+
+fn print_account(verb: &str, account: &CloudAccountInfo) {
+    println!(
+        "{verb} {} account {} on {}",
+        account.provider, account.provider_id, account.instance
+    );
+}
+
+fn parse_provider(name: &str) -> CtlResult<CloudProvider> {
+    CloudProvider::from_name(name)
+        .ok_or_else(|| CtlError::Invalid(format!("Unknown cloud provider {name}")))
+}
+
+async fn run(command: Command, json: bool) -> CtlResult<()> {
+    let ctx = Context::connect()?;
+    match command {
+        Command::User(UserCommand::List) => {
+            let mut users = Vec::new();
+            for user in ctx.repository.list_users().await? {
+                users.push(ctx.summary(user).await?);
+            }
+            emit(json, &users, |users| users.iter().for_each(print_user));
+        }
+        Command::User(UserCommand::CreateAdmin { username, password }) => {
+            let user = create_admin(&ctx, username, password).await?;
+            emit(json, &ctx.summary(user).await?, print_user);
+        }
+        Command::User(UserCommand::Disable { username }) => {
+            let user = ctx.find_user(&username).await?;
+            let user = ctx.repository.set_disabled(&user.id, true).await?;
+            emit(json, &ctx.summary(user).await?, print_user);
+        }
+        Command::User(UserCommand::Enable { username }) => {
+            let user = ctx.find_user(&username).await?;
+            let user = ctx.repository.set_disabled(&user.id, false).await?;
+            emit(json, &ctx.summary(user).await?, print_user);
+        }
+        Command::Role(RoleCommand::Assign { username, role }) => {
+            let user = ctx.find_user(&username).await?;
+            let Some(role) = ctx.repository.find_role(&role).await? else {
+                return Err(CtlError::Invalid(format!("No role named {role}")));
+            };
+            ctx.repository.assign_role(&user.id, &role.id).await?;
+            // The role's quota profile may change the user's effective quota
+            let cloud_users = list_cloud_users(&ctx.db, &user.id).await?;
+            if !cloud_users.is_empty() {
+                let state = ctx.app_state().await?;
+                sync_quotas(
+                    &state.db,
+                    &state.cloud_providers.load(),
+                    &cloud_users,
+                    ctx.config.quota_merge,
+                )
+                .await;
+            }
+            emit(json, &ctx.summary(user).await?, print_user);
+        }
+        Command::Account(AccountCommand::Provision(args)) => {
+            let provider = parse_provider(&args.provider)?;
+            let user = ctx.find_user(&args.username).await?;
+            let state = ctx.app_state().await?;
+            let account = provision_account(&state, &user.id, provider).await?;
+            emit(json, &account, |account| print_account("Created", account));
+        }
+        Command::Account(AccountCommand::Deprovision(args)) => {
+            let provider = parse_provider(&args.provider)?;
+            let user = ctx.find_user(&args.username).await?;
+            let state = ctx.app_state().await?;
+            let account = deprovision_account(&state, &user.id, provider).await?;
+            emit(json, &account, |account| print_account("Deleted", account));
+        }
+        Command::Tokens(TokensCommand::Flush { instance }) => {
+            let state = ctx.app_state().await?;
+            let cloud_providers = state.cloud_providers.load();
+            if let Some(instance) = &instance {
+                if cloud_providers.get(instance).is_none() {
+                    return Err(CtlError::Invalid(format!(
+                        "Cloud instance {instance} is not configured"
+                    )));
+                }
+            }
+            let mut flushed = Vec::new();
+            for provider in cloud_providers.iter() {
+                if instance
+                    .as_deref()
+                    .is_some_and(|name| name != provider.name())
+                {
+                    continue;
+                }
+                let Some(pattern) = provider.token_cache_pattern() else {
+                    continue;
+                };
+                flushed.push(FlushedTokens {
+                    instance: provider.name().to_string(),
+                    flushed: state.cache.del_matching(&pattern).await?,
+                });
+            }
+            emit(json, &flushed, |flushed| {
+                for entry in flushed {
+                    println!("Flushed {} tokens of {}", entry.flushed, entry.instance);
+                }
+            });
+        }
+        Command::Reconcile { delete } => {
+            let state = ctx.app_state().await?;
+            let pass = ctx.db.exclusive(RECONCILE_LOCK, || {
+                reconcile(&state, &ctx.config.reconcile, |_| delete)
+            });
+            let Some(report) = pass.await? else {
+                return Err(CtlError::Invalid(
+                    "A reconciliation is already running".into(),
+                ));
+            };
+            let report = report?;
+            emit(json, &report, |report| {
+                for orphan in &report.orphans {
+                    println!("Orphaned {} account {}", orphan.instance, orphan.account);
+                }
+                for orphan in &report.deleted {
+                    println!("Deleted {} account {}", orphan.instance, orphan.account);
+                }
+                println!("Rotated credentials of {} accounts", report.rotated);
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A password user with the default and the admin role
+async fn create_admin(
+    ctx: &Context,
+    username: String,
+    password: Option<String>,
+) -> CtlResult<User> {
+    if username.is_empty() || username.chars().all(|c| c.is_numeric()) {
+        return Err(CtlError::Invalid(
+            "Username cannot be empty or all numbers".into(),
+        ));
+    }
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            io::stdin()
+                .read_line(&mut line)
+                .map_err(|e| CtlError::Invalid(format!("Fail to read password: {e}")))?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        return Err(CtlError::Invalid("Password cannot be empty".into()));
+    }
+    let Some(admin) = ctx.repository.find_role(ADMIN_ROLE).await? else {
+        return Err(CtlError::Invalid(format!("No role named {ADMIN_ROLE}")));
+    };
+
+    let hashed_password = bcrypt::hash(&password, 10)
+        .map_err(|e| CtlError::Invalid(format!("Fail to hash password: {e}")))?;
+    let new_user = NewUser {
+        username: username.clone(),
+        loginProvider: LoginProvider::PASSWORD,
+        name: None,
+        password: Some(hashed_password),
+    };
+    let provisioned = ctx
+        .repository
+        .provision_user(new_user, DEFAULT_ROLE)
+        .await?;
+    if !provisioned.created {
+        return Err(CtlError::Invalid(format!(
+            "User {username} already exists, use `pikactl role assign {username} {ADMIN_ROLE}`"
+        )));
+    }
+    ctx.repository
+        .assign_role(&provisioned.user.id, &admin.id)
+        .await?;
+    Ok(provisioned.user)
+}
@@ -1,14 +1,16 @@
 use redis::{aio::MultiplexedConnection, AsyncCommands};
 
+/// Redis client. The multiplexed connection is clone-safe and every command
+/// runs on a clone of it, so `RedisClient` can be shared without a lock.
 #[derive(Clone)]
 pub struct RedisClient {
     conn: MultiplexedConnection,
 }
 
 impl RedisClient {
-    pub async fn new(redis_url: &String) -> CacheResult<Self> {
-        let client = redis::Client::open(redis_url.as_str())
-            .map_err(|e| CacheError::Connection(e.to_string()))?;
+    pub async fn new(redis_url: &str) -> CacheResult<Self> {
+        let client =
+            redis::Client::open(redis_url).map_err(|e| CacheError::Connection(e.to_string()))?;
         let conn = client
             .get_multiplexed_tokio_connection()
             .await
@@ -16,18 +18,105 @@ impl RedisClient {
         Ok(Self { conn })
     }
 
-    pub async fn get(&mut self, key: &str) -> Option<String> {
-        self.conn.get(key).await.unwrap_or(None)
+    pub async fn get(&self, key: &str) -> Option<String> {
+        self.conn.clone().get(key).await.unwrap_or(None)
+    }
+
+    /// Get and delete in one step, for single-use keys
+    pub async fn take(&self, key: &str) -> Option<String> {
+        self.conn.clone().get_del(key).await.unwrap_or(None)
+    }
+
+    pub async fn del(&self, key: &str) {
+        let _: Result<(), _> = self.conn.clone().del(key).await;
+    }
+
+    /// Delete every key matching the glob `pattern`, returns how many there
+    /// were
+    pub async fn del_matching(&self, pattern: &str) -> CacheResult<usize> {
+        let mut conn = self.conn.clone();
+        let mut keys: Vec<String> = Vec::new();
+        let mut iter = conn
+            .scan_match(pattern)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        drop(iter);
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        conn.del(&keys)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))
     }
 
-    pub async fn set(&mut self, key: &str, value: &str, expiration: u64) -> CacheResult<()> {
+    pub async fn set(&self, key: &str, value: &str, expiration: u64) -> CacheResult<()> {
         let _: () = self
             .conn
+            .clone()
             .set_ex(key, value, expiration)
             .await
             .map_err(|e| CacheError::Set(e.to_string()))?;
         Ok(())
     }
+
+    /// Append `value` to the list at `key`, for `pop` to take
+    pub async fn push(&self, key: &str, value: &str) -> CacheResult<()> {
+        let _: () = self
+            .conn
+            .clone()
+            .lpush(key, value)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Take the oldest value of the list at `key`, waiting up to `timeout`
+    /// seconds for one. Blocks the connection shared by every clone, so use a
+    /// client of its own.
+    pub async fn pop(&self, key: &str, timeout: f64) -> CacheResult<Option<String>> {
+        let popped: Option<(String, String)> = self
+            .conn
+            .clone()
+            .brpop(key, timeout)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))?;
+        Ok(popped.map(|(_, value)| value))
+    }
+
+    /// Add `value` to the sorted set at `key`, or move it, with `score`
+    pub async fn schedule(&self, key: &str, value: &str, score: i64) -> CacheResult<()> {
+        let _: () = self
+            .conn
+            .clone()
+            .zadd(key, value, score)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Values of the sorted set at `key` scored `max` or less
+    pub async fn due(&self, key: &str, max: i64) -> CacheResult<Vec<String>> {
+        self.conn
+            .clone()
+            .zrangebyscore(key, "-inf", max)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))
+    }
+
+    /// Remove `value` from the sorted set at `key`, false when someone else
+    /// removed it first
+    pub async fn unschedule(&self, key: &str, value: &str) -> CacheResult<bool> {
+        let removed: i64 = self
+            .conn
+            .clone()
+            .zrem(key, value)
+            .await
+            .map_err(|e| CacheError::Command(e.to_string()))?;
+        Ok(removed > 0)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -36,6 +125,8 @@ pub enum CacheError {
     Connection(String),
     #[error("Fail to set key: {0}")]
     Set(String),
+    #[error("Command failed: {0}")]
+    Command(String),
 }
 
 pub type CacheResult<T> = std::result::Result<T, CacheError>;
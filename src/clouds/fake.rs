@@ -0,0 +1,431 @@
+//! In-memory provider for development and tests
+//!
+//! Users, tokens, keypairs, instances and volumes live in the provider and
+//! are lost on restart. Every call can be slowed down and made to fail at
+//! random to exercise error paths, see `FakeConfig`.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    models::{
+        CloudCreateInfo, CloudProvider, ConsoleType, CreateInstance, Flavor, Instance,
+        RemoteConsole, Volume,
+    },
+    quota::Resources,
+};
+
+use super::{BaseCloudProvider, CloudError};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FakeConfig {
+    /// Added to every call, `PIKA_FAKE_LATENCY_MS` milliseconds
+    pub latency: Duration,
+    /// Probability in `[0, 1]` that a call fails, `PIKA_FAKE_FAILURE_RATE`
+    pub failure_rate: f64,
+}
+
+#[derive(Default)]
+struct FakeUser {
+    password: String,
+    keypairs: HashMap<String, String>,
+    instances: Vec<Instance>,
+    volumes: Vec<Volume>,
+    quota: Option<Resources>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    users: HashMap<String, FakeUser>,
+    /// Token to user
+    tokens: HashMap<String, String>,
+}
+
+impl FakeState {
+    fn user(&mut self, provider_id: &str) -> Result<&mut FakeUser, CloudError> {
+        self.users
+            .get_mut(provider_id)
+            .ok_or(CloudError::NotFound(format!("user {provider_id}")))
+    }
+}
+
+pub struct FakeCloudProvider {
+    name: String,
+    config: FakeConfig,
+    /// Never held across an await
+    state: Mutex<FakeState>,
+}
+
+impl FakeCloudProvider {
+    pub fn new() -> Self {
+        Self::with_config(FakeConfig::default())
+    }
+
+    /// A provider for the instance `name`
+    pub fn named(name: &str, config: FakeConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::with_config(config)
+        }
+    }
+
+    pub fn with_config(config: FakeConfig) -> Self {
+        Self {
+            name: "fake".to_string(),
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    /// The fixed flavor catalog
+    pub fn flavors() -> Vec<Flavor> {
+        [
+            ("small", 1, 1024, 10),
+            ("medium", 2, 4096, 20),
+            ("large", 4, 8192, 40),
+        ]
+        .into_iter()
+        .map(|(id, vcpus, ram_mb, disk_gb)| Flavor {
+            id: id.into(),
+            name: id.into(),
+            vcpus,
+            ram_mb,
+            disk_gb,
+        })
+        .collect()
+    }
+
+    fn flavor(flavor_id: &str) -> Result<Flavor, CloudError> {
+        Self::flavors()
+            .into_iter()
+            .find(|flavor| flavor.id == flavor_id)
+            .ok_or(CloudError::NotFound(format!("flavor {flavor_id}")))
+    }
+
+    /// Apply the configured latency and failure injection
+    async fn simulate(&self) -> Result<(), CloudError> {
+        if !self.config.latency.is_zero() {
+            tokio::time::sleep(self.config.latency).await;
+        }
+        if self.config.failure_rate > 0.0 && fastrand::f64() < self.config.failure_rate {
+            return Err(CloudError::SendRequest("injected failure".into()));
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check the credentials like a real cloud would, then run `f` on the
+    /// account
+    async fn with_user<T>(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        f: impl FnOnce(&mut FakeUser) -> Result<T, CloudError>,
+    ) -> Result<T, CloudError> {
+        self.get_user_token(provider_id.clone(), provider_pass)
+            .await?;
+        f(self.state().user(&provider_id)?)
+    }
+
+    fn usage(user: &FakeUser) -> Resources {
+        let mut usage = Resources {
+            instances: user.instances.len() as i32,
+            volumes: user.volumes.len() as i32,
+            ..Default::default()
+        };
+        for flavor_id in user.instances.iter().filter_map(|i| i.flavor_id.as_deref()) {
+            if let Ok(flavor) = Self::flavor(flavor_id) {
+                usage.vcpus += flavor.vcpus;
+                usage.ram_mb += flavor.ram_mb;
+            }
+        }
+        usage
+    }
+}
+
+impl Default for FakeCloudProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl BaseCloudProvider for FakeCloudProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn provider_type(&self) -> CloudProvider {
+        CloudProvider::FAKE
+    }
+
+    async fn get_admin_token(&self) -> Result<String, CloudError> {
+        self.simulate().await?;
+        Ok("fake-admin-token".into())
+    }
+
+    async fn create_user(&self, username: String) -> Result<CloudCreateInfo, CloudError> {
+        self.simulate().await?;
+        let provider_pass = uuid::Uuid::new_v4().to_string();
+        self.state().users.insert(
+            username.clone(),
+            FakeUser {
+                password: provider_pass.clone(),
+                ..Default::default()
+            },
+        );
+        Ok(CloudCreateInfo {
+            provider_id: username,
+            provider_pass,
+        })
+    }
+
+    async fn delete_user(&self, provider_id: String) -> Result<(), CloudError> {
+        self.simulate().await?;
+        let mut state = self.state();
+        state
+            .users
+            .remove(&provider_id)
+            .ok_or(CloudError::NotFound(format!("user {provider_id}")))?;
+        state.tokens.retain(|_, user| *user != provider_id);
+        Ok(())
+    }
+
+    async fn is_user_exist(&self, provider_id: String) -> Result<bool, CloudError> {
+        self.simulate().await?;
+        Ok(self.state().users.contains_key(&provider_id))
+    }
+
+    fn rotates_credentials(&self) -> bool {
+        true
+    }
+
+    async fn rotate_credentials(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<String, CloudError> {
+        let password = self
+            .with_user(provider_id.clone(), provider_pass, |user| {
+                user.password = uuid::Uuid::new_v4().to_string();
+                Ok(user.password.clone())
+            })
+            .await?;
+        self.state().tokens.retain(|_, user| *user != provider_id);
+        Ok(password)
+    }
+
+    async fn list_accounts(&self) -> Result<Vec<String>, CloudError> {
+        self.simulate().await?;
+        Ok(self.state().users.keys().cloned().collect())
+    }
+
+    async fn get_user_token(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<String, CloudError> {
+        self.simulate().await?;
+        let mut state = self.state();
+        match state.users.get(&provider_id) {
+            Some(user) if user.password == provider_pass => {}
+            _ => return Err(CloudError::NotFound(format!("user {provider_id}"))),
+        }
+        if let Some((token, _)) = state.tokens.iter().find(|(_, user)| **user == provider_id) {
+            return Ok(token.clone());
+        }
+        let token = uuid::Uuid::new_v4().to_string();
+        state.tokens.insert(token.clone(), provider_id);
+        Ok(token)
+    }
+
+    async fn import_keypair(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        key_name: String,
+        public_key: String,
+    ) -> Result<(), CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            user.keypairs.entry(key_name).or_insert(public_key);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_keypair(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        key_name: String,
+    ) -> Result<(), CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            user.keypairs.remove(&key_name);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_console(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+        console_type: ConsoleType,
+    ) -> Result<RemoteConsole, CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            if !user.instances.iter().any(|i| i.id == instance_id) {
+                return Err(CloudError::NotFound(format!("instance {instance_id}")));
+            }
+            Ok(RemoteConsole {
+                console_type,
+                url: format!("ws://fake.invalid/console/{instance_id}"),
+            })
+        })
+        .await
+    }
+
+    async fn list_instances(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Vec<Instance>, CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            Ok(user.instances.clone())
+        })
+        .await
+    }
+
+    async fn create_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance: CreateInstance,
+    ) -> Result<Instance, CloudError> {
+        let flavor = Self::flavor(&instance.flavor_id)?;
+        self.with_user(provider_id, provider_pass, |user| {
+            if let Some(quota) = &user.quota {
+                let request = Resources {
+                    vcpus: flavor.vcpus,
+                    ram_mb: flavor.ram_mb,
+                    instances: 1,
+                    ..Default::default()
+                };
+                let exceeded = Self::usage(user).sum(request).exceeding(quota);
+                if !exceeded.is_empty() {
+                    return Err(CloudError::SendRequest(format!(
+                        "quota exceeded: {}",
+                        exceeded.join(", ")
+                    )));
+                }
+            }
+            let instance = Instance {
+                id: uuid::Uuid::new_v4().to_string(),
+                name: instance.name,
+                status: "ACTIVE".into(),
+                flavor_id: Some(flavor.id),
+                created_at: Some(chrono::Utc::now().to_rfc3339()),
+            };
+            user.instances.push(instance.clone());
+            Ok(instance)
+        })
+        .await
+    }
+
+    async fn delete_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+    ) -> Result<(), CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            let before = user.instances.len();
+            user.instances.retain(|i| i.id != instance_id);
+            if user.instances.len() == before {
+                return Err(CloudError::NotFound(format!("instance {instance_id}")));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn stop_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+    ) -> Result<(), CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            let instance = user
+                .instances
+                .iter_mut()
+                .find(|i| i.id == instance_id)
+                .ok_or(CloudError::NotFound(format!("instance {instance_id}")))?;
+            instance.status = "SHUTOFF".into();
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_flavor(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        flavor_id: String,
+    ) -> Result<Flavor, CloudError> {
+        self.with_user(provider_id, provider_pass, |_| Self::flavor(&flavor_id))
+            .await
+    }
+
+    async fn list_volumes(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Vec<Volume>, CloudError> {
+        self.with_user(provider_id, provider_pass, |user| Ok(user.volumes.clone()))
+            .await
+    }
+
+    async fn create_volume(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        name: String,
+        size_gb: i32,
+    ) -> Result<Volume, CloudError> {
+        self.with_user(provider_id, provider_pass, |user| {
+            let volume = Volume {
+                id: uuid::Uuid::new_v4().to_string(),
+                name,
+                status: "available".into(),
+                size_gb,
+            };
+            user.volumes.push(volume.clone());
+            Ok(volume)
+        })
+        .await
+    }
+
+    async fn get_usage(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Resources, CloudError> {
+        self.with_user(provider_id, provider_pass, |user| Ok(Self::usage(user)))
+            .await
+    }
+
+    async fn set_quota(&self, provider_id: String, quota: Resources) -> Result<(), CloudError> {
+        self.simulate().await?;
+        self.state().user(&provider_id)?.quota = Some(quota);
+        Ok(())
+    }
+}
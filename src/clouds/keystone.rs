@@ -0,0 +1,515 @@
+//! Keystone v3 password and application credential authentication with a
+//! shared token cache
+//!
+//! Tokens are cached in Redis together with their expiry. A token is dropped
+//! `EXPIRY_MARGIN` seconds before Keystone expires it and refreshed in the
+//! background once it enters the last `REFRESH_AHEAD` seconds of its life.
+//! Concurrent misses for the same key share one Keystone request.
+//!
+//! Project-scoped tokens come with the service catalog, which is cached along
+//! with the token and used to look up the Nova, Cinder and Neutron endpoints.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::RedisClient;
+
+use super::CloudError;
+
+/// Seconds before expiry a cached token stops being handed out
+pub const EXPIRY_MARGIN: i64 = 300;
+
+/// Seconds before expiry a background refresh starts
+pub const REFRESH_AHEAD: i64 = 900;
+
+#[derive(Serialize)]
+struct AuthRequest {
+    auth: Auth,
+}
+
+#[derive(Serialize)]
+struct Auth {
+    identity: Identity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<Scope>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    project: Project,
+}
+
+#[derive(Serialize)]
+struct Project {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<Domain>,
+}
+
+#[derive(Serialize)]
+struct Identity {
+    methods: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<Password>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    application_credential: Option<ApplicationCredential>,
+}
+
+#[derive(Serialize)]
+struct Password {
+    user: User,
+}
+
+#[derive(Serialize)]
+struct User {
+    name: String,
+    domain: Domain,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct Domain {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    token: TokenInfo,
+}
+
+#[derive(Deserialize)]
+struct TokenInfo {
+    expires_at: Option<String>,
+    project: Option<ProjectInfo>,
+    #[serde(default)]
+    catalog: Vec<CatalogService>,
+}
+
+#[derive(Deserialize)]
+struct ProjectInfo {
+    id: String,
+}
+
+/// The OpenStack services listed in the Keystone catalog
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Identity,
+    Compute,
+    BlockStorage,
+    Network,
+}
+
+impl Service {
+    /// Catalog types the service may be registered under, preferred first
+    fn types(self) -> &'static [&'static str] {
+        match self {
+            Service::Identity => &["identity"],
+            Service::Compute => &["compute"],
+            Service::BlockStorage => &["block-storage", "volumev3", "volume"],
+            Service::Network => &["network"],
+        }
+    }
+}
+
+impl std::fmt::Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.types()[0])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogService {
+    #[serde(rename = "type")]
+    pub service_type: String,
+    pub endpoints: Vec<CatalogEndpoint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEndpoint {
+    pub interface: String,
+    #[serde(default)]
+    pub region_id: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    pub url: String,
+}
+
+/// What is stored under a token cache key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedToken {
+    pub token: String,
+    /// Unix time Keystone expires the token
+    pub expires_at: i64,
+    /// Project the token is scoped to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub catalog: Vec<CatalogService>,
+}
+
+impl CachedToken {
+    /// URL of `service` for `interface`, in `region` when one is given.
+    /// Endpoints registered with the project id of the token, like Cinder's
+    /// often are, have it stripped since requests are made for other projects.
+    pub fn endpoint(
+        &self,
+        service: Service,
+        interface: &str,
+        region: Option<&str>,
+    ) -> Option<String> {
+        let url = service.types().iter().find_map(|service_type| {
+            self.catalog
+                .iter()
+                .filter(|entry| entry.service_type == *service_type)
+                .flat_map(|entry| entry.endpoints.iter())
+                .find(|endpoint| {
+                    endpoint.interface == interface
+                        && region.is_none_or(|region| {
+                            endpoint.region_id.as_deref() == Some(region)
+                                || endpoint.region.as_deref() == Some(region)
+                        })
+                })
+        })?;
+        let url = url.url.trim_end_matches('/');
+        let url = match &self.project_id {
+            Some(project_id) => url
+                .strip_suffix(project_id.as_str())
+                .map_or(url, |url| url.trim_end_matches('/')),
+            None => url,
+        };
+        Some(url.to_string())
+    }
+}
+
+/// Project a token is scoped to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectScope {
+    /// A project of the Default domain
+    Name(String),
+    Id(String),
+}
+
+/// A Keystone user of the Default domain
+#[derive(Debug, Clone)]
+pub struct PasswordCredentials {
+    pub username: String,
+    pub password: String,
+    /// Project to scope the token to, unscoped tokens come without a catalog
+    /// and are refused by Nova and Cinder
+    pub project: Option<ProjectScope>,
+}
+
+/// Tokens of an application credential are always scoped to the project it
+/// was created in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplicationCredential {
+    pub id: String,
+    pub secret: String,
+}
+
+const STORED_PREFIX: &str = "application_credential:";
+
+impl ApplicationCredential {
+    /// The form kept in `CloudUser.cloudPassword`
+    pub fn to_stored(&self) -> String {
+        format!("{STORED_PREFIX}{}:{}", self.id, self.secret)
+    }
+
+    /// `None` for accounts still holding a plain password
+    pub fn from_stored(stored: &str) -> Option<Self> {
+        let (id, secret) = stored.strip_prefix(STORED_PREFIX)?.split_once(':')?;
+        Some(Self {
+            id: id.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Password(PasswordCredentials),
+    ApplicationCredential(ApplicationCredential),
+}
+
+impl From<PasswordCredentials> for Credentials {
+    fn from(credentials: PasswordCredentials) -> Self {
+        Credentials::Password(credentials)
+    }
+}
+
+impl From<ApplicationCredential> for Credentials {
+    fn from(credential: ApplicationCredential) -> Self {
+        Credentials::ApplicationCredential(credential)
+    }
+}
+
+impl Credentials {
+    fn describe(&self) -> String {
+        match self {
+            Credentials::Password(password) => password.username.clone(),
+            Credentials::ApplicationCredential(credential) => {
+                format!("application credential {}", credential.id)
+            }
+        }
+    }
+
+    fn auth_request(&self) -> AuthRequest {
+        let domain = || Domain {
+            name: "Default".to_string(),
+        };
+        let (identity, scope) = match self {
+            Credentials::Password(credentials) => (
+                Identity {
+                    methods: vec!["password".to_string()],
+                    password: Some(Password {
+                        user: User {
+                            name: credentials.username.clone(),
+                            domain: domain(),
+                            password: credentials.password.clone(),
+                        },
+                    }),
+                    application_credential: None,
+                },
+                credentials.project.as_ref().map(|project| Scope {
+                    project: match project {
+                        ProjectScope::Name(name) => Project {
+                            id: None,
+                            name: Some(name.clone()),
+                            domain: Some(domain()),
+                        },
+                        ProjectScope::Id(id) => Project {
+                            id: Some(id.clone()),
+                            name: None,
+                            domain: None,
+                        },
+                    },
+                }),
+            ),
+            Credentials::ApplicationCredential(credential) => (
+                Identity {
+                    methods: vec!["application_credential".to_string()],
+                    password: None,
+                    application_credential: Some(credential.clone()),
+                },
+                None,
+            ),
+        };
+        AuthRequest {
+            auth: Auth { identity, scope },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct KeystoneAuth {
+    cache: RedisClient,
+    client: reqwest::Client,
+    keystone: String,
+    /// One lock per cache key, held while a token is being issued
+    inflight: Arc<Mutex<Inflight>>,
+}
+
+type Inflight = HashMap<String, Arc<tokio::sync::Mutex<()>>>;
+
+/// Holds the lock of one cache key. Once the last holder or waiter is done
+/// the key is removed from `inflight`, so the map only has keys in use.
+struct InflightGuard {
+    inflight: Arc<Mutex<Inflight>>,
+    cache_key: String,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut inflight = self.inflight.lock().unwrap_or_else(|e| e.into_inner());
+        // Waiters hold a reference too, only the map's own is left once all
+        // are done
+        if inflight
+            .get(&self.cache_key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            inflight.remove(&self.cache_key);
+        }
+    }
+}
+
+impl KeystoneAuth {
+    pub fn new(cache: RedisClient, client: reqwest::Client, keystone: String) -> Self {
+        Self {
+            cache,
+            client,
+            keystone,
+            inflight: Arc::default(),
+        }
+    }
+
+    /// A valid token for `credentials`, from the cache when possible
+    pub async fn token(
+        &self,
+        cache_key: &str,
+        credentials: &Credentials,
+    ) -> Result<String, CloudError> {
+        Ok(self.entry(cache_key, credentials).await?.token)
+    }
+
+    /// Like `token`, along with its scope and catalog
+    pub async fn entry(
+        &self,
+        cache_key: &str,
+        credentials: &Credentials,
+    ) -> Result<CachedToken, CloudError> {
+        if let Some(cached) = self.cached(cache_key).await {
+            if cached.expires_at - chrono::Utc::now().timestamp() < REFRESH_AHEAD {
+                self.spawn_refresh(cache_key.to_string(), credentials.clone());
+            }
+            return Ok(cached);
+        }
+
+        let _guard = self.lock(cache_key).await;
+        // Whoever held the lock before us may have issued the token already
+        if let Some(cached) = self.cached(cache_key).await {
+            return Ok(cached);
+        }
+        self.issue(cache_key, credentials).await
+    }
+
+    /// Forget a cached token, e.g. after Keystone rejected it
+    pub async fn invalidate(&self, cache_key: &str) {
+        self.cache.del(cache_key).await;
+    }
+
+    async fn cached(&self, cache_key: &str) -> Option<CachedToken> {
+        let cached: CachedToken = serde_json::from_str(&self.cache.get(cache_key).await?).ok()?;
+        if cached.expires_at - chrono::Utc::now().timestamp() <= EXPIRY_MARGIN {
+            return None;
+        }
+        Some(cached)
+    }
+
+    fn lock_for(&self, cache_key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.inflight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(cache_key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    async fn lock(&self, cache_key: &str) -> InflightGuard {
+        let guard = self.lock_for(cache_key).lock_owned().await;
+        self.guard(cache_key, Some(guard))
+    }
+
+    fn try_lock(&self, cache_key: &str) -> Option<InflightGuard> {
+        match self.lock_for(cache_key).try_lock_owned() {
+            Ok(guard) => Some(self.guard(cache_key, Some(guard))),
+            // The holder may have finished meanwhile and left the key behind
+            Err(_) => {
+                drop(self.guard(cache_key, None));
+                None
+            }
+        }
+    }
+
+    fn guard(
+        &self,
+        cache_key: &str,
+        guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    ) -> InflightGuard {
+        InflightGuard {
+            inflight: self.inflight.clone(),
+            cache_key: cache_key.to_string(),
+            guard,
+        }
+    }
+
+    /// Number of cache keys with a token being issued
+    pub fn inflight_count(&self) -> usize {
+        self.inflight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    /// Replace a token that is about to expire without making callers wait.
+    /// Skipped when a refresh of the same key is already running.
+    fn spawn_refresh(&self, cache_key: String, credentials: Credentials) {
+        let auth = self.clone();
+        tokio::spawn(async move {
+            let Some(_guard) = auth.try_lock(&cache_key) else {
+                return;
+            };
+            match auth.cached(&cache_key).await {
+                Some(cached)
+                    if cached.expires_at - chrono::Utc::now().timestamp() >= REFRESH_AHEAD => {}
+                _ => {
+                    if let Err(e) = auth.issue(&cache_key, &credentials).await {
+                        log::warn!("Failed to refresh Keystone token {cache_key}: {e}");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Ask Keystone for a new token and cache it until shortly before expiry
+    async fn issue(
+        &self,
+        cache_key: &str,
+        credentials: &Credentials,
+    ) -> Result<CachedToken, CloudError> {
+        let cached = self.authenticate(credentials).await?;
+        let ttl = cached.expires_at - chrono::Utc::now().timestamp() - EXPIRY_MARGIN;
+        if ttl > 0 {
+            let value = serde_json::to_string(&cached)
+                .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+            self.cache
+                .set(cache_key, &value, ttl as u64)
+                .await
+                .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        }
+        Ok(cached)
+    }
+
+    /// Ask Keystone for a new token without caching it, for one-off requests
+    pub async fn authenticate(&self, credentials: &Credentials) -> Result<CachedToken, CloudError> {
+        let response = self
+            .client
+            .post(format!("{}/auth/tokens", self.keystone))
+            .json(&credentials.auth_request())
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?;
+
+        let missing =
+            || CloudError::NotFound(format!("Keystone token for {}", credentials.describe()));
+        let token = response
+            .headers()
+            .get("X-Subject-Token")
+            .ok_or_else(missing)?
+            .to_str()
+            .map_err(|_| missing())?
+            .to_string();
+        let response: AuthResponse = response.json().await.map_err(|_| missing())?;
+        let info = response.token;
+        let expires_at = info.expires_at.ok_or_else(missing)?;
+        let expires_at = chrono::DateTime::parse_from_rfc3339(&expires_at)
+            .map_err(|_| missing())?
+            .timestamp();
+        Ok(CachedToken {
+            token,
+            expires_at,
+            project_id: info.project.map(|project| project.id),
+            catalog: info.catalog,
+        })
+    }
+}
@@ -0,0 +1,430 @@
+//! Provider giving each user a namespace on a Kubernetes cluster
+//!
+//! `create_user` makes a namespace with a ServiceAccount, a RoleBinding of
+//! that account to `{PREFIX}_CLUSTER_ROLE` and a ResourceQuota. The namespace
+//! name is the provider id. User tokens are short-lived tokens of the
+//! ServiceAccount from the TokenRequest API, requested with the admin token,
+//! so accounts have no secret of their own and `provider_pass` is empty.
+//!
+//! Everything is labelled with the instance name, deleting the namespace
+//! deletes the rest with it. The quota covers CPU and memory, so pods have to
+//! declare requests and limits.
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    cache::RedisClient,
+    models::{ClientConfig, CloudCreateInfo, CloudProvider, ConfigFormat},
+    quota::Resources,
+};
+
+use super::{yaml_quote, BaseCloudProvider, CloudError};
+
+/// Label on every object created by `create_user`
+pub const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+
+/// Label holding the name of the instance that created a namespace
+pub const INSTANCE_LABEL: &str = "pikacloud.io/instance";
+
+/// Name of the ServiceAccount, RoleBinding and ResourceQuota in each namespace
+pub const ACCOUNT_NAME: &str = "pikacloud-user";
+
+/// Seconds before expiry a cached user token stops being handed out
+const TOKEN_MARGIN: i64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KubernetesConfig {
+    /// Instance name, also the prefix of the cache keys
+    pub name: String,
+    /// API server URL, `https://kubernetes.default.svc` from inside the cluster
+    pub api: String,
+    /// API server URL put in user kubeconfigs when `api` is internal
+    pub public_api: Option<String>,
+    /// Bearer token allowed to manage namespaces, RBAC and quotas
+    pub token: String,
+    /// PEM file of the CA that signed the API server certificate
+    pub ca_file: Option<String>,
+    /// Prepended to usernames to form namespace names
+    pub namespace_prefix: String,
+    /// ClusterRole users get inside their namespace
+    pub cluster_role: String,
+    /// Lifetime requested for user tokens, at least 600
+    pub token_seconds: u64,
+    /// ResourceQuota of new namespaces
+    pub quota: Resources,
+}
+
+pub struct KubernetesCloudProvider {
+    cache: RedisClient,
+    client: reqwest::Client,
+    config: KubernetesConfig,
+    /// PEM of `config.ca_file`
+    ca: Option<Vec<u8>>,
+}
+
+impl KubernetesCloudProvider {
+    pub fn with_config(cache: RedisClient, config: KubernetesConfig) -> Self {
+        let ca = config.ca_file.as_ref().map(|ca_file| {
+            std::fs::read(ca_file).unwrap_or_else(|e| panic!("Failed to read {ca_file}: {e}"))
+        });
+        let mut client = reqwest::Client::builder();
+        if let Some(pem) = &ca {
+            let certificate = reqwest::Certificate::from_pem(pem)
+                .unwrap_or_else(|e| panic!("Invalid certificate in {:?}: {e}", config.ca_file));
+            client = client.add_root_certificate(certificate);
+        }
+        Self {
+            cache,
+            client: client
+                .build()
+                .expect("Failed to build the Kubernetes client"),
+            config,
+            ca,
+        }
+    }
+
+    fn user_token_key(&self, namespace: &str) -> String {
+        format!("{}:user-token-{namespace}", self.config.name)
+    }
+
+    /// A valid namespace name for `username`: lowercase letters, digits and
+    /// `-`, at most 63 characters
+    pub fn namespace_for(&self, username: &str) -> String {
+        let name: String = format!("{}{username}", self.config.namespace_prefix)
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .take(63)
+            .collect();
+        name.trim_matches('-').to_string()
+    }
+
+    fn labels(&self) -> Value {
+        json!({
+            MANAGED_BY_LABEL: "pikacloud",
+            INSTANCE_LABEL: self.config.name,
+        })
+    }
+
+    /// `spec.hard` of the ResourceQuota for `quota`
+    fn hard_limits(quota: &Resources) -> Value {
+        let memory = format!("{}Mi", quota.ram_mb);
+        json!({
+            "requests.cpu": quota.vcpus.to_string(),
+            "limits.cpu": quota.vcpus.to_string(),
+            "requests.memory": memory,
+            "limits.memory": memory,
+            "pods": quota.instances.to_string(),
+            "persistentvolumeclaims": quota.volumes.to_string(),
+            "services.loadbalancers": quota.floating_ips.to_string(),
+        })
+    }
+
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, CloudError> {
+        request
+            .bearer_auth(&self.config.token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))
+    }
+
+    /// Map 404 to `CloudError::NotFound` and other failures to `Provider`
+    fn check(response: reqwest::Response, what: String) -> Result<reqwest::Response, CloudError> {
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(CloudError::NotFound(what));
+        }
+        Ok(response.error_for_status()?)
+    }
+
+    async fn create_objects(&self, namespace: &str) -> Result<(), CloudError> {
+        let api = &self.config.api;
+        let labels = self.labels();
+        let metadata = json!({ "name": ACCOUNT_NAME, "labels": labels });
+        let objects = [
+            (
+                format!("{api}/api/v1/namespaces/{namespace}/serviceaccounts"),
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "ServiceAccount",
+                    "metadata": metadata,
+                }),
+            ),
+            (
+                format!(
+                    "{api}/apis/rbac.authorization.k8s.io/v1/namespaces/{namespace}/rolebindings"
+                ),
+                json!({
+                    "apiVersion": "rbac.authorization.k8s.io/v1",
+                    "kind": "RoleBinding",
+                    "metadata": metadata,
+                    "roleRef": {
+                        "apiGroup": "rbac.authorization.k8s.io",
+                        "kind": "ClusterRole",
+                        "name": self.config.cluster_role,
+                    },
+                    "subjects": [{
+                        "kind": "ServiceAccount",
+                        "name": ACCOUNT_NAME,
+                        "namespace": namespace,
+                    }],
+                }),
+            ),
+            (
+                format!("{api}/api/v1/namespaces/{namespace}/resourcequotas"),
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "ResourceQuota",
+                    "metadata": metadata,
+                    "spec": { "hard": Self::hard_limits(&self.config.quota) },
+                }),
+            ),
+        ];
+        for (url, object) in objects {
+            self.send(self.client.post(url).json(&object))
+                .await?
+                .error_for_status()?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BaseCloudProvider for KubernetesCloudProvider {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn provider_type(&self) -> CloudProvider {
+        CloudProvider::KUBERNETES
+    }
+
+    fn token_cache_pattern(&self) -> Option<String> {
+        Some(format!("{}:*-token*", self.config.name))
+    }
+
+    async fn get_admin_token(&self) -> Result<String, CloudError> {
+        Ok(self.config.token.clone())
+    }
+
+    /// Create the namespace, then the objects inside it. When one of those
+    /// fails the namespace is deleted again, taking the others with it.
+    async fn create_user(&self, username: String) -> Result<CloudCreateInfo, CloudError> {
+        let namespace = self.namespace_for(&username);
+        self.send(
+            self.client
+                .post(format!("{}/api/v1/namespaces", self.config.api))
+                .json(&json!({
+                    "apiVersion": "v1",
+                    "kind": "Namespace",
+                    "metadata": { "name": namespace, "labels": self.labels() },
+                })),
+        )
+        .await?
+        .error_for_status()?;
+
+        if let Err(e) = self.create_objects(&namespace).await {
+            if let Err(rollback) = self.delete_user(namespace.clone()).await {
+                log::error!(
+                    "Rollback failed to delete namespace {namespace}, delete it by hand: {rollback}"
+                );
+            }
+            return Err(e);
+        }
+        Ok(CloudCreateInfo {
+            provider_id: namespace,
+            provider_pass: String::new(),
+        })
+    }
+
+    async fn delete_user(&self, provider_id: String) -> Result<(), CloudError> {
+        let response = self
+            .send(self.client.delete(format!(
+                "{}/api/v1/namespaces/{provider_id}",
+                self.config.api
+            )))
+            .await?;
+        Self::check(response, format!("namespace {provider_id}"))?;
+        self.cache.del(&self.user_token_key(&provider_id)).await;
+        Ok(())
+    }
+
+    async fn is_user_exist(&self, provider_id: String) -> Result<bool, CloudError> {
+        let response = self
+            .send(self.client.get(format!(
+                "{}/api/v1/namespaces/{provider_id}",
+                self.config.api
+            )))
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn list_accounts(&self) -> Result<Vec<String>, CloudError> {
+        #[derive(Deserialize)]
+        struct NamespaceList {
+            items: Vec<Namespace>,
+        }
+
+        #[derive(Deserialize)]
+        struct Namespace {
+            metadata: Metadata,
+        }
+
+        #[derive(Deserialize)]
+        struct Metadata {
+            name: String,
+        }
+
+        let selector = format!(
+            "{MANAGED_BY_LABEL}=pikacloud,{INSTANCE_LABEL}={}",
+            self.config.name
+        );
+        let namespaces: NamespaceList = self
+            .send(
+                self.client
+                    .get(format!("{}/api/v1/namespaces", self.config.api))
+                    .query(&[("labelSelector", selector)]),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(namespaces
+            .items
+            .into_iter()
+            .map(|namespace| namespace.metadata.name)
+            .collect())
+    }
+
+    /// A token of the namespace's ServiceAccount, cached until shortly before
+    /// it expires
+    async fn get_user_token(
+        &self,
+        provider_id: String,
+        _provider_pass: String,
+    ) -> Result<String, CloudError> {
+        #[derive(Deserialize)]
+        struct TokenRequest {
+            status: TokenStatus,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct TokenStatus {
+            token: String,
+            expiration_timestamp: String,
+        }
+
+        let cache_key = self.user_token_key(&provider_id);
+        if let Some(token) = self.cache.get(&cache_key).await {
+            return Ok(token);
+        }
+        let response = self
+            .send(
+                self.client
+                    .post(format!(
+                        "{}/api/v1/namespaces/{provider_id}/serviceaccounts/{ACCOUNT_NAME}/token",
+                        self.config.api
+                    ))
+                    .json(&json!({
+                        "apiVersion": "authentication.k8s.io/v1",
+                        "kind": "TokenRequest",
+                        "spec": { "expirationSeconds": self.config.token_seconds },
+                    })),
+            )
+            .await?;
+        let status = Self::check(response, format!("service account of {provider_id}"))?
+            .json::<TokenRequest>()
+            .await?
+            .status;
+
+        let expires_at = chrono::DateTime::parse_from_rfc3339(&status.expiration_timestamp)
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .timestamp();
+        let ttl = expires_at - chrono::Utc::now().timestamp() - TOKEN_MARGIN;
+        if ttl > 0 {
+            self.cache
+                .set(&cache_key, &status.token, ttl as u64)
+                .await
+                .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        }
+        Ok(status.token)
+    }
+
+    /// A kubeconfig with a ServiceAccount token, which stops working after
+    /// `token_seconds`
+    async fn client_config(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        format: Option<ConfigFormat>,
+    ) -> Result<ClientConfig, CloudError> {
+        match format.unwrap_or(ConfigFormat::Kubeconfig) {
+            ConfigFormat::Kubeconfig => {}
+            format => return Err(CloudError::Unsupported(format!("{format} configs"))),
+        }
+        let token = self
+            .get_user_token(provider_id.clone(), provider_pass)
+            .await?;
+        let name = &self.config.name;
+        let server = self.config.public_api.as_ref().unwrap_or(&self.config.api);
+        let mut lines = vec![
+            "apiVersion: v1".to_string(),
+            "kind: Config".to_string(),
+            "clusters:".to_string(),
+            format!("- name: {name}"),
+            "  cluster:".to_string(),
+            format!("    server: {}", yaml_quote(server)),
+        ];
+        if let Some(pem) = &self.ca {
+            lines.push(format!(
+                "    certificate-authority-data: {}",
+                STANDARD.encode(pem)
+            ));
+        }
+        lines.extend([
+            "users:".to_string(),
+            format!("- name: {provider_id}"),
+            "  user:".to_string(),
+            format!("    token: {}", yaml_quote(&token)),
+            "contexts:".to_string(),
+            format!("- name: {name}"),
+            "  context:".to_string(),
+            format!("    cluster: {name}"),
+            format!("    user: {provider_id}"),
+            format!("    namespace: {provider_id}"),
+            format!("current-context: {name}"),
+        ]);
+        let content = lines.join("\n") + "\n";
+        Ok(ClientConfig {
+            filename: format!("{name}-kubeconfig.yaml"),
+            content,
+        })
+    }
+
+    async fn set_quota(&self, provider_id: String, quota: Resources) -> Result<(), CloudError> {
+        let response = self
+            .send(
+                self.client
+                    .patch(format!(
+                        "{}/api/v1/namespaces/{provider_id}/resourcequotas/{ACCOUNT_NAME}",
+                        self.config.api
+                    ))
+                    .header("Content-Type", "application/merge-patch+json")
+                    .body(json!({ "spec": { "hard": Self::hard_limits(&quota) } }).to_string()),
+            )
+            .await?;
+        Self::check(response, format!("resource quota of {provider_id}"))?;
+        Ok(())
+    }
+}
@@ -1,26 +1,243 @@
 use async_trait::async_trait;
 
-use crate::models::CloudCreateInfo;
+use crate::{
+    models::{
+        ClientConfig, CloudCreateInfo, CloudProvider, CloudUser, ConfigFormat, ConsoleType,
+        CreateInstance, Flavor, Instance, RemoteConsole, SshKey, Volume,
+    },
+    quota::Resources,
+};
 
+pub mod fake;
+pub mod keystone;
+pub mod kubernetes;
 pub mod openstack;
+pub mod pikacloud;
 
 #[async_trait]
-pub trait BaseCloudProvider: Send {
-    fn name(&self) -> &'static str;
+pub trait BaseCloudProvider: Send + Sync {
+    /// Instance name from `CLOUD_PROVIDER`, stored in `CloudUser.cloudInstance`
+    fn name(&self) -> &str;
 
-    async fn get_admin_token(&mut self) -> Result<String, CloudError>;
+    /// Value stored in `CloudUser.cloudProvider` for accounts of this provider
+    fn provider_type(&self) -> CloudProvider;
 
-    async fn create_user(&mut self, username: String) -> Result<CloudCreateInfo, CloudError>;
+    /// Glob matching the Redis keys of the tokens the instance caches, so they
+    /// can be flushed. `None` when it caches none.
+    fn token_cache_pattern(&self) -> Option<String> {
+        None
+    }
 
-    async fn delete_user(&mut self, provider_id: String) -> Result<(), CloudError>;
+    async fn get_admin_token(&self) -> Result<String, CloudError>;
 
-    async fn is_user_exist(&mut self, provider_id: String) -> Result<bool, CloudError>;
+    async fn create_user(&self, username: String) -> Result<CloudCreateInfo, CloudError>;
+
+    async fn delete_user(&self, provider_id: String) -> Result<(), CloudError>;
+
+    async fn is_user_exist(&self, provider_id: String) -> Result<bool, CloudError>;
 
     async fn get_user_token(
-        &mut self,
+        &self,
         provider_id: String,
         provider_pass: String,
     ) -> Result<String, CloudError>;
+
+    /// Like `get_user_token`, scoped to `project_id` instead of the user's own
+    /// project, for users that are members of several
+    async fn get_project_token(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _project_id: String,
+    ) -> Result<String, CloudError> {
+        Err(CloudError::Unsupported("project scoped tokens".into()))
+    }
+
+    /// Whether `rotate_credentials` is supported
+    fn rotates_credentials(&self) -> bool {
+        false
+    }
+
+    /// Replace the secret of an account and return the new `provider_pass`.
+    /// The previous secret stops working.
+    async fn rotate_credentials(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+    ) -> Result<String, CloudError> {
+        Err(CloudError::Unsupported("credential rotation".into()))
+    }
+
+    /// Render a config file for the provider's own CLI tools, in `format` or
+    /// else the format the provider's tools usually read
+    async fn client_config(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _format: Option<ConfigFormat>,
+    ) -> Result<ClientConfig, CloudError> {
+        Err(CloudError::Unsupported("client configs".into()))
+    }
+
+    /// Provider ids of every account this backend created on the cloud,
+    /// including ones no `CloudUser` points at anymore
+    async fn list_accounts(&self) -> Result<Vec<String>, CloudError> {
+        Err(CloudError::Unsupported("account listing".into()))
+    }
+
+    /// Register an SSH public key for the cloud user, replacing nothing if a
+    /// key with the same name is already present.
+    async fn import_keypair(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _key_name: String,
+        _public_key: String,
+    ) -> Result<(), CloudError> {
+        Err(CloudError::Unsupported("keypairs".into()))
+    }
+
+    async fn delete_keypair(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _key_name: String,
+    ) -> Result<(), CloudError> {
+        Err(CloudError::Unsupported("keypairs".into()))
+    }
+
+    /// Request a remote console URL for an instance. Providers must fail with
+    /// `CloudError::NotFound` when the instance does not belong to the user.
+    async fn get_console(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _instance_id: String,
+        _console_type: ConsoleType,
+    ) -> Result<RemoteConsole, CloudError> {
+        Err(CloudError::Unsupported("remote consoles".into()))
+    }
+
+    async fn list_instances(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+    ) -> Result<Vec<Instance>, CloudError> {
+        Err(CloudError::Unsupported("instances".into()))
+    }
+
+    async fn create_instance(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _instance: CreateInstance,
+    ) -> Result<Instance, CloudError> {
+        Err(CloudError::Unsupported("instances".into()))
+    }
+
+    async fn delete_instance(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _instance_id: String,
+    ) -> Result<(), CloudError> {
+        Err(CloudError::Unsupported("instances".into()))
+    }
+
+    /// Shut the instance down without deleting it
+    async fn stop_instance(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _instance_id: String,
+    ) -> Result<(), CloudError> {
+        Err(CloudError::Unsupported("instances".into()))
+    }
+
+    async fn get_flavor(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _flavor_id: String,
+    ) -> Result<Flavor, CloudError> {
+        Err(CloudError::Unsupported("flavors".into()))
+    }
+
+    async fn list_volumes(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+    ) -> Result<Vec<Volume>, CloudError> {
+        Err(CloudError::Unsupported("volumes".into()))
+    }
+
+    async fn create_volume(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+        _name: String,
+        _size_gb: i32,
+    ) -> Result<Volume, CloudError> {
+        Err(CloudError::Unsupported("volumes".into()))
+    }
+
+    /// Resources currently consumed by the cloud user
+    async fn get_usage(
+        &self,
+        _provider_id: String,
+        _provider_pass: String,
+    ) -> Result<Resources, CloudError> {
+        Err(CloudError::Unsupported("usage".into()))
+    }
+
+    /// Apply limits to the cloud user's project, with admin credentials
+    async fn set_quota(&self, _provider_id: String, _quota: Resources) -> Result<(), CloudError> {
+        Err(CloudError::Unsupported("quotas".into()))
+    }
+}
+
+/// Prefix of the environment variables configuring an instance, e.g.
+/// `OPENSTACK_GPU` for `openstack-gpu`
+pub fn env_prefix(instance: &str) -> String {
+    instance.to_uppercase().replace('-', "_")
+}
+
+/// `value` as a double-quoted YAML scalar, JSON strings being valid YAML
+pub(crate) fn yaml_quote(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// `value` single-quoted for POSIX shells
+pub(crate) fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Push every key in `keys` to the cloud account. Keys that fail are logged and
+/// skipped so one bad key does not block the others.
+pub async fn sync_ssh_keys(
+    provider: &dyn BaseCloudProvider,
+    cloud_user: &CloudUser,
+    keys: &[SshKey],
+) {
+    for key in keys {
+        match provider
+            .import_keypair(
+                cloud_user.cloudUsername.clone(),
+                cloud_user.cloudPassword.clone(),
+                key.name.clone(),
+                key.publicKey.clone(),
+            )
+            .await
+        {
+            Ok(()) | Err(CloudError::Unsupported(_)) => {}
+            Err(e) => log::warn!(
+                "Failed to sync ssh key {} to {} user {}: {e}",
+                key.name,
+                provider.name(),
+                cloud_user.cloudUsername
+            ),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -29,6 +246,10 @@ pub enum CloudError {
     SendRequest(String),
     #[error("NotFound: {0}")]
     NotFound(String),
+    #[error("Unsupported by provider: {0}")]
+    Unsupported(String),
+    #[error("Timed out {0}")]
+    Timeout(String),
     #[error("Provider: {0}")]
     Provider(#[from] reqwest::Error),
 }
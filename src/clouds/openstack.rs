@@ -1,42 +1,24 @@
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use crate::{cache::RedisClient, models::CloudCreateInfo, utils::load_env_panic};
-
-use super::{BaseCloudProvider, CloudError};
-
-#[derive(Serialize)]
-struct AuthRequest {
-    auth: Auth,
-}
-
-#[derive(Serialize)]
-struct Auth {
-    identity: Identity,
-}
-
-#[derive(Serialize)]
-struct Identity {
-    methods: Vec<String>,
-    password: Password,
-}
-
-#[derive(Serialize)]
-struct User {
-    name: String,
-    domain: Domain,
-    password: String,
-}
-
-#[derive(Serialize)]
-struct Domain {
-    name: String,
-}
+use crate::{
+    cache::RedisClient,
+    models::{
+        ClientConfig, CloudCreateInfo, CloudProvider, ConfigFormat, ConsoleType, CreateInstance,
+        Flavor, Instance, RemoteConsole, Volume,
+    },
+    quota::Resources,
+};
 
-#[derive(Deserialize)]
-struct AuthResponse {
-    expires_at: Option<String>,
-}
+use super::{
+    keystone::{
+        ApplicationCredential, Credentials, KeystoneAuth, PasswordCredentials, ProjectScope,
+        Service,
+    },
+    shell_quote, yaml_quote, BaseCloudProvider, CloudError,
+};
 
 #[derive(Deserialize)]
 struct DomainsResponse {
@@ -48,11 +30,6 @@ struct RolesResponse {
     roles: Vec<RoleInfo>,
 }
 
-#[derive(Serialize)]
-struct Password {
-    user: User,
-}
-
 #[derive(Deserialize)]
 struct DomainInfo {
     id: String,
@@ -65,36 +42,238 @@ struct RoleInfo {
     pub name: String,
 }
 
+/// Tag on every project created by `create_user`, so reconciliation never
+/// touches projects it does not own
+pub const MANAGED_PROJECT_TAG: &str = "pikacloud";
+
+/// How long `delete_user` waits for Nova to finish deleting servers
+const SERVER_DELETE_TIMEOUT: Duration = Duration::from_secs(300);
+const SERVER_DELETE_POLL: Duration = Duration::from_secs(2);
+
+/// Neutron ports that connect a subnet to a router
+const ROUTER_INTERFACE_OWNERS: [&str; 3] = [
+    "network:router_interface",
+    "network:router_interface_distributed",
+    "network:ha_router_replicated_interface",
+];
+
+fn device_owner(port: &serde_json::Value) -> &str {
+    port["device_owner"].as_str().unwrap_or("")
+}
+
+/// Resources made by `create_user`, deleted again if a later step fails
+#[derive(Debug)]
+enum CreatedResource {
+    Project(String),
+    User(String),
+}
+
+/// Keystone, admin credentials and how to pick endpoints from the catalog.
+/// `nova`, `cinder` and `neutron` override the catalog when set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenStackConfig {
+    /// Instance name, also the prefix of the cache keys
+    pub name: String,
+    pub keystone: String,
+    /// Catalog interface, `public` or `internal`
+    pub interface: String,
+    pub region: Option<String>,
+    pub nova: Option<String>,
+    pub cinder: Option<String>,
+    pub neutron: Option<String>,
+    pub admin_username: String,
+    pub admin_password: String,
+    /// Project the admin token is scoped to
+    pub admin_project: String,
+    /// Lifetime of the application credentials given to users, `None` for
+    /// credentials that never expire
+    pub credential_days: Option<i64>,
+}
+
 pub struct OpenStackCloudProvider {
     cache: RedisClient,
     client: reqwest::Client,
+    auth: KeystoneAuth,
+    config: OpenStackConfig,
 }
 
 impl OpenStackCloudProvider {
-    pub fn new(cache: RedisClient) -> Self {
+    pub fn with_config(cache: RedisClient, config: OpenStackConfig) -> Self {
+        let client = reqwest::Client::new();
         Self {
+            auth: KeystoneAuth::new(cache.clone(), client.clone(), config.keystone.clone()),
             cache,
-            client: reqwest::Client::new(),
+            client,
+            config,
         }
     }
 
+    fn admin_token_key(&self) -> String {
+        format!("{}:admin-token", self.config.name)
+    }
+
+    fn user_token_key(&self, provider_id: &str, project: &ProjectScope) -> String {
+        match project {
+            ProjectScope::Name(name) => {
+                format!("{}:user-token-{provider_id}:{name}", self.config.name)
+            }
+            ProjectScope::Id(id) => {
+                format!("{}:user-token-{provider_id}:id-{id}", self.config.name)
+            }
+        }
+    }
+
+    /// Every account gets a project named after it, see `create_user`
+    fn default_project(provider_id: &str) -> ProjectScope {
+        ProjectScope::Name(provider_id.to_string())
+    }
+
+    fn domain_id_key(&self) -> String {
+        format!("{}_default_domain_id", self.config.name)
+    }
+
+    fn member_role_key(&self) -> String {
+        format!("{}_member_role_id", self.config.name)
+    }
+
+    fn admin_credentials(&self) -> Credentials {
+        PasswordCredentials {
+            username: self.config.admin_username.clone(),
+            password: self.config.admin_password.clone(),
+            project: Some(ProjectScope::Name(self.config.admin_project.clone())),
+        }
+        .into()
+    }
+
+    /// `provider_pass` is an application credential, or a plain password for
+    /// accounts created before those were used. `project` only applies to
+    /// passwords, application credentials carry their own project.
+    fn user_credentials(
+        provider_id: &str,
+        provider_pass: &str,
+        project: ProjectScope,
+    ) -> Credentials {
+        match ApplicationCredential::from_stored(provider_pass) {
+            Some(credential) => credential.into(),
+            None => PasswordCredentials {
+                username: provider_id.to_string(),
+                password: provider_pass.to_string(),
+                project: Some(project),
+            }
+            .into(),
+        }
+    }
+
+    /// URL of `service`, the configured override or else the endpoint listed
+    /// in the catalog of the admin token
+    async fn endpoint(&self, service: Service) -> Result<String, CloudError> {
+        let configured = match service {
+            Service::Identity => &None,
+            Service::Compute => &self.config.nova,
+            Service::BlockStorage => &self.config.cinder,
+            Service::Network => &self.config.neutron,
+        };
+        if let Some(url) = configured {
+            return Ok(url.clone());
+        }
+        let credentials = self.admin_credentials();
+        self.auth
+            .entry(&self.admin_token_key(), &credentials)
+            .await?
+            .endpoint(
+                service,
+                &self.config.interface,
+                self.config.region.as_deref(),
+            )
+            .ok_or_else(|| {
+                CloudError::NotFound(format!(
+                    "{service} endpoint for the {} interface in the Keystone catalog",
+                    self.config.interface
+                ))
+            })
+    }
+
+    /// Keystone as users reach it, the public identity endpoint of the catalog
+    /// or else the configured URL
+    async fn public_keystone(&self) -> Result<String, CloudError> {
+        let credentials = self.admin_credentials();
+        let entry = self
+            .auth
+            .entry(&self.admin_token_key(), &credentials)
+            .await?;
+        Ok(entry
+            .endpoint(Service::Identity, "public", self.config.region.as_deref())
+            .unwrap_or_else(|| self.config.keystone.clone()))
+    }
+
+    /// Send `request` with the admin token. Keystone answering 401 means the
+    /// cached token was revoked early, so it is dropped and the request is
+    /// sent once more with a fresh token.
+    async fn send_as_admin(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, CloudError> {
+        let credentials = self.admin_credentials();
+        self.send_with_token(&self.admin_token_key(), &credentials, request)
+            .await
+    }
+
+    /// Send `request` with the user's token for their own project, see
+    /// `send_as_admin`
+    async fn send_as_user(
+        &self,
+        provider_id: &str,
+        provider_pass: &str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, CloudError> {
+        let project = Self::default_project(provider_id);
+        let cache_key = self.user_token_key(provider_id, &project);
+        let credentials = Self::user_credentials(provider_id, provider_pass, project);
+        self.send_with_token(&cache_key, &credentials, request)
+            .await
+    }
+
+    async fn send_with_token(
+        &self,
+        cache_key: &str,
+        credentials: &Credentials,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, CloudError> {
+        // Only streaming bodies cannot be cloned and none are sent here
+        let retry = request.try_clone();
+        let token = self.auth.token(cache_key, credentials).await?;
+        let response = request
+            .header("X-Auth-Token", token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        let Some(retry) = retry.filter(|_| response.status() == reqwest::StatusCode::UNAUTHORIZED)
+        else {
+            return Ok(response);
+        };
+
+        log::info!("Keystone rejected cached token {cache_key}, retrying with a new one");
+        self.auth.invalidate(cache_key).await;
+        let token = self.auth.token(cache_key, credentials).await?;
+        retry
+            .header("X-Auth-Token", token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))
+    }
+
     // Get default domain id, store in redis
-    async fn get_default_domain_id(&mut self) -> Result<String, CloudError> {
+    async fn get_default_domain_id(&self) -> Result<String, CloudError> {
         // Get default domain id, store in redis
-        if let Some(domain_id) = self.cache.get("openstack_default_domain_id").await {
+        if let Some(domain_id) = self.cache.get(&self.domain_id_key()).await {
             return Ok(domain_id);
         }
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
-        let token = self.get_admin_token().await?;
+        let keystone = self.config.keystone.clone();
 
         // Get domain id by name 'Default'
         let response = self
-            .client
-            .get(format!("{}/domains", keystone))
-            .header("X-Auth-Token", token)
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .send_as_admin(self.client.get(format!("{}/domains", keystone)))
+            .await?
             .json::<DomainsResponse>()
             .await?;
         let default_domain = response
@@ -105,26 +284,21 @@ impl OpenStackCloudProvider {
 
         // Cache for 1 day
         self.cache
-            .set("openstack_default_domain_id", &default_domain.id, 86400)
+            .set(&self.domain_id_key(), &default_domain.id, 86400)
             .await
             .map_err(|e| CloudError::SendRequest(e.to_string()))?;
         Ok(default_domain.id.clone())
     }
 
-    async fn get_member_role_id(&mut self) -> Result<String, CloudError> {
-        if let Some(member_role_id) = self.cache.get("openstack_member_role_id").await {
+    async fn get_member_role_id(&self) -> Result<String, CloudError> {
+        if let Some(member_role_id) = self.cache.get(&self.member_role_key()).await {
             return Ok(member_role_id);
         }
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
-        let token = self.get_admin_token().await?;
+        let keystone = self.config.keystone.clone();
         // Get domain id by name 'Default'
         let response = self
-            .client
-            .get(format!("{}/roles", keystone))
-            .header("X-Auth-Token", token)
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .send_as_admin(self.client.get(format!("{}/roles", keystone)))
+            .await?
             .json::<RolesResponse>()
             .await?;
         let member_role = response
@@ -133,261 +307,1288 @@ impl OpenStackCloudProvider {
             .find(|role| role.name == "member")
             .ok_or(CloudError::NotFound("member role".into()))?;
         self.cache
-            .set("openstack_member_role_id", &member_role.id, 86400)
+            .set(&self.member_role_key(), &member_role.id, 86400)
             .await
             .map_err(|e| CloudError::SendRequest(e.to_string()))?;
         Ok(member_role.id.clone())
     }
-}
 
-#[async_trait]
-impl BaseCloudProvider for OpenStackCloudProvider {
-    fn name(&self) -> &'static str {
-        "openstack"
+    /// Projects are named after the user, see `create_user`. Only projects
+    /// tagged `MANAGED_PROJECT_TAG` are found, a project of the same name that
+    /// someone else created is never touched.
+    async fn get_project_id(&self, project_name: &str) -> Result<String, CloudError> {
+        #[derive(Deserialize)]
+        struct ProjectsResponse {
+            projects: Vec<ProjectInfo>,
+        }
+
+        #[derive(Deserialize)]
+        struct ProjectInfo {
+            id: String,
+            #[serde(default)]
+            tags: Vec<String>,
+        }
+
+        let keystone = self.config.keystone.clone();
+        let response: ProjectsResponse = self
+            .send_as_admin(
+                self.client
+                    .get(format!("{keystone}/projects"))
+                    .query(&[("name", project_name), ("tags", MANAGED_PROJECT_TAG)]),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // Checked again in case the filter is not supported
+        response
+            .projects
+            .into_iter()
+            .find(|project| project.tags.iter().any(|tag| tag == MANAGED_PROJECT_TAG))
+            .map(|project| project.id)
+            .ok_or(CloudError::NotFound(format!("project {project_name}")))
     }
 
-    async fn get_admin_token(&mut self) -> Result<String, CloudError> {
-        if let Some(token) = self.cache.get("openstack:admin-token").await {
-            return Ok(token);
+    async fn get_user_id(&self, username: &str) -> Result<String, CloudError> {
+        #[derive(Deserialize)]
+        struct UsersResponse {
+            users: Vec<UserInfo>,
+        }
+
+        #[derive(Deserialize)]
+        struct UserInfo {
+            id: String,
         }
 
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
-        let username = load_env_panic("OPENSTACK_ADMIN_USERNAME");
-        let password = load_env_panic("OPENSTACK_ADMIN_PASSWORD");
+        let keystone = self.config.keystone.clone();
+        let response: UsersResponse = self
+            .send_as_admin(
+                self.client
+                    .get(format!("{keystone}/users"))
+                    .query(&[("name", username), ("domain_id", "default")]),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response
+            .users
+            .into_iter()
+            .next()
+            .map(|user| user.id)
+            .ok_or(CloudError::NotFound(format!("user {username}")))
+    }
 
+    /// DELETE with the admin token, a resource that is already gone is fine
+    async fn admin_delete(&self, url: String) -> Result<(), CloudError> {
         let response = self
-            .client
-            .post(format!("{}/auth/tokens", keystone))
-            .json(&AuthRequest {
-                auth: Auth {
-                    identity: Identity {
-                        methods: vec!["password".to_string()],
-                        password: Password {
-                            user: User {
-                                name: username,
-                                domain: Domain {
-                                    name: "Default".to_string(),
-                                },
-                                password,
-                            },
-                        },
+            .send_as_admin(
+                self.client
+                    .delete(url)
+                    // Deleting another user's keypair needs compute microversion 2.10
+                    .header("OpenStack-API-Version", "compute 2.10"),
+            )
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    /// GET with the admin token and return the items in `key`
+    async fn admin_list(
+        &self,
+        url: String,
+        query: &[(&str, &str)],
+        key: &str,
+    ) -> Result<Vec<serde_json::Value>, CloudError> {
+        let mut response: serde_json::Value = self
+            .send_as_admin(
+                self.client
+                    .get(url)
+                    .query(query)
+                    // user_id filtering of keypairs needs compute microversion 2.10
+                    .header("OpenStack-API-Version", "compute 2.10"),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match response[key].take() {
+            serde_json::Value::Array(items) => Ok(items),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// `admin_list` and pick the `id` of every item
+    async fn admin_list_ids(
+        &self,
+        url: String,
+        query: &[(&str, &str)],
+        key: &str,
+    ) -> Result<Vec<String>, CloudError> {
+        Ok(self
+            .admin_list(url, query, key)
+            .await?
+            .iter()
+            .filter_map(|item| {
+                // Keypairs come wrapped as {"keypair": {...}} and are keyed by name
+                let item = item.get("keypair").unwrap_or(item);
+                item.get("id")
+                    .or_else(|| item.get("name"))
+                    .and_then(|id| id.as_str())
+                    .map(String::from)
+            })
+            .collect())
+    }
+
+    /// Delete everything in a project so that Keystone can drop it: servers,
+    /// floating IPs, routers, ports, networks, security groups, snapshots and
+    /// volumes
+    async fn purge_project(&self, project_id: &str) -> Result<(), CloudError> {
+        let nova = self.endpoint(Service::Compute).await?;
+        let cinder = self.endpoint(Service::BlockStorage).await?;
+        let neutron = self.endpoint(Service::Network).await?;
+        let all_tenants = [("all_tenants", "1"), ("project_id", project_id)];
+        let project = [("project_id", project_id)];
+
+        let servers = self
+            .admin_list_ids(format!("{nova}/servers"), &all_tenants, "servers")
+            .await?;
+        for server_id in &servers {
+            self.admin_delete(format!("{nova}/servers/{server_id}"))
+                .await?;
+        }
+        if !servers.is_empty() {
+            self.wait_for_servers_deleted(&nova, project_id).await?;
+        }
+
+        for floating_ip_id in self
+            .admin_list_ids(format!("{neutron}/floatingips"), &project, "floatingips")
+            .await?
+        {
+            self.admin_delete(format!("{neutron}/floatingips/{floating_ip_id}"))
+                .await?;
+        }
+
+        // Routers only go away once their interfaces are removed
+        let ports = self
+            .admin_list(format!("{neutron}/ports"), &project, "ports")
+            .await?;
+        for port in ports
+            .iter()
+            .filter(|port| ROUTER_INTERFACE_OWNERS.contains(&device_owner(port)))
+        {
+            let (Some(port_id), Some(router_id)) =
+                (port["id"].as_str(), port["device_id"].as_str())
+            else {
+                continue;
+            };
+            let response = self
+                .send_as_admin(
+                    self.client
+                        .put(format!(
+                            "{neutron}/routers/{router_id}/remove_router_interface"
+                        ))
+                        .json(&serde_json::json!({ "port_id": port_id })),
+                )
+                .await?;
+            if response.status() != reqwest::StatusCode::NOT_FOUND {
+                response.error_for_status()?;
+            }
+        }
+        for router_id in self
+            .admin_list_ids(format!("{neutron}/routers"), &project, "routers")
+            .await?
+        {
+            self.admin_delete(format!("{neutron}/routers/{router_id}"))
+                .await?;
+        }
+        // DHCP and other ports owned by Neutron go with their network
+        for port in ports
+            .iter()
+            .filter(|port| !device_owner(port).starts_with("network:"))
+        {
+            if let Some(port_id) = port["id"].as_str() {
+                self.admin_delete(format!("{neutron}/ports/{port_id}"))
+                    .await?;
+            }
+        }
+        for network_id in self
+            .admin_list_ids(format!("{neutron}/networks"), &project, "networks")
+            .await?
+        {
+            self.admin_delete(format!("{neutron}/networks/{network_id}"))
+                .await?;
+        }
+        for security_group_id in self
+            .admin_list_ids(
+                format!("{neutron}/security-groups"),
+                &project,
+                "security_groups",
+            )
+            .await?
+        {
+            self.admin_delete(format!("{neutron}/security-groups/{security_group_id}"))
+                .await?;
+        }
+
+        for snapshot_id in self
+            .admin_list_ids(format!("{cinder}/snapshots"), &all_tenants, "snapshots")
+            .await?
+        {
+            self.admin_delete(format!("{cinder}/snapshots/{snapshot_id}"))
+                .await?;
+        }
+        let volumes = self
+            .admin_list_ids(format!("{cinder}/volumes"), &all_tenants, "volumes")
+            .await?;
+        for volume_id in volumes {
+            // cascade removes snapshots that are still being deleted
+            self.admin_delete(format!("{cinder}/volumes/{volume_id}?cascade=true"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Nova deletes servers in the background and their volumes stay
+    /// attached, and cannot be deleted, until it is done
+    async fn wait_for_servers_deleted(
+        &self,
+        nova: &str,
+        project_id: &str,
+    ) -> Result<(), CloudError> {
+        let started = Instant::now();
+        loop {
+            let servers = self
+                .admin_list_ids(
+                    format!("{nova}/servers"),
+                    &[("all_tenants", "1"), ("project_id", project_id)],
+                    "servers",
+                )
+                .await?;
+            if servers.is_empty() {
+                return Ok(());
+            }
+            if started.elapsed() >= SERVER_DELETE_TIMEOUT {
+                return Err(CloudError::Timeout(format!(
+                    "deleting {} servers of project {project_id}",
+                    servers.len()
+                )));
+            }
+            tokio::time::sleep(SERVER_DELETE_POLL).await;
+        }
+    }
+
+    /// Nova keeps keypairs when the Keystone user goes away
+    async fn purge_keypairs(&self, user_id: &str) -> Result<(), CloudError> {
+        let nova = self.endpoint(Service::Compute).await?;
+        let keypairs = self
+            .admin_list_ids(
+                format!("{nova}/os-keypairs"),
+                &[("user_id", user_id)],
+                "keypairs",
+            )
+            .await?;
+        for key_name in keypairs {
+            self.admin_delete(format!("{nova}/os-keypairs/{key_name}?user_id={user_id}"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn try_create_user(
+        &self,
+        username: &str,
+        created: &mut Vec<CreatedResource>,
+    ) -> Result<CloudCreateInfo, CloudError> {
+        let keystone = self.config.keystone.clone();
+
+        #[derive(Serialize, Deserialize)]
+        struct ProjectBody<T> {
+            project: T,
+        }
+
+        #[derive(Serialize)]
+        struct Project {
+            pub name: String,
+            pub domain_id: String,
+            pub tags: Vec<&'static str>,
+        }
+
+        #[derive(Deserialize)]
+        struct ProjectResponse {
+            pub id: String,
+        }
+
+        let domain_id = self.get_default_domain_id().await?;
+        let project: ProjectResponse =
+            self.send_as_admin(self.client.post(format!("{}/projects", keystone)).json(
+                &ProjectBody {
+                    project: Project {
+                        name: username.to_string(),
+                        domain_id,
+                        tags: vec![MANAGED_PROJECT_TAG],
                     },
                 },
+            ))
+            .await?
+            .error_for_status()?
+            .json::<ProjectBody<ProjectResponse>>()
+            .await?
+            .project;
+        created.push(CreatedResource::Project(project.id.clone()));
+
+        // Only used to mint the application credential, never stored
+        let password = uuid::Uuid::new_v4().to_string();
+
+        #[derive(Serialize, Deserialize)]
+        struct UserBody<T> {
+            user: T,
+        }
+
+        #[derive(Deserialize)]
+        struct CreateUserResponse {
+            pub id: String,
+        }
+
+        #[derive(Serialize)]
+        struct CreateUser {
+            name: String,
+            password: String,
+            default_project_id: String,
+        }
+
+        let user: CreateUserResponse = self
+            .send_as_admin(
+                self.client
+                    .post(format!("{}/users", keystone))
+                    .json(&UserBody {
+                        user: CreateUser {
+                            name: username.to_string(),
+                            password: password.clone(),
+                            default_project_id: project.id.clone(),
+                        },
+                    }),
+            )
+            .await?
+            .error_for_status()?
+            .json::<UserBody<CreateUserResponse>>()
+            .await?
+            .user;
+        created.push(CreatedResource::User(user.id.clone()));
+
+        let member_role_id = self.get_member_role_id().await?;
+        self.send_as_admin(self.client.put(format!(
+            "{keystone}/projects/{}/users/{}/roles/{member_role_id}",
+            project.id, user.id
+        )))
+        .await?
+        .error_for_status()?;
+
+        let credential = self
+            .create_application_credential(&user.id, username, &password)
+            .await?;
+        Ok(CloudCreateInfo {
+            provider_id: username.to_string(),
+            provider_pass: credential.to_stored(),
+        })
+    }
+
+    /// Mint an application credential for the user's project, limited to the
+    /// member role. Keystone only lets users create their own, so this logs in
+    /// with the user's password.
+    async fn create_application_credential(
+        &self,
+        user_id: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<ApplicationCredential, CloudError> {
+        #[derive(Serialize, Deserialize)]
+        struct CredentialBody<T> {
+            application_credential: T,
+        }
+
+        #[derive(Serialize)]
+        struct RoleRef {
+            id: String,
+        }
+
+        #[derive(Serialize)]
+        struct CreateCredential {
+            name: String,
+            description: &'static str,
+            roles: Vec<RoleRef>,
+            expires_at: Option<String>,
+            unrestricted: bool,
+        }
+
+        let keystone = self.config.keystone.clone();
+        let member_role_id = self.get_member_role_id().await?;
+        let user_token = self
+            .auth
+            .authenticate(
+                &PasswordCredentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                    project: Some(Self::default_project(username)),
+                }
+                .into(),
+            )
+            .await?;
+        let now = chrono::Utc::now();
+        let expires_at = self.config.credential_days.map(|days| {
+            (now + chrono::Duration::days(days)).to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        });
+        let credential: ApplicationCredential = self
+            .client
+            .post(format!(
+                "{keystone}/users/{user_id}/application_credentials"
+            ))
+            .header("X-Auth-Token", user_token.token)
+            .json(&CredentialBody {
+                application_credential: CreateCredential {
+                    name: format!("{MANAGED_PROJECT_TAG}-{}", now.timestamp()),
+                    description: "Managed by PikaCloud",
+                    roles: vec![RoleRef { id: member_role_id }],
+                    expires_at,
+                    unrestricted: false,
+                },
             })
             .send()
             .await
             .map_err(|e| CloudError::SendRequest(e.to_string()))?
-            .error_for_status()?;
-
-        let new_token = response
-            .headers()
-            .get("X-Subject-Token")
-            .ok_or(CloudError::NotFound("OpenStack admin token".into()))?
-            .to_str()
-            .map_err(|_| CloudError::NotFound("OpenStack admin token".into()))?
-            .to_string();
-        let response_json: AuthResponse = response
-            .json()
-            .await
-            .map_err(|_| CloudError::NotFound("OpenStack admin token".into()))?;
-        let expires_at = response_json
-            .expires_at
-            .ok_or(CloudError::NotFound("OpenStack admin token".into()))?;
-        let expires_at_seconds = chrono::DateTime::parse_from_rfc3339(&expires_at)
-            .map_err(|_| CloudError::NotFound("OpenStack admin token".into()))?
-            .timestamp()
-            - 300;
-        self.cache
-            .set(
-                "openstack:admin-token",
-                &new_token,
-                expires_at_seconds as u64,
-            )
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
-        Ok(new_token)
+            .error_for_status()?
+            .json::<CredentialBody<ApplicationCredential>>()
+            .await?
+            .application_credential;
+        Ok(credential)
+    }
+
+    /// Undo a failed `create_user`, newest resource first
+    async fn rollback(&self, created: Vec<CreatedResource>) {
+        let keystone = self.config.keystone.clone();
+        for resource in created.into_iter().rev() {
+            let url = match &resource {
+                CreatedResource::Project(id) => format!("{keystone}/projects/{id}"),
+                CreatedResource::User(id) => format!("{keystone}/users/{id}"),
+            };
+            if let Err(e) = self.admin_delete(url).await {
+                log::error!("Rollback failed to delete {resource:?}, delete it by hand: {e}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BaseCloudProvider for OpenStackCloudProvider {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn provider_type(&self) -> CloudProvider {
+        CloudProvider::OPENSTACK
+    }
+
+    fn token_cache_pattern(&self) -> Option<String> {
+        Some(format!("{}:*-token*", self.config.name))
+    }
+
+    async fn get_admin_token(&self) -> Result<String, CloudError> {
+        let credentials = self.admin_credentials();
+        self.auth.token(&self.admin_token_key(), &credentials).await
     }
 
     async fn get_user_token(
-        &mut self,
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<String, CloudError> {
+        let project = Self::default_project(&provider_id);
+        let cache_key = self.user_token_key(&provider_id, &project);
+        let credentials = Self::user_credentials(&provider_id, &provider_pass, project);
+        self.auth.token(&cache_key, &credentials).await
+    }
+
+    async fn get_project_token(
+        &self,
         provider_id: String,
         provider_pass: String,
+        project_id: String,
     ) -> Result<String, CloudError> {
-        if let Some(token) = self
-            .cache
-            .get(&format!("openstack:user-token-{}", provider_id))
+        let project = ProjectScope::Id(project_id.clone());
+        let credentials = Self::user_credentials(&provider_id, &provider_pass, project.clone());
+        if let Credentials::ApplicationCredential(_) = credentials {
+            // The token is scoped to the credential's project whatever is asked
+            // for, so it is shared with `get_user_token`
+            let cache_key = self.user_token_key(&provider_id, &Self::default_project(&provider_id));
+            let entry = self.auth.entry(&cache_key, &credentials).await?;
+            return match entry.project_id {
+                Some(id) if id == project_id => Ok(entry.token),
+                _ => Err(CloudError::Unsupported(format!(
+                    "project {project_id} with an application credential of another project"
+                ))),
+            };
+        }
+        self.auth
+            .token(&self.user_token_key(&provider_id, &project), &credentials)
             .await
-        {
-            return Ok(token);
+    }
+
+    /// Create the project, the user and the role assignment in turn. When a
+    /// step fails, whatever was already created is deleted again.
+    async fn create_user(&self, username: String) -> Result<CloudCreateInfo, CloudError> {
+        let mut created = Vec::new();
+        match self.try_create_user(&username, &mut created).await {
+            Ok(info) => Ok(info),
+            Err(e) => {
+                log::error!("Failed to create user {username}, rolling back: {e}");
+                self.rollback(created).await;
+                Err(e)
+            }
         }
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
-        let response = self
-            .client
-            .post(format!("{}/auth/tokens", keystone))
-            .json(&AuthRequest {
-                auth: Auth {
-                    identity: Identity {
-                        methods: vec!["password".to_string()],
-                        password: Password {
-                            user: User {
-                                name: provider_id.clone(),
-                                domain: Domain {
-                                    name: "Default".to_string(),
-                                },
-                                password: provider_pass,
-                            },
-                        },
+    }
+
+    /// Delete everything in the user's project, then the user and the project
+    async fn delete_user(&self, provider_id: String) -> Result<(), CloudError> {
+        let keystone = self.config.keystone.clone();
+        let user_id = match self.get_user_id(&provider_id).await {
+            Ok(user_id) => Some(user_id),
+            Err(CloudError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let project_id = match self.get_project_id(&provider_id).await {
+            Ok(project_id) => Some(project_id),
+            Err(CloudError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if user_id.is_none() && project_id.is_none() {
+            return Err(CloudError::NotFound(format!("user {provider_id}")));
+        }
+
+        if let Some(project_id) = &project_id {
+            self.purge_project(project_id).await?;
+        }
+        if let Some(user_id) = &user_id {
+            self.purge_keypairs(user_id).await?;
+            self.admin_delete(format!("{keystone}/users/{user_id}"))
+                .await?;
+        }
+        if let Some(project_id) = project_id {
+            self.admin_delete(format!("{keystone}/projects/{project_id}"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn rotates_credentials(&self) -> bool {
+        true
+    }
+
+    /// Give the user a new password, mint a new application credential with
+    /// it and delete the old one
+    async fn rotate_credentials(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<String, CloudError> {
+        #[derive(Serialize)]
+        struct UserBody {
+            user: SetPassword,
+        }
+
+        #[derive(Serialize)]
+        struct SetPassword {
+            password: String,
+        }
+
+        let keystone = self.config.keystone.clone();
+        let user_id = self.get_user_id(&provider_id).await?;
+        let password = uuid::Uuid::new_v4().to_string();
+        self.send_as_admin(
+            self.client
+                .patch(format!("{keystone}/users/{user_id}"))
+                .json(&UserBody {
+                    user: SetPassword {
+                        password: password.clone(),
                     },
-                },
-            })
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
-            .error_for_status()?;
-        let new_token = response
-            .headers()
-            .get("X-Subject-Token")
-            .ok_or(CloudError::NotFound("OpenStack user token".into()))?
-            .to_str()
-            .map_err(|_| CloudError::NotFound("OpenStack user token".into()))?
-            .to_string();
-        let response_json: AuthResponse = response
+                }),
+        )
+        .await?
+        .error_for_status()?;
+        let credential = self
+            .create_application_credential(&user_id, &provider_id, &password)
+            .await?;
+
+        if let Some(old) = ApplicationCredential::from_stored(&provider_pass) {
+            let url = format!(
+                "{keystone}/users/{user_id}/application_credentials/{}",
+                old.id
+            );
+            if let Err(e) = self.admin_delete(url).await {
+                log::warn!(
+                    "Failed to delete application credential {} of {provider_id}: {e}",
+                    old.id
+                );
+            }
+        }
+        self.auth
+            .invalidate(&self.user_token_key(&provider_id, &Self::default_project(&provider_id)))
+            .await;
+        Ok(credential.to_stored())
+    }
+
+    /// A `clouds.yaml` or `openrc` for the public interface. Accounts with an
+    /// application credential get a config using it, older accounts one with
+    /// their password.
+    async fn client_config(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        format: Option<ConfigFormat>,
+    ) -> Result<ClientConfig, CloudError> {
+        let project = Self::default_project(&provider_id);
+        let (auth_type, mut auth) =
+            match Self::user_credentials(&provider_id, &provider_pass, project) {
+                Credentials::ApplicationCredential(credential) => (
+                    "v3applicationcredential",
+                    vec![
+                        ("application_credential_id", credential.id),
+                        ("application_credential_secret", credential.secret),
+                    ],
+                ),
+                Credentials::Password(credentials) => (
+                    "password",
+                    vec![
+                        ("username", credentials.username),
+                        ("password", credentials.password),
+                        ("project_name", provider_id),
+                        ("user_domain_name", "Default".to_string()),
+                        ("project_domain_name", "Default".to_string()),
+                    ],
+                ),
+            };
+        auth.insert(0, ("auth_url", self.public_keystone().await?));
+        let mut settings = vec![
+            ("interface", "public".to_string()),
+            ("identity_api_version", "3".to_string()),
+        ];
+        if let Some(region) = &self.config.region {
+            settings.push(("region_name", region.clone()));
+        }
+
+        match format.unwrap_or(ConfigFormat::CloudsYaml) {
+            ConfigFormat::CloudsYaml => {
+                let mut content = format!(
+                    "clouds:\n  {}:\n    auth_type: {auth_type}\n    auth:\n",
+                    self.config.name
+                );
+                for (key, value) in &auth {
+                    content += &format!("      {key}: {}\n", yaml_quote(value));
+                }
+                for (key, value) in &settings {
+                    content += &format!("    {key}: {}\n", yaml_quote(value));
+                }
+                Ok(ClientConfig {
+                    filename: "clouds.yaml".to_string(),
+                    content,
+                })
+            }
+            ConfigFormat::Openrc => {
+                let mut content = format!("export OS_AUTH_TYPE={auth_type}\n");
+                for (key, value) in auth.iter().chain(&settings) {
+                    content +=
+                        &format!("export OS_{}={}\n", key.to_uppercase(), shell_quote(value));
+                }
+                Ok(ClientConfig {
+                    filename: format!("{}-openrc.sh", self.config.name),
+                    content,
+                })
+            }
+            format => Err(CloudError::Unsupported(format!("{format} configs"))),
+        }
+    }
+
+    async fn is_user_exist(&self, provider_id: String) -> Result<bool, CloudError> {
+        match self.get_user_id(&provider_id).await {
+            Ok(_) => Ok(true),
+            Err(CloudError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_accounts(&self) -> Result<Vec<String>, CloudError> {
+        #[derive(Deserialize)]
+        struct ProjectsResponse {
+            projects: Vec<ProjectInfo>,
+        }
+
+        #[derive(Deserialize)]
+        struct ProjectInfo {
+            name: String,
+        }
+
+        let keystone = self.config.keystone.clone();
+        let response: ProjectsResponse = self
+            .send_as_admin(
+                self.client
+                    .get(format!("{keystone}/projects"))
+                    .query(&[("tags", MANAGED_PROJECT_TAG)]),
+            )
+            .await?
+            .error_for_status()?
             .json()
-            .await
-            .map_err(|_| CloudError::NotFound("OpenStack user token".into()))?;
-        let expires_at = response_json
-            .expires_at
-            .ok_or(CloudError::NotFound("OpenStack user token".into()))?;
-        let expires_at_seconds = chrono::DateTime::parse_from_rfc3339(&expires_at)
-            .map_err(|_| CloudError::NotFound("OpenStack user token".into()))?
-            .timestamp()
-            - 300;
-        self.cache
-            .set(
-                &format!("openstack:user-token-{provider_id}"),
-                &new_token,
-                expires_at_seconds as u64,
+            .await?;
+        Ok(response
+            .projects
+            .into_iter()
+            .map(|project| project.name)
+            .collect())
+    }
+
+    async fn import_keypair(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        key_name: String,
+        public_key: String,
+    ) -> Result<(), CloudError> {
+        #[derive(Serialize)]
+        struct Keypair {
+            name: String,
+            public_key: String,
+        }
+
+        #[derive(Serialize)]
+        struct ImportKeypair {
+            keypair: Keypair,
+        }
+
+        let nova = self.endpoint(Service::Compute).await?;
+        let response = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client
+                    .post(format!("{nova}/os-keypairs"))
+                    .json(&ImportKeypair {
+                        keypair: Keypair {
+                            name: key_name,
+                            public_key,
+                        },
+                    }),
             )
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
-        Ok(new_token)
+            .await?;
+        // Nova answers 409 when the keypair already exists
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            return Ok(());
+        }
+        response.error_for_status()?;
+        Ok(())
     }
 
-    async fn create_user(&mut self, username: String) -> Result<CloudCreateInfo, CloudError> {
-        let admin_token = self.get_admin_token().await?;
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
+    async fn delete_keypair(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        key_name: String,
+    ) -> Result<(), CloudError> {
+        let nova = self.endpoint(Service::Compute).await?;
+        let response = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.delete(format!("{nova}/os-keypairs/{key_name}")),
+            )
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
 
+    async fn get_console(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+        console_type: ConsoleType,
+    ) -> Result<RemoteConsole, CloudError> {
         #[derive(Serialize)]
-        struct Project {
-            pub name: String,
-            pub domain_id: String,
+        struct RemoteConsoleRequest {
+            remote_console: RemoteConsoleBody,
+        }
+
+        #[derive(Serialize)]
+        struct RemoteConsoleBody {
+            protocol: &'static str,
+            #[serde(rename = "type")]
+            console_type: &'static str,
         }
 
         #[derive(Deserialize)]
-        struct ProjectResponse {
-            pub id: Option<String>,
+        struct RemoteConsoleResponse {
+            remote_console: RemoteConsoleUrl,
+        }
+
+        #[derive(Deserialize)]
+        struct RemoteConsoleUrl {
+            url: String,
         }
 
+        let nova = self.endpoint(Service::Compute).await?;
+
+        // The user's token only sees servers of their own project, so a
+        // successful lookup doubles as the ownership check.
         let response = self
-            .client
-            .post(&format!("{}/projects", keystone))
-            .header("X-Auth-Token", &admin_token)
-            .json(&Project {
-                name: username.clone(),
-                domain_id: "default".to_string(),
-            })
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
-            .error_for_status()?;
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{nova}/servers/{instance_id}")),
+            )
+            .await?;
+        if matches!(
+            response.status(),
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::FORBIDDEN
+        ) {
+            return Err(CloudError::NotFound(format!("instance {instance_id}")));
+        }
+        response.error_for_status()?;
 
-        let project: ProjectResponse = response
+        let (protocol, nova_type) = match console_type {
+            ConsoleType::Novnc => ("vnc", "novnc"),
+            ConsoleType::Serial => ("serial", "serial"),
+        };
+        let response: RemoteConsoleResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client
+                    .post(format!("{nova}/servers/{instance_id}/remote-consoles"))
+                    // remote-consoles was introduced in compute microversion 2.6
+                    .header("OpenStack-API-Version", "compute 2.6")
+                    .json(&RemoteConsoleRequest {
+                        remote_console: RemoteConsoleBody {
+                            protocol,
+                            console_type: nova_type,
+                        },
+                    }),
+            )
+            .await?
+            .error_for_status()?
             .json()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+            .await?;
 
-        let provider_pass = uuid::Uuid::new_v4().to_string();
+        Ok(RemoteConsole {
+            console_type,
+            url: response.remote_console.url,
+        })
+    }
 
+    async fn list_instances(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Vec<Instance>, CloudError> {
         #[derive(Deserialize)]
-        struct CreateUserResponse {
-            pub id: Option<String>,
+        struct ServersResponse {
+            servers: Vec<Server>,
         }
 
+        let nova = self.endpoint(Service::Compute).await?;
+        let response: ServersResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{nova}/servers/detail")),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.servers.into_iter().map(Instance::from).collect())
+    }
+
+    async fn create_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance: CreateInstance,
+    ) -> Result<Instance, CloudError> {
         #[derive(Serialize)]
-        struct CreateUser {
+        struct CreateServerRequest {
+            server: CreateServer,
+        }
+
+        #[derive(Serialize)]
+        struct CreateServer {
             name: String,
-            password: String,
-            default_project_id: Option<String>,
+            #[serde(rename = "flavorRef")]
+            flavor_ref: String,
+            #[serde(rename = "imageRef")]
+            image_ref: String,
+            networks: serde_json::Value,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            key_name: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct CreateServerResponse {
+            server: CreatedServer,
+        }
+
+        #[derive(Deserialize)]
+        struct CreatedServer {
+            id: String,
+        }
+
+        let nova = self.endpoint(Service::Compute).await?;
+        let networks = match &instance.network_id {
+            Some(network_id) => serde_json::json!([{ "uuid": network_id }]),
+            None => serde_json::json!("auto"),
+        };
+        let response: CreateServerResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client
+                    .post(format!("{nova}/servers"))
+                    // `"networks": "auto"` needs compute microversion 2.37
+                    .header("OpenStack-API-Version", "compute 2.37")
+                    .json(&CreateServerRequest {
+                        server: CreateServer {
+                            name: instance.name.clone(),
+                            flavor_ref: instance.flavor_id.clone(),
+                            image_ref: instance.image_id,
+                            networks,
+                            key_name: instance.key_name,
+                        },
+                    }),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(Instance {
+            id: response.server.id,
+            name: instance.name,
+            status: "BUILD".into(),
+            flavor_id: Some(instance.flavor_id),
+            created_at: None,
+        })
+    }
+
+    async fn delete_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+    ) -> Result<(), CloudError> {
+        let nova = self.endpoint(Service::Compute).await?;
+        let response = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.delete(format!("{nova}/servers/{instance_id}")),
+            )
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(CloudError::NotFound(format!("instance {instance_id}")));
         }
+        response.error_for_status()?;
+        Ok(())
+    }
 
+    async fn stop_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+    ) -> Result<(), CloudError> {
+        let nova = self.endpoint(Service::Compute).await?;
         let response = self
-            .client
-            .post(&format!("{}/users", keystone))
-            .header("X-Auth-Token", &admin_token)
-            .json(&CreateUser {
-                name: username.clone(),
-                password: provider_pass.clone(),
-                default_project_id: project.id,
-            })
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
-            .error_for_status()?;
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client
+                    .post(format!("{nova}/servers/{instance_id}/action"))
+                    .json(&serde_json::json!({ "os-stop": null })),
+            )
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(CloudError::NotFound(format!("instance {instance_id}")));
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
 
-        let user: CreateUserResponse = response
+    async fn get_flavor(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        flavor_id: String,
+    ) -> Result<Flavor, CloudError> {
+        #[derive(Deserialize)]
+        struct FlavorResponse {
+            flavor: FlavorInfo,
+        }
+
+        #[derive(Deserialize)]
+        struct FlavorInfo {
+            id: String,
+            name: String,
+            vcpus: i32,
+            ram: i32,
+            disk: i32,
+        }
+
+        let nova = self.endpoint(Service::Compute).await?;
+        let response = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{nova}/flavors/{flavor_id}")),
+            )
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(CloudError::NotFound(format!("flavor {flavor_id}")));
+        }
+        let FlavorResponse { flavor } = response.error_for_status()?.json().await?;
+        Ok(Flavor {
+            id: flavor.id,
+            name: flavor.name,
+            vcpus: flavor.vcpus,
+            ram_mb: flavor.ram,
+            disk_gb: flavor.disk,
+        })
+    }
+
+    async fn list_volumes(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Vec<Volume>, CloudError> {
+        #[derive(Deserialize)]
+        struct VolumesResponse {
+            volumes: Vec<VolumeInfo>,
+        }
+
+        let cinder = self.endpoint(Service::BlockStorage).await?;
+        let response: VolumesResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{cinder}/volumes/detail")),
+            )
+            .await?
+            .error_for_status()?
             .json()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
-        if user.id.is_none() {
-            log::error!("Failed to create user {username}");
-            return Err(CloudError::NotFound(format!(
-                "Failed to create user {username}"
-            )));
+            .await?;
+        Ok(response.volumes.into_iter().map(Volume::from).collect())
+    }
+
+    async fn create_volume(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        name: String,
+        size_gb: i32,
+    ) -> Result<Volume, CloudError> {
+        #[derive(Serialize)]
+        struct CreateVolumeRequest {
+            volume: CreateVolume,
         }
-        let domain_id = self.get_default_domain_id().await?;
-        let member_role_id = self.get_member_role_id().await?;
-        let _response = self
-            .client
-            .put(&format!(
-                "{keystone}/domains/{domain_id}/users/{0}/roles/{member_role_id}",
-                user.id.unwrap()
-            ))
-            .header("X-Auth-Token", &admin_token)
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
-            .error_for_status()?;
 
-        Ok(CloudCreateInfo {
-            provider_id: username,
-            provider_pass,
+        #[derive(Serialize)]
+        struct CreateVolume {
+            name: String,
+            size: i32,
+        }
+
+        #[derive(Deserialize)]
+        struct CreateVolumeResponse {
+            volume: VolumeInfo,
+        }
+
+        let cinder = self.endpoint(Service::BlockStorage).await?;
+        let response: CreateVolumeResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client
+                    .post(format!("{cinder}/volumes"))
+                    .json(&CreateVolumeRequest {
+                        volume: CreateVolume {
+                            name,
+                            size: size_gb,
+                        },
+                    }),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.volume.into())
+    }
+
+    async fn get_usage(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Resources, CloudError> {
+        #[derive(Deserialize)]
+        struct LimitsResponse {
+            limits: Limits,
+        }
+
+        #[derive(Deserialize)]
+        struct Limits {
+            absolute: serde_json::Map<String, serde_json::Value>,
+        }
+
+        #[derive(Deserialize)]
+        struct FloatingIpsResponse {
+            floatingips: Vec<serde_json::Value>,
+        }
+
+        let nova = self.endpoint(Service::Compute).await?;
+        let cinder = self.endpoint(Service::BlockStorage).await?;
+        let neutron = self.endpoint(Service::Network).await?;
+
+        let compute: LimitsResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{nova}/limits")),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let volume: LimitsResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{cinder}/limits")),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let floating_ips: FloatingIpsResponse = self
+            .send_as_user(
+                &provider_id,
+                &provider_pass,
+                self.client.get(format!("{neutron}/floatingips")),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let absolute = |limits: &Limits, key: &str| {
+            limits
+                .absolute
+                .get(key)
+                .and_then(|v| v.as_i64())
+                .unwrap_or(0) as i32
+        };
+        Ok(Resources {
+            vcpus: absolute(&compute.limits, "totalCoresUsed"),
+            ram_mb: absolute(&compute.limits, "totalRAMUsed"),
+            instances: absolute(&compute.limits, "totalInstancesUsed"),
+            volumes: absolute(&volume.limits, "totalVolumesUsed"),
+            floating_ips: floating_ips.floatingips.len() as i32,
         })
     }
 
-    async fn delete_user(&mut self, provider_id: String) -> Result<(), CloudError> {
-        let admin_token = self.get_admin_token().await?;
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
-        let _response = self
-            .client
-            .delete(&format!("{keystone}/users/{provider_id}"))
-            .header("X-Auth-Token", &admin_token)
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?
-            .error_for_status()?;
+    /// OpenStack enforces limits per service, so the quota is split across the
+    /// Nova, Cinder and Neutron quota sets of the user's project.
+    async fn set_quota(&self, provider_id: String, quota: Resources) -> Result<(), CloudError> {
+        let project_id = self.get_project_id(&provider_id).await?;
+        let nova = self.endpoint(Service::Compute).await?;
+        let cinder = self.endpoint(Service::BlockStorage).await?;
+        let neutron = self.endpoint(Service::Network).await?;
+
+        self.send_as_admin(
+            self.client
+                .put(format!("{nova}/os-quota-sets/{project_id}"))
+                .json(&serde_json::json!({
+                    "quota_set": {
+                        "cores": quota.vcpus,
+                        "ram": quota.ram_mb,
+                        "instances": quota.instances,
+                    }
+                })),
+        )
+        .await?
+        .error_for_status()?;
+        self.send_as_admin(
+            self.client
+                .put(format!("{cinder}/os-quota-sets/{project_id}"))
+                .json(&serde_json::json!({
+                    "quota_set": { "volumes": quota.volumes }
+                })),
+        )
+        .await?
+        .error_for_status()?;
+        self.send_as_admin(
+            self.client
+                .put(format!("{neutron}/quotas/{project_id}"))
+                .json(&serde_json::json!({
+                    "quota": { "floatingip": quota.floating_ips }
+                })),
+        )
+        .await?
+        .error_for_status()?;
         Ok(())
     }
+}
 
-    async fn is_user_exist(&mut self, provider_id: String) -> Result<bool, CloudError> {
-        let admin_token = self.get_admin_token().await?;
-        let keystone = load_env_panic("OPENSTACK_KEYSTONE");
-        let response = self
-            .client
-            .get(&format!("{keystone}/users/{provider_id}"))
-            .header("X-Auth-Token", &admin_token)
-            .send()
-            .await
-            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
-        Ok(response.status().is_success())
+#[derive(Deserialize)]
+struct Server {
+    id: String,
+    name: String,
+    status: String,
+    flavor: Option<ServerFlavor>,
+    created: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ServerFlavor {
+    id: Option<String>,
+}
+
+impl From<Server> for Instance {
+    fn from(server: Server) -> Self {
+        Instance {
+            id: server.id,
+            name: server.name,
+            status: server.status,
+            flavor_id: server.flavor.and_then(|flavor| flavor.id),
+            created_at: server.created,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VolumeInfo {
+    id: String,
+    name: Option<String>,
+    status: String,
+    size: i32,
+}
+
+impl From<VolumeInfo> for Volume {
+    fn from(volume: VolumeInfo) -> Self {
+        Volume {
+            id: volume.id,
+            name: volume.name.unwrap_or_default(),
+            status: volume.status,
+            size_gb: volume.size,
+        }
     }
 }
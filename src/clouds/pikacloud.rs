@@ -0,0 +1,451 @@
+//! Native provider talking to a PikaCloud agent over HTTP
+//!
+//! The agent manages containers and VMs on its host and speaks JSON:
+//!
+//! - `POST /users {name, password}`, `GET /users`, `GET /users/{name}`,
+//!   `DELETE /users/{name}` and `PUT /users/{name}/quota` take the admin token
+//!   `PIKACLOUD_AGENT_TOKEN`, `GET /users` lists `[{name}]`
+//! - `POST /tokens {name, password}` returns `{token, expiresIn}`
+//! - `/instances`, `/volumes`, `/flavors/{id}`, `/keys/{name}` and `/usage`
+//!   take a user token and use the same JSON shapes as the `/api/me` routes
+//!
+//! Every request carries `Authorization: Bearer <token>`.
+
+use async_trait::async_trait;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::RedisClient,
+    models::{
+        CloudCreateInfo, CloudProvider, ConsoleType, CreateInstance, Flavor, Instance,
+        RemoteConsole, Volume,
+    },
+    quota::Resources,
+};
+
+use super::{BaseCloudProvider, CloudError};
+
+#[derive(Serialize)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    token: String,
+    /// Seconds
+    expires_in: u64,
+}
+
+/// Agent of a PikaCloud instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct PikaCloudConfig {
+    /// Instance name, also the prefix of the cache keys
+    pub name: String,
+    /// Base URL of the agent API, `{PREFIX}_AGENT`
+    pub agent: String,
+    /// `{PREFIX}_AGENT_TOKEN`
+    pub agent_token: String,
+}
+
+pub struct PikaCloudProvider {
+    name: String,
+    cache: RedisClient,
+    client: reqwest::Client,
+    agent: String,
+    agent_token: String,
+}
+
+impl PikaCloudProvider {
+    pub fn with_config(cache: RedisClient, config: PikaCloudConfig) -> Self {
+        Self {
+            name: config.name,
+            cache,
+            client: reqwest::Client::new(),
+            agent: config.agent,
+            agent_token: config.agent_token,
+        }
+    }
+
+    /// Agent URL with `segments` appended, each escaped as one path segment
+    fn url(&self, segments: &[&str]) -> Result<Url, CloudError> {
+        let invalid = |e: String| CloudError::SendRequest(format!("Agent URL {}: {e}", self.agent));
+        let mut url = Url::parse(&self.agent).map_err(|e| invalid(e.to_string()))?;
+        url.path_segments_mut()
+            .map_err(|_| invalid("not a base URL".into()))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// Map 404 to `CloudError::NotFound` and other failures to `Provider`
+    fn check(response: reqwest::Response, what: String) -> Result<reqwest::Response, CloudError> {
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(CloudError::NotFound(what));
+        }
+        Ok(response.error_for_status()?)
+    }
+}
+
+#[async_trait]
+impl BaseCloudProvider for PikaCloudProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn provider_type(&self) -> CloudProvider {
+        CloudProvider::PIKACLOUD
+    }
+
+    fn token_cache_pattern(&self) -> Option<String> {
+        Some(format!("{}:*-token*", self.name))
+    }
+
+    async fn get_admin_token(&self) -> Result<String, CloudError> {
+        Ok(self.agent_token.clone())
+    }
+
+    async fn create_user(&self, username: String) -> Result<CloudCreateInfo, CloudError> {
+        let admin_token = self.get_admin_token().await?;
+        let provider_pass = uuid::Uuid::new_v4().to_string();
+        self.client
+            .post(self.url(&["users"])?)
+            .bearer_auth(&admin_token)
+            .json(&Credentials {
+                name: username.clone(),
+                password: provider_pass.clone(),
+            })
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?;
+        Ok(CloudCreateInfo {
+            provider_id: username,
+            provider_pass,
+        })
+    }
+
+    async fn delete_user(&self, provider_id: String) -> Result<(), CloudError> {
+        let admin_token = self.get_admin_token().await?;
+        let response = self
+            .client
+            .delete(self.url(&["users", &provider_id])?)
+            .bearer_auth(&admin_token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        Self::check(response, format!("user {provider_id}"))?;
+        Ok(())
+    }
+
+    async fn is_user_exist(&self, provider_id: String) -> Result<bool, CloudError> {
+        let admin_token = self.get_admin_token().await?;
+        let response = self
+            .client
+            .get(self.url(&["users", &provider_id])?)
+            .bearer_auth(&admin_token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn list_accounts(&self) -> Result<Vec<String>, CloudError> {
+        #[derive(Deserialize)]
+        struct AgentUser {
+            name: String,
+        }
+
+        let admin_token = self.get_admin_token().await?;
+        let users: Vec<AgentUser> = self
+            .client
+            .get(self.url(&["users"])?)
+            .bearer_auth(&admin_token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(users.into_iter().map(|user| user.name).collect())
+    }
+
+    async fn get_user_token(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<String, CloudError> {
+        let cache_key = format!("{}:user-token-{provider_id}", self.name);
+        if let Some(token) = self.cache.get(&cache_key).await {
+            return Ok(token);
+        }
+        let response: TokenResponse = self
+            .client
+            .post(self.url(&["tokens"])?)
+            .json(&Credentials {
+                name: provider_id,
+                password: provider_pass,
+            })
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?;
+        // Stop using the token a minute before the agent does
+        let ttl = response.expires_in.saturating_sub(60);
+        if ttl > 0 {
+            self.cache
+                .set(&cache_key, &response.token, ttl)
+                .await
+                .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        }
+        Ok(response.token)
+    }
+
+    async fn import_keypair(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        key_name: String,
+        public_key: String,
+    ) -> Result<(), CloudError> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Keypair {
+            public_key: String,
+        }
+
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        self.client
+            .put(self.url(&["keys", &key_name])?)
+            .bearer_auth(&token)
+            .json(&Keypair { public_key })
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn delete_keypair(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        key_name: String,
+    ) -> Result<(), CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        let response = self
+            .client
+            .delete(self.url(&["keys", &key_name])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    async fn get_console(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+        console_type: ConsoleType,
+    ) -> Result<RemoteConsole, CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        // The agent only resolves instances owned by the token's user
+        let response = self
+            .client
+            .get(self.url(&["instances", &instance_id, "console"])?)
+            .query(&[("type", console_type)])
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        Ok(Self::check(response, format!("instance {instance_id}"))?
+            .json()
+            .await?)
+    }
+
+    async fn list_instances(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Vec<Instance>, CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        Ok(self
+            .client
+            .get(self.url(&["instances"])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn create_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance: CreateInstance,
+    ) -> Result<Instance, CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        Ok(self
+            .client
+            .post(self.url(&["instances"])?)
+            .bearer_auth(&token)
+            .json(&instance)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn delete_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+    ) -> Result<(), CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        let response = self
+            .client
+            .delete(self.url(&["instances", &instance_id])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        Self::check(response, format!("instance {instance_id}"))?;
+        Ok(())
+    }
+
+    async fn stop_instance(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        instance_id: String,
+    ) -> Result<(), CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        let response = self
+            .client
+            .post(self.url(&["instances", &instance_id, "stop"])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        Self::check(response, format!("instance {instance_id}"))?;
+        Ok(())
+    }
+
+    async fn get_flavor(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        flavor_id: String,
+    ) -> Result<Flavor, CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        let response = self
+            .client
+            .get(self.url(&["flavors", &flavor_id])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        Ok(Self::check(response, format!("flavor {flavor_id}"))?
+            .json()
+            .await?)
+    }
+
+    async fn list_volumes(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Vec<Volume>, CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        Ok(self
+            .client
+            .get(self.url(&["volumes"])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn create_volume(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+        name: String,
+        size_gb: i32,
+    ) -> Result<Volume, CloudError> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct CreateVolume {
+            name: String,
+            size_gb: i32,
+        }
+
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        Ok(self
+            .client
+            .post(self.url(&["volumes"])?)
+            .bearer_auth(&token)
+            .json(&CreateVolume { name, size_gb })
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn get_usage(
+        &self,
+        provider_id: String,
+        provider_pass: String,
+    ) -> Result<Resources, CloudError> {
+        let token = self.get_user_token(provider_id, provider_pass).await?;
+        Ok(self
+            .client
+            .get(self.url(&["usage"])?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn set_quota(&self, provider_id: String, quota: Resources) -> Result<(), CloudError> {
+        let admin_token = self.get_admin_token().await?;
+        let response = self
+            .client
+            .put(self.url(&["users", &provider_id, "quota"])?)
+            .bearer_auth(&admin_token)
+            .json(&quota)
+            .send()
+            .await
+            .map_err(|e| CloudError::SendRequest(e.to_string()))?;
+        Self::check(response, format!("user {provider_id}"))?;
+        Ok(())
+    }
+}
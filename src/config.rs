@@ -0,0 +1,752 @@
+//! Configuration of the server and `pikactl`
+//!
+//! Settings come from a TOML file, `PIKA_CONFIG` or `pikacloud.toml` in the
+//! working directory, overlaid with environment variables, which win. Every
+//! setting keeps the variable it was read from before the file existed, see
+//! `.env.example` and `pikacloud.example.toml`. Everything is validated at
+//! once, so a broken deployment reports all its mistakes in one go.
+
+use std::{
+    collections::HashSet, fmt::Display, net::SocketAddr, path::Path, str::FromStr, time::Duration,
+};
+
+use serde::de::DeserializeOwned;
+use toml::{Table, Value};
+
+use crate::{
+    auth::{iaaa::IaaaConfig, lcpu::LcpuConfig, password::PasswordConfig},
+    clouds::{
+        env_prefix, fake::FakeConfig, kubernetes::KubernetesConfig, openstack::OpenStackConfig,
+        pikacloud::PikaCloudConfig,
+    },
+    db::PoolConfig,
+    jobs::JobConfig,
+    models::CloudProvider,
+    quota::{MergeStrategy, Resources},
+    reconcile::ReconcileConfig,
+    usage::UsageConfig,
+};
+
+/// File read when `PIKA_CONFIG` is not set, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "pikacloud.toml";
+
+/// Auth providers `auth.providers` may list
+pub const AUTH_PROVIDERS: [&str; 3] = ["iaaa", "lcpu", "password"];
+
+/// Every problem found, one per entry
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+pub struct ConfigError(pub Vec<String>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Address the HTTP server listens on, `PIKA_BIND`
+    pub bind: SocketAddr,
+    /// `TRUST_PROXY`
+    pub trust_proxy: Option<String>,
+    /// `DATABASE_URL`
+    pub database_url: String,
+    /// `REDIS_URL`
+    pub redis_url: String,
+    pub db_pool: PoolConfig,
+    /// Apply pending migrations instead of refusing to start,
+    /// `PIKA_MIGRATE_ON_START`
+    pub migrate_on_start: bool,
+    /// Hand out `/api/console/{ticket}` websocket URLs instead of the raw
+    /// console URLs, `PIKA_CONSOLE_PROXY`
+    pub console_proxy: bool,
+    /// `PIKA_QUOTA_MERGE`
+    pub quota_merge: MergeStrategy,
+    pub auth: AuthConfig,
+    /// Cloud instances, in the order they were listed
+    pub clouds: Vec<CloudConfig>,
+    pub jobs: JobConfig,
+    pub reconcile: ReconcileConfig,
+    pub usage: UsageConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthConfig {
+    /// Names of the enabled providers, `AUTH_PROVIDERS`
+    pub providers: Vec<String>,
+    /// Key of the tokens, `JWT_SECRET`
+    pub jwt_secret: String,
+    /// `PIKA_ENABLE_MFA`
+    pub enable_mfa: bool,
+    /// Set when `iaaa` is enabled
+    pub iaaa: Option<IaaaConfig>,
+    /// Set when `lcpu` is enabled
+    pub lcpu: Option<LcpuConfig>,
+    pub password: PasswordConfig,
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self, provider: &str) -> bool {
+        self.providers.iter().any(|name| name == provider)
+    }
+}
+
+/// A cloud instance and the settings of its provider
+#[derive(Debug, Clone, PartialEq)]
+pub enum CloudConfig {
+    OpenStack(OpenStackConfig),
+    PikaCloud(PikaCloudConfig),
+    Fake { name: String, config: FakeConfig },
+    Kubernetes(KubernetesConfig),
+}
+
+impl CloudConfig {
+    /// Instance name
+    pub fn name(&self) -> &str {
+        match self {
+            CloudConfig::OpenStack(config) => &config.name,
+            CloudConfig::PikaCloud(config) => &config.name,
+            CloudConfig::Fake { name, .. } => name,
+            CloudConfig::Kubernetes(config) => &config.name,
+        }
+    }
+
+    pub fn provider(&self) -> CloudProvider {
+        match self {
+            CloudConfig::OpenStack(_) => CloudProvider::OPENSTACK,
+            CloudConfig::PikaCloud(_) => CloudProvider::PIKACLOUD,
+            CloudConfig::Fake { .. } => CloudProvider::FAKE,
+            CloudConfig::Kubernetes(_) => CloudProvider::KUBERNETES,
+        }
+    }
+}
+
+impl Config {
+    /// Read the file named by `PIKA_CONFIG`, or `pikacloud.toml` when it
+    /// exists, and the process environment
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = match std::env::var("PIKA_CONFIG") {
+            Ok(path) => Some(path),
+            Err(_) => Path::new(DEFAULT_CONFIG_FILE)
+                .exists()
+                .then(|| DEFAULT_CONFIG_FILE.to_string()),
+        };
+        let file = match &path {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError(vec![format!("Failed to read {path}: {e}")]))?,
+            ),
+            None => None,
+        };
+        Self::from_sources(file.as_deref(), |key| std::env::var(key).ok())
+    }
+
+    /// `file` is the content of a TOML file, `env` looks up environment
+    /// variables
+    pub fn from_sources(
+        file: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let table = match file {
+            Some(file) => file
+                .parse::<Table>()
+                .map_err(|e| ConfigError(vec![e.to_string()]))?,
+            None => Table::new(),
+        };
+        let mut loader = Loader {
+            env: &env,
+            errors: Vec::new(),
+            read: HashSet::new(),
+        };
+        let config = loader.config(&table);
+        loader.unknown_keys(&table, "");
+        match loader.errors.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError(loader.errors)),
+        }
+    }
+
+    /// Settings changed in `new` that are only read at startup
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("bind", self.bind != new.bind),
+            ("trust_proxy", self.trust_proxy != new.trust_proxy),
+            ("database_url", self.database_url != new.database_url),
+            ("redis_url", self.redis_url != new.redis_url),
+            ("db_pool", self.db_pool != new.db_pool),
+            (
+                "migrate_on_start",
+                self.migrate_on_start != new.migrate_on_start,
+            ),
+            (
+                "auth.jwt_secret",
+                self.auth.jwt_secret != new.auth.jwt_secret,
+            ),
+            ("jobs.workers", self.jobs.workers != new.jobs.workers),
+            ("jobs.backoff", self.jobs.backoff != new.jobs.backoff),
+            ("reconcile", self.reconcile != new.reconcile),
+            ("usage", self.usage != new.usage),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(setting, _)| setting)
+        .collect()
+    }
+
+    /// `new` with the settings of `restart_required` left as in `self`, which
+    /// is what a running server uses after reloading `new`
+    pub fn reloaded(&self, new: Config) -> Config {
+        Config {
+            bind: self.bind,
+            trust_proxy: self.trust_proxy.clone(),
+            database_url: self.database_url.clone(),
+            redis_url: self.redis_url.clone(),
+            db_pool: self.db_pool.clone(),
+            migrate_on_start: self.migrate_on_start,
+            auth: AuthConfig {
+                jwt_secret: self.auth.jwt_secret.clone(),
+                ..new.auth
+            },
+            jobs: JobConfig {
+                workers: self.jobs.workers,
+                backoff: self.jobs.backoff,
+                ..new.jobs
+            },
+            reconcile: self.reconcile.clone(),
+            usage: self.usage.clone(),
+            ..new
+        }
+    }
+}
+
+/// A table of the file, which may be missing
+struct Section<'a> {
+    /// Dotted path, empty for the root
+    path: String,
+    table: Option<&'a Table>,
+}
+
+impl Section<'_> {
+    fn key(&self, key: &str) -> String {
+        match self.path.is_empty() {
+            true => key.to_string(),
+            false => format!("{}.{key}", self.path),
+        }
+    }
+}
+
+/// Reads settings and collects errors instead of stopping at the first one.
+/// Values missing or invalid stand in as their default, the configuration is
+/// discarded anyway when there are errors.
+struct Loader<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+    /// Paths of the file that were looked at, anything else is a typo
+    read: HashSet<String>,
+}
+
+impl Loader<'_> {
+    fn env(&self, var: &str) -> Option<String> {
+        (self.env)(var).filter(|value| !value.trim().is_empty())
+    }
+
+    fn error(&mut self, error: String) {
+        self.errors.push(error);
+    }
+
+    fn section<'t>(&mut self, parent: &Section<'t>, key: &str) -> Section<'t> {
+        let path = parent.key(key);
+        self.read.insert(path.clone());
+        let table = match parent.table.and_then(|table| table.get(key)) {
+            Some(Value::Table(table)) => Some(table),
+            Some(_) => {
+                self.error(format!("{path}: expected a table"));
+                None
+            }
+            None => None,
+        };
+        Section { path, table }
+    }
+
+    /// Settings of a disabled feature are kept but not read
+    fn skip(&mut self, section: &Section) {
+        for key in section.table.into_iter().flat_map(|table| table.keys()) {
+            self.read.insert(section.key(key));
+        }
+    }
+
+    /// `key` of `section`, or the environment variable `var` when it is set
+    fn get<T>(&mut self, section: &Section, key: &str, var: &str) -> Option<T>
+    where
+        T: FromStr + DeserializeOwned,
+        T::Err: Display,
+    {
+        let path = section.key(key);
+        self.read.insert(path.clone());
+        if let Some(value) = self.env(var) {
+            return match value.trim().parse() {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    self.error(format!("{var}={value}: {e}"));
+                    None
+                }
+            };
+        }
+        let value = section.table?.get(key)?.clone();
+        match value.try_into() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error(format!("{path}: {e}"));
+                None
+            }
+        }
+    }
+
+    fn or<T>(&mut self, section: &Section, key: &str, var: &str, default: T) -> T
+    where
+        T: FromStr + DeserializeOwned,
+        T::Err: Display,
+    {
+        self.get(section, key, var).unwrap_or(default)
+    }
+
+    fn required<T>(&mut self, section: &Section, key: &str, var: &str) -> T
+    where
+        T: FromStr + DeserializeOwned + Default,
+        T::Err: Display,
+    {
+        let errors = self.errors.len();
+        match self.get(section, key, var) {
+            Some(value) => value,
+            None => {
+                if self.errors.len() == errors {
+                    self.error(format!("{} or {var} must be set", section.key(key)));
+                }
+                T::default()
+            }
+        }
+    }
+
+    /// An array in the file, a comma-separated list in the environment
+    fn list(&mut self, section: &Section, key: &str, var: &str) -> Option<Vec<String>> {
+        let path = section.key(key);
+        self.read.insert(path.clone());
+        if let Some(value) = self.env(var) {
+            return Some(
+                value
+                    .split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect(),
+            );
+        }
+        let value = section.table?.get(key)?.clone();
+        match value.try_into() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error(format!("{path}: {e}"));
+                None
+            }
+        }
+    }
+
+    fn config(&mut self, table: &Table) -> Config {
+        let root = Section {
+            path: String::new(),
+            table: Some(table),
+        };
+        let default_bind = SocketAddr::from(([127, 0, 0, 1], 8080));
+        Config {
+            bind: self.or(&root, "bind", "PIKA_BIND", default_bind),
+            trust_proxy: self.get(&root, "trust_proxy", "TRUST_PROXY"),
+            database_url: self.required(&root, "database_url", "DATABASE_URL"),
+            redis_url: self.required(&root, "redis_url", "REDIS_URL"),
+            db_pool: self.db_pool(&root),
+            migrate_on_start: self.or(&root, "migrate_on_start", "PIKA_MIGRATE_ON_START", false),
+            console_proxy: self.or(&root, "console_proxy", "PIKA_CONSOLE_PROXY", false),
+            quota_merge: self.or(&root, "quota_merge", "PIKA_QUOTA_MERGE", MergeStrategy::Max),
+            auth: self.auth(&root),
+            clouds: self.clouds(&root),
+            jobs: self.jobs(&root),
+            reconcile: self.reconcile(&root),
+            usage: self.usage(&root),
+        }
+    }
+
+    fn db_pool(&mut self, root: &Section) -> PoolConfig {
+        let section = self.section(root, "db_pool");
+        let default = PoolConfig::default();
+        let pool = PoolConfig {
+            max_size: self.or(&section, "max_size", "PIKA_DB_POOL_SIZE", default.max_size),
+            min_idle: self.get(&section, "min_idle", "PIKA_DB_MIN_IDLE"),
+            connection_timeout: self
+                .get(&section, "timeout", "PIKA_DB_TIMEOUT")
+                .map(Duration::from_secs)
+                .unwrap_or(default.connection_timeout),
+        };
+        if pool.max_size == 0 {
+            self.error(format!("{}: must be at least 1", section.key("max_size")));
+        }
+        if pool
+            .min_idle
+            .is_some_and(|min_idle| min_idle > pool.max_size)
+        {
+            self.error(format!(
+                "{}: must not exceed max_size",
+                section.key("min_idle")
+            ));
+        }
+        pool
+    }
+
+    fn auth(&mut self, root: &Section) -> AuthConfig {
+        let section = self.section(root, "auth");
+        let providers = self
+            .list(&section, "providers", "AUTH_PROVIDERS")
+            .unwrap_or_default();
+        for provider in &providers {
+            if !AUTH_PROVIDERS.contains(&provider.as_str()) {
+                self.error(format!(
+                    "{}: unknown auth provider {provider}, expected one of {}",
+                    section.key("providers"),
+                    AUTH_PROVIDERS.join(", ")
+                ));
+            }
+        }
+        let enabled = |name: &str| providers.iter().any(|provider| provider == name);
+
+        let iaaa = self.section(&section, "iaaa");
+        let iaaa = match enabled("iaaa") {
+            true => Some(IaaaConfig {
+                id: self.required(&iaaa, "id", "IAAA_ID"),
+                key: self.required(&iaaa, "key", "IAAA_KEY"),
+            }),
+            false => {
+                self.skip(&iaaa);
+                None
+            }
+        };
+        let lcpu = self.section(&section, "lcpu");
+        let lcpu = match enabled("lcpu") {
+            true => Some(LcpuConfig {
+                app_root: self.required(&lcpu, "app_root", "LCPU_APP_ROOT"),
+                app_id: self.required(&lcpu, "app_id", "LCPU_APP_ID"),
+                app_key: self.required(&lcpu, "app_key", "LCPU_APP_KEY"),
+            }),
+            false => {
+                self.skip(&lcpu);
+                None
+            }
+        };
+        let password = self.section(&section, "password");
+        let password = PasswordConfig {
+            allow_register: self.or(
+                &password,
+                "allow_register",
+                "PIKA_ALLOW_PASSWORD_REGISTER",
+                false,
+            ),
+        };
+        AuthConfig {
+            jwt_secret: self.required(&section, "jwt_secret", "JWT_SECRET"),
+            enable_mfa: self.or(&section, "enable_mfa", "PIKA_ENABLE_MFA", false),
+            providers,
+            iaaa,
+            lcpu,
+            password,
+        }
+    }
+
+    /// Instances are listed by `CLOUD_PROVIDER`, entries being an instance
+    /// name optionally followed by `:<provider>` when the name is not the
+    /// provider's own, e.g. `openstack-gpu:openstack`. Without it they are
+    /// the `[[clouds]]` of the file. Either way an instance is configured by
+    /// its `[[clouds]]` entry of the same name and by the variables with its
+    /// prefix, e.g. `OPENSTACK_GPU_KEYSTONE`, see `env_prefix`.
+    fn clouds(&mut self, root: &Section) -> Vec<CloudConfig> {
+        self.read.insert("clouds".to_string());
+        let entries: Vec<&Table> = match root.table.and_then(|table| table.get("clouds")) {
+            Some(Value::Array(entries)) => entries.iter().filter_map(Value::as_table).collect(),
+            Some(_) => {
+                self.error("clouds: expected an array of tables".to_string());
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        fn entry_name(entry: &Table) -> Option<&str> {
+            entry.get("name").and_then(Value::as_str)
+        }
+
+        // (name, provider, index of the entry in the file)
+        let mut instances: Vec<(String, String, Option<usize>)> = Vec::new();
+        match self.env("CLOUD_PROVIDER") {
+            Some(list) => {
+                for item in list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                {
+                    let (name, kind) = item.split_once(':').unwrap_or((item, item));
+                    let index = entries
+                        .iter()
+                        .position(|entry| entry_name(entry) == Some(name));
+                    instances.push((name.to_string(), kind.to_string(), index));
+                }
+            }
+            None => {
+                for (index, entry) in entries.iter().enumerate() {
+                    let Some(name) = entry_name(entry) else {
+                        self.error(format!("clouds[{index}].name must be set"));
+                        continue;
+                    };
+                    let kind = entry.get("provider").and_then(Value::as_str);
+                    let kind = kind.unwrap_or(name);
+                    instances.push((name.to_string(), kind.to_string(), Some(index)));
+                }
+            }
+        }
+        if instances.is_empty() {
+            self.error("clouds or CLOUD_PROVIDER must list at least one cloud instance".into());
+        }
+
+        let mut clouds = Vec::new();
+        for (name, kind, index) in instances {
+            if clouds
+                .iter()
+                .any(|cloud: &CloudConfig| cloud.name() == name)
+            {
+                self.error(format!("Cloud instance {name} is configured twice"));
+                continue;
+            }
+            let section = match index {
+                Some(index) => {
+                    let path = format!("clouds[{index}]");
+                    self.read.insert(path.clone());
+                    self.read.insert(format!("{path}.name"));
+                    self.read.insert(format!("{path}.provider"));
+                    Section {
+                        path,
+                        table: Some(entries[index]),
+                    }
+                }
+                None => Section {
+                    path: format!("clouds.{name}"),
+                    table: None,
+                },
+            };
+            let config = match CloudProvider::from_name(&kind) {
+                Some(CloudProvider::OPENSTACK) => {
+                    CloudConfig::OpenStack(self.openstack(&section, &name))
+                }
+                Some(CloudProvider::PIKACLOUD) => {
+                    CloudConfig::PikaCloud(self.pikacloud(&section, &name))
+                }
+                Some(CloudProvider::FAKE) => CloudConfig::Fake {
+                    config: self.fake(&section),
+                    name,
+                },
+                Some(CloudProvider::KUBERNETES) => {
+                    CloudConfig::Kubernetes(self.kubernetes(&section, &name))
+                }
+                None => {
+                    self.error(format!("Unknown cloud provider {kind} of instance {name}"));
+                    continue;
+                }
+            };
+            clouds.push(config);
+        }
+        clouds
+    }
+
+    fn openstack(&mut self, section: &Section, name: &str) -> OpenStackConfig {
+        let prefix = env_prefix(name);
+        let var = |suffix: &str| format!("{prefix}_{suffix}");
+        let config = OpenStackConfig {
+            name: name.to_string(),
+            keystone: self.required(section, "keystone", &var("KEYSTONE")),
+            interface: self.or(section, "interface", &var("INTERFACE"), "public".into()),
+            region: self.get(section, "region", &var("REGION")),
+            nova: self.get(section, "nova", &var("NOVA")),
+            cinder: self.get(section, "cinder", &var("CINDER")),
+            neutron: self.get(section, "neutron", &var("NEUTRON")),
+            admin_username: self.required(section, "admin_username", &var("ADMIN_USERNAME")),
+            admin_password: self.required(section, "admin_password", &var("ADMIN_PASSWORD")),
+            admin_project: self.or(
+                section,
+                "admin_project",
+                &var("ADMIN_PROJECT"),
+                "admin".into(),
+            ),
+            credential_days: Some(self.or(section, "credential_days", &var("CREDENTIAL_DAYS"), 90))
+                .filter(|days| *days > 0),
+        };
+        if !["public", "internal"].contains(&config.interface.as_str()) {
+            self.error(format!(
+                "{}: expected public or internal, got {}",
+                section.key("interface"),
+                config.interface
+            ));
+        }
+        config
+    }
+
+    fn pikacloud(&mut self, section: &Section, name: &str) -> PikaCloudConfig {
+        let prefix = env_prefix(name);
+        PikaCloudConfig {
+            name: name.to_string(),
+            agent: self.required(section, "agent", &format!("{prefix}_AGENT")),
+            agent_token: self.required(section, "agent_token", &format!("{prefix}_AGENT_TOKEN")),
+        }
+    }
+
+    /// Every fake instance shares the `PIKA_FAKE_*` variables
+    fn fake(&mut self, section: &Section) -> FakeConfig {
+        let latency = self.or(section, "latency_ms", "PIKA_FAKE_LATENCY_MS", 0);
+        let failure_rate = self.or(section, "failure_rate", "PIKA_FAKE_FAILURE_RATE", 0.0);
+        if !(0.0..=1.0).contains(&failure_rate) {
+            self.error(format!(
+                "{}: must be between 0 and 1, got {failure_rate}",
+                section.key("failure_rate")
+            ));
+        }
+        FakeConfig {
+            latency: Duration::from_millis(latency),
+            failure_rate,
+        }
+    }
+
+    fn kubernetes(&mut self, section: &Section, name: &str) -> KubernetesConfig {
+        let prefix = env_prefix(name);
+        let var = |suffix: &str| format!("{prefix}_{suffix}");
+        let config = KubernetesConfig {
+            name: name.to_string(),
+            api: self.required(section, "api", &var("API")),
+            public_api: self.get(section, "public_api", &var("PUBLIC_API")),
+            token: self.required(section, "token", &var("TOKEN")),
+            ca_file: self.get(section, "ca_file", &var("CA_FILE")),
+            namespace_prefix: self.or(
+                section,
+                "namespace_prefix",
+                &var("NAMESPACE_PREFIX"),
+                "pika-".into(),
+            ),
+            cluster_role: self.or(section, "cluster_role", &var("CLUSTER_ROLE"), "edit".into()),
+            token_seconds: self.or(section, "token_seconds", &var("TOKEN_SECONDS"), 3600),
+            quota: Resources {
+                vcpus: self.or(section, "quota_vcpus", &var("QUOTA_VCPUS"), 2),
+                ram_mb: self.or(section, "quota_ram_mb", &var("QUOTA_RAM_MB"), 4096),
+                instances: self.or(section, "quota_pods", &var("QUOTA_PODS"), 10),
+                volumes: self.or(section, "quota_volumes", &var("QUOTA_VOLUMES"), 5),
+                floating_ips: self.or(
+                    section,
+                    "quota_load_balancers",
+                    &var("QUOTA_LOAD_BALANCERS"),
+                    0,
+                ),
+            },
+        };
+        if config.token_seconds < 600 {
+            self.error(format!(
+                "{}: must be at least 600, got {}",
+                section.key("token_seconds"),
+                config.token_seconds
+            ));
+        }
+        if let Some(ca_file) = &config.ca_file {
+            if !Path::new(ca_file).is_file() {
+                self.error(format!(
+                    "{}: {ca_file} is not a file",
+                    section.key("ca_file")
+                ));
+            }
+        }
+        config
+    }
+
+    fn jobs(&mut self, root: &Section) -> JobConfig {
+        let section = self.section(root, "jobs");
+        let default = JobConfig::default();
+        let config = JobConfig {
+            workers: self.or(&section, "workers", "PIKA_JOB_WORKERS", default.workers),
+            max_attempts: self.or(
+                &section,
+                "max_attempts",
+                "PIKA_JOB_MAX_ATTEMPTS",
+                default.max_attempts,
+            ),
+            backoff: self
+                .get(&section, "backoff", "PIKA_JOB_BACKOFF")
+                .map(Duration::from_secs)
+                .unwrap_or(default.backoff),
+        };
+        if config.max_attempts < 1 {
+            self.error(format!(
+                "{}: must be at least 1",
+                section.key("max_attempts")
+            ));
+        }
+        config
+    }
+
+    fn reconcile(&mut self, root: &Section) -> ReconcileConfig {
+        let section = self.section(root, "reconcile");
+        let default = ReconcileConfig::default();
+        ReconcileConfig {
+            interval: self.or(
+                &section,
+                "interval",
+                "PIKA_RECONCILE_INTERVAL",
+                default.interval,
+            ),
+            delete: self.or(&section, "delete", "PIKA_RECONCILE_DELETE", default.delete),
+            credential_rotation_days: self.or(
+                &section,
+                "credential_rotation_days",
+                "PIKA_CREDENTIAL_ROTATION_DAYS",
+                default.credential_rotation_days,
+            ),
+        }
+    }
+
+    fn usage(&mut self, root: &Section) -> UsageConfig {
+        let section = self.section(root, "usage");
+        let default = UsageConfig::default();
+        UsageConfig {
+            interval: self.or(
+                &section,
+                "interval",
+                "PIKA_USAGE_INTERVAL",
+                default.interval,
+            ),
+            credit_grace_hours: self.or(
+                &section,
+                "credit_grace_hours",
+                "PIKA_CREDIT_GRACE_HOURS",
+                default.credit_grace_hours,
+            ),
+        }
+    }
+
+    /// Report keys of the file nothing reads, which are most likely typos
+    fn unknown_keys(&mut self, table: &Table, path: &str) {
+        for (key, value) in table {
+            let key = match path.is_empty() {
+                true => key.to_string(),
+                false => format!("{path}.{key}"),
+            };
+            match value {
+                Value::Table(table) if self.read.contains(&key) => self.unknown_keys(table, &key),
+                Value::Array(entries) if key == "clouds" => {
+                    for (index, entry) in entries.iter().enumerate() {
+                        let entry_key = format!("{key}[{index}]");
+                        match entry.as_table() {
+                            // Entries left out by `CLOUD_PROVIDER` are not read
+                            Some(table) if self.read.contains(&entry_key) => {
+                                self.unknown_keys(table, &entry_key)
+                            }
+                            Some(_) => {}
+                            None => self.error(format!("{entry_key}: expected a table")),
+                        }
+                    }
+                }
+                _ if self.read.contains(&key) => {}
+                _ => self.error(format!("{key}: unknown setting")),
+            }
+        }
+    }
+}
@@ -1,6 +1,9 @@
+use std::{future::Future, time::Duration};
+
 use diesel::{
     r2d2::{ConnectionManager, Pool, PooledConnection},
-    PgConnection,
+    sql_types::{BigInt, Text},
+    PgConnection, QueryResult, RunQueryDsl,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -9,32 +12,177 @@ pub enum DBError {
     Connection(String),
     #[error("Fail to fetch connection: {0}")]
     FetchConn(String),
+    #[error("{0}")]
+    Query(#[from] diesel::result::Error),
+    #[error("Database task failed: {0}")]
+    Task(String),
 }
 
 pub type DBResult<T> = std::result::Result<T, DBError>;
 
+pub type DBConn = PooledConnection<ConnectionManager<PgConnection>>;
+
+/// Sizing of the connection pool
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    /// Connections kept at most, `PIKA_DB_POOL_SIZE`
+    pub max_size: u32,
+    /// Idle connections kept ready, all of them when `None`,
+    /// `PIKA_DB_MIN_IDLE`
+    pub min_idle: Option<u32>,
+    /// Wait for a free connection before giving up, `PIKA_DB_TIMEOUT` seconds
+    pub connection_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
 /// Database client. Since `PgPool` is clone-safe, `DBClient` is clone-safe as well.
+///
+/// Diesel connections block, so async code goes through `run` or `query`,
+/// which use tokio's blocking pool instead of the async workers.
 #[derive(Debug, Clone)]
 pub struct DBClient {
     pool: Pool<ConnectionManager<PgConnection>>,
 }
 
 impl DBClient {
-    pub fn connect(database_url: &String) -> DBResult<Self> {
+    pub fn connect(database_url: &str, config: &PoolConfig) -> DBResult<Self> {
         let manager = ConnectionManager::<PgConnection>::new(database_url);
         let pool = Pool::builder()
             .test_on_check_out(true)
+            .max_size(config.max_size)
+            .min_idle(config.min_idle)
+            .connection_timeout(config.connection_timeout)
             .build(manager)
             .map_err(|e| DBError::Connection(e.to_string()))?;
 
         Ok(Self { pool })
     }
 
-    pub fn get_conn(&self) -> DBResult<PooledConnection<ConnectionManager<PgConnection>>> {
+    /// A connection for synchronous callers. Blocks until one is free.
+    pub fn get_conn(&self) -> DBResult<DBConn> {
         let conn = self
             .pool
             .get()
             .map_err(|e| DBError::FetchConn(e.to_string()))?;
         Ok(conn)
     }
+
+    /// Run `f` with a connection on the blocking pool
+    pub async fn run<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut DBConn) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<DBError> + Send + 'static,
+    {
+        let client = self.clone();
+        tokio::task::spawn_blocking(move || f(&mut client.get_conn()?))
+            .await
+            .map_err(|e| DBError::Task(e.to_string()))?
+    }
+
+    /// `run` for closures made of Diesel queries
+    pub async fn query<T, F>(&self, f: F) -> DBResult<T>
+    where
+        F: FnOnce(&mut DBConn) -> QueryResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(move |conn| Ok(f(conn)?)).await
+    }
+
+    /// Take the advisory lock named `key` unless someone else holds it. The
+    /// lock belongs to a connection, which is kept out of the pool until the
+    /// returned guard is released or dropped. Nothing waits for the lock, so
+    /// contention never ties up more than the holder's connection.
+    pub async fn try_advisory_lock(&self, key: &str) -> DBResult<Option<AdvisoryLock>> {
+        let client = self.clone();
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = client.get_conn()?;
+            let locked = diesel::select(pg_try_advisory_lock(hashtextextended(&key, 0)))
+                .get_result::<bool>(&mut conn)?;
+            Ok(locked.then(|| AdvisoryLock {
+                conn: Some(conn),
+                key,
+            }))
+        })
+        .await
+        .map_err(|e| DBError::Task(e.to_string()))?
+    }
+
+    /// Run `f` while holding the advisory lock named `key`, or skip it and
+    /// return `None` when someone else holds the lock. Background tasks run on
+    /// every replica and go through this, so that passes never overlap.
+    pub async fn exclusive<T, F, Fut>(&self, key: &str, f: F) -> DBResult<Option<T>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let Some(lock) = self.try_advisory_lock(key).await? else {
+            return Ok(None);
+        };
+        let output = f().await;
+        if let Err(e) = lock.release().await {
+            log::error!("Failed to release advisory lock {key}: {e}");
+        }
+        Ok(Some(output))
+    }
+}
+
+diesel::define_sql_function!(fn pg_try_advisory_lock(key: BigInt) -> Bool);
+diesel::define_sql_function!(fn pg_advisory_unlock(key: BigInt) -> Bool);
+diesel::define_sql_function!(fn hashtextextended(text: Text, seed: BigInt) -> BigInt);
+
+/// Holds a lock taken by `DBClient::try_advisory_lock`
+pub struct AdvisoryLock {
+    conn: Option<DBConn>,
+    key: String,
+}
+
+impl AdvisoryLock {
+    /// Give the lock up now rather than when the guard is dropped
+    pub async fn release(mut self) -> DBResult<()> {
+        let Some(mut conn) = self.conn.take() else {
+            return Ok(());
+        };
+        let key = std::mem::take(&mut self.key);
+        tokio::task::spawn_blocking(move || unlock(&mut conn, &key))
+            .await
+            .map_err(|e| DBError::Task(e.to_string()))?
+    }
+}
+
+fn unlock(conn: &mut DBConn, key: &str) -> DBResult<()> {
+    diesel::select(pg_advisory_unlock(hashtextextended(key, 0))).get_result::<bool>(conn)?;
+    Ok(())
+}
+
+impl Drop for AdvisoryLock {
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else {
+            return;
+        };
+        let key = std::mem::take(&mut self.key);
+        // The connection goes back to the pool, so it must not keep the lock
+        let mut release = move || {
+            if let Err(e) = unlock(&mut conn, &key) {
+                log::error!("Failed to release advisory lock {key}: {e}");
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(release);
+            }
+            // Outside a runtime nothing async is held up by blocking here
+            Err(_) => release(),
+        }
+    }
 }
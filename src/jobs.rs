@@ -0,0 +1,886 @@
+//! Asynchronous cloud operations
+//!
+//! Creating accounts and creating or deleting instances are slow remote calls,
+//! so the API records them as `Job` rows and returns right away. A pool of
+//! workers takes the ids of runnable jobs from the Redis list `jobs:queue`.
+//! Failed attempts that may succeed later are retried with exponential backoff
+//! by parking the id in the sorted set `jobs:delayed`, scored by the time the
+//! job may run again, until `maxAttempts` is reached.
+//!
+//! The row is the source of truth: a worker only runs a job it could move from
+//! `PENDING` to `RUNNING`, so stale or duplicate ids in Redis are harmless, and
+//! on startup every pending job is queued again. Running jobs record the
+//! process running them and a heartbeat, so with several replicas a job is
+//! only taken over once its worker has stopped sending heartbeats. Abandoned
+//! instance creations and jobs on their last attempt fail instead.
+
+use std::{sync::OnceLock, time::Duration};
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::IntervalDsl;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{CacheError, RedisClient},
+    clouds::{sync_ssh_keys, CloudError},
+    db::{DBClient, DBConn, DBError},
+    models::{
+        self, CloudAccountInfo, CloudProvider, CreateInstance, Job, JobKind, JobStatus,
+        NewCloudUser, NewJob,
+    },
+    placement::place,
+    quota::{check_quota, effective_quota, lock_quota, sync_quota, QuotaError, Resources},
+    repository::{self, find_cloud_account, CloudAccountError},
+    schema,
+    server::AppState,
+};
+
+const JOB_QUEUE_KEY: &str = "jobs:queue";
+const JOB_DELAYED_KEY: &str = "jobs:delayed";
+
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// How long a creation waits for another one of the same user to finish its
+/// quota check before it is retried later
+const QUOTA_LOCK_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a running job's heartbeat is written
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A running job whose last heartbeat is older than this lost its worker and
+/// is run again
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// `workerId` of the jobs this process runs
+fn worker_id() -> &'static str {
+    static WORKER_ID: OnceLock<String> = OnceLock::new();
+    WORKER_ID.get_or_init(|| {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".into());
+        format!("{host}:{}", std::process::id())
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("Fail to fetch connection: {0}")]
+    Connection(DBError),
+    #[error("Cache error: {0}")]
+    Cache(#[from] CacheError),
+    #[error("Cloud error: {0}")]
+    Cloud(#[from] CloudError),
+    #[error("Invalid payload: {0}")]
+    Payload(#[from] serde_json::Error),
+    /// The operation cannot succeed as requested, e.g. over quota
+    #[error("{0}")]
+    Rejected(String),
+    /// Another job of the user holds what this one needs, it runs again later
+    #[error("{0}")]
+    Busy(String),
+}
+
+impl From<DBError> for JobError {
+    fn from(err: DBError) -> Self {
+        match err {
+            DBError::Query(e) => JobError::Database(e),
+            e => JobError::Connection(e),
+        }
+    }
+}
+
+impl From<QuotaError> for JobError {
+    fn from(err: QuotaError) -> Self {
+        match err {
+            QuotaError::Exceeded(_) => JobError::Rejected(err.to_string()),
+            QuotaError::Database(e) => JobError::Database(e),
+            QuotaError::Connection(e) => JobError::Connection(e),
+            QuotaError::Cloud(e) => JobError::Cloud(e),
+            QuotaError::Busy => JobError::Busy(err.to_string()),
+        }
+    }
+}
+
+impl From<CloudAccountError> for JobError {
+    fn from(err: CloudAccountError) -> Self {
+        match err {
+            CloudAccountError::Database(e) => e.into(),
+            e => JobError::Rejected(e.to_string()),
+        }
+    }
+}
+
+impl JobError {
+    /// Whether another attempt could succeed, e.g. after a network error or a
+    /// 5xx. A 4xx such as a bad flavor or an exceeded cloud quota stays.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            JobError::Database(_) | JobError::Connection(_) | JobError::Cache(_) => true,
+            JobError::Busy(_) => true,
+            JobError::Cloud(CloudError::SendRequest(_) | CloudError::Timeout(_)) => true,
+            JobError::Cloud(CloudError::Provider(err)) => {
+                err.status().is_none_or(|status| status.is_server_error())
+            }
+            JobError::Cloud(_) | JobError::Payload(_) | JobError::Rejected(_) => false,
+        }
+    }
+
+    /// Whether the cloud may have carried out the request although no answer
+    /// arrived, e.g. when the connection dropped after sending it
+    pub fn is_ambiguous(&self) -> bool {
+        match self {
+            JobError::Cloud(CloudError::SendRequest(_) | CloudError::Timeout(_)) => true,
+            JobError::Cloud(CloudError::Provider(err)) => err.status().is_none(),
+            _ => false,
+        }
+    }
+
+    /// `is_retryable` for a job of `kind`. Creating an instance is not
+    /// idempotent, so it is not retried when Nova may have accepted it,
+    /// which would create a second server.
+    pub fn is_retryable_for(&self, kind: JobKind) -> bool {
+        self.is_retryable() && !(kind == JobKind::CREATE_INSTANCE && self.is_ambiguous())
+    }
+}
+
+pub type JobResult<T> = std::result::Result<T, JobError>;
+
+/// Payload of `DELETE_INSTANCE` jobs. `CREATE_INSTANCE` jobs carry the
+/// `CreateInstance` request and `PROVISION_ACCOUNT` jobs nothing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteInstancePayload {
+    pub instance_id: String,
+}
+
+/// A job as shown to its owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: JobKind,
+    pub provider: String,
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    /// When a pending job will be tried next
+    pub run_after: NaiveDateTime,
+    pub cancel_requested: bool,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+impl From<Job> for JobInfo {
+    fn from(job: Job) -> Self {
+        Self {
+            id: job.id,
+            kind: job.kind,
+            provider: job.cloudProvider.name().to_string(),
+            status: job.status,
+            attempts: job.attempts,
+            max_attempts: job.maxAttempts,
+            run_after: job.runAfter,
+            cancel_requested: job.cancelRequested,
+            result: job
+                .result
+                .and_then(|result| serde_json::from_str(&result).ok()),
+            error: job.error,
+            created_at: job.createdAt,
+            finished_at: job.finishedAt,
+        }
+    }
+}
+
+/// Workers and retries of jobs
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobConfig {
+    /// Workers running jobs, 0 leaves them pending, `PIKA_JOB_WORKERS`
+    pub workers: usize,
+    /// Attempts before a job is given up, `PIKA_JOB_MAX_ATTEMPTS`
+    pub max_attempts: i32,
+    /// Wait after the first failed attempt, `PIKA_JOB_BACKOFF` seconds
+    pub backoff: Duration,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_attempts: 5,
+            backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Wait before retrying a job that failed its `attempt`th attempt, doubling
+/// from `base` up to `MAX_BACKOFF`
+pub fn backoff(base: Duration, attempt: i32) -> Duration {
+    let exponent = attempt.saturating_sub(1).clamp(0, 16) as u32;
+    base.saturating_mul(2u32.pow(exponent)).min(MAX_BACKOFF)
+}
+
+/// Record a job and queue it for the workers
+pub async fn enqueue(
+    db: &DBClient,
+    cache: &RedisClient,
+    config: &JobConfig,
+    user_id: &str,
+    kind: JobKind,
+    provider: CloudProvider,
+    payload: &impl Serialize,
+) -> JobResult<Job> {
+    let new_job = NewJob {
+        userId: user_id.to_string(),
+        kind,
+        cloudProvider: provider,
+        payload: serde_json::to_string(payload)?,
+        maxAttempts: config.max_attempts,
+    };
+    let job = db
+        .query(move |conn| {
+            diesel::insert_into(schema::Job::table)
+                .values(&new_job)
+                .returning(models::Job::as_returning())
+                .get_result(conn)
+        })
+        .await?;
+    if let Err(e) = cache.push(JOB_QUEUE_KEY, &job.id).await {
+        // Nobody would pick the job up before the next restart
+        let job_id = job.id.clone();
+        db.query(move |conn| diesel::delete(schema::Job::dsl::Job.find(&job_id)).execute(conn))
+            .await?;
+        return Err(e.into());
+    }
+    Ok(job)
+}
+
+/// The user's unfinished job of `kind` on `provider`, if any
+pub fn find_unfinished(
+    conn: &mut DBConn,
+    user_id: &str,
+    kind: JobKind,
+    provider: CloudProvider,
+) -> QueryResult<Option<Job>> {
+    schema::Job::dsl::Job
+        .filter(schema::Job::userId.eq(user_id))
+        .filter(schema::Job::kind.eq(kind))
+        .filter(schema::Job::cloudProvider.eq(provider))
+        .filter(schema::Job::status.eq_any([JobStatus::PENDING, JobStatus::RUNNING]))
+        .select(models::Job::as_select())
+        .first(conn)
+        .optional()
+}
+
+/// Cancel the user's job. A pending job is cancelled right away, a running one
+/// once its current attempt ends, unless that attempt succeeds. Returns the
+/// job, unchanged when it had already finished.
+pub fn cancel_job(conn: &mut DBConn, user_id: &str, job_id: &str) -> QueryResult<Job> {
+    let owned = schema::Job::dsl::Job
+        .filter(schema::Job::id.eq(job_id))
+        .filter(schema::Job::userId.eq(user_id));
+    let cancelled = diesel::update(owned.filter(schema::Job::status.eq(JobStatus::PENDING)))
+        .set((
+            schema::Job::status.eq(JobStatus::CANCELLED),
+            schema::Job::cancelRequested.eq(true),
+            schema::Job::updatedAt.eq(diesel::dsl::now),
+            schema::Job::finishedAt.eq(diesel::dsl::now),
+        ))
+        .returning(models::Job::as_returning())
+        .get_result(conn)
+        .optional()?;
+    if let Some(job) = cancelled {
+        return Ok(job);
+    }
+    let requested = diesel::update(owned.filter(schema::Job::status.eq(JobStatus::RUNNING)))
+        .set((
+            schema::Job::cancelRequested.eq(true),
+            schema::Job::updatedAt.eq(diesel::dsl::now),
+        ))
+        .returning(models::Job::as_returning())
+        .get_result(conn)
+        .optional()?;
+    match requested {
+        Some(job) => Ok(job),
+        None => owned.select(models::Job::as_select()).first(conn),
+    }
+}
+
+/// Queue pending and interrupted jobs again, then start the workers and the
+/// task moving retried jobs back to the queue once they are due. Workers block
+/// on Redis, so each gets a connection of its own to `redis_url`.
+pub async fn spawn_job_workers(state: AppState, redis_url: &str, config: JobConfig) {
+    let JobConfig {
+        workers, backoff, ..
+    } = config;
+    if workers == 0 {
+        log::info!("Job workers are disabled");
+        return;
+    }
+    match recover_jobs(&state).await {
+        Ok(0) => {}
+        Ok(count) => log::info!("Queued {count} unfinished jobs again"),
+        Err(e) => log::error!("Failed to queue unfinished jobs: {e}"),
+    }
+
+    for worker in 0..workers {
+        let queue = match RedisClient::new(redis_url).await {
+            Ok(queue) => queue,
+            Err(e) => {
+                log::error!("Job worker {worker} cannot connect to Redis: {e}");
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match queue.pop(JOB_QUEUE_KEY, 5.0).await {
+                    Ok(Some(job_id)) => {
+                        if let Err(e) = run_job(&state, &job_id, backoff).await {
+                            log::error!("Job {job_id} could not be run: {e}");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log::error!("Job worker {worker} failed to read the queue: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+    }
+
+    let sweeper = state.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(HEARTBEAT_TIMEOUT);
+        loop {
+            ticker.tick().await;
+            match requeue_abandoned(&sweeper).await {
+                Ok(0) => {}
+                Ok(count) => log::warn!("Queued {count} jobs of stopped workers again"),
+                Err(e) => log::error!("Failed to queue jobs of stopped workers: {e}"),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = promote_due(&state).await {
+                log::error!("Failed to queue retried jobs: {e}");
+            }
+        }
+    });
+}
+
+/// Running jobs whose worker stopped sending heartbeats, e.g. because its
+/// process exited, go back to `PENDING`. Jobs that must not run again fail
+/// instead: instance creations, which Nova may have accepted already, and jobs
+/// that used their last attempt. Returns the ids and `runAfter` of the jobs
+/// going back to `PENDING`.
+pub fn reset_abandoned(conn: &mut DBConn) -> QueryResult<Vec<(String, NaiveDateTime)>> {
+    let abandoned = || {
+        let cutoff = diesel::dsl::now - (HEARTBEAT_TIMEOUT.as_secs() as i32).seconds();
+        schema::Job::status.eq(JobStatus::RUNNING).and(
+            schema::Job::heartbeatAt
+                .is_null()
+                .or(schema::Job::heartbeatAt.lt(cutoff.nullable())),
+        )
+    };
+    conn.transaction(|conn| {
+        let creations: Vec<String> = diesel::update(
+            schema::Job::dsl::Job
+                .filter(abandoned())
+                .filter(schema::Job::kind.eq(JobKind::CREATE_INSTANCE)),
+        )
+        .set((
+            schema::Job::status.eq(JobStatus::FAILED),
+            schema::Job::error.eq("The worker stopped during the attempt, the instance may exist"),
+            schema::Job::workerId.eq(None::<String>),
+            schema::Job::updatedAt.eq(diesel::dsl::now),
+            schema::Job::finishedAt.eq(diesel::dsl::now),
+        ))
+        .returning(schema::Job::id)
+        .get_results(conn)?;
+        let exhausted: Vec<String> = diesel::update(
+            schema::Job::dsl::Job
+                .filter(abandoned())
+                .filter(schema::Job::attempts.ge(schema::Job::maxAttempts)),
+        )
+        .set((
+            schema::Job::status.eq(JobStatus::FAILED),
+            schema::Job::error.eq("The worker stopped during the last attempt"),
+            schema::Job::workerId.eq(None::<String>),
+            schema::Job::updatedAt.eq(diesel::dsl::now),
+            schema::Job::finishedAt.eq(diesel::dsl::now),
+        ))
+        .returning(schema::Job::id)
+        .get_results(conn)?;
+        for id in creations.iter().chain(&exhausted) {
+            log::warn!("Job {id} failed, its worker stopped while running it");
+        }
+
+        diesel::update(schema::Job::dsl::Job.filter(abandoned()))
+            .set((
+                schema::Job::status.eq(JobStatus::PENDING),
+                schema::Job::workerId.eq(None::<String>),
+                schema::Job::updatedAt.eq(diesel::dsl::now),
+            ))
+            .returning((schema::Job::id, schema::Job::runAfter))
+            .get_results(conn)
+    })
+}
+
+/// Schedule every pending job, taking over the ones abandoned by stopped
+/// workers, and return how many there were. Jobs other replicas are still
+/// running are left alone.
+async fn recover_jobs(state: &AppState) -> JobResult<usize> {
+    let pending: Vec<(String, NaiveDateTime)> = state
+        .db
+        .query(|conn| {
+            reset_abandoned(conn)?;
+            schema::Job::dsl::Job
+                .filter(schema::Job::status.eq(JobStatus::PENDING))
+                .select((schema::Job::id, schema::Job::runAfter))
+                .load(conn)
+        })
+        .await?;
+    schedule_all(state, &pending).await?;
+    Ok(pending.len())
+}
+
+/// Schedule the jobs abandoned since the last sweep
+async fn requeue_abandoned(state: &AppState) -> JobResult<usize> {
+    let abandoned = state.db.query(reset_abandoned).await?;
+    schedule_all(state, &abandoned).await?;
+    Ok(abandoned.len())
+}
+
+async fn schedule_all(state: &AppState, jobs: &[(String, NaiveDateTime)]) -> JobResult<()> {
+    for (id, run_after) in jobs {
+        state
+            .cache
+            .schedule(JOB_DELAYED_KEY, id, run_after.and_utc().timestamp_millis())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Keep writing the job's heartbeat until the returned task is aborted
+fn spawn_heartbeat(db: DBClient, job_id: String) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + HEARTBEAT_INTERVAL,
+            HEARTBEAT_INTERVAL,
+        );
+        loop {
+            ticker.tick().await;
+            let job_id = job_id.clone();
+            let beat = db
+                .query(move |conn| {
+                    diesel::update(
+                        schema::Job::dsl::Job
+                            .find(job_id)
+                            .filter(schema::Job::status.eq(JobStatus::RUNNING))
+                            .filter(schema::Job::workerId.eq(worker_id())),
+                    )
+                    .set(schema::Job::heartbeatAt.eq(diesel::dsl::now))
+                    .execute(conn)
+                })
+                .await;
+            if let Err(e) = beat {
+                log::warn!("Failed to write a job heartbeat: {e}");
+            }
+        }
+    })
+}
+
+/// Move the delayed jobs that are due to the queue
+async fn promote_due(state: &AppState) -> JobResult<()> {
+    let cache = &state.cache;
+    let now = Utc::now().timestamp_millis();
+    for id in cache.due(JOB_DELAYED_KEY, now).await? {
+        // Only the caller that removed it queues it
+        if cache.unschedule(JOB_DELAYED_KEY, &id).await? {
+            cache.push(JOB_QUEUE_KEY, &id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Claim the job and run one attempt of it, recording the outcome. Failed
+/// attempts are retried after `backoff`, doubled for each attempt.
+async fn run_job(state: &AppState, job_id: &str, backoff: Duration) -> JobResult<()> {
+    let job_id = job_id.to_string();
+    let claimed = state
+        .db
+        .query(move |conn| {
+            diesel::update(
+                schema::Job::dsl::Job
+                    .filter(schema::Job::id.eq(job_id))
+                    .filter(schema::Job::status.eq(JobStatus::PENDING)),
+            )
+            .set((
+                schema::Job::status.eq(JobStatus::RUNNING),
+                schema::Job::attempts.eq(schema::Job::attempts + 1),
+                schema::Job::workerId.eq(worker_id()),
+                schema::Job::heartbeatAt.eq(diesel::dsl::now),
+                schema::Job::updatedAt.eq(diesel::dsl::now),
+            ))
+            .returning(models::Job::as_returning())
+            .get_result(conn)
+            .optional()
+        })
+        .await?;
+    // Cancelled, finished or taken by another worker
+    let Some(job) = claimed else {
+        return Ok(());
+    };
+
+    let heartbeat = spawn_heartbeat(state.db.clone(), job.id.clone());
+    let outcome = execute(state, &job).await;
+    heartbeat.abort();
+    let job_id = job.id.clone();
+    let retry_at = state
+        .db
+        .run(move |conn| record_outcome(conn, &job, outcome, backoff))
+        .await?;
+    if let Some(retry_at) = retry_at {
+        state
+            .cache
+            .schedule(JOB_DELAYED_KEY, &job_id, retry_at)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Store the outcome of an attempt. Returns when to run the job again, in
+/// milliseconds since the epoch, if it is retried.
+fn record_outcome(
+    conn: &mut DBConn,
+    job: &Job,
+    outcome: JobResult<serde_json::Value>,
+    base_backoff: Duration,
+) -> JobResult<Option<i64>> {
+    let cancel_requested: bool = schema::Job::dsl::Job
+        .find(&job.id)
+        .select(schema::Job::cancelRequested)
+        .first(conn)?;
+    let target = schema::Job::dsl::Job.find(&job.id);
+    match outcome {
+        Ok(result) => {
+            log::info!("Job {} ({:?}) succeeded", job.id, job.kind);
+            diesel::update(target)
+                .set((
+                    schema::Job::status.eq(JobStatus::SUCCEEDED),
+                    schema::Job::result.eq(result.to_string()),
+                    schema::Job::updatedAt.eq(diesel::dsl::now),
+                    schema::Job::finishedAt.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            Ok(None)
+        }
+        Err(err)
+            if !cancel_requested
+                && err.is_retryable_for(job.kind)
+                && job.attempts < job.maxAttempts =>
+        {
+            let delay = backoff(base_backoff, job.attempts);
+            let run_after = Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+            log::warn!(
+                "Job {} ({:?}) failed attempt {}, retrying in {}s: {err}",
+                job.id,
+                job.kind,
+                job.attempts,
+                delay.as_secs()
+            );
+            diesel::update(target)
+                .set((
+                    schema::Job::status.eq(JobStatus::PENDING),
+                    schema::Job::runAfter.eq(run_after.naive_utc()),
+                    schema::Job::error.eq(err.to_string()),
+                    schema::Job::updatedAt.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            Ok(Some(run_after.timestamp_millis()))
+        }
+        Err(err) => {
+            let status = if cancel_requested {
+                JobStatus::CANCELLED
+            } else {
+                JobStatus::FAILED
+            };
+            log::warn!("Job {} ({:?}) ended {:?}: {err}", job.id, job.kind, status);
+            diesel::update(target)
+                .set((
+                    schema::Job::status.eq(status),
+                    schema::Job::error.eq(err.to_string()),
+                    schema::Job::updatedAt.eq(diesel::dsl::now),
+                    schema::Job::finishedAt.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Perform the job's operation and return its JSON outcome. Jobs of users
+/// disabled after queueing them are rejected.
+async fn execute(state: &AppState, job: &Job) -> JobResult<serde_json::Value> {
+    if !repository::is_user_enabled(&state.db, &job.userId).await? {
+        return Err(JobError::Rejected("User is disabled".into()));
+    }
+    match job.kind {
+        JobKind::PROVISION_ACCOUNT => {
+            let info = provision_account(state, &job.userId, job.cloudProvider).await?;
+            Ok(serde_json::to_value(info)?)
+        }
+        JobKind::CREATE_INSTANCE => {
+            let request: CreateInstance = serde_json::from_str(&job.payload)?;
+            let instance = create_instance(state, &job.userId, job.cloudProvider, request).await?;
+            Ok(serde_json::to_value(instance)?)
+        }
+        JobKind::DELETE_INSTANCE => {
+            let payload: DeleteInstancePayload = serde_json::from_str(&job.payload)?;
+            delete_instance(state, &job.userId, job.cloudProvider, payload.instance_id).await?;
+            Ok(serde_json::Value::Null)
+        }
+    }
+}
+
+/// Create the user's account on the instance they are placed on and push their
+/// SSH keys and quota to it. Run by `PROVISION_ACCOUNT` jobs, and directly by
+/// `pikactl`.
+pub async fn provision_account(
+    state: &AppState,
+    user_id: &str,
+    provider_type: CloudProvider,
+) -> JobResult<CloudAccountInfo> {
+    if repository::find_cloud_user(&state.db, user_id, provider_type)
+        .await?
+        .is_some()
+    {
+        return Err(JobError::Rejected("Cloud account already exists".into()));
+    }
+
+    let cloud_providers = state.cloud_providers.load();
+    let instances: Vec<String> = cloud_providers
+        .instances_of(provider_type)
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let owner = user_id.to_string();
+    // Keys added before the account existed still need to reach the cloud.
+    // They are loaded before creating it, so that a failure leaves nothing
+    // behind and the job can be retried.
+    let (instance, db_user, keys) = state
+        .db
+        .query(move |conn| {
+            let instances: Vec<&str> = instances.iter().map(String::as_str).collect();
+            let instance = place(conn, &owner, provider_type, &instances)?;
+            let db_user = schema::User::dsl::User
+                .find(&owner)
+                .select(models::User::as_select())
+                .first(conn)?;
+            let keys = schema::SshKey::dsl::SshKey
+                .filter(schema::SshKey::userId.eq(&owner))
+                .select(models::SshKey::as_select())
+                .load(conn)?;
+            Ok((instance, db_user, keys))
+        })
+        .await?;
+    let Some(instance) = instance else {
+        return Err(JobError::Rejected(format!(
+            "No {} instance is configured",
+            provider_type.name()
+        )));
+    };
+    let Some(cloud_provider) = cloud_providers.get(&instance) else {
+        return Err(JobError::Rejected("Placed on an unknown instance".into()));
+    };
+
+    let info = cloud_provider.create_user(db_user.username).await?;
+    let new_cloud_user = NewCloudUser {
+        userId: user_id.to_string(),
+        cloudProvider: cloud_provider.provider_type(),
+        cloudInstance: cloud_provider.name().to_string(),
+        cloudUsername: info.provider_id,
+        cloudPassword: info.provider_pass,
+    };
+    let cloud_username = new_cloud_user.cloudUsername.clone();
+    let cloud_user = state
+        .db
+        .query(move |conn| {
+            diesel::insert_into(schema::CloudUser::table)
+                .values(&new_cloud_user)
+                .returning(models::CloudUser::as_returning())
+                .get_result(conn)
+        })
+        .await;
+    let cloud_user = match cloud_user {
+        Ok(cloud_user) => cloud_user,
+        Err(err) => {
+            // Another job provisioned the account meanwhile. Its account may
+            // be the one just "created" again, which must stay.
+            let conflict = matches!(
+                err,
+                DBError::Query(DieselError::DatabaseError(
+                    DatabaseErrorKind::UniqueViolation,
+                    _
+                ))
+            );
+            let winner = if conflict {
+                repository::find_cloud_user(&state.db, user_id, provider_type)
+                    .await
+                    .ok()
+                    .flatten()
+            } else {
+                None
+            };
+            let shared = winner.is_some_and(|winner| {
+                winner.cloudInstance == cloud_provider.name()
+                    && winner.cloudUsername == cloud_username
+            });
+            // Without the row nobody could use or find the account again
+            if !shared {
+                if let Err(e) = cloud_provider.delete_user(cloud_username.clone()).await {
+                    log::error!(
+                        "Failed to remove {} account {} after a database error: {e}",
+                        cloud_provider.name(),
+                        cloud_username
+                    );
+                }
+            }
+            if conflict {
+                return Err(JobError::Rejected("Cloud account already exists".into()));
+            }
+            return Err(err.into());
+        }
+    };
+
+    sync_ssh_keys(cloud_provider, &cloud_user, &keys).await;
+    if let Err(e) = sync_quota(
+        &state.db,
+        cloud_provider,
+        &cloud_user,
+        state.config.load().quota_merge,
+    )
+    .await
+    {
+        log::warn!(
+            "Failed to apply quota to {} user {}: {e}",
+            cloud_provider.name(),
+            cloud_user.cloudUsername
+        );
+    }
+
+    Ok(CloudAccountInfo {
+        provider: provider_type.name().to_string(),
+        instance: cloud_user.cloudInstance,
+        provider_id: cloud_user.cloudUsername,
+    })
+}
+
+/// Delete the user's account, along with what the provider removes with it,
+/// and forget it. Only `pikactl` does this, there is no job for it.
+pub async fn deprovision_account(
+    state: &AppState,
+    user_id: &str,
+    provider_type: CloudProvider,
+) -> JobResult<CloudAccountInfo> {
+    let cloud_providers = state.cloud_providers.load();
+    let (cloud_user, cloud_provider) =
+        find_cloud_account(&state.db, &cloud_providers, user_id, provider_type).await?;
+    match cloud_provider
+        .delete_user(cloud_user.cloudUsername.clone())
+        .await
+    {
+        // Already gone on the cloud side, only the row is left
+        Ok(()) | Err(CloudError::NotFound(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let id = cloud_user.id.clone();
+    state
+        .db
+        .query(move |conn| diesel::delete(schema::CloudUser::dsl::CloudUser.find(id)).execute(conn))
+        .await?;
+
+    Ok(CloudAccountInfo {
+        provider: provider_type.name().to_string(),
+        instance: cloud_user.cloudInstance,
+        provider_id: cloud_user.cloudUsername,
+    })
+}
+
+/// Create an instance after checking it fits in the user's quota. Checks and
+/// creations of the same user run one at a time.
+async fn create_instance(
+    state: &AppState,
+    user_id: &str,
+    provider_type: CloudProvider,
+    request: CreateInstance,
+) -> JobResult<models::Instance> {
+    let cloud_providers = state.cloud_providers.load();
+    let (cloud_user, cloud_provider) =
+        find_cloud_account(&state.db, &cloud_providers, user_id, provider_type).await?;
+
+    let owner = user_id.to_string();
+    let strategy = state.config.load().quota_merge;
+    let quota = state
+        .db
+        .run(move |conn| effective_quota(conn, &owner, strategy))
+        .await?;
+    // Held until the instance exists, so that concurrent creations see it in
+    // each other's usage
+    let mut _quota_lock = None;
+    if quota.is_some() {
+        _quota_lock = Some(lock_quota(&state.db, user_id, QUOTA_LOCK_WAIT).await?);
+        let flavor = cloud_provider
+            .get_flavor(
+                cloud_user.cloudUsername.clone(),
+                cloud_user.cloudPassword.clone(),
+                request.flavor_id.clone(),
+            )
+            .await?;
+        let usage = cloud_provider
+            .get_usage(
+                cloud_user.cloudUsername.clone(),
+                cloud_user.cloudPassword.clone(),
+            )
+            .await?;
+        let wanted = Resources {
+            vcpus: flavor.vcpus,
+            ram_mb: flavor.ram_mb,
+            instances: 1,
+            ..Default::default()
+        };
+        check_quota(quota.as_ref(), &usage, &wanted)?;
+    }
+
+    Ok(cloud_provider
+        .create_instance(cloud_user.cloudUsername, cloud_user.cloudPassword, request)
+        .await?)
+}
+
+async fn delete_instance(
+    state: &AppState,
+    user_id: &str,
+    provider_type: CloudProvider,
+    instance_id: String,
+) -> JobResult<()> {
+    let cloud_providers = state.cloud_providers.load();
+    let (cloud_user, cloud_provider) =
+        find_cloud_account(&state.db, &cloud_providers, user_id, provider_type).await?;
+    Ok(cloud_provider
+        .delete_instance(
+            cloud_user.cloudUsername,
+            cloud_user.cloudPassword,
+            instance_id,
+        )
+        .await?)
+}
@@ -1,11 +1,23 @@
 pub mod auth;
+pub mod billing;
 pub mod clouds;
+pub mod config;
 pub mod db;
 pub mod cache;
 pub mod error;
+pub mod jobs;
 pub mod models;
+#[allow(non_snake_case)]
 pub mod schema;
 pub mod routes;
 pub mod middleware;
+pub mod migrations;
+pub mod placement;
+pub mod quota;
+pub mod reconcile;
+pub mod repository;
+pub mod registry;
+pub mod reload;
 pub mod server;
-pub mod utils;
+pub mod ssh;
+pub mod usage;
@@ -1,10 +1,57 @@
-use std::io;
+use std::{env, io, process};
 
-use pikacloud_backend::server;
+use diesel::{Connection, PgConnection};
+use pikacloud_backend::{config::Config, migrations, server};
+
+const USAGE: &str = "Usage: pikacloud-backend [migrate <up|down|status>]";
 
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
     dotenvy::dotenv().ok();
-    println!("Hello, world!");
-    server::server().await
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => server::server().await,
+        ["migrate", action] => migrate(action),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    }
+}
+
+fn migrate(action: &str) -> io::Result<()> {
+    let config = Config::load().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut conn = PgConnection::establish(&config.database_url).map_err(io::Error::other)?;
+    match action {
+        "up" => {
+            let applied = migrations::run_pending(&mut conn).map_err(io::Error::other)?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for name in applied {
+                println!("Applied {name}");
+            }
+        }
+        "down" => {
+            let name = migrations::revert_last(&mut conn).map_err(io::Error::other)?;
+            println!("Reverted {name}");
+        }
+        "status" => {
+            let status = migrations::status(&mut conn).map_err(io::Error::other)?;
+            for name in status.applied {
+                println!("[x] {name}");
+            }
+            for name in status.pending {
+                println!("[ ] {name}");
+            }
+            for version in status.unknown {
+                println!("[?] {version} (not known to this binary)");
+            }
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    }
+    Ok(())
 }
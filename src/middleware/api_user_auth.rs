@@ -1,20 +1,35 @@
-use std::future::{ready, Ready};
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
 
 use actix_web::{
     body::EitherBody,
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
     http::header::AUTHORIZATION,
-    Error, HttpResponse,
+    Error, HttpMessage, HttpResponse,
 };
 use futures_util::{future::LocalBoxFuture, FutureExt};
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 
-use crate::{models::UserJwtInfo, utils::load_env_panic};
-pub struct ApiUserAuth;
+use crate::{db::DBClient, models::UserJwtInfo, repository::is_user_enabled};
+
+/// Verifies the bearer token of every request with `secret`, `JWT_SECRET`,
+/// and turns away users disabled after the token was issued
+pub struct ApiUserAuth {
+    secret: String,
+    db: DBClient,
+}
+
+impl ApiUserAuth {
+    pub fn new(secret: String, db: DBClient) -> Self {
+        Self { secret, db }
+    }
+}
 
 impl<S, B> Transform<S, ServiceRequest> for ApiUserAuth
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -24,17 +39,23 @@ where
     type Transform = ApiUserAuthMiddleware<S>;
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(ApiUserAuthMiddleware { service }))
+        ready(Ok(ApiUserAuthMiddleware {
+            service: Rc::new(service),
+            secret: self.secret.clone(),
+            db: self.db.clone(),
+        }))
     }
 }
 
 pub struct ApiUserAuthMiddleware<S> {
-    service: S,
+    service: Rc<S>,
+    secret: String,
+    db: DBClient,
 }
 
 impl<S, B> Service<ServiceRequest> for ApiUserAuthMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -45,8 +66,6 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        // Handle before request
-        println!("Hi from start. You requested: {}", req.path());
         let requested_path = req.uri().path();
         if requested_path.starts_with("/api/auth") {
             let http_res = HttpResponse::Unauthorized().finish();
@@ -55,6 +74,13 @@ where
             return (async move { Ok(res.map_into_right_body()) }).boxed_local();
         }
 
+        // Console websockets authenticate with the single-use ticket in their path,
+        // browsers cannot attach an Authorization header to them
+        if requested_path.starts_with("/api/console/") {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
         let auth_header = req
             .headers()
             .get(AUTHORIZATION)
@@ -69,8 +95,7 @@ where
             if token.starts_with("Bearer ") {
                 let token = token.replacen("Bearer ", "", 1);
                 // Verify token and get user info
-                let secret = load_env_panic("JWT_SECRET");
-                let user_info = verify_token(&token, &secret);
+                let user_info = verify_token(&token, &self.secret);
 
                 if let Ok(user_info) = user_info {
                     // Search for admin role
@@ -81,13 +106,27 @@ where
                         || requested_path.starts_with("/admin"))
                         && !admin_role_found)
                     {
-                        let fut = self.service.call(req);
+                        let service = self.service.clone();
+                        let db = self.db.clone();
                         return Box::pin(async move {
-                            // Process response
-                            let res = fut.await?;
+                            let response = match is_user_enabled(&db, &user_info.id).await {
+                                Ok(true) => None,
+                                Ok(false) => {
+                                    Some(HttpResponse::Forbidden().body("User is disabled"))
+                                }
+                                Err(e) => {
+                                    Some(HttpResponse::InternalServerError().body(e.to_string()))
+                                }
+                            };
+                            if let Some(http_res) = response {
+                                let (http_req, _) = req.into_parts();
+                                let res = ServiceResponse::new(http_req, http_res);
+                                return Ok(res.map_into_right_body());
+                            }
 
-                            // Handle after response
-                            // println!("Hi from response");
+                            // Make the caller available to handlers via `web::ReqData<UserJwtInfo>`
+                            req.extensions_mut().insert(user_info);
+                            let res = service.call(req).await?;
                             Ok(res.map_into_left_body())
                         });
                     }
@@ -99,7 +138,7 @@ where
         let http_res = HttpResponse::Unauthorized().finish();
         let (http_req, _) = req.into_parts();
         let res = ServiceResponse::new(http_req, http_res);
-        return (async move { Ok(res.map_into_right_body()) }).boxed_local();
+        (async move { Ok(res.map_into_right_body()) }).boxed_local()
     }
 }
 
@@ -0,0 +1,113 @@
+//! Schema migrations, embedded in the binary
+//!
+//! The server refuses to start against a database with pending migrations,
+//! they are applied with `pikacloud-backend migrate up`, or at startup when
+//! `PIKA_MIGRATE_ON_START` is set.
+
+use diesel::{migration::MigrationSource, pg::Pg};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("Migration failed: {0}")]
+    Migration(String),
+    #[error(
+        "Database schema is out of date, pending migrations: {}. \
+        Run `pikacloud-backend migrate up` first",
+        .0.join(", ")
+    )]
+    Pending(Vec<String>),
+}
+
+pub type MigrationResult<T> = std::result::Result<T, MigrationError>;
+
+/// Migrations by name, e.g. `2024-09-12-093015_maintain_updated_at`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationStatus {
+    /// Applied migrations this binary knows about, oldest first
+    pub applied: Vec<String>,
+    /// Oldest first
+    pub pending: Vec<String>,
+    /// Versions applied by a newer binary
+    pub unknown: Vec<String>,
+}
+
+fn migration_error(e: Box<dyn std::error::Error + Send + Sync>) -> MigrationError {
+    MigrationError::Migration(e.to_string())
+}
+
+/// Names of the embedded migrations, oldest first
+pub fn embedded() -> Vec<String> {
+    let mut names: Vec<String> = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .expect("embedded migrations are valid")
+        .iter()
+        .map(|migration| migration.name().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn status(conn: &mut impl MigrationHarness<Pg>) -> MigrationResult<MigrationStatus> {
+    let mut applied: Vec<String> = conn
+        .applied_migrations()
+        .map_err(migration_error)?
+        .iter()
+        .map(|version| version.to_string())
+        .collect();
+    let mut status = MigrationStatus::default();
+    for migration in MigrationSource::<Pg>::migrations(&MIGRATIONS).map_err(migration_error)? {
+        let version = migration.name().version().to_string();
+        let name = migration.name().to_string();
+        match applied.iter().position(|v| *v == version) {
+            Some(index) => {
+                applied.swap_remove(index);
+                status.applied.push(name);
+            }
+            None => status.pending.push(name),
+        }
+    }
+    status.applied.sort();
+    status.pending.sort();
+    applied.sort();
+    status.unknown = applied;
+    Ok(status)
+}
+
+/// Apply every pending migration, returns their names
+pub fn run_pending(conn: &mut impl MigrationHarness<Pg>) -> MigrationResult<Vec<String>> {
+    let pending = status(conn)?.pending;
+    conn.run_pending_migrations(MIGRATIONS)
+        .map_err(migration_error)?;
+    Ok(pending)
+}
+
+/// Revert the latest applied migration, returns its name
+pub fn revert_last(conn: &mut impl MigrationHarness<Pg>) -> MigrationResult<String> {
+    let version = conn
+        .revert_last_migration(MIGRATIONS)
+        .map_err(migration_error)?
+        .to_string();
+    let name = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .map_err(migration_error)?
+        .iter()
+        .find(|migration| migration.name().version().to_string() == version)
+        .map(|migration| migration.name().to_string());
+    Ok(name.unwrap_or(version))
+}
+
+/// Fails with `Pending` when the schema lags behind this binary
+pub fn ensure_up_to_date(conn: &mut impl MigrationHarness<Pg>) -> MigrationResult<()> {
+    let status = status(conn)?;
+    if !status.pending.is_empty() {
+        return Err(MigrationError::Pending(status.pending));
+    }
+    if !status.unknown.is_empty() {
+        log::warn!(
+            "Database has migrations this binary does not know: {}",
+            status.unknown.join(", ")
+        );
+    }
+    Ok(())
+}
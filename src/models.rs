@@ -11,15 +11,27 @@ use diesel::*;
 use serde::{Deserialize, Serialize};
 
 use crate::schema::sql_types::CloudProvider as CloudProviderType;
+use crate::schema::sql_types::JobKind as JobKindType;
+use crate::schema::sql_types::JobStatus as JobStatusType;
+use crate::schema::sql_types::LedgerEntryType as LedgerEntryTypeType;
 use crate::schema::sql_types::LoginProvider as LoginProviderType;
 
-#[derive(Debug, PartialEq, FromSqlRow, AsExpression, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, FromSqlRow, AsExpression, Eq)]
 #[diesel(sql_type = LoginProviderType)]
 pub enum LoginProvider {
     IAAA,
     PASSWORD,
 }
 
+impl LoginProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoginProvider::IAAA => "iaaa",
+            LoginProvider::PASSWORD => "password",
+        }
+    }
+}
+
 impl ToSql<LoginProviderType, Pg> for LoginProvider {
     fn to_sql<'b>(
         &'b self,
@@ -45,11 +57,38 @@ impl FromSql<LoginProviderType, Pg> for LoginProvider {
     }
 }
 
-#[derive(Debug, PartialEq, FromSqlRow, AsExpression, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, FromSqlRow, AsExpression, Eq)]
 #[diesel(sql_type = CloudProviderType)]
 pub enum CloudProvider {
     OPENSTACK,
     PIKACLOUD,
+    /// In-memory provider for development, see `clouds::fake`
+    FAKE,
+    /// Namespaces on a Kubernetes cluster, see `clouds::kubernetes`
+    KUBERNETES,
+}
+
+impl CloudProvider {
+    /// Lowercase name used in routes and in `CLOUD_PROVIDER`
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloudProvider::OPENSTACK => "openstack",
+            CloudProvider::PIKACLOUD => "pikacloud",
+            CloudProvider::FAKE => "fake",
+            CloudProvider::KUBERNETES => "kubernetes",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            CloudProvider::OPENSTACK,
+            CloudProvider::PIKACLOUD,
+            CloudProvider::FAKE,
+            CloudProvider::KUBERNETES,
+        ]
+        .into_iter()
+        .find(|provider| provider.name() == name)
+    }
 }
 
 impl ToSql<CloudProviderType, Pg> for CloudProvider {
@@ -57,6 +96,8 @@ impl ToSql<CloudProviderType, Pg> for CloudProvider {
         match *self {
             CloudProvider::OPENSTACK => out.write_all(b"OPENSTACK")?,
             CloudProvider::PIKACLOUD => out.write_all(b"PIKACLOUD")?,
+            CloudProvider::FAKE => out.write_all(b"FAKE")?,
+            CloudProvider::KUBERNETES => out.write_all(b"KUBERNETES")?,
         }
         Ok(IsNull::No)
     }
@@ -67,12 +108,122 @@ impl FromSql<CloudProviderType, Pg> for CloudProvider {
         match bytes.as_bytes() {
             b"OPENSTACK" => Ok(CloudProvider::OPENSTACK),
             b"PIKACLOUD" => Ok(CloudProvider::PIKACLOUD),
+            b"FAKE" => Ok(CloudProvider::FAKE),
+            b"KUBERNETES" => Ok(CloudProvider::KUBERNETES),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, FromSqlRow, AsExpression, Eq, Serialize, Deserialize)]
+#[diesel(sql_type = LedgerEntryTypeType)]
+pub enum LedgerEntryType {
+    GRANT,
+    CHARGE,
+    REFUND,
+}
+
+impl ToSql<LedgerEntryTypeType, Pg> for LedgerEntryType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        match *self {
+            LedgerEntryType::GRANT => out.write_all(b"GRANT")?,
+            LedgerEntryType::CHARGE => out.write_all(b"CHARGE")?,
+            LedgerEntryType::REFUND => out.write_all(b"REFUND")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<LedgerEntryTypeType, Pg> for LedgerEntryType {
+    fn from_sql(bytes: <Pg as backend::Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"GRANT" => Ok(LedgerEntryType::GRANT),
+            b"CHARGE" => Ok(LedgerEntryType::CHARGE),
+            b"REFUND" => Ok(LedgerEntryType::REFUND),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+/// Cloud operations run by the job workers, see `jobs`
+#[derive(Debug, Clone, Copy, PartialEq, FromSqlRow, AsExpression, Eq, Serialize, Deserialize)]
+#[diesel(sql_type = JobKindType)]
+#[allow(non_camel_case_types)]
+pub enum JobKind {
+    PROVISION_ACCOUNT,
+    CREATE_INSTANCE,
+    DELETE_INSTANCE,
+}
+
+impl ToSql<JobKindType, Pg> for JobKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        match *self {
+            JobKind::PROVISION_ACCOUNT => out.write_all(b"PROVISION_ACCOUNT")?,
+            JobKind::CREATE_INSTANCE => out.write_all(b"CREATE_INSTANCE")?,
+            JobKind::DELETE_INSTANCE => out.write_all(b"DELETE_INSTANCE")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<JobKindType, Pg> for JobKind {
+    fn from_sql(bytes: <Pg as backend::Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"PROVISION_ACCOUNT" => Ok(JobKind::PROVISION_ACCOUNT),
+            b"CREATE_INSTANCE" => Ok(JobKind::CREATE_INSTANCE),
+            b"DELETE_INSTANCE" => Ok(JobKind::DELETE_INSTANCE),
             _ => Err("Unrecognized enum variant".into()),
         }
     }
 }
 
-#[derive(Debug, PartialEq,Queryable, Identifiable, Selectable, AsChangeset)]
+#[derive(Debug, Clone, Copy, PartialEq, FromSqlRow, AsExpression, Eq, Serialize, Deserialize)]
+#[diesel(sql_type = JobStatusType)]
+pub enum JobStatus {
+    PENDING,
+    RUNNING,
+    SUCCEEDED,
+    FAILED,
+    CANCELLED,
+}
+
+impl JobStatus {
+    /// Whether the job will not change anymore
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::SUCCEEDED | JobStatus::FAILED | JobStatus::CANCELLED
+        )
+    }
+}
+
+impl ToSql<JobStatusType, Pg> for JobStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        match *self {
+            JobStatus::PENDING => out.write_all(b"PENDING")?,
+            JobStatus::RUNNING => out.write_all(b"RUNNING")?,
+            JobStatus::SUCCEEDED => out.write_all(b"SUCCEEDED")?,
+            JobStatus::FAILED => out.write_all(b"FAILED")?,
+            JobStatus::CANCELLED => out.write_all(b"CANCELLED")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<JobStatusType, Pg> for JobStatus {
+    fn from_sql(bytes: <Pg as backend::Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"PENDING" => Ok(JobStatus::PENDING),
+            b"RUNNING" => Ok(JobStatus::RUNNING),
+            b"SUCCEEDED" => Ok(JobStatus::SUCCEEDED),
+            b"FAILED" => Ok(JobStatus::FAILED),
+            b"CANCELLED" => Ok(JobStatus::CANCELLED),
+            _ => Err("Unrecognized enum variant".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, AsChangeset)]
 #[diesel(table_name = crate::schema::User)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct User {
@@ -84,25 +235,21 @@ pub struct User {
     pub password: Option<String>,
     pub createdAt: NaiveDateTime,
     pub updatedAt: NaiveDateTime,
+    /// Set while the user is not allowed to log in
+    pub disabledAt: Option<NaiveDateTime>,
 }
 
-#[derive(Insertable)]
-#[diesel(table_name = crate::schema::User)]
-pub struct PasswordNewUser {
-    pub username: String,
-    pub loginProvider: LoginProvider,
-    pub password: Option<String>,
-}
-
-#[derive(Insertable)]
+/// `password` is a bcrypt hash, only set for `LoginProvider::PASSWORD`
+#[derive(Debug, Clone, Insertable)]
 #[diesel(table_name = crate::schema::User)]
-pub struct IaaaNewUser {
+pub struct NewUser {
     pub username: String,
     pub loginProvider: LoginProvider,
     pub name: Option<String>,
+    pub password: Option<String>,
 }
 
-#[derive(Debug, PartialEq,  Queryable, Identifiable, Selectable, AsChangeset)]
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, AsChangeset)]
 #[diesel(table_name = crate::schema::Role)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Role {
@@ -119,8 +266,8 @@ pub struct IaaaNewRole {
 }
 
 #[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, Associations, AsChangeset)]
-#[belongs_to(User, foreign_key = "userId")]
-#[belongs_to(Role, foreign_key = "roleId")]
+#[diesel(belongs_to(User, foreign_key = userId))]
+#[diesel(belongs_to(Role, foreign_key = roleId))]
 #[diesel(table_name = crate::schema::UserRole)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct UserRole {
@@ -132,7 +279,7 @@ pub struct UserRole {
 }
 
 #[derive(Debug, PartialEq,  Queryable, Identifiable, Selectable, Associations, AsChangeset)]
-#[belongs_to(User, foreign_key = "userId")]
+#[diesel(belongs_to(User, foreign_key = userId))]
 #[diesel(table_name = crate::schema::CloudUser)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct CloudUser {
@@ -143,6 +290,8 @@ pub struct CloudUser {
     pub cloudPassword: String,
     pub createdAt: NaiveDateTime,
     pub updatedAt: NaiveDateTime,
+    /// Name of the provider instance the account lives on
+    pub cloudInstance: String,
 }
 
 #[derive(Insertable)]
@@ -152,6 +301,183 @@ pub struct NewUserRole {
     pub roleId: String,
 }
 
+#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, Associations, AsChangeset)]
+#[diesel(belongs_to(Role, foreign_key = roleId))]
+#[diesel(table_name = crate::schema::RoleQuota)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RoleQuota {
+    pub id: String,
+    pub roleId: String,
+    pub vcpus: i32,
+    pub ramMb: i32,
+    pub instances: i32,
+    pub volumes: i32,
+    pub floatingIps: i32,
+    pub createdAt: NaiveDateTime,
+    pub updatedAt: NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::RoleQuota)]
+pub struct NewRoleQuota {
+    pub roleId: String,
+    pub vcpus: i32,
+    pub ramMb: i32,
+    pub instances: i32,
+    pub volumes: i32,
+    pub floatingIps: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::CloudUser)]
+pub struct NewCloudUser {
+    pub userId: String,
+    pub cloudProvider: CloudProvider,
+    pub cloudInstance: String,
+    pub cloudUsername: String,
+    pub cloudPassword: String,
+}
+
+#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable)]
+#[diesel(table_name = crate::schema::CloudPlacement)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CloudPlacement {
+    pub id: String,
+    pub cloudProvider: CloudProvider,
+    pub cloudInstance: String,
+    pub userId: Option<String>,
+    pub roleId: Option<String>,
+    pub priority: i32,
+    pub createdAt: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::CloudPlacement)]
+pub struct NewCloudPlacement {
+    pub cloudProvider: CloudProvider,
+    pub cloudInstance: String,
+    pub userId: Option<String>,
+    pub roleId: Option<String>,
+    pub priority: i32,
+}
+
+#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, Associations, AsChangeset)]
+#[diesel(belongs_to(User, foreign_key = userId))]
+#[diesel(table_name = crate::schema::SshKey)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SshKey {
+    pub id: String,
+    pub userId: String,
+    pub name: String,
+    pub keyType: String,
+    pub publicKey: String,
+    pub fingerprint: String,
+    pub createdAt: NaiveDateTime,
+    pub updatedAt: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::SshKey)]
+pub struct NewSshKey {
+    pub userId: String,
+    pub name: String,
+    pub keyType: String,
+    pub publicKey: String,
+    pub fingerprint: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::UsageSample)]
+pub struct NewUsageSample {
+    pub userId: String,
+    pub cloudProvider: CloudProvider,
+    pub resourceType: String,
+    pub resourceId: String,
+    pub resourceName: String,
+    pub status: String,
+    pub flavorId: Option<String>,
+    pub vcpus: i32,
+    pub ramMb: i32,
+    pub storageGb: i32,
+    pub intervalSeconds: i32,
+}
+
+#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User, foreign_key = userId))]
+#[diesel(table_name = crate::schema::CreditLedger)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CreditLedger {
+    pub id: String,
+    pub userId: String,
+    pub entryType: LedgerEntryType,
+    pub amount: i64,
+    pub description: String,
+    pub usagePeriod: Option<NaiveDateTime>,
+    pub createdAt: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::CreditLedger)]
+pub struct NewCreditLedger {
+    pub userId: String,
+    pub entryType: LedgerEntryType,
+    pub amount: i64,
+    pub description: String,
+    pub usagePeriod: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User, foreign_key = userId))]
+#[diesel(table_name = crate::schema::Job)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Job {
+    pub id: String,
+    pub userId: String,
+    pub kind: JobKind,
+    pub cloudProvider: CloudProvider,
+    pub payload: String,
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub maxAttempts: i32,
+    pub runAfter: NaiveDateTime,
+    pub cancelRequested: bool,
+    pub result: Option<String>,
+    pub error: Option<String>,
+    pub createdAt: NaiveDateTime,
+    pub updatedAt: NaiveDateTime,
+    pub finishedAt: Option<NaiveDateTime>,
+    pub workerId: Option<String>,
+    pub heartbeatAt: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::Job)]
+pub struct NewJob {
+    pub userId: String,
+    pub kind: JobKind,
+    pub cloudProvider: CloudProvider,
+    pub payload: String,
+    pub maxAttempts: i32,
+}
+
+#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, AsChangeset)]
+#[diesel(table_name = crate::schema::Price)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Price {
+    pub id: String,
+    pub resource: String,
+    pub milliCreditsPerHour: i64,
+    pub createdAt: NaiveDateTime,
+    pub updatedAt: NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::schema::Price)]
+pub struct NewPrice {
+    pub resource: String,
+    pub milliCreditsPerHour: i64,
+}
+
 // #[derive(Serialize, Deserialize, Debug, sqlx::FromRow, sqlx::Type)]
 // pub struct NewUserRole {
 //     #[serde(rename = "userId")]
@@ -190,6 +516,16 @@ pub struct NewUserRole {
 //     pub updated_at: DateTime<Utc>,
 // }
 
+/// A user's account on a provider instance, without its secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloudAccountInfo {
+    pub provider: String,
+    /// Provider instance the account was placed on
+    pub instance: String,
+    pub provider_id: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CloudCreateInfo {
     #[serde(rename = "providerId")]
@@ -199,6 +535,103 @@ pub struct CloudCreateInfo {
     pub provider_pass: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleType {
+    Novnc,
+    Serial,
+}
+
+/// Config files for the CLI tools of a provider
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[serde(rename = "clouds.yaml")]
+    CloudsYaml,
+    #[serde(rename = "openrc")]
+    Openrc,
+    #[serde(rename = "kubeconfig")]
+    Kubeconfig,
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConfigFormat::CloudsYaml => "clouds.yaml",
+            ConfigFormat::Openrc => "openrc",
+            ConfigFormat::Kubeconfig => "kubeconfig",
+        })
+    }
+}
+
+/// A rendered config file, see `ConfigFormat`
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub filename: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteConsole {
+    #[serde(rename = "type")]
+    pub console_type: ConsoleType,
+
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Instance {
+    pub id: String,
+    pub name: String,
+    pub status: String,
+
+    #[serde(rename = "flavorId")]
+    pub flavor_id: Option<String>,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateInstance {
+    pub name: String,
+
+    #[serde(rename = "flavorId")]
+    pub flavor_id: String,
+
+    #[serde(rename = "imageId")]
+    pub image_id: String,
+
+    /// Let the cloud pick a network when absent
+    #[serde(rename = "networkId")]
+    pub network_id: Option<String>,
+
+    #[serde(rename = "keyName")]
+    pub key_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Flavor {
+    pub id: String,
+    pub name: String,
+    pub vcpus: i32,
+
+    #[serde(rename = "ramMb")]
+    pub ram_mb: i32,
+
+    #[serde(rename = "diskGb")]
+    pub disk_gb: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Volume {
+    pub id: String,
+    pub name: String,
+    pub status: String,
+
+    #[serde(rename = "sizeGb")]
+    pub size_gb: i32,
+}
+
 /// Send to client
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserJwtInfo {
@@ -0,0 +1,63 @@
+//! Choosing the provider instance a new cloud account is created on
+//!
+//! Several instances can serve one provider, e.g. `openstack-main` and
+//! `openstack-gpu`. `CloudPlacement` rules pick one per user: rules naming the
+//! user win over rules naming one of their roles, which win over rules naming
+//! neither, and within each group the highest priority wins. Rules pointing at
+//! an instance that is not configured are ignored. Without a matching rule the
+//! first configured instance of the provider is used.
+
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+
+use crate::{
+    models::{self, CloudPlacement, CloudProvider},
+    schema,
+};
+
+/// The instance among `instances`, the configured instances of `provider` in
+/// order, that `user_id` gets. `None` when `instances` is empty.
+pub fn place(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: &str,
+    provider: CloudProvider,
+    instances: &[&str],
+) -> QueryResult<Option<String>> {
+    let role_ids: Vec<String> = schema::UserRole::dsl::UserRole
+        .filter(schema::UserRole::userId.eq(user_id))
+        .select(schema::UserRole::roleId)
+        .load(conn)?;
+    let rules = schema::CloudPlacement::dsl::CloudPlacement
+        .filter(schema::CloudPlacement::cloudProvider.eq(provider))
+        .select(models::CloudPlacement::as_select())
+        .load(conn)?;
+
+    Ok(choose(&rules, user_id, &role_ids, instances))
+}
+
+/// `place` once the rules of the provider and the user's roles are loaded
+pub fn choose(
+    rules: &[CloudPlacement],
+    user_id: &str,
+    role_ids: &[String],
+    instances: &[&str],
+) -> Option<String> {
+    let chosen = rules
+        .iter()
+        .filter(|rule| instances.contains(&rule.cloudInstance.as_str()))
+        .filter_map(|rule| Some((rank(rule, user_id, role_ids)?, rule)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, rule)| rule.cloudInstance.clone());
+    chosen.or_else(|| instances.first().map(|instance| instance.to_string()))
+}
+
+/// Lower is better, `None` when the rule does not apply to the user
+fn rank(rule: &CloudPlacement, user_id: &str, role_ids: &[String]) -> Option<(u8, i32)> {
+    let group = match (&rule.userId, &rule.roleId) {
+        (Some(id), _) if id == user_id => 0,
+        (None, Some(id)) if role_ids.contains(id) => 1,
+        (None, None) => 2,
+        _ => return None,
+    };
+    Some((group, -rule.priority))
+}
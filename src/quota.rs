@@ -0,0 +1,246 @@
+//! Per-role resource quotas
+//!
+//! A quota profile is attached to a `Role`. A user's effective quota combines
+//! the profiles of all their roles, either taking the largest value of each
+//! resource or adding them up, depending on `MergeStrategy`.
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clouds::{BaseCloudProvider, CloudError},
+    db::{AdvisoryLock, DBClient, DBError},
+    models::{self, CloudUser, NewRoleQuota, RoleQuota},
+    registry::ProviderRegistry,
+    schema,
+};
+
+/// How often `lock_quota` tries again while the lock is taken
+const QUOTA_LOCK_POLL: Duration = Duration::from_millis(500);
+
+/// Amounts of cloud resources, used both for limits and for current usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Resources {
+    pub vcpus: i32,
+    pub ram_mb: i32,
+    pub instances: i32,
+    pub volumes: i32,
+    pub floating_ips: i32,
+}
+
+impl Resources {
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            vcpus: self.vcpus.max(other.vcpus),
+            ram_mb: self.ram_mb.max(other.ram_mb),
+            instances: self.instances.max(other.instances),
+            volumes: self.volumes.max(other.volumes),
+            floating_ips: self.floating_ips.max(other.floating_ips),
+        }
+    }
+
+    pub fn sum(self, other: Self) -> Self {
+        Self {
+            vcpus: self.vcpus.saturating_add(other.vcpus),
+            ram_mb: self.ram_mb.saturating_add(other.ram_mb),
+            instances: self.instances.saturating_add(other.instances),
+            volumes: self.volumes.saturating_add(other.volumes),
+            floating_ips: self.floating_ips.saturating_add(other.floating_ips),
+        }
+    }
+
+    /// Names of the resources in `self` that are larger than in `limit`
+    pub fn exceeding(&self, limit: &Self) -> Vec<&'static str> {
+        [
+            ("vcpus", self.vcpus, limit.vcpus),
+            ("ramMb", self.ram_mb, limit.ram_mb),
+            ("instances", self.instances, limit.instances),
+            ("volumes", self.volumes, limit.volumes),
+            ("floatingIps", self.floating_ips, limit.floating_ips),
+        ]
+        .into_iter()
+        .filter(|(_, value, limit)| value > limit)
+        .map(|(name, _, _)| name)
+        .collect()
+    }
+
+    pub fn to_role_quota(self, role_id: String) -> NewRoleQuota {
+        NewRoleQuota {
+            roleId: role_id,
+            vcpus: self.vcpus,
+            ramMb: self.ram_mb,
+            instances: self.instances,
+            volumes: self.volumes,
+            floatingIps: self.floating_ips,
+        }
+    }
+}
+
+impl From<&RoleQuota> for Resources {
+    fn from(quota: &RoleQuota) -> Self {
+        Self {
+            vcpus: quota.vcpus,
+            ram_mb: quota.ramMb,
+            instances: quota.instances,
+            volumes: quota.volumes,
+            floating_ips: quota.floatingIps,
+        }
+    }
+}
+
+/// How the profiles of several roles combine, `PIKA_QUOTA_MERGE`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    #[default]
+    Max,
+    Sum,
+}
+
+impl MergeStrategy {
+    /// Combine `quotas`, `None` if there are none
+    pub fn merge(self, quotas: impl IntoIterator<Item = Resources>) -> Option<Resources> {
+        quotas.into_iter().reduce(|acc, quota| match self {
+            MergeStrategy::Max => acc.max(quota),
+            MergeStrategy::Sum => acc.sum(quota),
+        })
+    }
+}
+
+impl FromStr for MergeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(MergeStrategy::Max),
+            "sum" => Ok(MergeStrategy::Sum),
+            _ => Err(format!("expected max or sum, got {s}")),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("Quota exceeded: {}", .0.join(", "))]
+    Exceeded(Vec<&'static str>),
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("Fail to fetch connection: {0}")]
+    Connection(#[from] DBError),
+    #[error("Cloud error: {0}")]
+    Cloud(#[from] CloudError),
+    /// Another creation of the same user is checking the quota
+    #[error("Another resource of this user is being created")]
+    Busy,
+}
+
+pub type QuotaResult<T> = std::result::Result<T, QuotaError>;
+
+/// Combine the quota profiles of all the user's roles. `None` means none of the
+/// roles has a profile and the cloud's own defaults apply.
+pub fn effective_quota(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: &str,
+    strategy: MergeStrategy,
+) -> QuotaResult<Option<Resources>> {
+    let quotas: Vec<RoleQuota> = schema::RoleQuota::table
+        .inner_join(
+            schema::UserRole::table.on(schema::UserRole::roleId.eq(schema::RoleQuota::roleId)),
+        )
+        .filter(schema::UserRole::userId.eq(user_id))
+        .select(models::RoleQuota::as_select())
+        .load(conn)?;
+
+    Ok(strategy.merge(quotas.iter().map(Resources::from)))
+}
+
+/// Refuse `request` if it would take `usage` over `quota`
+pub fn check_quota(
+    quota: Option<&Resources>,
+    usage: &Resources,
+    request: &Resources,
+) -> QuotaResult<()> {
+    let Some(quota) = quota else {
+        return Ok(());
+    };
+    let exceeded = usage.sum(*request).exceeding(quota);
+    if !exceeded.is_empty() {
+        return Err(QuotaError::Exceeded(exceeded));
+    }
+    Ok(())
+}
+
+/// Lock held by whoever checks the user's quota, until the resource the check
+/// allowed is created. Otherwise concurrent requests could all pass the check.
+/// While another check holds it, this tries again for up to `wait`, without
+/// keeping a connection meanwhile, and then gives up with `QuotaError::Busy`.
+pub async fn lock_quota(db: &DBClient, user_id: &str, wait: Duration) -> QuotaResult<AdvisoryLock> {
+    let key = format!("quota:{user_id}");
+    let deadline = Instant::now() + wait;
+    loop {
+        if let Some(lock) = db.try_advisory_lock(&key).await? {
+            return Ok(lock);
+        }
+        if Instant::now() >= deadline {
+            return Err(QuotaError::Busy);
+        }
+        tokio::time::sleep(QUOTA_LOCK_POLL).await;
+    }
+}
+
+/// Push the user's effective quota to their cloud project
+pub async fn sync_quota(
+    db: &DBClient,
+    provider: &dyn BaseCloudProvider,
+    cloud_user: &CloudUser,
+    strategy: MergeStrategy,
+) -> QuotaResult<()> {
+    let user_id = cloud_user.userId.clone();
+    let quota = db
+        .run(move |conn| effective_quota(conn, &user_id, strategy))
+        .await?;
+    if let Some(quota) = quota {
+        match provider
+            .set_quota(cloud_user.cloudUsername.clone(), quota)
+            .await
+        {
+            Ok(()) | Err(CloudError::Unsupported(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// `sync_quota` for each of `cloud_users` whose instance is configured.
+/// Failures are logged and counted, returns how many accounts were updated
+/// and how many failed.
+pub async fn sync_quotas(
+    db: &DBClient,
+    cloud_providers: &ProviderRegistry<dyn BaseCloudProvider>,
+    cloud_users: &[CloudUser],
+    strategy: MergeStrategy,
+) -> (usize, usize) {
+    let (mut synced, mut failed) = (0, 0);
+    for cloud_user in cloud_users {
+        let Some(provider) = cloud_providers.get(&cloud_user.cloudInstance) else {
+            continue;
+        };
+        match sync_quota(db, provider, cloud_user, strategy).await {
+            Ok(()) => synced += 1,
+            Err(e) => {
+                failed += 1;
+                log::warn!(
+                    "Failed to apply quota to {} user {}: {e}",
+                    provider.name(),
+                    cloud_user.cloudUsername
+                );
+            }
+        }
+    }
+    (synced, failed)
+}
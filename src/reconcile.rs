@@ -0,0 +1,267 @@
+//! Reconciliation of cloud accounts with `CloudUser`
+//!
+//! A cloud account can outlive its `CloudUser` row, for instance when the row
+//! could not be written after the cloud side succeeded. A background task
+//! lists every provider's accounts and reports the ones no row points at.
+//! With `ReconcileConfig::delete` it also deletes orphans that were already
+//! reported by the previous run, so accounts still being provisioned are
+//! left alone.
+//!
+//! The same task rotates the credentials of accounts whose row has not been
+//! updated for `credential_rotation_days`, so they are replaced before
+//! they expire. Config files users downloaded with the old credentials stop
+//! working, so each file states after which date it must be downloaded again.
+
+use std::{collections::HashSet, time::Duration};
+
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    clouds::{BaseCloudProvider, CloudError},
+    db::DBClient,
+    models::{self, CloudUser},
+    schema,
+    server::AppState,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconcileError {
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("Fail to fetch connection: {0}")]
+    Connection(#[from] crate::db::DBError),
+    #[error("Cloud error: {0}")]
+    Cloud(#[from] CloudError),
+}
+
+pub type ReconcileResult<T> = std::result::Result<T, ReconcileError>;
+
+/// Schedule of the background pass
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconcileConfig {
+    /// Seconds between two runs, 0 disables the task,
+    /// `PIKA_RECONCILE_INTERVAL`
+    pub interval: u64,
+    /// Delete orphans found by two runs in a row, `PIKA_RECONCILE_DELETE`
+    pub delete: bool,
+    /// Rotate credentials this many days after they were issued, 0 disables
+    /// rotation. Keep it below the lifetime of the credentials, e.g.
+    /// `credential_days` of OpenStack. Rotation breaks the client configs
+    /// users downloaded before. `PIKA_CREDENTIAL_ROTATION_DAYS`
+    pub credential_rotation_days: i64,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            interval: 86400,
+            delete: false,
+            credential_rotation_days: 60,
+        }
+    }
+}
+
+/// Give the account new credentials and store them. The old ones stop working
+/// right away.
+pub async fn rotate_credentials(
+    db: &DBClient,
+    provider: &dyn BaseCloudProvider,
+    cloud_user: &CloudUser,
+) -> ReconcileResult<()> {
+    let secret = provider
+        .rotate_credentials(
+            cloud_user.cloudUsername.clone(),
+            cloud_user.cloudPassword.clone(),
+        )
+        .await?;
+    let id = cloud_user.id.clone();
+    db.run(move |conn| {
+        diesel::update(schema::CloudUser::dsl::CloudUser.find(id))
+            .set((
+                schema::CloudUser::cloudPassword.eq(secret),
+                schema::CloudUser::updatedAt.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Accounts on the provider without a `CloudUser`. Providers that cannot list
+/// their accounts have none.
+pub async fn find_orphans(
+    db: &DBClient,
+    provider: &dyn BaseCloudProvider,
+) -> ReconcileResult<Vec<String>> {
+    let accounts = match provider.list_accounts().await {
+        Ok(accounts) => accounts,
+        Err(CloudError::Unsupported(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let instance = provider.name().to_string();
+    let known: HashSet<String> = db
+        .run(move |conn| {
+            Ok::<_, ReconcileError>(
+                schema::CloudUser::dsl::CloudUser
+                    .filter(schema::CloudUser::cloudInstance.eq(instance))
+                    .select(schema::CloudUser::cloudUsername)
+                    .load::<String>(conn)?,
+            )
+        })
+        .await?
+        .into_iter()
+        .collect();
+    Ok(accounts
+        .into_iter()
+        .filter(|account| !known.contains(account))
+        .collect())
+}
+
+/// A cloud account no `CloudUser` points at
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Orphan {
+    pub instance: String,
+    pub account: String,
+}
+
+/// Outcome of a reconciliation pass
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    /// Orphans left in place
+    pub orphans: Vec<Orphan>,
+    pub deleted: Vec<Orphan>,
+    /// Accounts whose credentials were rotated
+    pub rotated: usize,
+}
+
+/// Advisory lock held by whoever runs a reconciliation pass
+pub const RECONCILE_LOCK: &str = "reconcile";
+
+/// Reconcile every `config.interval` seconds in the background. Every replica
+/// runs it, and a replica skips its pass while another one is reconciling.
+pub fn spawn_reconciler(state: AppState, config: ReconcileConfig) {
+    let interval = config.interval;
+    if interval == 0 {
+        log::info!("Cloud account reconciliation is disabled");
+        return;
+    }
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        // Orphans reported by the previous run
+        let mut reported: HashSet<Orphan> = HashSet::new();
+        loop {
+            ticker.tick().await;
+            let delete = |orphan: &Orphan| config.delete && reported.contains(orphan);
+            let pass = state
+                .db
+                .exclusive(RECONCILE_LOCK, || reconcile(&state, &config, delete))
+                .await;
+            match pass {
+                Ok(Some(Ok(report))) => reported = report.orphans.into_iter().collect(),
+                Ok(None) => log::debug!("Another replica is reconciling cloud accounts"),
+                Ok(Some(Err(e))) => log::error!("Cloud account reconciliation failed: {e}"),
+                Err(e) => log::error!("Cloud account reconciliation failed: {e}"),
+            }
+        }
+    });
+}
+
+/// One reconciliation pass. Orphans `delete` accepts are deleted, the others,
+/// and those that could not be deleted, are reported.
+pub async fn reconcile(
+    state: &AppState,
+    config: &ReconcileConfig,
+    delete: impl Fn(&Orphan) -> bool,
+) -> ReconcileResult<ReconcileReport> {
+    let cloud_providers = state.cloud_providers.load();
+    let mut report = ReconcileReport::default();
+    for provider in cloud_providers.iter() {
+        let accounts = match find_orphans(&state.db, provider).await {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                log::warn!("Failed to reconcile {}: {e}", provider.name());
+                continue;
+            }
+        };
+        for account in accounts {
+            let orphan = Orphan {
+                instance: provider.name().to_string(),
+                account,
+            };
+            if delete(&orphan) {
+                match provider.delete_user(orphan.account.clone()).await {
+                    Ok(()) => {
+                        log::info!(
+                            "Deleted orphaned {} account {}",
+                            orphan.instance,
+                            orphan.account
+                        );
+                        report.deleted.push(orphan);
+                        continue;
+                    }
+                    Err(e) => log::warn!(
+                        "Failed to delete orphaned {} account {}: {e}",
+                        orphan.instance,
+                        orphan.account
+                    ),
+                }
+            }
+            log::warn!("Orphaned {} account {}", orphan.instance, orphan.account);
+            report.orphans.push(orphan);
+        }
+    }
+
+    let days = config.credential_rotation_days;
+    if days > 0 {
+        let before = chrono::Utc::now().naive_utc() - chrono::Duration::days(days);
+        for provider in cloud_providers.iter() {
+            report.rotated += rotate_stale(&state.db, provider, before).await?;
+        }
+    }
+    Ok(report)
+}
+
+/// Rotate the credentials of the provider's accounts not updated since
+/// `before`, except those of disabled users. Returns how many were rotated.
+async fn rotate_stale(
+    db: &DBClient,
+    provider: &dyn BaseCloudProvider,
+    before: chrono::NaiveDateTime,
+) -> ReconcileResult<usize> {
+    let instance = provider.name().to_string();
+    let cloud_users = db
+        .run(move |conn| {
+            Ok::<_, ReconcileError>(
+                schema::CloudUser::table
+                    .inner_join(schema::User::table)
+                    .filter(schema::CloudUser::cloudInstance.eq(instance))
+                    .filter(schema::CloudUser::updatedAt.lt(before))
+                    .filter(schema::User::disabledAt.is_null())
+                    .select(models::CloudUser::as_select())
+                    .load(conn)?,
+            )
+        })
+        .await?;
+    let mut rotated = 0;
+    for cloud_user in &cloud_users {
+        match rotate_credentials(db, provider, cloud_user).await {
+            Ok(()) => {
+                log::info!(
+                    "Rotated credentials of {} account {}",
+                    provider.name(),
+                    cloud_user.cloudUsername
+                );
+                rotated += 1;
+            }
+            Err(ReconcileError::Cloud(CloudError::Unsupported(_))) => return Ok(rotated),
+            Err(e) => log::warn!(
+                "Failed to rotate credentials of {} account {}: {e}",
+                provider.name(),
+                cloud_user.cloudUsername
+            ),
+        }
+    }
+    Ok(rotated)
+}
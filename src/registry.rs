@@ -0,0 +1,85 @@
+//! Configured providers, looked up by name
+//!
+//! A registry never changes once built, so it is shared between workers
+//! without a lock. A reload builds a new one and swaps it in whole, see
+//! `reload`. Providers take `&self` and handle their own synchronization, if
+//! they need any.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{auth::BaseAuthProvider, clouds::BaseCloudProvider, models::CloudProvider};
+
+/// Something a registry can hold
+pub trait Named {
+    fn name(&self) -> &str;
+}
+
+impl Named for dyn BaseAuthProvider {
+    fn name(&self) -> &str {
+        BaseAuthProvider::name(self)
+    }
+}
+
+impl Named for dyn BaseCloudProvider {
+    fn name(&self) -> &str {
+        BaseCloudProvider::name(self)
+    }
+}
+
+pub struct ProviderRegistry<P: ?Sized> {
+    /// In configuration order. Shared so a reload can keep the providers
+    /// whose configuration did not change.
+    providers: Vec<Arc<P>>,
+    by_name: HashMap<String, usize>,
+}
+
+impl<P: ?Sized + Named> ProviderRegistry<P> {
+    /// Panics when two providers share a name
+    pub fn new(providers: Vec<Box<P>>) -> Self {
+        Self::from_shared(providers.into_iter().map(Arc::from).collect())
+    }
+
+    /// Panics when two providers share a name
+    pub fn from_shared(providers: Vec<Arc<P>>) -> Self {
+        let mut by_name = HashMap::new();
+        for (index, provider) in providers.iter().enumerate() {
+            if by_name.insert(provider.name().to_string(), index).is_some() {
+                panic!("Provider {} is configured twice", provider.name());
+            }
+        }
+        Self { providers, by_name }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&P> {
+        self.by_name
+            .get(name)
+            .map(|&index| self.providers[index].as_ref())
+    }
+
+    /// The provider itself, to be kept by another registry
+    pub fn get_shared(&self, name: &str) -> Option<Arc<P>> {
+        self.by_name
+            .get(name)
+            .map(|&index| self.providers[index].clone())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &P> {
+        self.providers.iter().map(|provider| provider.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.iter()
+            .map(|provider| provider.name().to_string())
+            .collect()
+    }
+}
+
+impl ProviderRegistry<dyn BaseCloudProvider> {
+    /// Names of the configured instances of `provider`, in configuration order
+    pub fn instances_of(&self, provider: CloudProvider) -> Vec<&str> {
+        self.iter()
+            .filter(|p| p.provider_type() == provider)
+            .map(|p| p.name())
+            .collect()
+    }
+}
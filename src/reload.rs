@@ -0,0 +1,169 @@
+//! Reloading the configuration of a running server
+//!
+//! On SIGHUP, or `POST /api/admin/reload`, the configuration is read again and
+//! the auth and cloud providers are rebuilt from it. Each registry is swapped
+//! in whole, so a request sees either the old or the new one. Cloud providers
+//! whose settings did not change are kept as they are, along with their state.
+//!
+//! Settings only read at startup, like `bind` or the database, keep their old
+//! value and are reported as needing a restart. Environment variables are the
+//! ones the process started with, only the file changes between reloads.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Serialize;
+
+use crate::{
+    cache::RedisClient,
+    clouds::BaseCloudProvider,
+    config::{CloudConfig, Config, ConfigError},
+    registry::ProviderRegistry,
+    server::{cloud_provider, load_auth_providers, AppState},
+};
+
+/// Reloads run one at a time
+static RELOAD: Mutex<()> = Mutex::new(());
+
+/// A value replaced as a whole on reload. Readers take the current value and
+/// keep using it, even if a reload replaces it meanwhile.
+pub struct Reloadable<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: impl Into<Arc<T>>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(value.into())),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn store(&self, value: impl Into<Arc<T>>) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = value.into();
+    }
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+        }
+    }
+}
+
+/// Outcome of a reload
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadReport {
+    /// Enabled auth providers
+    pub auth_providers: Vec<String>,
+    /// Configured cloud instances
+    pub cloud_instances: Vec<String>,
+    /// Cloud instances added or rebuilt with new settings
+    pub reconfigured_instances: Vec<String>,
+    /// Changed settings that keep their old value until a restart
+    pub restart_required: Vec<String>,
+}
+
+/// Cloud providers for `clouds`, taking the providers of `registry` whose
+/// configuration in `previous` is the same. Also returns the names of the
+/// instances that got a new provider.
+pub fn reload_cloud_providers(
+    clouds: &[CloudConfig],
+    previous: &[CloudConfig],
+    registry: &ProviderRegistry<dyn BaseCloudProvider>,
+    cache: RedisClient,
+) -> (ProviderRegistry<dyn BaseCloudProvider>, Vec<String>) {
+    let mut reconfigured = Vec::new();
+    let providers = clouds
+        .iter()
+        .map(|cloud| {
+            let kept = registry
+                .get_shared(cloud.name())
+                .filter(|_| previous.contains(cloud));
+            kept.unwrap_or_else(|| {
+                reconfigured.push(cloud.name().to_string());
+                Arc::from(cloud_provider(cloud.clone(), cache.clone()))
+            })
+        })
+        .collect();
+    (ProviderRegistry::from_shared(providers), reconfigured)
+}
+
+/// Switch `state` over to `config`, except for the settings needing a restart
+pub fn apply(state: &AppState, config: Config) -> ReloadReport {
+    let _reload = RELOAD.lock().unwrap_or_else(|e| e.into_inner());
+    let current = state.config.load();
+    let restart_required = current.restart_required(&config);
+    let config = current.reloaded(config);
+
+    let auth_providers = load_auth_providers(&config.auth, state.db.clone());
+    let (cloud_providers, reconfigured_instances) = reload_cloud_providers(
+        &config.clouds,
+        &current.clouds,
+        &state.cloud_providers.load(),
+        state.cache.clone(),
+    );
+    let report = ReloadReport {
+        auth_providers: auth_providers.names(),
+        cloud_instances: cloud_providers.names(),
+        reconfigured_instances,
+        restart_required: restart_required.into_iter().map(String::from).collect(),
+    };
+    state.auth_providers.store(auth_providers);
+    state.cloud_providers.store(cloud_providers);
+    state.config.store(config);
+    report
+}
+
+/// Read the configuration again and apply it. An invalid configuration
+/// changes nothing.
+pub async fn reload(state: &AppState) -> Result<ReloadReport, ConfigError> {
+    let state = state.clone();
+    // Reading the file and CA certificates blocks
+    let reloaded = tokio::task::spawn_blocking(move || Config::load().map(|c| apply(&state, c)))
+        .await
+        .unwrap_or_else(|e| Err(ConfigError(vec![format!("Reload failed: {e}")])));
+    match &reloaded {
+        Ok(report) => {
+            log::info!(
+                "Configuration reloaded, auth providers: {}, cloud instances: {}",
+                report.auth_providers.join(", "),
+                report.cloud_instances.join(", ")
+            );
+            if !report.restart_required.is_empty() {
+                log::warn!(
+                    "Changed settings need a restart: {}",
+                    report.restart_required.join(", ")
+                );
+            }
+        }
+        Err(e) => log::error!("Configuration not reloaded: {e}"),
+    }
+    reloaded
+}
+
+/// Reload whenever the process gets SIGHUP
+#[cfg(unix)]
+pub fn spawn_reload_on_sighup(state: AppState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("Cannot listen for SIGHUP, reload with /api/admin/reload: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let _ = reload(&state).await;
+        }
+    });
+}
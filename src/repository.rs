@@ -0,0 +1,502 @@
+//! Users and their roles, behind traits so auth providers can run without a
+//! database
+//!
+//! `PgRepository` is the real thing and runs its queries on the blocking pool.
+//! `MemoryRepository` keeps everything in a `Vec` and is meant for tests.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+use crate::{
+    clouds::BaseCloudProvider,
+    db::{DBClient, DBConn, DBError, DBResult},
+    models::{self, CloudProvider, CloudUser, IaaaNewRole, NewUser, NewUserRole, Role, User},
+    registry::ProviderRegistry,
+    schema,
+};
+
+/// Outcome of `UserRepository::provision_user`
+#[derive(Debug, Clone)]
+pub struct ProvisionedUser {
+    pub user: User,
+    pub roles: Vec<String>,
+    /// Whether this call created the user
+    pub created: bool,
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn find_user_by_username(&self, username: &str) -> DBResult<Option<User>>;
+    /// Every user, by username
+    async fn list_users(&self) -> DBResult<Vec<User>>;
+    /// Fails with a unique violation when the username is taken
+    async fn create_user(&self, new_user: NewUser) -> DBResult<User>;
+    /// Return the user named like `new_user`, creating them with the role
+    /// `default_role` if they do not exist yet. Both happen atomically, so
+    /// concurrent calls agree on one user and nobody is left without a role.
+    /// Fails with `NotFound` when `default_role` does not exist.
+    async fn provision_user(
+        &self,
+        new_user: NewUser,
+        default_role: &str,
+    ) -> DBResult<ProvisionedUser>;
+    /// Allow or forbid the user to log in. Fails with `NotFound` when there is
+    /// no such user.
+    async fn set_disabled(&self, user_id: &str, disabled: bool) -> DBResult<User>;
+}
+
+#[async_trait]
+pub trait RoleRepository: Send + Sync {
+    /// Names of the user's roles
+    async fn role_names(&self, user_id: &str) -> DBResult<Vec<String>>;
+    async fn find_role(&self, name: &str) -> DBResult<Option<Role>>;
+    async fn create_role(&self, name: &str) -> DBResult<Role>;
+    /// Does nothing when the user already has the role
+    async fn assign_role(&self, user_id: &str, role_id: &str) -> DBResult<()>;
+}
+
+/// Everything an auth provider needs
+pub trait Repository: UserRepository + RoleRepository {}
+
+impl<T: UserRepository + RoleRepository> Repository for T {}
+
+#[derive(Debug, Clone)]
+pub struct PgRepository {
+    db: DBClient,
+}
+
+impl PgRepository {
+    pub fn new(db: DBClient) -> Self {
+        Self { db }
+    }
+
+    pub fn shared(db: DBClient) -> Arc<dyn Repository> {
+        Arc::new(Self::new(db))
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgRepository {
+    async fn find_user_by_username(&self, username: &str) -> DBResult<Option<User>> {
+        let username = username.to_string();
+        self.db
+            .query(move |conn| {
+                schema::User::dsl::User
+                    .filter(schema::User::username.eq(username))
+                    .select(models::User::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+    }
+
+    async fn list_users(&self) -> DBResult<Vec<User>> {
+        self.db
+            .query(move |conn| {
+                schema::User::dsl::User
+                    .order(schema::User::username.asc())
+                    .select(models::User::as_select())
+                    .load(conn)
+            })
+            .await
+    }
+
+    async fn create_user(&self, new_user: NewUser) -> DBResult<User> {
+        self.db
+            .query(move |conn| {
+                diesel::insert_into(schema::User::table)
+                    .values(&new_user)
+                    .returning(models::User::as_returning())
+                    .get_result(conn)
+            })
+            .await
+    }
+
+    async fn provision_user(
+        &self,
+        new_user: NewUser,
+        default_role: &str,
+    ) -> DBResult<ProvisionedUser> {
+        let default_role = default_role.to_string();
+        self.db
+            .query(move |conn| {
+                conn.transaction(|conn| {
+                    // A concurrent first login waits for ours and inserts nothing
+                    let inserted = diesel::insert_into(schema::User::table)
+                        .values(&new_user)
+                        .on_conflict(schema::User::username)
+                        .do_nothing()
+                        .returning(models::User::as_returning())
+                        .get_result(conn)
+                        .optional()?;
+                    let (user, created) = match inserted {
+                        Some(user) => {
+                            let role_id: String = schema::Role::dsl::Role
+                                .filter(schema::Role::name.eq(&default_role))
+                                .select(schema::Role::id)
+                                .first(conn)?;
+                            diesel::insert_into(schema::UserRole::table)
+                                .values(&NewUserRole {
+                                    userId: user.id.clone(),
+                                    roleId: role_id,
+                                })
+                                .on_conflict((schema::UserRole::userId, schema::UserRole::roleId))
+                                .do_nothing()
+                                .execute(conn)?;
+                            (user, true)
+                        }
+                        None => {
+                            let user = schema::User::dsl::User
+                                .filter(schema::User::username.eq(&new_user.username))
+                                .select(models::User::as_select())
+                                .first(conn)?;
+                            (user, false)
+                        }
+                    };
+                    let roles = load_role_names(conn, &user.id)?;
+                    Ok(ProvisionedUser {
+                        user,
+                        roles,
+                        created,
+                    })
+                })
+            })
+            .await
+    }
+
+    async fn set_disabled(&self, user_id: &str, disabled: bool) -> DBResult<User> {
+        let user_id = user_id.to_string();
+        let disabled_at = disabled.then(|| Utc::now().naive_utc());
+        self.db
+            .query(move |conn| {
+                diesel::update(schema::User::dsl::User.find(user_id))
+                    .set(schema::User::disabledAt.eq(disabled_at))
+                    .returning(models::User::as_returning())
+                    .get_result(conn)
+            })
+            .await
+    }
+}
+
+fn load_role_names(conn: &mut DBConn, user_id: &str) -> QueryResult<Vec<String>> {
+    schema::UserRole::table
+        .inner_join(schema::Role::table)
+        .filter(schema::UserRole::userId.eq(user_id))
+        .order(schema::Role::name.asc())
+        .select(schema::Role::name)
+        .load(conn)
+}
+
+#[async_trait]
+impl RoleRepository for PgRepository {
+    async fn role_names(&self, user_id: &str) -> DBResult<Vec<String>> {
+        let user_id = user_id.to_string();
+        self.db
+            .query(move |conn| load_role_names(conn, &user_id))
+            .await
+    }
+
+    async fn find_role(&self, name: &str) -> DBResult<Option<Role>> {
+        let name = name.to_string();
+        self.db
+            .query(move |conn| {
+                schema::Role::dsl::Role
+                    .filter(schema::Role::name.eq(name))
+                    .select(models::Role::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+    }
+
+    async fn create_role(&self, name: &str) -> DBResult<Role> {
+        let new_role = IaaaNewRole {
+            name: name.to_string(),
+        };
+        self.db
+            .query(move |conn| {
+                diesel::insert_into(schema::Role::table)
+                    .values(&new_role)
+                    .returning(models::Role::as_returning())
+                    .get_result(conn)
+            })
+            .await
+    }
+
+    async fn assign_role(&self, user_id: &str, role_id: &str) -> DBResult<()> {
+        let new_user_role = NewUserRole {
+            userId: user_id.to_string(),
+            roleId: role_id.to_string(),
+        };
+        self.db
+            .query(move |conn| {
+                diesel::insert_into(schema::UserRole::table)
+                    .values(&new_user_role)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .map(|_| ())
+            })
+            .await
+    }
+}
+
+/// Whether the user exists and is not disabled, checked on every request
+/// since tokens issued before `pikactl user disable` stay valid
+pub async fn is_user_enabled(db: &DBClient, user_id: &str) -> DBResult<bool> {
+    let user_id = user_id.to_string();
+    let disabled_at = db
+        .query(move |conn| {
+            schema::User::dsl::User
+                .find(user_id)
+                .select(schema::User::disabledAt)
+                .first::<Option<chrono::NaiveDateTime>>(conn)
+                .optional()
+        })
+        .await?;
+    Ok(matches!(disabled_at, Some(None)))
+}
+
+/// The user's account on `provider`. Cloud accounts only live in the
+/// database, so this is not part of `Repository`.
+pub async fn find_cloud_user(
+    db: &DBClient,
+    user_id: &str,
+    provider: CloudProvider,
+) -> DBResult<Option<CloudUser>> {
+    let user_id = user_id.to_string();
+    db.query(move |conn| {
+        schema::CloudUser::dsl::CloudUser
+            .filter(schema::CloudUser::userId.eq(user_id))
+            .filter(schema::CloudUser::cloudProvider.eq(provider))
+            .select(models::CloudUser::as_select())
+            .first(conn)
+            .optional()
+    })
+    .await
+}
+
+/// All of the user's cloud accounts
+pub async fn list_cloud_users(db: &DBClient, user_id: &str) -> DBResult<Vec<CloudUser>> {
+    let user_id = user_id.to_string();
+    db.query(move |conn| {
+        schema::CloudUser::dsl::CloudUser
+            .filter(schema::CloudUser::userId.eq(user_id))
+            .select(models::CloudUser::as_select())
+            .load(conn)
+    })
+    .await
+}
+
+/// Why the user's cloud account cannot be used
+#[derive(Debug, thiserror::Error)]
+pub enum CloudAccountError {
+    #[error("No cloud account")]
+    NoAccount,
+    /// The account lives on an instance that was removed from the
+    /// configuration
+    #[error("Cloud instance {0} is not configured")]
+    InstanceMissing(String),
+    #[error("{0}")]
+    Database(#[from] DBError),
+}
+
+/// The user's account on `provider` and the provider of the instance it lives
+/// on
+pub async fn find_cloud_account<'a>(
+    db: &DBClient,
+    cloud_providers: &'a ProviderRegistry<dyn BaseCloudProvider>,
+    user_id: &str,
+    provider: CloudProvider,
+) -> Result<(CloudUser, &'a dyn BaseCloudProvider), CloudAccountError> {
+    let cloud_user = find_cloud_user(db, user_id, provider)
+        .await?
+        .ok_or(CloudAccountError::NoAccount)?;
+    match cloud_providers.get(&cloud_user.cloudInstance) {
+        Some(cloud_provider) => Ok((cloud_user, cloud_provider)),
+        None => Err(CloudAccountError::InstanceMissing(cloud_user.cloudInstance)),
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    users: Vec<User>,
+    roles: Vec<Role>,
+    /// (user id, role id)
+    user_roles: Vec<(String, String)>,
+}
+
+impl MemoryState {
+    fn insert_user(&mut self, new_user: NewUser) -> DBResult<User> {
+        if self.users.iter().any(|u| u.username == new_user.username) {
+            return Err(unique_violation("User_username_key"));
+        }
+        let now = Utc::now().naive_utc();
+        let user = User {
+            id: uuid::Uuid::new_v4().to_string(),
+            username: new_user.username,
+            loginProvider: new_user.loginProvider,
+            name: new_user.name,
+            email: None,
+            password: new_user.password,
+            createdAt: now,
+            updatedAt: now,
+            disabledAt: None,
+        };
+        self.users.push(user.clone());
+        Ok(user)
+    }
+
+    fn assign_role(&mut self, user_id: &str, role_id: &str) {
+        let assignment = (user_id.to_string(), role_id.to_string());
+        if !self.user_roles.contains(&assignment) {
+            self.user_roles.push(assignment);
+        }
+    }
+
+    fn role_names(&self, user_id: &str) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .user_roles
+            .iter()
+            .filter(|(user, _)| user == user_id)
+            .filter_map(|(_, role_id)| self.roles.iter().find(|role| &role.id == role_id))
+            .map(|role| role.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+/// In-memory repository for tests. Enforces the same unique constraints as
+/// the database, and starts without roles.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn unique_violation(constraint: &str) -> DBError {
+    DBError::Query(DieselError::DatabaseError(
+        DatabaseErrorKind::UniqueViolation,
+        Box::new(format!(
+            "duplicate key value violates unique constraint \"{constraint}\""
+        )),
+    ))
+}
+
+#[async_trait]
+impl UserRepository for MemoryRepository {
+    async fn find_user_by_username(&self, username: &str) -> DBResult<Option<User>> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned())
+    }
+
+    async fn list_users(&self) -> DBResult<Vec<User>> {
+        let mut users = self.state().users.clone();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    async fn create_user(&self, new_user: NewUser) -> DBResult<User> {
+        self.state().insert_user(new_user)
+    }
+
+    async fn provision_user(
+        &self,
+        new_user: NewUser,
+        default_role: &str,
+    ) -> DBResult<ProvisionedUser> {
+        let mut state = self.state();
+        if let Some(user) = state
+            .users
+            .iter()
+            .find(|user| user.username == new_user.username)
+            .cloned()
+        {
+            let roles = state.role_names(&user.id);
+            return Ok(ProvisionedUser {
+                user,
+                roles,
+                created: false,
+            });
+        }
+        let Some(role_id) = state
+            .roles
+            .iter()
+            .find(|role| role.name == default_role)
+            .map(|role| role.id.clone())
+        else {
+            return Err(DBError::Query(DieselError::NotFound));
+        };
+        let user = state.insert_user(new_user)?;
+        state.assign_role(&user.id, &role_id);
+        let roles = state.role_names(&user.id);
+        Ok(ProvisionedUser {
+            user,
+            roles,
+            created: true,
+        })
+    }
+
+    async fn set_disabled(&self, user_id: &str, disabled: bool) -> DBResult<User> {
+        let mut state = self.state();
+        let Some(user) = state.users.iter_mut().find(|user| user.id == user_id) else {
+            return Err(DBError::Query(DieselError::NotFound));
+        };
+        user.disabledAt = disabled.then(|| Utc::now().naive_utc());
+        Ok(user.clone())
+    }
+}
+
+#[async_trait]
+impl RoleRepository for MemoryRepository {
+    async fn role_names(&self, user_id: &str) -> DBResult<Vec<String>> {
+        Ok(self.state().role_names(user_id))
+    }
+
+    async fn find_role(&self, name: &str) -> DBResult<Option<Role>> {
+        Ok(self
+            .state()
+            .roles
+            .iter()
+            .find(|role| role.name == name)
+            .cloned())
+    }
+
+    async fn create_role(&self, name: &str) -> DBResult<Role> {
+        let mut state = self.state();
+        if state.roles.iter().any(|role| role.name == name) {
+            return Err(unique_violation("Role_name_key"));
+        }
+        let now = Utc::now().naive_utc();
+        let role = Role {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            createdAt: now,
+            updatedAt: now,
+        };
+        state.roles.push(role.clone());
+        Ok(role)
+    }
+
+    async fn assign_role(&self, user_id: &str, role_id: &str) -> DBResult<()> {
+        self.state().assign_role(user_id, role_id);
+        Ok(())
+    }
+}
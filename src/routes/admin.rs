@@ -0,0 +1,620 @@
+//! Administration routes, only reachable with the `admin` role (see `ApiUserAuth`)
+
+use actix_web::{web, HttpResponse};
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing::{append_entries, balances, to_credits, to_milli_credits, BillingError},
+    db::DBError,
+    models::{
+        self, CloudPlacement, CloudProvider, LedgerEntryType, NewCloudPlacement, NewPrice, Role,
+        RoleQuota,
+    },
+    quota::{sync_quotas, Resources},
+    reconcile::find_orphans,
+    reload::reload,
+    routes::me::usage_response,
+    schema,
+    server::AppState,
+    usage::{aggregate_usage, UsageQuery},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RoleQuotaInfo {
+    role: String,
+    quota: Resources,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QuotaSyncResult {
+    role: String,
+    quota: Resources,
+    synced: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BalanceInfo {
+    username: String,
+    credits: f64,
+}
+
+/// Grant credits to the listed users and to every member of `role`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GrantRequest {
+    #[serde(default)]
+    usernames: Vec<String>,
+    role: Option<String>,
+    credits: f64,
+    description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RefundRequest {
+    username: String,
+    credits: f64,
+    description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LedgerResult {
+    entries: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PriceInfo {
+    resource: String,
+    credits_per_hour: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetPriceRequest {
+    credits_per_hour: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PlacementInfo {
+    id: String,
+    provider: String,
+    instance: String,
+    username: Option<String>,
+    role: Option<String>,
+    priority: i32,
+}
+
+impl PlacementInfo {
+    fn new(placement: CloudPlacement, username: Option<String>, role: Option<String>) -> Self {
+        Self {
+            id: placement.id,
+            provider: placement.cloudProvider.name().to_string(),
+            instance: placement.cloudInstance,
+            username,
+            role,
+            priority: placement.priority,
+        }
+    }
+}
+
+/// Place `username`, the members of `role`, or everyone when neither is given,
+/// on `instance` when they create a `provider` account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PlacementRequest {
+    provider: String,
+    instance: String,
+    username: Option<String>,
+    role: Option<String>,
+    #[serde(default)]
+    priority: i32,
+}
+
+pub fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/quotas").route(web::get().to(list_quotas_handler)))
+        .service(
+            web::resource("/roles/{role}/quota")
+                .route(web::put().to(set_role_quota_handler))
+                .route(web::delete().to(delete_role_quota_handler)),
+        )
+        .service(web::resource("/usage").route(web::get().to(get_usage_handler)))
+        .service(web::resource("/credits").route(web::get().to(list_balances_handler)))
+        .service(web::resource("/credits/grants").route(web::post().to(grant_credits_handler)))
+        .service(web::resource("/credits/refunds").route(web::post().to(refund_credits_handler)))
+        .service(
+            web::resource("/clouds/{provider}/orphans").route(web::get().to(list_orphans_handler)),
+        )
+        .service(
+            web::resource("/clouds/{provider}/orphans/{account}")
+                .route(web::delete().to(delete_orphan_handler)),
+        )
+        .service(
+            web::resource("/placements")
+                .route(web::get().to(list_placements_handler))
+                .route(web::post().to(add_placement_handler)),
+        )
+        .service(
+            web::resource("/placements/{id}").route(web::delete().to(delete_placement_handler)),
+        )
+        .service(web::resource("/prices").route(web::get().to(list_prices_handler)))
+        .service(
+            web::resource("/prices/{resource}")
+                .route(web::put().to(set_price_handler))
+                .route(web::delete().to(delete_price_handler)),
+        )
+        .service(web::resource("/reload").route(web::post().to(reload_handler)));
+}
+
+async fn list_quotas_handler(data: web::Data<AppState>) -> HttpResponse {
+    let quotas = data
+        .db
+        .query(|conn| {
+            schema::RoleQuota::table
+                .inner_join(schema::Role::table)
+                .select((models::RoleQuota::as_select(), models::Role::as_select()))
+                .load::<(RoleQuota, Role)>(conn)
+        })
+        .await;
+    match quotas {
+        Ok(quotas) => HttpResponse::Ok().json(
+            quotas
+                .into_iter()
+                .map(|(quota, role)| RoleQuotaInfo {
+                    role: role.name,
+                    quota: Resources::from(&quota),
+                })
+                .collect::<Vec<RoleQuotaInfo>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Create or replace the role's quota profile, then push the new effective
+/// quotas to the cloud projects of every user holding the role. Users who get
+/// the role later have it applied by `pikactl role assign`.
+async fn set_role_quota_handler(
+    data: web::Data<AppState>,
+    role_name: web::Path<String>,
+    req: web::Json<Resources>,
+) -> HttpResponse {
+    let quota = req.into_inner();
+    if !Resources::default().exceeding(&quota).is_empty() {
+        return HttpResponse::BadRequest().body("Quota values cannot be negative");
+    }
+
+    let updated = data
+        .db
+        .query(move |conn| {
+            let role = schema::Role::dsl::Role
+                .filter(schema::Role::name.eq(role_name.as_str()))
+                .select(models::Role::as_select())
+                .first(conn)?;
+
+            let new_quota = quota.to_role_quota(role.id.clone());
+            diesel::insert_into(schema::RoleQuota::table)
+                .values(&new_quota)
+                .on_conflict(schema::RoleQuota::roleId)
+                .do_update()
+                .set((
+                    &new_quota,
+                    schema::RoleQuota::updatedAt.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+
+            let cloud_users = schema::CloudUser::table
+                .inner_join(
+                    schema::UserRole::table
+                        .on(schema::UserRole::userId.eq(schema::CloudUser::userId)),
+                )
+                .filter(schema::UserRole::roleId.eq(&role.id))
+                .select(models::CloudUser::as_select())
+                .load(conn)?;
+            Ok((role, cloud_users))
+        })
+        .await;
+    let (role, cloud_users) = match updated {
+        Ok(updated) => updated,
+        Err(DBError::Query(DieselError::NotFound)) => {
+            return HttpResponse::NotFound().body("Role not found")
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let (synced, failed) = sync_quotas(
+        &data.db,
+        &data.cloud_providers.load(),
+        &cloud_users,
+        data.config.load().quota_merge,
+    )
+    .await;
+
+    HttpResponse::Ok().json(QuotaSyncResult {
+        role: role.name,
+        quota,
+        synced,
+        failed,
+    })
+}
+
+/// Users of the role keep their current cloud limits until another quota
+/// change touches them
+async fn delete_role_quota_handler(
+    data: web::Data<AppState>,
+    role_name: web::Path<String>,
+) -> HttpResponse {
+    let deleted = data
+        .db
+        .query(move |conn| {
+            let role_ids = schema::Role::dsl::Role
+                .filter(schema::Role::name.eq(role_name.as_str()))
+                .select(schema::Role::id);
+            diesel::delete(
+                schema::RoleQuota::dsl::RoleQuota
+                    .filter(schema::RoleQuota::roleId.eq_any(role_ids)),
+            )
+            .execute(conn)
+        })
+        .await;
+    match deleted {
+        Ok(0) => HttpResponse::NotFound().body("Role has no quota"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn get_usage_handler(
+    data: web::Data<AppState>,
+    query: web::Query<UsageQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let filter = query.clone();
+    let rows = data
+        .db
+        .run(move |conn| aggregate_usage(conn, None, &filter))
+        .await;
+    usage_response(rows, &query)
+}
+
+async fn list_balances_handler(data: web::Data<AppState>) -> HttpResponse {
+    match data.db.run(balances).await {
+        Ok(rows) => HttpResponse::Ok().json(
+            rows.into_iter()
+                .map(|row| BalanceInfo {
+                    username: row.username,
+                    credits: to_credits(row.milli_credits),
+                })
+                .collect::<Vec<BalanceInfo>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn grant_credits_handler(
+    data: web::Data<AppState>,
+    req: web::Json<GrantRequest>,
+) -> HttpResponse {
+    if req.credits <= 0.0 {
+        return HttpResponse::BadRequest().body("Granted credits must be positive");
+    }
+    let mut req = req.into_inner();
+    req.usernames.sort();
+    req.usernames.dedup();
+    // `None` when a username is unknown
+    let granted = data
+        .db
+        .run(move |conn| {
+            let mut user_ids = schema::User::dsl::User
+                .filter(schema::User::username.eq_any(&req.usernames))
+                .select(schema::User::id)
+                .load::<String>(conn)?;
+            if user_ids.len() != req.usernames.len() {
+                return Ok(None);
+            }
+            if let Some(role) = &req.role {
+                let members = schema::UserRole::table
+                    .inner_join(schema::Role::table)
+                    .filter(schema::Role::name.eq(role))
+                    .select(schema::UserRole::userId)
+                    .load::<String>(conn)?;
+                user_ids.extend(members);
+            }
+            user_ids.sort();
+            user_ids.dedup();
+
+            append_entries(
+                conn,
+                &user_ids,
+                LedgerEntryType::GRANT,
+                to_milli_credits(req.credits),
+                &req.description,
+            )
+            .map(Some)
+        })
+        .await;
+    match granted {
+        Ok(Some(entries)) => HttpResponse::Ok().json(LedgerResult { entries }),
+        Ok(None) => HttpResponse::BadRequest().body("Unknown username in grant"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn refund_credits_handler(
+    data: web::Data<AppState>,
+    req: web::Json<RefundRequest>,
+) -> HttpResponse {
+    if req.credits <= 0.0 {
+        return HttpResponse::BadRequest().body("Refunded credits must be positive");
+    }
+    let req = req.into_inner();
+    let refunded = data
+        .db
+        .run(move |conn| {
+            let user_id = schema::User::dsl::User
+                .filter(schema::User::username.eq(&req.username))
+                .select(schema::User::id)
+                .first::<String>(conn)?;
+            append_entries(
+                conn,
+                &[user_id],
+                LedgerEntryType::REFUND,
+                to_milli_credits(req.credits),
+                &req.description,
+            )
+        })
+        .await;
+    match refunded {
+        Ok(entries) => HttpResponse::Ok().json(LedgerResult { entries }),
+        Err(BillingError::Database(DieselError::NotFound)) => {
+            HttpResponse::NotFound().body("User not found")
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn list_prices_handler(data: web::Data<AppState>) -> HttpResponse {
+    let prices = data
+        .db
+        .query(|conn| {
+            schema::Price::dsl::Price
+                .order(schema::Price::resource.asc())
+                .select(models::Price::as_select())
+                .load(conn)
+        })
+        .await;
+    match prices {
+        Ok(prices) => HttpResponse::Ok().json(
+            prices
+                .into_iter()
+                .map(|price| PriceInfo {
+                    resource: price.resource,
+                    credits_per_hour: to_credits(price.milliCreditsPerHour),
+                })
+                .collect::<Vec<PriceInfo>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// `resource` is `flavor:<flavor id>` or `storage` (per GB)
+async fn set_price_handler(
+    data: web::Data<AppState>,
+    resource: web::Path<String>,
+    req: web::Json<SetPriceRequest>,
+) -> HttpResponse {
+    if req.credits_per_hour < 0.0 {
+        return HttpResponse::BadRequest().body("Price cannot be negative");
+    }
+    let new_price = NewPrice {
+        resource: resource.into_inner(),
+        milliCreditsPerHour: to_milli_credits(req.credits_per_hour),
+    };
+    let upserted = data
+        .db
+        .query(move |conn| {
+            diesel::insert_into(schema::Price::table)
+                .values(&new_price)
+                .on_conflict(schema::Price::resource)
+                .do_update()
+                .set((&new_price, schema::Price::updatedAt.eq(diesel::dsl::now)))
+                .execute(conn)?;
+            Ok(new_price)
+        })
+        .await;
+    match upserted {
+        Ok(new_price) => HttpResponse::Ok().json(PriceInfo {
+            resource: new_price.resource,
+            credits_per_hour: to_credits(new_price.milliCreditsPerHour),
+        }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn delete_price_handler(
+    data: web::Data<AppState>,
+    resource: web::Path<String>,
+) -> HttpResponse {
+    let deleted = data
+        .db
+        .query(move |conn| {
+            diesel::delete(
+                schema::Price::dsl::Price.filter(schema::Price::resource.eq(resource.as_str())),
+            )
+            .execute(conn)
+        })
+        .await;
+    match deleted {
+        Ok(0) => HttpResponse::NotFound().body("Price not found"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn list_placements_handler(data: web::Data<AppState>) -> HttpResponse {
+    let placements = data
+        .db
+        .query(|conn| {
+            schema::CloudPlacement::table
+                .left_join(schema::User::table)
+                .left_join(schema::Role::table)
+                .order((
+                    schema::CloudPlacement::cloudProvider.asc(),
+                    schema::CloudPlacement::priority.desc(),
+                ))
+                .select((
+                    models::CloudPlacement::as_select(),
+                    schema::User::username.nullable(),
+                    schema::Role::name.nullable(),
+                ))
+                .load::<(CloudPlacement, Option<String>, Option<String>)>(conn)
+        })
+        .await;
+    match placements {
+        Ok(placements) => HttpResponse::Ok().json(
+            placements
+                .into_iter()
+                .map(|(placement, username, role)| PlacementInfo::new(placement, username, role))
+                .collect::<Vec<PlacementInfo>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn add_placement_handler(
+    data: web::Data<AppState>,
+    req: web::Json<PlacementRequest>,
+) -> HttpResponse {
+    let req = req.into_inner();
+    let Some(provider) = CloudProvider::from_name(&req.provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let configured = data
+        .cloud_providers
+        .load()
+        .get(&req.instance)
+        .is_some_and(|p| p.provider_type() == provider);
+    if !configured {
+        return HttpResponse::BadRequest().body(format!(
+            "{} is not a configured {} instance",
+            req.instance, req.provider
+        ));
+    }
+    if req.username.is_some() && req.role.is_some() {
+        return HttpResponse::BadRequest().body("Give either a username or a role");
+    }
+
+    let (username, role) = (req.username.clone(), req.role.clone());
+    // `Err` names the user or role that does not exist
+    let placement = data
+        .db
+        .query(move |conn| {
+            let user_id = match &req.username {
+                Some(username) => match schema::User::dsl::User
+                    .filter(schema::User::username.eq(username))
+                    .select(schema::User::id)
+                    .first::<String>(conn)
+                    .optional()?
+                {
+                    Some(id) => Some(id),
+                    None => return Ok(Err("User not found")),
+                },
+                None => None,
+            };
+            let role_id = match &req.role {
+                Some(role) => match schema::Role::dsl::Role
+                    .filter(schema::Role::name.eq(role))
+                    .select(schema::Role::id)
+                    .first::<String>(conn)
+                    .optional()?
+                {
+                    Some(id) => Some(id),
+                    None => return Ok(Err("Role not found")),
+                },
+                None => None,
+            };
+
+            diesel::insert_into(schema::CloudPlacement::table)
+                .values(&NewCloudPlacement {
+                    cloudProvider: provider,
+                    cloudInstance: req.instance,
+                    userId: user_id,
+                    roleId: role_id,
+                    priority: req.priority,
+                })
+                .returning(models::CloudPlacement::as_returning())
+                .get_result(conn)
+                .map(Ok)
+        })
+        .await;
+    match placement {
+        Ok(Ok(placement)) => {
+            HttpResponse::Created().json(PlacementInfo::new(placement, username, role))
+        }
+        Ok(Err(missing)) => HttpResponse::NotFound().body(missing),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Accounts already placed stay on their instance
+async fn delete_placement_handler(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let deleted = data
+        .db
+        .query(move |conn| {
+            diesel::delete(
+                schema::CloudPlacement::dsl::CloudPlacement
+                    .filter(schema::CloudPlacement::id.eq(id.as_str())),
+            )
+            .execute(conn)
+        })
+        .await;
+    match deleted {
+        Ok(0) => HttpResponse::NotFound().body("Placement not found"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn list_orphans_handler(
+    data: web::Data<AppState>,
+    provider: web::Path<String>,
+) -> HttpResponse {
+    let cloud_providers = data.cloud_providers.load();
+    let Some(cloud_provider) = cloud_providers.get(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    match find_orphans(&data.db, cloud_provider).await {
+        Ok(orphans) => HttpResponse::Ok().json(orphans),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Tear down an orphaned account, refusing accounts that still have a user
+async fn delete_orphan_handler(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (provider, account) = path.into_inner();
+    let cloud_providers = data.cloud_providers.load();
+    let Some(cloud_provider) = cloud_providers.get(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    match find_orphans(&data.db, cloud_provider).await {
+        Ok(orphans) if orphans.contains(&account) => {}
+        Ok(_) => return HttpResponse::NotFound().body("No such orphaned account"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+    match cloud_provider.delete_user(account).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Read the configuration again, the same as SIGHUP
+async fn reload_handler(data: web::Data<AppState>) -> HttpResponse {
+    match reload(&data).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(err) => HttpResponse::UnprocessableEntity().body(err.to_string()),
+    }
+}
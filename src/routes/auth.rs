@@ -17,7 +17,6 @@ struct LoginRequest {
     payload: serde_json::Value,
 }
 
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RegisterRequest {
     provider: String,
@@ -41,11 +40,7 @@ pub fn auth_routes(cfg: &mut web::ServiceConfig) {
 }
 
 async fn get_auth_providers_handler(data: web::Data<AppState>) -> HttpResponse {
-    let auth_providers = data.auth_providers.lock().await;
-    let providers = auth_providers
-        .iter()
-        .map(|x| x.name().to_string())
-        .collect::<Vec<String>>();
+    let providers = data.auth_providers.load().names();
     HttpResponse::Ok().json(GetProviders { providers })
 }
 
@@ -55,12 +50,12 @@ async fn login_handler(
     req: web::Json<LoginRequest>,
     session: Session,
 ) -> HttpResponse {
-    let mut auth_providers = data.auth_providers.lock().await;
+    let auth_providers = data.auth_providers.load();
     let provider = req.provider.clone();
 
     let ip_address = request.peer_addr().map(|addr| addr.ip().to_string());
 
-    if let Some(auth_provider) = auth_providers.iter_mut().find(|p| p.name() == provider) {
+    if let Some(auth_provider) = auth_providers.get(&provider) {
         match auth_provider.login(req.payload.clone(), ip_address).await {
             Ok((user_id, roles)) => {
                 let user_info = UserJwtInfo { id: user_id, roles };
@@ -80,10 +75,10 @@ async fn register_handler(
     req: web::Json<RegisterRequest>,
     session: Session,
 ) -> HttpResponse {
-    let mut auth_providers = data.auth_providers.lock().await;
+    let auth_providers = data.auth_providers.load();
     let provider = req.provider.clone();
 
-    if let Some(auth_provider) = auth_providers.iter_mut().find(|p| p.name() == provider) {
+    if let Some(auth_provider) = auth_providers.get(&provider) {
         match auth_provider.register(req.payload.clone()).await {
             Ok((user_id, roles)) => {
                 let user_info = UserJwtInfo { id: user_id, roles };
@@ -105,17 +100,17 @@ async fn oauth_callback_handler(
     query: web::Query<OAuthQuery>,
     session: Session,
 ) -> HttpResponse {
-    let mut auth_providers = data.auth_providers.lock().await;
+    let auth_providers = data.auth_providers.load();
     let provider_name = provider.into_inner();
 
     let ip_address = request.peer_addr().map(|addr| addr.ip().to_string());
 
-    if let Some(auth_provider) = auth_providers
-        .iter_mut()
-        .find(|p| p.name() == provider_name)
-    {
+    if let Some(auth_provider) = auth_providers.get(&provider_name) {
         match auth_provider
-            .login(serde_json::json!({ "token": query.code.clone() }), ip_address)
+            .login(
+                serde_json::json!({ "token": query.code.clone() }),
+                ip_address,
+            )
             .await
         {
             Ok((user_id, roles)) => {
@@ -0,0 +1,165 @@
+//! Websocket proxy for instance consoles
+//!
+//! `/api/me/clouds/{provider}/instances/{id}/console` stores the upstream Nova
+//! console URL under a single-use ticket, the browser then connects here and we
+//! pump frames both ways, so the Nova console proxy need not be public.
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+};
+
+use crate::{
+    cache::{CacheResult, RedisClient},
+    models::{ConsoleType, RemoteConsole},
+    server::AppState,
+};
+
+/// How long a console ticket stays valid, in seconds
+pub const CONSOLE_TICKET_TTL: u64 = 60;
+
+pub fn console_ticket_key(ticket: &str) -> String {
+    format!("console:ticket-{ticket}")
+}
+
+pub fn console_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/{ticket}").route(web::get().to(console_proxy_handler)));
+}
+
+/// Websocket endpoint behind a Nova console URL.
+///
+/// Serial console URLs already are websockets. noVNC URLs point to the HTML
+/// client and carry the token either as `token` or inside the `path` parameter.
+/// The websocket is at `path` when given, otherwise next to the HTML client,
+/// so proxies served under a prefix keep it.
+pub fn console_websocket_url(console: &RemoteConsole) -> Option<String> {
+    let url = Url::parse(&console.url).ok()?;
+    if console.console_type == ConsoleType::Serial {
+        return Some(url.to_string());
+    }
+
+    let mut websocket = url.join("websockify").ok()?;
+    let mut token = None;
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "token" => token = Some(v.into_owned()),
+            "path" => {
+                let path = url.join(&format!("/{}", v.trim_start_matches('/'))).ok()?;
+                websocket.set_path(path.path());
+                if let Some((_, v)) = path.query_pairs().find(|(k, _)| k == "token") {
+                    token.get_or_insert(v.into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    let token = token?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    websocket.set_scheme(scheme).ok()?;
+    websocket.set_fragment(None);
+    websocket
+        .query_pairs_mut()
+        .clear()
+        .append_pair("token", &token);
+    Some(websocket.to_string())
+}
+
+/// Store `upstream` under a new single-use ticket, valid for
+/// [`CONSOLE_TICKET_TTL`] seconds
+pub async fn issue_console_ticket(cache: &RedisClient, upstream: &str) -> CacheResult<String> {
+    let ticket = uuid::Uuid::new_v4().to_string();
+    cache
+        .set(&console_ticket_key(&ticket), upstream, CONSOLE_TICKET_TTL)
+        .await?;
+    Ok(ticket)
+}
+
+/// The websocket URL stored under `ticket`, which cannot be used again
+pub async fn redeem_console_ticket(cache: &RedisClient, ticket: &str) -> Option<String> {
+    cache.take(&console_ticket_key(ticket)).await
+}
+
+async fn console_proxy_handler(
+    data: web::Data<AppState>,
+    ticket: web::Path<String>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> HttpResponse {
+    let upstream = redeem_console_ticket(&data.cache, &ticket).await;
+    let Some(upstream) = upstream else {
+        return HttpResponse::NotFound().body("Invalid or expired console ticket");
+    };
+
+    let mut upstream_req = match upstream.as_str().into_client_request() {
+        Ok(upstream_req) => upstream_req,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    // Nova's console proxies reject websockets without a matching Origin
+    let origin = upstream
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let origin = Url::parse(&origin)
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default();
+    if let Ok(origin) = HeaderValue::from_str(&origin) {
+        upstream_req.headers_mut().insert("Origin", origin);
+    }
+    let upstream_ws = match connect_async(upstream_req).await {
+        Ok((upstream_ws, _)) => upstream_ws,
+        Err(err) => return HttpResponse::BadGateway().body(err.to_string()),
+    };
+
+    let (response, mut session, mut client_ws) = match actix_ws::handle(&req, body) {
+        Ok(handshake) => handshake,
+        Err(err) => return err.as_response_error().error_response(),
+    };
+
+    actix_rt::spawn(async move {
+        let (mut upstream_tx, mut upstream_rx) = upstream_ws.split();
+        loop {
+            tokio::select! {
+                msg = client_ws.next() => match msg {
+                    Some(Ok(actix_ws::Message::Binary(bytes))) => {
+                        if upstream_tx.send(Message::Binary(bytes.to_vec())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Text(text))) => {
+                        if upstream_tx.send(Message::Text(text.to_string())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                msg = upstream_rx.next() => match msg {
+                    Some(Ok(Message::Binary(bytes))) => {
+                        if session.binary(bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Text(text))) => {
+                        if session.text(text).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // tungstenite answers upstream pings by itself
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        let _ = upstream_tx.close().await;
+        let _ = session.close(None).await;
+    });
+
+    response
+}
@@ -0,0 +1,239 @@
+//! Instances and volumes of the logged in user, with quota enforcement
+//!
+//! Creating and deleting instances is slow, so those requests only queue a job
+//! and answer 202 with it.
+
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    jobs::{enqueue, DeleteInstancePayload},
+    models::{CloudProvider, CreateInstance, JobKind, UserJwtInfo},
+    quota::{check_quota, effective_quota, lock_quota, QuotaError, Resources},
+    repository::{find_cloud_account, find_cloud_user},
+    routes::{
+        jobs::{job_accepted_response, job_error_response},
+        me::{cloud_account_error_response, cloud_error_response},
+    },
+    server::AppState,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateVolumeRequest {
+    name: String,
+    size_gb: i32,
+}
+
+pub fn instance_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/clouds/{provider}/instances")
+            .route(web::get().to(list_instances_handler))
+            .route(web::post().to(create_instance_handler)),
+    )
+    .service(
+        web::resource("/clouds/{provider}/instances/{instance_id}")
+            .route(web::delete().to(delete_instance_handler)),
+    )
+    .service(
+        web::resource("/clouds/{provider}/volumes")
+            .route(web::get().to(list_volumes_handler))
+            .route(web::post().to(create_volume_handler)),
+    );
+}
+
+fn quota_error_response(err: QuotaError) -> HttpResponse {
+    match err {
+        QuotaError::Exceeded(_) => HttpResponse::Forbidden().body(err.to_string()),
+        QuotaError::Busy => HttpResponse::Conflict().body(err.to_string()),
+        QuotaError::Cloud(err) => cloud_error_response(err),
+        QuotaError::Database(_) | QuotaError::Connection(_) => {
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
+
+async fn list_instances_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+) -> HttpResponse {
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let cloud_providers = data.cloud_providers.load();
+    let account = find_cloud_account(&data.db, &cloud_providers, &user.id, provider_type).await;
+    let (cloud_user, cloud_provider) = match account {
+        Ok(account) => account,
+        Err(err) => return cloud_account_error_response(err),
+    };
+
+    match cloud_provider
+        .list_instances(cloud_user.cloudUsername, cloud_user.cloudPassword)
+        .await
+    {
+        Ok(instances) => HttpResponse::Ok().json(instances),
+        Err(err) => cloud_error_response(err),
+    }
+}
+
+/// Queue the creation of an instance, checked against the user's quota when
+/// the job runs
+async fn create_instance_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+    req: web::Json<CreateInstance>,
+) -> HttpResponse {
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    match find_cloud_user(&data.db, &user.id, provider_type).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("No cloud account"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let job = enqueue(
+        &data.db,
+        &data.cache,
+        &data.config.load().jobs,
+        &user.id,
+        JobKind::CREATE_INSTANCE,
+        provider_type,
+        &req.into_inner(),
+    )
+    .await;
+    match job {
+        Ok(job) => job_accepted_response(job),
+        Err(err) => job_error_response(err),
+    }
+}
+
+async fn delete_instance_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (provider, instance_id) = path.into_inner();
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    match find_cloud_user(&data.db, &user.id, provider_type).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("No cloud account"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let job = enqueue(
+        &data.db,
+        &data.cache,
+        &data.config.load().jobs,
+        &user.id,
+        JobKind::DELETE_INSTANCE,
+        provider_type,
+        &DeleteInstancePayload { instance_id },
+    )
+    .await;
+    match job {
+        Ok(job) => job_accepted_response(job),
+        Err(err) => job_error_response(err),
+    }
+}
+
+async fn list_volumes_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+) -> HttpResponse {
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let cloud_providers = data.cloud_providers.load();
+    let account = find_cloud_account(&data.db, &cloud_providers, &user.id, provider_type).await;
+    let (cloud_user, cloud_provider) = match account {
+        Ok(account) => account,
+        Err(err) => return cloud_account_error_response(err),
+    };
+
+    match cloud_provider
+        .list_volumes(cloud_user.cloudUsername, cloud_user.cloudPassword)
+        .await
+    {
+        Ok(volumes) => HttpResponse::Ok().json(volumes),
+        Err(err) => cloud_error_response(err),
+    }
+}
+
+async fn create_volume_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+    req: web::Json<CreateVolumeRequest>,
+) -> HttpResponse {
+    if req.size_gb <= 0 {
+        return HttpResponse::BadRequest().body("Volume size must be positive");
+    }
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let cloud_providers = data.cloud_providers.load();
+    let account = find_cloud_account(&data.db, &cloud_providers, &user.id, provider_type).await;
+    let (cloud_user, cloud_provider) = match account {
+        Ok(account) => account,
+        Err(err) => return cloud_account_error_response(err),
+    };
+
+    let user_id = user.id.clone();
+    let strategy = data.config.load().quota_merge;
+    let quota = data
+        .db
+        .run(move |conn| effective_quota(conn, &user_id, strategy))
+        .await;
+    let quota = match quota {
+        Ok(quota) => quota,
+        Err(err) => return quota_error_response(err),
+    };
+    let mut _quota_lock = None;
+    if quota.is_some() {
+        // A request must not wait for another one, which may take as long as
+        // the cloud does
+        match lock_quota(&data.db, &user.id, Duration::ZERO).await {
+            Ok(lock) => _quota_lock = Some(lock),
+            Err(err) => return quota_error_response(err),
+        }
+        let usage = match cloud_provider
+            .get_usage(
+                cloud_user.cloudUsername.clone(),
+                cloud_user.cloudPassword.clone(),
+            )
+            .await
+        {
+            Ok(usage) => usage,
+            Err(err) => return cloud_error_response(err),
+        };
+        let request = Resources {
+            volumes: 1,
+            ..Default::default()
+        };
+        if let Err(err) = check_quota(quota.as_ref(), &usage, &request) {
+            return quota_error_response(err);
+        }
+    }
+
+    let CreateVolumeRequest { name, size_gb } = req.into_inner();
+    match cloud_provider
+        .create_volume(
+            cloud_user.cloudUsername,
+            cloud_user.cloudPassword,
+            name,
+            size_gb,
+        )
+        .await
+    {
+        Ok(volume) => HttpResponse::Created().json(volume),
+        Err(err) => cloud_error_response(err),
+    }
+}
@@ -0,0 +1,112 @@
+//! Jobs of the logged in user, see `crate::jobs`
+
+use actix_web::{web, HttpResponse};
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+
+use crate::{
+    db::DBError,
+    jobs::{cancel_job, JobError, JobInfo},
+    models::{self, Job, UserJwtInfo},
+    schema,
+    server::AppState,
+};
+
+pub fn job_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/jobs").route(web::get().to(list_jobs_handler)))
+        .service(
+            web::resource("/jobs/{id}")
+                .route(web::get().to(get_job_handler))
+                .route(web::delete().to(cancel_job_handler)),
+        );
+}
+
+/// 202 pointing at the job that will carry out the request
+pub(crate) fn job_accepted_response(job: Job) -> HttpResponse {
+    HttpResponse::Accepted()
+        .insert_header(("Location", format!("/api/me/jobs/{}", job.id)))
+        .json(JobInfo::from(job))
+}
+
+pub(crate) fn job_error_response(err: JobError) -> HttpResponse {
+    match err {
+        JobError::Payload(_) | JobError::Rejected(_) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        _ => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn list_jobs_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let jobs = data
+        .db
+        .query(move |conn| {
+            schema::Job::dsl::Job
+                .filter(schema::Job::userId.eq(user_id))
+                .order(schema::Job::createdAt.desc())
+                .limit(100)
+                .select(models::Job::as_select())
+                .load(conn)
+        })
+        .await;
+    match jobs {
+        Ok(jobs) => HttpResponse::Ok().json(
+            jobs.into_iter()
+                .map(JobInfo::from)
+                .collect::<Vec<JobInfo>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn get_job_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    job_id: web::Path<String>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let job = data
+        .db
+        .query(move |conn| {
+            schema::Job::dsl::Job
+                .filter(schema::Job::id.eq(job_id.as_str()))
+                .filter(schema::Job::userId.eq(user_id))
+                .select(models::Job::as_select())
+                .first(conn)
+        })
+        .await;
+    match job {
+        Ok(job) => HttpResponse::Ok().json(JobInfo::from(job)),
+        Err(DBError::Query(DieselError::NotFound)) => {
+            HttpResponse::NotFound().body("Job not found")
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Cancel a pending job, or ask a running one to stop retrying
+async fn cancel_job_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    job_id: web::Path<String>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let job = data
+        .db
+        .query(move |conn| cancel_job(conn, &user_id, &job_id))
+        .await;
+    match job {
+        Ok(job) if job.status.is_finished() && job.status != models::JobStatus::CANCELLED => {
+            HttpResponse::Conflict().body("Job already finished")
+        }
+        Ok(job) => HttpResponse::Ok().json(JobInfo::from(job)),
+        Err(DBError::Query(DieselError::NotFound)) => {
+            HttpResponse::NotFound().body("Job not found")
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
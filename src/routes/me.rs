@@ -0,0 +1,553 @@
+//! Routes acting on the logged in user
+
+use actix_web::{web, HttpResponse};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing::{balance, to_credits, BillingError},
+    clouds::{sync_ssh_keys, CloudError},
+    db::DBError,
+    jobs::{enqueue, find_unfinished},
+    models::{
+        self, CloudProvider, ConfigFormat, ConsoleType, JobKind, LedgerEntryType, NewSshKey,
+        UserJwtInfo,
+    },
+    quota::{effective_quota, Resources},
+    reconcile::{rotate_credentials, ReconcileError},
+    repository::{find_cloud_account, find_cloud_user, CloudAccountError},
+    routes::{
+        console::{console_websocket_url, issue_console_ticket},
+        instances::instance_routes,
+        jobs::{job_accepted_response, job_error_response, job_routes},
+    },
+    schema,
+    server::AppState,
+    ssh::{validate_key_name, PublicKey},
+    usage::{aggregate_usage, usage_csv, UsageFormat, UsageQuery, UsageResult, UsageRow},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SshKeyInfo {
+    id: String,
+    name: String,
+    key_type: String,
+    public_key: String,
+    fingerprint: String,
+    created_at: NaiveDateTime,
+}
+
+impl From<models::SshKey> for SshKeyInfo {
+    fn from(key: models::SshKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            key_type: key.keyType,
+            public_key: key.publicKey,
+            fingerprint: key.fingerprint,
+            created_at: key.createdAt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AddSshKeyRequest {
+    /// Defaults to the key comment
+    name: Option<String>,
+    public_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ConsoleQuery {
+    #[serde(rename = "type")]
+    console_type: Option<ConsoleType>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ConfigQuery {
+    format: Option<ConfigFormat>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ConsoleInfo {
+    #[serde(rename = "type")]
+    console_type: ConsoleType,
+    url: String,
+    /// Whether `url` is our websocket proxy rather than the Nova console itself
+    proxied: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QuotaInfo {
+    /// `None` when no role carries a quota and the cloud defaults apply
+    quota: Option<Resources>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LedgerEntryInfo {
+    entry_type: LedgerEntryType,
+    credits: f64,
+    description: String,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CreditsInfo {
+    balance: f64,
+    /// Most recent first
+    entries: Vec<LedgerEntryInfo>,
+}
+
+pub fn me_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/ssh-keys")
+            .route(web::get().to(list_ssh_keys_handler))
+            .route(web::post().to(add_ssh_key_handler)),
+    )
+    .service(web::resource("/ssh-keys/{id}").route(web::delete().to(delete_ssh_key_handler)))
+    .service(web::resource("/clouds/{provider}").route(web::post().to(provision_cloud_handler)))
+    .service(
+        web::resource("/clouds/{provider}/credentials")
+            .route(web::post().to(rotate_credentials_handler)),
+    )
+    .service(web::resource("/clouds/{provider}/config").route(web::get().to(client_config_handler)))
+    .service(
+        web::resource("/clouds/{provider}/instances/{instance_id}/console")
+            .route(web::get().to(instance_console_handler)),
+    )
+    .service(web::resource("/quota").route(web::get().to(get_quota_handler)))
+    .service(web::resource("/usage").route(web::get().to(get_usage_handler)))
+    .service(web::resource("/credits").route(web::get().to(get_credits_handler)))
+    .configure(instance_routes)
+    .configure(job_routes);
+}
+
+pub(crate) fn usage_response(rows: UsageResult<Vec<UsageRow>>, query: &UsageQuery) -> HttpResponse {
+    match (rows, query.format) {
+        (Ok(rows), UsageFormat::Json) => HttpResponse::Ok().json(rows),
+        (Ok(rows), UsageFormat::Csv) => HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header(("Content-Disposition", "attachment; filename=\"usage.csv\""))
+            .body(usage_csv(&rows, query.period)),
+        (Err(err), _) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+pub(crate) fn cloud_account_error_response(err: CloudAccountError) -> HttpResponse {
+    match err {
+        CloudAccountError::NoAccount => HttpResponse::NotFound().body(err.to_string()),
+        CloudAccountError::InstanceMissing(_) => {
+            HttpResponse::ServiceUnavailable().body(err.to_string())
+        }
+        CloudAccountError::Database(_) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+pub(crate) fn cloud_error_response(err: CloudError) -> HttpResponse {
+    match err {
+        CloudError::NotFound(msg) => HttpResponse::NotFound().body(msg),
+        CloudError::Unsupported(_) => HttpResponse::BadRequest().body(err.to_string()),
+        _ => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn list_ssh_keys_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let keys = data
+        .db
+        .query(move |conn| {
+            schema::SshKey::dsl::SshKey
+                .filter(schema::SshKey::userId.eq(user_id))
+                .order(schema::SshKey::createdAt.asc())
+                .select(models::SshKey::as_select())
+                .load(conn)
+        })
+        .await;
+    match keys {
+        Ok(keys) => HttpResponse::Ok().json(
+            keys.into_iter()
+                .map(SshKeyInfo::from)
+                .collect::<Vec<SshKeyInfo>>(),
+        ),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn add_ssh_key_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    req: web::Json<AddSshKeyRequest>,
+) -> HttpResponse {
+    let key = match PublicKey::parse(&req.public_key) {
+        Ok(key) => key,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let Some(name) = req.name.clone().or(key.comment.clone()) else {
+        return HttpResponse::BadRequest().body("Key name is required");
+    };
+    if let Err(err) = validate_key_name(&name) {
+        return HttpResponse::BadRequest().body(err.to_string());
+    }
+
+    let new_key = NewSshKey {
+        userId: user.id.clone(),
+        name,
+        keyType: key.key_type.clone(),
+        publicKey: key.to_openssh(),
+        fingerprint: key.fingerprint.clone(),
+    };
+    let user_id = user.id.clone();
+    let inserted = data
+        .db
+        .query(move |conn| {
+            // Without the accounts the key would never reach the cloud
+            conn.transaction(|conn| {
+                let ssh_key = diesel::insert_into(schema::SshKey::table)
+                    .values(&new_key)
+                    .returning(models::SshKey::as_returning())
+                    .get_result(conn)?;
+                let cloud_users = schema::CloudUser::dsl::CloudUser
+                    .filter(schema::CloudUser::userId.eq(user_id))
+                    .select(models::CloudUser::as_select())
+                    .load(conn)?;
+                Ok((ssh_key, cloud_users))
+            })
+        })
+        .await;
+    let (ssh_key, cloud_users) = match inserted {
+        Ok(inserted) => inserted,
+        Err(DBError::Query(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _))) => {
+            return HttpResponse::Conflict().body("Key name or fingerprint already exists")
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let cloud_providers = data.cloud_providers.load();
+    for cloud_user in &cloud_users {
+        if let Some(provider) = cloud_providers.get(&cloud_user.cloudInstance) {
+            sync_ssh_keys(provider, cloud_user, std::slice::from_ref(&ssh_key)).await;
+        }
+    }
+
+    HttpResponse::Created().json(SshKeyInfo::from(ssh_key))
+}
+
+async fn delete_ssh_key_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    key_id: web::Path<String>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let deleted = data
+        .db
+        .query(move |conn| {
+            conn.transaction(|conn| {
+                let ssh_key = diesel::delete(
+                    schema::SshKey::dsl::SshKey
+                        .filter(schema::SshKey::id.eq(key_id.as_str()))
+                        .filter(schema::SshKey::userId.eq(&user_id)),
+                )
+                .returning(models::SshKey::as_returning())
+                .get_result(conn)?;
+                let cloud_users = schema::CloudUser::dsl::CloudUser
+                    .filter(schema::CloudUser::userId.eq(&user_id))
+                    .select(models::CloudUser::as_select())
+                    .load(conn)?;
+                Ok((ssh_key, cloud_users))
+            })
+        })
+        .await;
+    let (ssh_key, cloud_users) = match deleted {
+        Ok(deleted) => deleted,
+        Err(DBError::Query(DieselError::NotFound)) => {
+            return HttpResponse::NotFound().body("Key not found")
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let cloud_providers = data.cloud_providers.load();
+    for cloud_user in cloud_users {
+        let Some(provider) = cloud_providers.get(&cloud_user.cloudInstance) else {
+            continue;
+        };
+        if let Err(e) = provider
+            .delete_keypair(
+                cloud_user.cloudUsername.clone(),
+                cloud_user.cloudPassword.clone(),
+                ssh_key.name.clone(),
+            )
+            .await
+        {
+            log::warn!(
+                "Failed to remove ssh key {} from {} user {}: {e}",
+                ssh_key.name,
+                provider.name(),
+                cloud_user.cloudUsername
+            );
+        }
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+/// Queue the creation of the user's account on a cloud provider, see
+/// `JobKind::PROVISION_ACCOUNT`
+async fn provision_cloud_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+) -> HttpResponse {
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    if data
+        .cloud_providers
+        .load()
+        .instances_of(provider_type)
+        .is_empty()
+    {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    }
+    match find_cloud_user(&data.db, &user.id, provider_type).await {
+        Ok(None) => {}
+        Ok(Some(_)) => return HttpResponse::Conflict().body("Cloud account already exists"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+    let user_id = user.id.clone();
+    let unfinished = data
+        .db
+        .query(move |conn| {
+            find_unfinished(conn, &user_id, JobKind::PROVISION_ACCOUNT, provider_type)
+        })
+        .await;
+    match unfinished {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return HttpResponse::Conflict().body("Cloud account is already being created")
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let job = enqueue(
+        &data.db,
+        &data.cache,
+        &data.config.load().jobs,
+        &user.id,
+        JobKind::PROVISION_ACCOUNT,
+        provider_type,
+        &serde_json::Value::Null,
+    )
+    .await;
+    match job {
+        Ok(job) => job_accepted_response(job),
+        Err(err) => job_error_response(err),
+    }
+}
+
+/// Replace the stored credentials of the user's cloud account, e.g. after they
+/// were exposed
+async fn rotate_credentials_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+) -> HttpResponse {
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let cloud_providers = data.cloud_providers.load();
+    let account = find_cloud_account(&data.db, &cloud_providers, &user.id, provider_type).await;
+    let (cloud_user, cloud_provider) = match account {
+        Ok(account) => account,
+        Err(err) => return cloud_account_error_response(err),
+    };
+
+    match rotate_credentials(&data.db, cloud_provider, &cloud_user).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(ReconcileError::Cloud(err)) => cloud_error_response(err),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Download a config file for the provider's CLI tools, see `ConfigFormat`
+async fn client_config_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    provider: web::Path<String>,
+    query: web::Query<ConfigQuery>,
+) -> HttpResponse {
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let cloud_providers = data.cloud_providers.load();
+    let account = find_cloud_account(&data.db, &cloud_providers, &user.id, provider_type).await;
+    let (cloud_user, cloud_provider) = match account {
+        Ok(account) => account,
+        Err(err) => return cloud_account_error_response(err),
+    };
+
+    // Credentials are rotated by the first reconciliation after that date,
+    // which breaks the file
+    let rotation_days = data.config.load().reconcile.credential_rotation_days;
+    let rotate_after = (rotation_days > 0 && cloud_provider.rotates_credentials())
+        .then(|| cloud_user.updatedAt + chrono::Duration::days(rotation_days));
+    match cloud_provider
+        .client_config(
+            cloud_user.cloudUsername,
+            cloud_user.cloudPassword,
+            query.format,
+        )
+        .await
+    {
+        // The file holds credentials
+        Ok(config) => {
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type("text/plain; charset=utf-8")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}\"", config.filename),
+                ))
+                .insert_header(("Cache-Control", "no-store"));
+            let Some(rotate_after) = rotate_after else {
+                return response.body(config.content);
+            };
+            let date = rotate_after.format("%Y-%m-%d");
+            response
+                .insert_header(("X-Credentials-Rotate-After", date.to_string()))
+                .body(format!(
+                    "# These credentials are replaced after {date} UTC, \
+                     download this file again then\n{}",
+                    config.content
+                ))
+        }
+        Err(err) => cloud_error_response(err),
+    }
+}
+
+async fn instance_console_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ConsoleQuery>,
+) -> HttpResponse {
+    let (provider, instance_id) = path.into_inner();
+    let console_type = query.console_type.unwrap_or(ConsoleType::Novnc);
+
+    let Some(provider_type) = CloudProvider::from_name(&provider) else {
+        return HttpResponse::BadRequest().body("Invalid provider");
+    };
+    let cloud_providers = data.cloud_providers.load();
+    let account = find_cloud_account(&data.db, &cloud_providers, &user.id, provider_type).await;
+    let (cloud_user, cloud_provider) = match account {
+        Ok(account) => account,
+        Err(err) => return cloud_account_error_response(err),
+    };
+
+    let console = cloud_provider
+        .get_console(
+            cloud_user.cloudUsername,
+            cloud_user.cloudPassword,
+            instance_id,
+            console_type,
+        )
+        .await;
+    let console = match console {
+        Ok(console) => console,
+        Err(err) => return cloud_error_response(err),
+    };
+
+    if !data.config.load().console_proxy {
+        return HttpResponse::Ok().json(ConsoleInfo {
+            console_type,
+            url: console.url,
+            proxied: false,
+        });
+    }
+
+    let Some(upstream) = console_websocket_url(&console) else {
+        return HttpResponse::InternalServerError().body("Unrecognized console URL");
+    };
+    let ticket = match issue_console_ticket(&data.cache, &upstream).await {
+        Ok(ticket) => ticket,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    HttpResponse::Ok().json(ConsoleInfo {
+        console_type,
+        url: format!("/api/console/{ticket}"),
+        proxied: true,
+    })
+}
+
+async fn get_quota_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let strategy = data.config.load().quota_merge;
+    let quota = data
+        .db
+        .run(move |conn| effective_quota(conn, &user_id, strategy))
+        .await;
+    match quota {
+        Ok(quota) => HttpResponse::Ok().json(QuotaInfo { quota }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+async fn get_usage_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+    query: web::Query<UsageQuery>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let query = query.into_inner();
+    let filter = query.clone();
+    let rows = data
+        .db
+        .run(move |conn| aggregate_usage(conn, Some(&user_id), &filter))
+        .await;
+    usage_response(rows, &query)
+}
+
+async fn get_credits_handler(
+    data: web::Data<AppState>,
+    user: web::ReqData<UserJwtInfo>,
+) -> HttpResponse {
+    let user_id = user.id.clone();
+    let credits = data
+        .db
+        .run(move |conn| {
+            let milli_credits = balance(conn, &user_id)?;
+            let entries = schema::CreditLedger::dsl::CreditLedger
+                .filter(schema::CreditLedger::userId.eq(&user_id))
+                .order(schema::CreditLedger::createdAt.desc())
+                .limit(100)
+                .select(models::CreditLedger::as_select())
+                .load(conn)?;
+            Ok::<_, BillingError>((milli_credits, entries))
+        })
+        .await;
+    match credits {
+        Ok((milli_credits, entries)) => HttpResponse::Ok().json(CreditsInfo {
+            balance: to_credits(milli_credits),
+            entries: entries
+                .into_iter()
+                .map(|entry| LedgerEntryInfo {
+                    entry_type: entry.entryType,
+                    credits: to_credits(entry.amount),
+                    description: entry.description,
+                    created_at: entry.createdAt,
+                })
+                .collect(),
+        }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
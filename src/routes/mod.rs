@@ -1,8 +1,19 @@
 use actix_web::web;
+use admin::admin_routes;
 use auth::auth_routes;
+use console::console_routes;
+use me::me_routes;
 
+pub mod admin;
 pub mod auth;
+pub mod console;
+pub mod instances;
+pub mod jobs;
+pub mod me;
 
 pub fn api_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::scope("/auth").configure(auth_routes));
+    cfg.service(web::scope("/auth").configure(auth_routes))
+        .service(web::scope("/me").configure(me_routes))
+        .service(web::scope("/console").configure(console_routes))
+        .service(web::scope("/admin").configure(admin_routes));
 }
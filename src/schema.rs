@@ -5,11 +5,38 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "CloudProvider"))]
     pub struct CloudProvider;
 
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "JobKind"))]
+    pub struct JobKind;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "JobStatus"))]
+    pub struct JobStatus;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "LedgerEntryType"))]
+    pub struct LedgerEntryType;
+
     #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "LoginProvider"))]
     pub struct LoginProvider;
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CloudProvider;
+
+    CloudPlacement (id) {
+        id -> Text,
+        cloudProvider -> CloudProvider,
+        cloudInstance -> Text,
+        userId -> Nullable<Text>,
+        roleId -> Nullable<Text>,
+        priority -> Int4,
+        createdAt -> Timestamp,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::CloudProvider;
@@ -22,6 +49,59 @@ diesel::table! {
         cloudPassword -> Text,
         createdAt -> Timestamp,
         updatedAt -> Timestamp,
+        cloudInstance -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::LedgerEntryType;
+
+    CreditLedger (id) {
+        id -> Text,
+        userId -> Text,
+        entryType -> LedgerEntryType,
+        amount -> Int8,
+        description -> Text,
+        usagePeriod -> Nullable<Timestamp>,
+        createdAt -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::JobKind;
+    use super::sql_types::JobStatus;
+    use super::sql_types::CloudProvider;
+
+    Job (id) {
+        id -> Text,
+        userId -> Text,
+        kind -> JobKind,
+        cloudProvider -> CloudProvider,
+        payload -> Text,
+        status -> JobStatus,
+        attempts -> Int4,
+        maxAttempts -> Int4,
+        runAfter -> Timestamp,
+        cancelRequested -> Bool,
+        result -> Nullable<Text>,
+        error -> Nullable<Text>,
+        createdAt -> Timestamp,
+        updatedAt -> Timestamp,
+        finishedAt -> Nullable<Timestamp>,
+        workerId -> Nullable<Text>,
+        heartbeatAt -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    Price (id) {
+        id -> Text,
+        resource -> Text,
+        milliCreditsPerHour -> Int8,
+        createdAt -> Timestamp,
+        updatedAt -> Timestamp,
     }
 }
 
@@ -34,6 +114,54 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    RoleQuota (id) {
+        id -> Text,
+        roleId -> Text,
+        vcpus -> Int4,
+        ramMb -> Int4,
+        instances -> Int4,
+        volumes -> Int4,
+        floatingIps -> Int4,
+        createdAt -> Timestamp,
+        updatedAt -> Timestamp,
+    }
+}
+
+diesel::table! {
+    SshKey (id) {
+        id -> Text,
+        userId -> Text,
+        name -> Text,
+        keyType -> Text,
+        publicKey -> Text,
+        fingerprint -> Text,
+        createdAt -> Timestamp,
+        updatedAt -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CloudProvider;
+
+    UsageSample (id) {
+        id -> Text,
+        userId -> Text,
+        cloudProvider -> CloudProvider,
+        resourceType -> Text,
+        resourceId -> Text,
+        resourceName -> Text,
+        status -> Text,
+        flavorId -> Nullable<Text>,
+        vcpus -> Int4,
+        ramMb -> Int4,
+        storageGb -> Int4,
+        intervalSeconds -> Int4,
+        sampledAt -> Timestamp,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::LoginProvider;
@@ -47,6 +175,7 @@ diesel::table! {
         password -> Nullable<Text>,
         createdAt -> Timestamp,
         updatedAt -> Timestamp,
+        disabledAt -> Nullable<Timestamp>,
     }
 }
 
@@ -60,13 +189,27 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(CloudPlacement -> Role (roleId));
+diesel::joinable!(CloudPlacement -> User (userId));
 diesel::joinable!(CloudUser -> User (userId));
+diesel::joinable!(CreditLedger -> User (userId));
+diesel::joinable!(Job -> User (userId));
+diesel::joinable!(RoleQuota -> Role (roleId));
+diesel::joinable!(SshKey -> User (userId));
+diesel::joinable!(UsageSample -> User (userId));
 diesel::joinable!(UserRole -> Role (roleId));
 diesel::joinable!(UserRole -> User (userId));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    CloudPlacement,
     CloudUser,
+    CreditLedger,
+    Job,
+    Price,
     Role,
+    RoleQuota,
+    SshKey,
+    UsageSample,
     User,
     UserRole,
 );
@@ -1,118 +1,147 @@
 use std::{io, sync::Arc};
 
 use actix_web::{middleware, web, App, HttpServer};
-use serde::Deserialize;
-use tokio::sync::Mutex;
 
 use crate::{
     auth::{iaaa::IaaaAuthProvider, password::PasswordAuthProvider, BaseAuthProvider},
     cache::RedisClient,
-    clouds::{openstack::OpenStackCloudProvider, BaseCloudProvider},
+    clouds::{
+        fake::FakeCloudProvider, kubernetes::KubernetesCloudProvider,
+        openstack::OpenStackCloudProvider, pikacloud::PikaCloudProvider, BaseCloudProvider,
+    },
+    config::{AuthConfig, CloudConfig, Config},
     db::DBClient,
+    jobs::spawn_job_workers,
     middleware::api_user_auth::ApiUserAuth,
+    migrations,
+    reconcile::spawn_reconciler,
+    registry::ProviderRegistry,
+    reload::{self, Reloadable},
     routes::api_routes,
-    utils::{load_env_optional, load_env_panic},
+    usage::spawn_usage_collector,
 };
 
+/// Shared by every worker. Clients are clone-safe and providers take `&self`.
+/// The configuration and the providers built from it are replaced on reload,
+/// handlers `load` them once and use what they got.
 #[derive(Clone)]
 pub struct AppState {
-    pub cache: Arc<Mutex<RedisClient>>,
-    pub db: Arc<Mutex<DBClient>>,
-    pub auth_providers: Arc<Mutex<Vec<Box<dyn BaseAuthProvider>>>>,
-    pub cloud_providers: Arc<Mutex<Vec<Box<dyn BaseCloudProvider>>>>,
-}
-
-#[derive(Debug, Deserialize, Default)]
-pub struct Config {
-    trust_proxy: Option<String>,
-    redis_url: String,
-    database_url: String,
-    cloud_providers: Vec<String>,
-    auth_providers: Vec<String>,
+    pub cache: RedisClient,
+    pub db: DBClient,
+    pub auth_providers: Reloadable<ProviderRegistry<dyn BaseAuthProvider>>,
+    pub cloud_providers: Reloadable<ProviderRegistry<dyn BaseCloudProvider>>,
+    pub config: Reloadable<Config>,
 }
 
 pub async fn server() -> io::Result<()> {
     env_logger::init();
-    let config = load_config();
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("{e}");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+    };
     if config.trust_proxy.is_some() {
         log::warn!("Trust proxy is enabled");
     }
     let redis_client = RedisClient::new(&config.redis_url).await.unwrap();
-    let db_client = DBClient::connect(&config.database_url).unwrap();
-
-    let auth_providers = load_auth_providers(&config.auth_providers, db_client.clone()).await;
-    let cloud_providers = load_cloud_providers(&config.cloud_providers, redis_client.clone()).await;
+    let db_client = DBClient::connect(&config.database_url, &config.db_pool).unwrap();
+    check_schema(&db_client, config.migrate_on_start)?;
 
-    let cache = Arc::new(Mutex::new(redis_client));
-    let db = Arc::new(Mutex::new(db_client.clone()));
-    let auth_providers = Arc::new(Mutex::new(auth_providers));
-    let cloud_providers = Arc::new(Mutex::new(cloud_providers));
+    let auth_providers = load_auth_providers(&config.auth, db_client.clone());
+    let cloud_providers = load_cloud_providers(&config.clouds, redis_client.clone());
 
     let state = AppState {
-        cache,
-        db,
-        auth_providers,
-        cloud_providers,
+        cache: redis_client,
+        db: db_client,
+        auth_providers: Reloadable::new(auth_providers),
+        cloud_providers: Reloadable::new(cloud_providers),
+        config: Reloadable::new(config.clone()),
     };
 
-    log::info!("Server is ready");
+    spawn_usage_collector(state.clone(), config.usage.clone());
+    spawn_reconciler(state.clone(), config.reconcile.clone());
+    spawn_job_workers(state.clone(), &config.redis_url, config.jobs.clone()).await;
+    #[cfg(unix)]
+    reload::spawn_reload_on_sighup(state.clone());
 
+    log::info!("Server is ready on {}", config.bind);
+
+    let jwt_secret = config.auth.jwt_secret.clone();
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(state.clone()))
             .wrap(middleware::Logger::default()) // Attach a logger
-            .wrap(ApiUserAuth) // Filter non-admin access to "/api/admin" or "/admin"
+            // Filter non-admin access to "/api/admin" or "/admin"
+            .wrap(ApiUserAuth::new(jwt_secret.clone(), state.db.clone()))
             .configure(configure_services)
     })
-    .bind("127.0.0.1:8080")?
+    .bind(config.bind)?
     .run()
     .await
 }
 
-fn load_config() -> Config {
-    let auth_providers: Vec<String> = load_env_panic("AUTH_PROVIDERS")
-        .split(',')
-        .into_iter()
-        .map(|s| s.to_string())
-        .collect();
-    let cloud_providers: Vec<String> = load_env_panic("CLOUD_PROVIDER")
-        .split(',')
-        .into_iter()
-        .map(|s| s.to_string())
-        .collect();
-    let database_url = load_env_panic("DATABASE_URL");
-    let redis_url = load_env_panic("REDIS_URL");
-    let trust_proxy = load_env_optional("TRUST_PROXY");
-    Config {
-        trust_proxy,
-        redis_url,
-        database_url,
-        cloud_providers,
-        auth_providers,
+/// Refuse to serve a schema older than this binary expects
+fn check_schema(db: &DBClient, migrate_on_start: bool) -> io::Result<()> {
+    let mut conn = db.get_conn().map_err(io::Error::other)?;
+    if migrate_on_start {
+        for name in migrations::run_pending(&mut conn).map_err(io::Error::other)? {
+            log::info!("Applied migration {name}");
+        }
     }
+    migrations::ensure_up_to_date(&mut conn).map_err(io::Error::other)
 }
 
-async fn load_auth_providers(
-    auth_providers: &[String],
+pub fn load_auth_providers(
+    config: &AuthConfig,
     db: DBClient,
-) -> Vec<Box<dyn BaseAuthProvider>> {
-    let mut providers: Vec<Box<dyn BaseAuthProvider>> = vec![
-        Box::new(PasswordAuthProvider::new(db.clone())),
-        Box::new(IaaaAuthProvider::new(db.clone())),
-        // Box::new(LcpuAuthProvider::new(db)),
-    ];
-    providers.retain(|provider| auth_providers.contains(&provider.name().to_string()));
-    providers
+) -> ProviderRegistry<dyn BaseAuthProvider> {
+    let mut providers: Vec<Box<dyn BaseAuthProvider>> = Vec::new();
+    if config.is_enabled("password") {
+        providers.push(Box::new(PasswordAuthProvider::new(
+            db.clone(),
+            &config.password,
+            config.enable_mfa,
+        )));
+    }
+    if let Some(iaaa) = &config.iaaa {
+        providers.push(Box::new(IaaaAuthProvider::new(
+            db.clone(),
+            iaaa.clone(),
+            config.enable_mfa,
+        )));
+    }
+    // if let Some(lcpu) = &config.lcpu {
+    //     providers.push(Box::new(LcpuAuthProvider::new(db, lcpu.clone(), config.enable_mfa)));
+    // }
+    ProviderRegistry::new(providers)
 }
 
-async fn load_cloud_providers(
-    cloud_providers: &[String],
+/// One provider per configured cloud instance, see `Config::clouds`
+pub fn load_cloud_providers(
+    clouds: &[CloudConfig],
     cache: RedisClient,
-) -> Vec<Box<dyn BaseCloudProvider>> {
-    let mut providers: Vec<Box<dyn BaseCloudProvider>> =
-        vec![Box::new(OpenStackCloudProvider::new(cache.clone()))];
-    providers.retain(|provider| cloud_providers.contains(&provider.name().to_string()));
-    providers
+) -> ProviderRegistry<dyn BaseCloudProvider> {
+    let providers = clouds
+        .iter()
+        .map(|cloud| cloud_provider(cloud.clone(), cache.clone()))
+        .collect();
+    ProviderRegistry::new(providers)
+}
+
+/// The provider of one cloud instance
+pub fn cloud_provider(cloud: CloudConfig, cache: RedisClient) -> Box<dyn BaseCloudProvider> {
+    match cloud {
+        CloudConfig::OpenStack(config) => {
+            Box::new(OpenStackCloudProvider::with_config(cache, config))
+        }
+        CloudConfig::PikaCloud(config) => Box::new(PikaCloudProvider::with_config(cache, config)),
+        CloudConfig::Fake { name, config } => Box::new(FakeCloudProvider::named(&name, config)),
+        CloudConfig::Kubernetes(config) => {
+            Box::new(KubernetesCloudProvider::with_config(cache, config))
+        }
+    }
 }
 
 fn configure_services(cfg: &mut web::ServiceConfig) {
@@ -0,0 +1,172 @@
+//! OpenSSH public key validation
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use sha2::{Digest, Sha256};
+
+/// Smallest RSA modulus we accept, in bits
+pub const MIN_RSA_BITS: usize = 2048;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SshKeyError {
+    #[error("Malformed public key: {0}")]
+    Malformed(String),
+    #[error("Unsupported key type: {0}")]
+    Unsupported(String),
+    #[error("RSA key too small: {0} bits, at least {MIN_RSA_BITS} required")]
+    WeakRsa(usize),
+    #[error("Invalid key name: {0}")]
+    InvalidName(String),
+}
+
+pub type SshKeyResult<T> = std::result::Result<T, SshKeyError>;
+
+/// A validated OpenSSH public key
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    /// Algorithm name, e.g. `ssh-ed25519`
+    pub key_type: String,
+    /// Base64 encoded key blob, exactly as in `authorized_keys`
+    pub data: String,
+    pub comment: Option<String>,
+    /// `SHA256:...` fingerprint, same format as `ssh-keygen -l`
+    pub fingerprint: String,
+}
+
+impl PublicKey {
+    /// Parse a single `authorized_keys` style line: `<type> <base64> [comment]`
+    pub fn parse(line: &str) -> SshKeyResult<Self> {
+        let mut parts = line.split_whitespace();
+        let key_type = parts
+            .next()
+            .ok_or(SshKeyError::Malformed("empty key".into()))?
+            .to_string();
+        let data = parts
+            .next()
+            .ok_or(SshKeyError::Malformed("missing key data".into()))?
+            .to_string();
+        let comment = parts.collect::<Vec<_>>().join(" ");
+        let comment = (!comment.is_empty()).then_some(comment);
+
+        let blob = STANDARD
+            .decode(&data)
+            .map_err(|e| SshKeyError::Malformed(e.to_string()))?;
+        validate_blob(&key_type, &blob)?;
+
+        let fingerprint = format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&blob)));
+
+        Ok(Self {
+            key_type,
+            data,
+            comment,
+            fingerprint,
+        })
+    }
+
+    /// Key without comment, as sent to cloud providers
+    pub fn to_openssh(&self) -> String {
+        format!("{} {}", self.key_type, self.data)
+    }
+}
+
+/// Key names end up as Nova keypair names, so keep to the characters Nova allows.
+pub fn validate_key_name(name: &str) -> SshKeyResult<()> {
+    if name.is_empty() || name.len() > 64 {
+        return Err(SshKeyError::InvalidName(
+            "must be 1 to 64 characters".into(),
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@' | ' '))
+    {
+        return Err(SshKeyError::InvalidName(name.into()));
+    }
+    Ok(())
+}
+
+/// Reader over the SSH wire format (RFC 4251)
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn string(&mut self) -> SshKeyResult<&'a [u8]> {
+        if self.buf.len() < 4 {
+            return Err(SshKeyError::Malformed("truncated key data".into()));
+        }
+        let (len, rest) = self.buf.split_at(4);
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if rest.len() < len {
+            return Err(SshKeyError::Malformed("truncated key data".into()));
+        }
+        let (value, rest) = rest.split_at(len);
+        self.buf = rest;
+        Ok(value)
+    }
+
+    fn finish(&self) -> SshKeyResult<()> {
+        if !self.buf.is_empty() {
+            return Err(SshKeyError::Malformed("trailing key data".into()));
+        }
+        Ok(())
+    }
+}
+
+fn validate_blob(key_type: &str, blob: &[u8]) -> SshKeyResult<()> {
+    let mut reader = Reader { buf: blob };
+    if reader.string()? != key_type.as_bytes() {
+        return Err(SshKeyError::Malformed(
+            "key type does not match key data".into(),
+        ));
+    }
+
+    match key_type {
+        "ssh-ed25519" => {
+            if reader.string()?.len() != 32 {
+                return Err(SshKeyError::Malformed("bad ed25519 key length".into()));
+            }
+        }
+        "ssh-rsa" => {
+            let _exponent = reader.string()?;
+            let bits = mpint_bits(reader.string()?);
+            if bits < MIN_RSA_BITS {
+                return Err(SshKeyError::WeakRsa(bits));
+            }
+        }
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+            let (curve, coord_len) = match key_type {
+                "ecdsa-sha2-nistp256" => ("nistp256", 32),
+                "ecdsa-sha2-nistp384" => ("nistp384", 48),
+                _ => ("nistp521", 66),
+            };
+            if reader.string()? != curve.as_bytes() {
+                return Err(SshKeyError::Malformed(
+                    "curve does not match key type".into(),
+                ));
+            }
+            // Uncompressed point: 0x04 || X || Y
+            let point = reader.string()?;
+            if point.len() != 1 + 2 * coord_len || point[0] != 0x04 {
+                return Err(SshKeyError::Malformed("bad ecdsa point".into()));
+            }
+        }
+        other => return Err(SshKeyError::Unsupported(other.into())),
+    }
+
+    reader.finish()
+}
+
+/// Bit length of an unsigned SSH mpint
+fn mpint_bits(mpint: &[u8]) -> usize {
+    let mut bytes = mpint;
+    while let [0, rest @ ..] = bytes {
+        bytes = rest;
+    }
+    match bytes.first() {
+        Some(first) => (bytes.len() - 1) * 8 + (8 - first.leading_zeros() as usize),
+        None => 0,
+    }
+}
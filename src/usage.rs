@@ -0,0 +1,364 @@
+//! Usage metering
+//!
+//! A background task samples every `CloudUser`'s instances and volumes into
+//! `UsageSample`. Each sample stands for `intervalSeconds` of usage, so
+//! resource-hours are sums of `amount * intervalSeconds / 3600`.
+
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Days, Months, NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::sql_types::{Double, Nullable, Text, Timestamp};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    billing::{charge_usage, enforce_balances},
+    clouds::CloudError,
+    models::{self, Flavor, NewUsageSample},
+    schema,
+    server::AppState,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum UsageError {
+    #[error("Database error: {0}")]
+    Database(#[from] diesel::result::Error),
+    #[error("Fail to fetch connection: {0}")]
+    Connection(#[from] crate::db::DBError),
+    #[error("Cloud error: {0}")]
+    Cloud(#[from] CloudError),
+}
+
+pub type UsageResult<T> = std::result::Result<T, UsageError>;
+
+/// Advisory lock held by the replica collecting usage
+const USAGE_LOCK: &str = "usage:collect";
+
+/// Metering and enforcement of credit balances
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageConfig {
+    /// Seconds between two collections, 0 disables metering,
+    /// `PIKA_USAGE_INTERVAL`
+    pub interval: u64,
+    /// Hours a user may stay out of credits before their instances are
+    /// stopped, `PIKA_CREDIT_GRACE_HOURS`
+    pub credit_grace_hours: i64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            interval: 3600,
+            credit_grace_hours: 24,
+        }
+    }
+}
+
+/// Run the collector every `config.interval` seconds in the background,
+/// storing each collection along with its charges to the credit ledger. Every
+/// replica runs it, but only one of them collects each period.
+pub fn spawn_usage_collector(state: AppState, config: UsageConfig) {
+    let interval = config.interval;
+    let grace = chrono::Duration::hours(config.credit_grace_hours);
+    if interval == 0 {
+        log::info!("Usage metering is disabled");
+        return;
+    }
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            ticker.tick().await;
+            let pass = state
+                .db
+                .exclusive(USAGE_LOCK, || collect_period(&state, interval, grace))
+                .await;
+            match pass {
+                Ok(Some(())) => {}
+                Ok(None) => log::debug!("Another replica is collecting usage"),
+                Err(e) => log::error!("Usage collection failed: {e}"),
+            }
+        }
+    });
+}
+
+/// Start of the period of `interval` seconds `at` falls in. Periods are
+/// aligned on the Unix epoch, so every replica computes the same ones.
+pub fn period_start(at: NaiveDateTime, interval: u64) -> NaiveDateTime {
+    let seconds = at.and_utc().timestamp();
+    let start = seconds - seconds.rem_euclid(interval.max(1) as i64);
+    DateTime::from_timestamp(start, 0)
+        .unwrap_or_default()
+        .naive_utc()
+}
+
+/// Collect, store and charge the usage of the current period, unless another
+/// replica already did, then enforce credit balances
+async fn collect_period(state: &AppState, interval: u64, grace: chrono::Duration) {
+    let period = period_start(Utc::now().naive_utc(), interval);
+    let sampled = state
+        .db
+        .query(move |conn| {
+            diesel::select(diesel::dsl::exists(
+                schema::UsageSample::table.filter(schema::UsageSample::sampledAt.ge(period)),
+            ))
+            .get_result::<bool>(conn)
+        })
+        .await;
+    match sampled {
+        Ok(false) => {}
+        Ok(true) => {
+            log::debug!("Usage of the period starting at {period} is already collected");
+            return;
+        }
+        Err(e) => {
+            log::error!("Usage collection failed: {e}");
+            return;
+        }
+    }
+
+    match collect_usage(state, interval as i32).await {
+        Ok(samples) => {
+            let count = samples.len();
+            let charged = state
+                .db
+                .run(move |conn| charge_usage(conn, &samples, period))
+                .await;
+            match charged {
+                Ok(_) => log::info!("Collected {count} usage samples"),
+                Err(e) => log::error!("Storing and charging usage failed: {e}"),
+            }
+        }
+        Err(e) => log::error!("Usage collection failed: {e}"),
+    }
+    if let Err(e) = enforce_balances(state, grace).await {
+        log::error!("Enforcing credit balances failed: {e}");
+    }
+}
+
+/// Take one sample of every instance and volume of every cloud user that is
+/// not disabled. They are stored by `charge_usage`.
+pub async fn collect_usage(
+    state: &AppState,
+    interval_seconds: i32,
+) -> UsageResult<Vec<NewUsageSample>> {
+    let cloud_users = state
+        .db
+        .query(|conn| {
+            schema::CloudUser::table
+                .inner_join(schema::User::table)
+                .filter(schema::User::disabledAt.is_null())
+                .select(models::CloudUser::as_select())
+                .load(conn)
+        })
+        .await?;
+
+    let cloud_providers = state.cloud_providers.load();
+    let mut samples = Vec::new();
+    for cloud_user in &cloud_users {
+        let Some(provider) = cloud_providers.get(&cloud_user.cloudInstance) else {
+            continue;
+        };
+        let provider_id = cloud_user.cloudUsername.clone();
+        let provider_pass = cloud_user.cloudPassword.clone();
+
+        match provider
+            .list_instances(provider_id.clone(), provider_pass.clone())
+            .await
+        {
+            Ok(instances) => {
+                let mut flavors: HashMap<String, Option<Flavor>> = HashMap::new();
+                for instance in instances {
+                    let flavor = match &instance.flavor_id {
+                        Some(flavor_id) => {
+                            if !flavors.contains_key(flavor_id) {
+                                let flavor = provider
+                                    .get_flavor(
+                                        provider_id.clone(),
+                                        provider_pass.clone(),
+                                        flavor_id.clone(),
+                                    )
+                                    .await
+                                    .ok();
+                                flavors.insert(flavor_id.clone(), flavor);
+                            }
+                            flavors[flavor_id].clone()
+                        }
+                        None => None,
+                    };
+                    samples.push(NewUsageSample {
+                        userId: cloud_user.userId.clone(),
+                        cloudProvider: cloud_user.cloudProvider,
+                        resourceType: "instance".into(),
+                        resourceId: instance.id,
+                        resourceName: instance.name,
+                        status: instance.status,
+                        flavorId: instance.flavor_id,
+                        vcpus: flavor.as_ref().map_or(0, |f| f.vcpus),
+                        ramMb: flavor.as_ref().map_or(0, |f| f.ram_mb),
+                        storageGb: flavor.as_ref().map_or(0, |f| f.disk_gb),
+                        intervalSeconds: interval_seconds,
+                    });
+                }
+            }
+            Err(CloudError::Unsupported(_)) => {}
+            Err(e) => log::warn!("Failed to list instances of {provider_id}: {e}"),
+        }
+
+        match provider
+            .list_volumes(provider_id.clone(), provider_pass)
+            .await
+        {
+            Ok(volumes) => samples.extend(volumes.into_iter().map(|volume| NewUsageSample {
+                userId: cloud_user.userId.clone(),
+                cloudProvider: cloud_user.cloudProvider,
+                resourceType: "volume".into(),
+                resourceId: volume.id,
+                resourceName: volume.name,
+                status: volume.status,
+                flavorId: None,
+                vcpus: 0,
+                ramMb: 0,
+                storageGb: volume.size_gb,
+                intervalSeconds: interval_seconds,
+            })),
+            Err(CloudError::Unsupported(_)) => {}
+            Err(e) => log::warn!("Failed to list volumes of {provider_id}: {e}"),
+        }
+    }
+
+    Ok(samples)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsagePeriod {
+    #[default]
+    Day,
+    Month,
+}
+
+impl UsagePeriod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UsagePeriod::Day => "day",
+            UsagePeriod::Month => "month",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UsageQuery {
+    #[serde(default)]
+    pub period: UsagePeriod,
+    /// Inclusive, defaults to 30 days or 12 months ago
+    pub from: Option<NaiveDate>,
+    /// Exclusive, defaults to tomorrow
+    pub to: Option<NaiveDate>,
+    #[serde(default)]
+    pub format: UsageFormat,
+}
+
+impl UsageQuery {
+    fn range(&self) -> (NaiveDateTime, NaiveDateTime) {
+        let today = Utc::now().date_naive();
+        let from = self.from.unwrap_or_else(|| match self.period {
+            UsagePeriod::Day => today - Days::new(30),
+            UsagePeriod::Month => today - Months::new(12),
+        });
+        let to = self.to.unwrap_or(today + Days::new(1));
+        (
+            from.and_hms_opt(0, 0, 0).unwrap_or_default(),
+            to.and_hms_opt(0, 0, 0).unwrap_or_default(),
+        )
+    }
+}
+
+/// Resource consumption of one user over one day or month
+#[derive(Debug, Clone, QueryableByName, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageRow {
+    #[diesel(sql_type = Text)]
+    pub user_id: String,
+    #[diesel(sql_type = Text)]
+    pub username: String,
+    #[diesel(sql_type = Timestamp)]
+    pub period: NaiveDateTime,
+    /// Hours instances spent running
+    #[diesel(sql_type = Double)]
+    pub instance_hours: f64,
+    #[diesel(sql_type = Double)]
+    pub vcpu_hours: f64,
+    #[diesel(sql_type = Double)]
+    pub ram_gb_hours: f64,
+    /// Volume size times hours, root disks of instances excluded
+    #[diesel(sql_type = Double)]
+    pub storage_gb_hours: f64,
+}
+
+/// Usage per user and period, for one user or for everyone
+pub fn aggregate_usage(
+    conn: &mut PooledConnection<ConnectionManager<PgConnection>>,
+    user_id: Option<&str>,
+    query: &UsageQuery,
+) -> UsageResult<Vec<UsageRow>> {
+    let (from, to) = query.range();
+    let rows = diesel::sql_query(
+        r#"SELECT s."userId" AS user_id, u."username" AS username,
+            date_trunc($1, s."sampledAt") AS period,
+            (COALESCE(SUM(s."intervalSeconds") FILTER (WHERE s."resourceType" = 'instance' AND s."status" = 'ACTIVE'), 0) / 3600.0)::float8 AS instance_hours,
+            (COALESCE(SUM(s."vcpus"::bigint * s."intervalSeconds") FILTER (WHERE s."resourceType" = 'instance' AND s."status" = 'ACTIVE'), 0) / 3600.0)::float8 AS vcpu_hours,
+            (COALESCE(SUM(s."ramMb"::bigint * s."intervalSeconds") FILTER (WHERE s."resourceType" = 'instance' AND s."status" = 'ACTIVE'), 0) / 1024.0 / 3600.0)::float8 AS ram_gb_hours,
+            (COALESCE(SUM(s."storageGb"::bigint * s."intervalSeconds") FILTER (WHERE s."resourceType" = 'volume'), 0) / 3600.0)::float8 AS storage_gb_hours
+        FROM "UsageSample" s
+        JOIN "User" u ON u."id" = s."userId"
+        WHERE ($2 IS NULL OR s."userId" = $2) AND s."sampledAt" >= $3 AND s."sampledAt" < $4
+        GROUP BY s."userId", u."username", period
+        ORDER BY period, u."username""#,
+    )
+    .bind::<Text, _>(query.period.as_str())
+    .bind::<Nullable<Text>, _>(user_id)
+    .bind::<Timestamp, _>(from)
+    .bind::<Timestamp, _>(to)
+    .load(conn)?;
+    Ok(rows)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn usage_csv(rows: &[UsageRow], period: UsagePeriod) -> String {
+    let mut csv =
+        String::from("userId,username,period,instanceHours,vcpuHours,ramGbHours,storageGbHours\n");
+    for row in rows {
+        let period = match period {
+            UsagePeriod::Day => row.period.format("%Y-%m-%d"),
+            UsagePeriod::Month => row.period.format("%Y-%m"),
+        };
+        csv.push_str(&format!(
+            "{},{},{},{:.3},{:.3},{:.3},{:.3}\n",
+            csv_field(&row.user_id),
+            csv_field(&row.username),
+            period,
+            row.instance_hours,
+            row.vcpu_hours,
+            row.ram_gb_hours,
+            row.storage_gb_hours,
+        ));
+    }
+    csv
+}
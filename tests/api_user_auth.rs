@@ -0,0 +1,67 @@
+mod common;
+
+use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+use common::TestDatabase;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use pikacloud_backend::{
+    middleware::api_user_auth::ApiUserAuth,
+    models::{LoginProvider, NewUser},
+    repository::{PgRepository, UserRepository},
+};
+use serde_json::json;
+
+const SECRET: &str = "jwt-test-secret";
+
+fn token(user_id: &str) -> String {
+    let claims = json!({
+        "id": user_id,
+        "roles": ["user"],
+        "exp": chrono::Utc::now().timestamp() + 86400,
+    });
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(SECRET.as_ref()),
+    )
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn tokens_of_disabled_users_are_rejected() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let repository = PgRepository::new(test.db.clone());
+    let user = repository
+        .create_user(NewUser {
+            username: "alice".into(),
+            loginProvider: LoginProvider::PASSWORD,
+            name: None,
+            password: None,
+        })
+        .await
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .wrap(ApiUserAuth::new(SECRET.into(), test.db.clone()))
+            .route("/api/me", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let status = |user_id: &str| {
+        let request = test::TestRequest::get()
+            .uri("/api/me")
+            .insert_header(("Authorization", format!("Bearer {}", token(user_id))))
+            .to_request();
+        let app = &app;
+        async move { test::call_service(app, request).await.status() }
+    };
+
+    assert_eq!(status(&user.id).await, StatusCode::OK);
+    // The token was issued before, and is still valid after, the user was
+    // disabled
+    repository.set_disabled(&user.id, true).await.unwrap();
+    assert_eq!(status(&user.id).await, StatusCode::FORBIDDEN);
+    repository.set_disabled(&user.id, false).await.unwrap();
+    assert_eq!(status(&user.id).await, StatusCode::OK);
+    assert_eq!(status("deleted-user").await, StatusCode::FORBIDDEN);
+}
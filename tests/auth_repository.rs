@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use pikacloud_backend::{
+    auth::{
+        get_result_from_resp, iaaa::IAAAValidateResponse, password::PasswordAuthProvider,
+        AuthError, BaseAuthProvider,
+    },
+    repository::{MemoryRepository, RoleRepository, UserRepository},
+};
+use serde_json::json;
+
+async fn repository_with_member_role() -> Arc<MemoryRepository> {
+    let repository = Arc::new(MemoryRepository::new());
+    repository.create_role("member").await.unwrap();
+    repository
+}
+
+fn iaaa_response(identity_id: &str, name: &str) -> IAAAValidateResponse {
+    serde_json::from_value(json!({
+        "success": true,
+        "errCode": "0",
+        "errMsg": "",
+        "userInfo": {
+            "name": name,
+            "status": "Kaitong",
+            "identityId": identity_id,
+            "deptId": "00048",
+            "dept": "Computer Science",
+            "identityType": "Student",
+            "detailType": "Undergraduate",
+            "identityStatus": "Enrolled",
+            "campus": "Yanyuan"
+        }
+    }))
+    .unwrap()
+}
+
+fn credentials(username: &str, password: &str) -> serde_json::Value {
+    json!({ "f_username": username, "f_password": password })
+}
+
+#[tokio::test]
+async fn password_users_log_in_with_their_roles() {
+    let repository = repository_with_member_role().await;
+    let provider = PasswordAuthProvider::with_repository(repository.clone(), true);
+
+    let (registered, roles) = provider
+        .register(credentials("alice", "hunter22"))
+        .await
+        .unwrap();
+    assert_eq!(roles, vec!["member".to_string()]);
+
+    let admin = repository.create_role("admin").await.unwrap();
+    repository
+        .assign_role(&registered, &admin.id)
+        .await
+        .unwrap();
+
+    let (logged_in, roles) = provider
+        .login(credentials("alice", "hunter22"), None)
+        .await
+        .unwrap();
+    assert_eq!(logged_in, registered);
+    assert_eq!(roles, vec!["admin".to_string(), "member".to_string()]);
+}
+
+#[tokio::test]
+async fn password_login_rejects_bad_credentials() {
+    let repository = repository_with_member_role().await;
+    let provider = PasswordAuthProvider::with_repository(repository, true);
+    provider
+        .register(credentials("alice", "hunter22"))
+        .await
+        .unwrap();
+
+    let wrong_password = provider.login(credentials("alice", "hunter23"), None).await;
+    assert!(matches!(wrong_password, Err(AuthError::Unauthorized(_))));
+    let unknown_user = provider.login(credentials("bob", "hunter22"), None).await;
+    assert!(matches!(unknown_user, Err(AuthError::Unauthorized(_))));
+    let taken = provider.register(credentials("alice", "other")).await;
+    assert!(matches!(taken, Err(AuthError::Conflict(_))));
+}
+
+#[tokio::test]
+async fn password_register_can_be_disabled() {
+    let repository = repository_with_member_role().await;
+    let provider = PasswordAuthProvider::with_repository(repository, false);
+
+    let register = provider.register(credentials("alice", "hunter22")).await;
+    assert!(matches!(register, Err(AuthError::Forbidden(_))));
+}
+
+#[tokio::test]
+async fn iaaa_users_are_created_once() {
+    let repository = repository_with_member_role().await;
+
+    let (created, roles) =
+        get_result_from_resp(repository.as_ref(), iaaa_response("2200088888", "Tom"))
+            .await
+            .unwrap();
+    assert_eq!(roles, vec!["member".to_string()]);
+    let user = repository
+        .find_user_by_username("2200088888")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.id, created);
+    assert_eq!(user.name.as_deref(), Some("Tom"));
+
+    let (again, roles) =
+        get_result_from_resp(repository.as_ref(), iaaa_response("2200088888", "Tom"))
+            .await
+            .unwrap();
+    assert_eq!(again, created);
+    assert_eq!(roles, vec!["member".to_string()]);
+}
+
+#[tokio::test]
+async fn logins_do_not_create_the_default_role() {
+    let repository = MemoryRepository::new();
+    let login = get_result_from_resp(&repository, iaaa_response("2200088888", "Tom")).await;
+    assert!(matches!(login, Err(AuthError::InternalServerError(_))));
+    assert!(repository.find_role("member").await.unwrap().is_none());
+    assert!(repository
+        .find_user_by_username("2200088888")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn concurrent_first_logins_agree_on_one_user() {
+    let repository = repository_with_member_role().await;
+    let logins = (0..8).map(|_| {
+        let repository = repository.clone();
+        tokio::spawn(async move {
+            get_result_from_resp(repository.as_ref(), iaaa_response("2200088888", "Tom")).await
+        })
+    });
+    let mut ids = Vec::new();
+    for login in logins.collect::<Vec<_>>() {
+        let (id, roles) = login.await.unwrap().unwrap();
+        assert_eq!(roles, vec!["member".to_string()]);
+        ids.push(id);
+    }
+    ids.dedup();
+    assert_eq!(ids.len(), 1);
+}
+
+#[tokio::test]
+async fn disabled_users_cannot_log_in() {
+    let repository = repository_with_member_role().await;
+    let provider = PasswordAuthProvider::with_repository(repository.clone(), true);
+    let (alice, _) = provider
+        .register(credentials("alice", "hunter22"))
+        .await
+        .unwrap();
+    let (tom, _) = get_result_from_resp(repository.as_ref(), iaaa_response("2200088888", "Tom"))
+        .await
+        .unwrap();
+
+    for user_id in [&alice, &tom] {
+        let user = repository.set_disabled(user_id, true).await.unwrap();
+        assert!(user.disabledAt.is_some());
+    }
+    let login = provider.login(credentials("alice", "hunter22"), None).await;
+    assert!(matches!(login, Err(AuthError::Forbidden(_))));
+    let login = get_result_from_resp(repository.as_ref(), iaaa_response("2200088888", "Tom")).await;
+    assert!(matches!(login, Err(AuthError::Forbidden(_))));
+
+    repository.set_disabled(&alice, false).await.unwrap();
+    let (logged_in, _) = provider
+        .login(credentials("alice", "hunter22"), None)
+        .await
+        .unwrap();
+    assert_eq!(logged_in, alice);
+    let usernames: Vec<String> = repository
+        .list_users()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|user| user.username)
+        .collect();
+    assert_eq!(
+        usernames,
+        vec!["2200088888".to_string(), "alice".to_string()]
+    );
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use common::TestDatabase;
+use diesel::prelude::*;
+use pikacloud_backend::{
+    billing::{
+        balance, charge_usage, flavor_price_resource, price_samples, to_credits, to_milli_credits,
+        STORAGE_PRICE_RESOURCE,
+    },
+    models::{CloudProvider, LoginProvider, NewPrice, NewUsageSample, NewUser},
+    repository::{PgRepository, UserRepository},
+    schema,
+};
+
+fn sample(
+    user_id: &str,
+    resource_type: &str,
+    status: &str,
+    flavor_id: Option<&str>,
+) -> NewUsageSample {
+    NewUsageSample {
+        userId: user_id.into(),
+        cloudProvider: CloudProvider::OPENSTACK,
+        resourceType: resource_type.into(),
+        resourceId: "resource".into(),
+        resourceName: "resource".into(),
+        status: status.into(),
+        flavorId: flavor_id.map(String::from),
+        vcpus: 2,
+        ramMb: 4096,
+        storageGb: 10,
+        intervalSeconds: 300,
+    }
+}
+
+fn prices() -> HashMap<String, i64> {
+    HashMap::from([
+        (flavor_price_resource("m1.small"), 1200),
+        (flavor_price_resource("m1.tiny"), 5),
+        (STORAGE_PRICE_RESOURCE.to_string(), 36),
+    ])
+}
+
+#[test]
+fn running_instances_are_charged_by_flavor() {
+    let samples = [
+        sample("alice", "instance", "ACTIVE", Some("m1.small")),
+        sample("alice", "instance", "ACTIVE", Some("m1.small")),
+        sample("bob", "instance", "ACTIVE", Some("m1.small")),
+    ];
+    let costs = price_samples(&prices(), &samples);
+    assert_eq!(costs["alice"], 200);
+    assert_eq!(costs["bob"], 100);
+}
+
+#[test]
+fn stopped_instances_and_unpriced_flavors_are_free() {
+    let samples = [
+        sample("alice", "instance", "SHUTOFF", Some("m1.small")),
+        sample("alice", "instance", "ACTIVE", Some("m1.huge")),
+        sample("alice", "instance", "ACTIVE", None),
+        sample("alice", "floating_ip", "ACTIVE", None),
+    ];
+    assert_eq!(price_samples(&prices(), &samples)["alice"], 0);
+    assert!(price_samples(&HashMap::new(), &samples)
+        .values()
+        .all(|cost| *cost == 0));
+}
+
+#[test]
+fn volumes_are_charged_per_gb() {
+    // 10 GB at 36 per GB and hour, for 5 minutes
+    let costs = price_samples(&prices(), &[sample("alice", "volume", "in-use", None)]);
+    assert_eq!(costs["alice"], 30);
+}
+
+#[test]
+fn cheap_resources_add_up_before_rounding() {
+    // An hour of 5 minute samples at 5 milli-credits per hour
+    let samples: Vec<NewUsageSample> = (0..12)
+        .map(|_| sample("alice", "instance", "ACTIVE", Some("m1.tiny")))
+        .collect();
+    assert_eq!(price_samples(&prices(), &samples)["alice"], 5);
+    // Half a milli-credit and more rounds up, less rounds down
+    let mut prices = prices();
+    prices.insert(flavor_price_resource("m1.tiny"), 6);
+    let one = [sample("alice", "instance", "ACTIVE", Some("m1.tiny"))];
+    assert_eq!(price_samples(&prices, &one)["alice"], 1);
+    prices.insert(flavor_price_resource("m1.tiny"), 5);
+    assert_eq!(price_samples(&prices, &one)["alice"], 0);
+}
+
+#[test]
+fn credits_convert_to_milli_credits() {
+    assert_eq!(to_milli_credits(1.5), 1500);
+    assert_eq!(to_milli_credits(0.0004), 0);
+    assert_eq!(to_credits(-250), -0.25);
+}
+
+#[tokio::test]
+async fn samples_are_stored_with_their_charges() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let new_user = NewUser {
+        username: "alice".into(),
+        loginProvider: LoginProvider::PASSWORD,
+        name: None,
+        password: None,
+    };
+    let repository = PgRepository::new(test.db.clone());
+    let alice = repository.create_user(new_user).await.unwrap().id;
+    let conn = &mut test.db.get_conn().unwrap();
+    let samples = [
+        sample(&alice, "instance", "ACTIVE", Some("m1.small")),
+        sample(&alice, "volume", "in-use", None),
+    ];
+
+    let period = NaiveDate::from_ymd_opt(2024, 7, 1)
+        .unwrap()
+        .and_hms_opt(10, 0, 0)
+        .unwrap();
+
+    // Without prices the samples are stored but cost nothing
+    assert_eq!(charge_usage(conn, &samples, period).unwrap(), 0);
+    let new_price = NewPrice {
+        resource: flavor_price_resource("m1.small"),
+        milliCreditsPerHour: 1200,
+    };
+    diesel::insert_into(schema::Price::table)
+        .values(&new_price)
+        .execute(conn)
+        .unwrap();
+    assert_eq!(charge_usage(conn, &samples, period).unwrap(), 1);
+    // Another replica collecting the same period charges nothing
+    assert_eq!(charge_usage(conn, &samples, period).unwrap(), 0);
+
+    let stored: i64 = schema::UsageSample::table.count().get_result(conn).unwrap();
+    assert_eq!(stored, 4);
+    assert_eq!(balance(conn, &alice).unwrap(), -100);
+
+    let next_period = period + chrono::Duration::hours(1);
+    assert_eq!(charge_usage(conn, &samples, next_period).unwrap(), 1);
+    assert_eq!(balance(conn, &alice).unwrap(), -200);
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::{config, fixture, mock_keystone, MockRedis, APP_CREDENTIAL_ID, APP_CREDENTIAL_SECRET};
+use pikacloud_backend::{
+    clouds::{
+        keystone::ApplicationCredential,
+        kubernetes::{KubernetesCloudProvider, KubernetesConfig},
+        openstack::OpenStackCloudProvider,
+        BaseCloudProvider, CloudError,
+    },
+    models::ConfigFormat,
+    quota::Resources,
+};
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+fn stored_credential() -> String {
+    ApplicationCredential {
+        id: APP_CREDENTIAL_ID.into(),
+        secret: APP_CREDENTIAL_SECRET.into(),
+    }
+    .to_stored()
+}
+
+/// A provider whose admin token carries the catalog of `auth_token_scoped`
+async fn openstack(redis: &MockRedis, keystone: &MockServer) -> OpenStackCloudProvider {
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(
+            ResponseTemplate::new(201)
+                .insert_header("X-Subject-Token", "admin-token")
+                .set_body_json(fixture("keystone/auth_token_scoped")),
+        )
+        .mount(keystone)
+        .await;
+    let mut config = config(keystone);
+    config.region = Some("RegionOne".into());
+    OpenStackCloudProvider::with_config(redis.client().await, config)
+}
+
+#[tokio::test]
+async fn clouds_yaml_uses_the_application_credential() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    let provider = openstack(&redis, &keystone).await;
+
+    let config = provider
+        .client_config("alice".into(), stored_credential(), None)
+        .await
+        .unwrap();
+    assert_eq!(config.filename, "clouds.yaml");
+    assert_eq!(
+        config.content,
+        format!(
+            "clouds:
+  openstack:
+    auth_type: v3applicationcredential
+    auth:
+      auth_url: \"http://keystone.test/identity\"
+      application_credential_id: \"{APP_CREDENTIAL_ID}\"
+      application_credential_secret: \"{APP_CREDENTIAL_SECRET}\"
+    interface: \"public\"
+    identity_api_version: \"3\"
+    region_name: \"RegionOne\"
+"
+        )
+    );
+}
+
+#[tokio::test]
+async fn openrc_quotes_values_for_the_shell() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    let provider = openstack(&redis, &keystone).await;
+
+    let config = provider
+        .client_config(
+            "alice".into(),
+            "it's a password".into(),
+            Some(ConfigFormat::Openrc),
+        )
+        .await
+        .unwrap();
+    assert_eq!(config.filename, "openstack-openrc.sh");
+    let lines: Vec<_> = config.content.lines().collect();
+    assert_eq!(lines[0], "export OS_AUTH_TYPE=password");
+    assert!(lines.contains(&"export OS_AUTH_URL='http://keystone.test/identity'"));
+    assert!(lines.contains(&"export OS_USERNAME='alice'"));
+    assert!(lines.contains(&r"export OS_PASSWORD='it'\''s a password'"));
+    assert!(lines.contains(&"export OS_PROJECT_NAME='alice'"));
+    assert!(lines.contains(&"export OS_REGION_NAME='RegionOne'"));
+}
+
+#[tokio::test]
+async fn openstack_has_no_kubeconfig() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    let provider = openstack(&redis, &keystone).await;
+
+    match provider
+        .client_config(
+            "alice".into(),
+            stored_credential(),
+            Some(ConfigFormat::Kubeconfig),
+        )
+        .await
+    {
+        Err(CloudError::Unsupported(_)) => {}
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn kubeconfig_points_at_the_public_api() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(
+            "/api/v1/namespaces/pika-alice/serviceaccounts/pikacloud-user/token",
+        ))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("kubernetes/token_request")))
+        .mount(&api)
+        .await;
+    let provider = KubernetesCloudProvider::with_config(
+        redis.client().await,
+        KubernetesConfig {
+            name: "k8s-lab".into(),
+            api: api.uri(),
+            public_api: Some("https://k8s.example.org:6443".into()),
+            token: "admin".into(),
+            ca_file: None,
+            namespace_prefix: "pika-".into(),
+            cluster_role: "edit".into(),
+            token_seconds: 3600,
+            quota: Resources::default(),
+        },
+    );
+
+    let config = provider
+        .client_config("pika-alice".into(), String::new(), None)
+        .await
+        .unwrap();
+    assert_eq!(config.filename, "k8s-lab-kubeconfig.yaml");
+    assert_eq!(
+        config.content,
+        "apiVersion: v1
+kind: Config
+clusters:
+- name: k8s-lab
+  cluster:
+    server: \"https://k8s.example.org:6443\"
+users:
+- name: pika-alice
+  user:
+    token: \"eyJhbGciOiJSUzI1NiJ9.alice\"
+contexts:
+- name: k8s-lab
+  context:
+    cluster: k8s-lab
+    user: pika-alice
+    namespace: pika-alice
+current-context: k8s-lab
+"
+    );
+}
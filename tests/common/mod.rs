@@ -0,0 +1,277 @@
+//! Shared harness for the provider tests: a minimal in-process Redis, a mock
+//! Keystone serving recorded fixtures and throwaway Postgres databases
+
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use diesel::{Connection, PgConnection, RunQueryDsl};
+use pikacloud_backend::{
+    cache::RedisClient,
+    clouds::openstack::{OpenStackCloudProvider, OpenStackConfig},
+    db::{DBClient, PoolConfig},
+    migrations::run_pending,
+};
+use reqwest::Url;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use wiremock::{
+    matchers::{body_partial_json, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+pub const ADMIN_TOKEN: &str = "gAAAAABmAdminToken";
+pub const PROJECT_ID: &str = "0c4e939acacf4376bdcd1129f1a054ad";
+pub const USER_ID: &str = "ff4e51e3f2d24e2a8d5eaf1c33a8bc7e";
+pub const MEMBER_ROLE_ID: &str = "9fe2ff9ee4384b1894a90878d3e92bab";
+pub const USER_TOKEN: &str = "gAAAAABmUserToken";
+pub const APP_CREDENTIAL_ID: &str = "58d61ff8e6e34accb35874016d1dba8b";
+pub const APP_CREDENTIAL_SECRET: &str =
+    "rEaqvJka48mpv6Lq-sBs2gZdJfUbwLyClszMFx5BiZxrTfNlF3gqKsjgRmTs7lTbA9N6IrA3rB0n1hOAv7rGg";
+
+/// Key, value and the TTL given on SET
+type Store = Arc<Mutex<HashMap<String, (String, Option<u64>)>>>;
+
+/// Speaks just enough RESP for `RedisClient`: GET, SET, SETEX, GETDEL, DEL
+pub struct MockRedis {
+    pub url: String,
+    store: Store,
+}
+
+impl MockRedis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let store = Store::default();
+        let accept_store = store.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_redis(stream, accept_store.clone()));
+            }
+        });
+        Self { url, store }
+    }
+
+    pub async fn client(&self) -> RedisClient {
+        RedisClient::new(&self.url).await.unwrap()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.store.lock().unwrap().get(key).map(|(v, _)| v.clone())
+    }
+
+    pub fn ttl(&self, key: &str) -> Option<u64> {
+        self.store
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|(_, ttl)| *ttl)
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        self.store
+            .lock()
+            .unwrap()
+            .insert(key.into(), (value.into(), None));
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.store.lock().unwrap().remove(key);
+    }
+}
+
+async fn serve_redis(stream: tokio::net::TcpStream, store: Store) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    while let Some(args) = read_command(&mut reader).await {
+        let reply = execute(&store, &args);
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut buf = vec![0; len + 2];
+        reader.read_exact(&mut buf).await.ok()?;
+        buf.truncate(len);
+        args.push(String::from_utf8(buf).ok()?);
+    }
+    Some(args)
+}
+
+fn bulk(value: Option<String>) -> String {
+    match value {
+        Some(v) => format!("${}\r\n{v}\r\n", v.len()),
+        None => "$-1\r\n".into(),
+    }
+}
+
+fn execute(store: &Store, args: &[String]) -> String {
+    let mut store = store.lock().unwrap();
+    match args[0].to_uppercase().as_str() {
+        "GET" => bulk(store.get(&args[1]).map(|(v, _)| v.clone())),
+        "GETDEL" => bulk(store.remove(&args[1]).map(|(v, _)| v)),
+        "SETEX" => {
+            store.insert(args[1].clone(), (args[3].clone(), args[2].parse().ok()));
+            "+OK\r\n".into()
+        }
+        "SET" => {
+            let ttl = args
+                .iter()
+                .position(|a| a.eq_ignore_ascii_case("EX"))
+                .and_then(|i| args.get(i + 1))
+                .and_then(|v| v.parse().ok());
+            store.insert(args[1].clone(), (args[2].clone(), ttl));
+            "+OK\r\n".into()
+        }
+        "DEL" => {
+            let removed = args[1..]
+                .iter()
+                .filter(|k| store.remove(*k).is_some())
+                .count();
+            format!(":{removed}\r\n")
+        }
+        _ => "+OK\r\n".into(),
+    }
+}
+
+pub fn fixture(name: &str) -> Value {
+    let path = format!("{}/tests/fixtures/{name}.json", env!("CARGO_MANIFEST_DIR"));
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// A successful `POST /auth/tokens` handing out `token`
+pub fn token_response(token: &str) -> ResponseTemplate {
+    ResponseTemplate::new(201)
+        .insert_header("X-Subject-Token", token)
+        .set_body_json(fixture("keystone/auth_token"))
+}
+
+/// Keystone with the read-only endpoints `create_user` looks up
+pub async fn mock_keystone() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/domains"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/domains")))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/roles"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/roles")))
+        .mount(&server)
+        .await;
+    server
+}
+
+pub async fn mount_admin_token(server: &MockServer) {
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(token_response(ADMIN_TOKEN))
+        .mount(server)
+        .await;
+}
+
+/// Logging in as alice and minting her application credential
+pub async fn mount_application_credential(server: &MockServer) {
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": { "identity": { "password": { "user": { "name": "alice" } } } }
+        })))
+        .respond_with(token_response(USER_TOKEN))
+        .with_priority(1)
+        .mount(server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/users/{USER_ID}/application_credentials")))
+        .and(header("X-Auth-Token", USER_TOKEN))
+        .respond_with(
+            ResponseTemplate::new(201)
+                .set_body_json(fixture("keystone/application_credential_created")),
+        )
+        .mount(server)
+        .await;
+}
+
+pub fn config(keystone: &MockServer) -> OpenStackConfig {
+    OpenStackConfig {
+        name: "openstack".into(),
+        keystone: keystone.uri(),
+        interface: "public".into(),
+        region: None,
+        nova: Some(format!("{}/compute/v2.1", keystone.uri())),
+        cinder: Some(format!("{}/volume/v3", keystone.uri())),
+        neutron: Some(format!("{}/network/v2.0", keystone.uri())),
+        admin_username: "admin".into(),
+        admin_password: "secret".into(),
+        admin_project: "admin".into(),
+        credential_days: Some(90),
+    }
+}
+
+pub async fn openstack(redis: &MockRedis, keystone: &MockServer) -> OpenStackCloudProvider {
+    OpenStackCloudProvider::with_config(redis.client().await, config(keystone))
+}
+
+/// A database of its own with every migration applied, dropped with the value.
+/// Tests needing Postgres call `TestDatabase::create()` and return early on
+/// `None`, which is what they get unless `PIKA_TEST_DATABASE_URL` points at a
+/// server where the tests may create databases.
+pub struct TestDatabase {
+    pub db: DBClient,
+    server_url: String,
+    name: String,
+}
+
+impl TestDatabase {
+    pub fn create() -> Option<Self> {
+        let Ok(server_url) = std::env::var("PIKA_TEST_DATABASE_URL") else {
+            eprintln!("PIKA_TEST_DATABASE_URL is not set, skipping");
+            return None;
+        };
+        let name = format!("pika_test_{}", uuid::Uuid::new_v4().simple());
+        let mut server = PgConnection::establish(&server_url).unwrap();
+        diesel::sql_query(format!("CREATE DATABASE \"{name}\""))
+            .execute(&mut server)
+            .unwrap();
+
+        let mut url = Url::parse(&server_url).unwrap();
+        url.set_path(&name);
+        let pool = PoolConfig {
+            max_size: 4,
+            min_idle: Some(0),
+            ..Default::default()
+        };
+        let db = DBClient::connect(url.as_str(), &pool).unwrap();
+        run_pending(&mut db.get_conn().unwrap()).unwrap();
+        Some(Self {
+            db,
+            server_url,
+            name,
+        })
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        if let Ok(mut server) = PgConnection::establish(&self.server_url) {
+            let _ = diesel::sql_query(format!("DROP DATABASE \"{}\" WITH (FORCE)", self.name))
+                .execute(&mut server);
+        }
+    }
+}
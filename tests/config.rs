@@ -0,0 +1,225 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use pikacloud_backend::{
+    config::{CloudConfig, Config, ConfigError},
+    quota::MergeStrategy,
+};
+
+const MINIMAL: &str = r#"
+database_url = "postgres://localhost/pika"
+redis_url = "redis://localhost"
+
+[auth]
+providers = ["password"]
+jwt_secret = "secret"
+
+[[clouds]]
+name = "fake"
+"#;
+
+fn load(file: Option<&str>, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+    let env: HashMap<String, String> = env
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Config::from_sources(file, |key| env.get(key).cloned())
+}
+
+fn assert_reported(err: &ConfigError, needle: &str) {
+    assert!(
+        err.0.iter().any(|error| error.contains(needle)),
+        "{needle} not in {:?}",
+        err.0
+    );
+}
+
+#[test]
+fn minimal_file_gets_defaults() {
+    let config = load(Some(MINIMAL), &[]).unwrap();
+    assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 8080)));
+    assert_eq!(config.database_url, "postgres://localhost/pika");
+    assert_eq!(config.quota_merge, MergeStrategy::Max);
+    assert!(!config.console_proxy);
+    assert_eq!(config.auth.providers, vec!["password".to_string()]);
+    assert!(config.auth.iaaa.is_none());
+    assert_eq!(config.jobs.workers, 4);
+    assert_eq!(config.jobs.backoff, Duration::from_secs(5));
+    assert_eq!(config.reconcile.interval, 86400);
+    assert_eq!(config.usage.credit_grace_hours, 24);
+    assert_eq!(config.clouds.len(), 1);
+    assert!(matches!(&config.clouds[0], CloudConfig::Fake { name, .. } if name == "fake"));
+}
+
+#[test]
+fn environment_overrides_the_file() {
+    let file =
+        format!("{MINIMAL}\n[jobs]\nworkers = 2\nmax_attempts = 3\n\n[db_pool]\nmax_size = 4\n");
+    let config = load(
+        Some(&file),
+        &[
+            ("PIKA_BIND", "0.0.0.0:9090"),
+            ("PIKA_JOB_WORKERS", "8"),
+            ("PIKA_QUOTA_MERGE", "sum"),
+            ("PIKA_DB_TIMEOUT", "7"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(config.bind, "0.0.0.0:9090".parse().unwrap());
+    assert_eq!(config.jobs.workers, 8);
+    assert_eq!(config.jobs.max_attempts, 3);
+    assert_eq!(config.quota_merge, MergeStrategy::Sum);
+    assert_eq!(config.db_pool.max_size, 4);
+    assert_eq!(config.db_pool.connection_timeout, Duration::from_secs(7));
+}
+
+#[test]
+fn environment_alone_is_enough() {
+    let config = load(
+        None,
+        &[
+            ("DATABASE_URL", "postgres://localhost/pika"),
+            ("REDIS_URL", "redis://localhost"),
+            ("JWT_SECRET", "secret"),
+            ("AUTH_PROVIDERS", "iaaa, password"),
+            ("IAAA_ID", "app"),
+            ("IAAA_KEY", "key"),
+            ("CLOUD_PROVIDER", "openstack-gpu:openstack,kubernetes"),
+            ("OPENSTACK_GPU_KEYSTONE", "https://keystone.example.org/v3"),
+            ("OPENSTACK_GPU_ADMIN_USERNAME", "admin"),
+            ("OPENSTACK_GPU_ADMIN_PASSWORD", "password"),
+            ("OPENSTACK_GPU_CREDENTIAL_DAYS", "0"),
+            ("KUBERNETES_API", "https://localhost:6443"),
+            ("KUBERNETES_TOKEN", "token"),
+            ("KUBERNETES_QUOTA_PODS", "20"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(config.auth.iaaa.unwrap().id, "app");
+    let [CloudConfig::OpenStack(openstack), CloudConfig::Kubernetes(kubernetes)] =
+        &config.clouds[..]
+    else {
+        panic!("unexpected clouds {:?}", config.clouds);
+    };
+    assert_eq!(openstack.name, "openstack-gpu");
+    assert_eq!(openstack.interface, "public");
+    assert_eq!(openstack.admin_project, "admin");
+    assert_eq!(openstack.credential_days, None);
+    assert_eq!(kubernetes.namespace_prefix, "pika-");
+    assert_eq!(kubernetes.quota.instances, 20);
+}
+
+#[test]
+fn cloud_provider_picks_entries_of_the_file() {
+    let file = format!(
+        "{MINIMAL}
+[[clouds]]
+name = \"agent\"
+provider = \"pikacloud\"
+agent = \"http://localhost:9000/v1\"
+agent_token = \"token\"
+"
+    );
+    let config = load(Some(&file), &[("CLOUD_PROVIDER", "agent:pikacloud")]).unwrap();
+    let [CloudConfig::PikaCloud(agent)] = &config.clouds[..] else {
+        panic!("unexpected clouds {:?}", config.clouds);
+    };
+    assert_eq!(agent.agent, "http://localhost:9000/v1");
+    assert_eq!(agent.agent_token, "token");
+}
+
+#[test]
+fn every_error_is_reported_at_once() {
+    let file = r#"
+bind = "localhost"
+quota_merge = "min"
+typo = 1
+
+[auth]
+providers = ["iaaa", "github"]
+
+[jobs]
+workers = "many"
+
+[[clouds]]
+name = "kubernetes"
+api = "https://localhost:6443"
+token_seconds = 60
+
+[[clouds]]
+name = "fake"
+failure_rate = 2.0
+
+[[clouds]]
+name = "fake"
+
+[[clouds]]
+name = "aws"
+"#;
+    let err = load(Some(file), &[("PIKA_USAGE_INTERVAL", "hourly")]).unwrap_err();
+    for needle in [
+        "bind:",
+        "quota_merge:",
+        "typo: unknown setting",
+        "database_url or DATABASE_URL must be set",
+        "redis_url or REDIS_URL must be set",
+        "auth.jwt_secret or JWT_SECRET must be set",
+        "unknown auth provider github",
+        "auth.iaaa.id or IAAA_ID must be set",
+        "jobs.workers:",
+        "PIKA_USAGE_INTERVAL=hourly",
+        "clouds[0].token or KUBERNETES_TOKEN must be set",
+        "clouds[0].token_seconds: must be at least 600",
+        "clouds[1].failure_rate: must be between 0 and 1",
+        "Cloud instance fake is configured twice",
+        "Unknown cloud provider aws",
+    ] {
+        assert_reported(&err, needle);
+    }
+}
+
+#[test]
+fn clouds_are_required() {
+    let err = load(
+        None,
+        &[
+            ("DATABASE_URL", "postgres://localhost/pika"),
+            ("REDIS_URL", "redis://localhost"),
+            ("JWT_SECRET", "secret"),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(err.0.len(), 1);
+    assert_reported(&err, "at least one cloud instance");
+}
+
+#[test]
+fn example_file_is_valid() {
+    let file = std::fs::read_to_string("pikacloud.example.toml").unwrap();
+    let config = load(Some(&file), &[]).unwrap();
+    assert_eq!(config.clouds.len(), 1);
+    // Settings of disabled providers are kept without complaint
+    assert!(config.auth.lcpu.is_none());
+}
+
+#[test]
+fn reloads_keep_settings_needing_a_restart() {
+    let current = load(Some(MINIMAL), &[]).unwrap();
+    let file = format!(
+        "bind = \"0.0.0.0:9090\"\nquota_merge = \"sum\"\n{MINIMAL}\n[jobs]\nworkers = 1\nmax_attempts = 2\n"
+    )
+    .replace("jwt_secret = \"secret\"", "jwt_secret = \"rotated\"");
+    let new = load(Some(&file), &[("PIKA_ALLOW_PASSWORD_REGISTER", "true")]).unwrap();
+
+    assert_eq!(
+        current.restart_required(&new),
+        vec!["bind", "auth.jwt_secret", "jobs.workers"]
+    );
+    let reloaded = current.reloaded(new);
+    assert_eq!(reloaded.bind, current.bind);
+    assert_eq!(reloaded.auth.jwt_secret, "secret");
+    assert_eq!(reloaded.jobs.workers, 4);
+    assert_eq!(reloaded.jobs.max_attempts, 2);
+    assert_eq!(reloaded.quota_merge, MergeStrategy::Sum);
+    assert!(reloaded.auth.password.allow_register);
+    assert!(current.restart_required(&reloaded).is_empty());
+}
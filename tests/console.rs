@@ -0,0 +1,94 @@
+mod common;
+
+use common::MockRedis;
+use pikacloud_backend::{
+    models::{ConsoleType, RemoteConsole},
+    routes::console::{
+        console_ticket_key, console_websocket_url, issue_console_ticket, redeem_console_ticket,
+        CONSOLE_TICKET_TTL,
+    },
+};
+
+fn websocket(console_type: ConsoleType, url: &str) -> Option<String> {
+    console_websocket_url(&RemoteConsole {
+        console_type,
+        url: url.into(),
+    })
+}
+
+#[test]
+fn novnc_urls_point_to_websockify() {
+    assert_eq!(
+        websocket(
+            ConsoleType::Novnc,
+            "https://nova.example.org:6080/vnc_auto.html?token=abc"
+        )
+        .as_deref(),
+        Some("wss://nova.example.org:6080/websockify?token=abc")
+    );
+    // Newer Nova puts the token into noVNC's websocket path, which is
+    // relative to the host
+    assert_eq!(
+        websocket(
+            ConsoleType::Novnc,
+            "http://nova.example.org:6080/vnc_lite.html?path=%3Ftoken%3Dabc"
+        )
+        .as_deref(),
+        Some("ws://nova.example.org:6080/?token=abc")
+    );
+}
+
+#[test]
+fn novnc_paths_under_a_prefix_are_kept() {
+    assert_eq!(
+        websocket(
+            ConsoleType::Novnc,
+            "https://cloud.example.org/novnc/vnc_auto.html?token=abc"
+        )
+        .as_deref(),
+        Some("wss://cloud.example.org/novnc/websockify?token=abc")
+    );
+    assert_eq!(
+        websocket(
+            ConsoleType::Novnc,
+            "https://cloud.example.org/novnc/vnc_lite.html?path=novnc%2Fws%3Ftoken%3Dabc"
+        )
+        .as_deref(),
+        Some("wss://cloud.example.org/novnc/ws?token=abc")
+    );
+}
+
+#[test]
+fn serial_urls_are_used_as_they_are() {
+    let url = "ws://nova.example.org:6083/?token=abc";
+    assert_eq!(websocket(ConsoleType::Serial, url).as_deref(), Some(url));
+}
+
+#[test]
+fn novnc_urls_without_a_token_are_rejected() {
+    assert!(websocket(ConsoleType::Novnc, "https://nova.example.org/vnc_auto.html").is_none());
+    assert!(websocket(ConsoleType::Novnc, "not a url").is_none());
+}
+
+#[tokio::test]
+async fn tickets_can_be_redeemed_once() {
+    let redis = MockRedis::start().await;
+    let cache = redis.client().await;
+    let upstream = "wss://nova.example.org/websockify?token=abc";
+
+    let ticket = issue_console_ticket(&cache, upstream).await.unwrap();
+    let other = issue_console_ticket(&cache, upstream).await.unwrap();
+    assert_ne!(ticket, other);
+    let key = console_ticket_key(&ticket);
+    assert_eq!(redis.get(&key).as_deref(), Some(upstream));
+    assert_eq!(redis.ttl(&key), Some(CONSOLE_TICKET_TTL));
+
+    assert_eq!(
+        redeem_console_ticket(&cache, &ticket).await.as_deref(),
+        Some(upstream)
+    );
+    assert!(redeem_console_ticket(&cache, &ticket).await.is_none());
+    assert!(redeem_console_ticket(&cache, "unknown").await.is_none());
+    // Other tickets stay valid
+    assert!(redeem_console_ticket(&cache, &other).await.is_some());
+}
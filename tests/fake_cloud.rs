@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use pikacloud_backend::{
+    clouds::{fake::FakeCloudProvider, BaseCloudProvider, CloudError},
+    models::CreateInstance,
+    quota::Resources,
+};
+
+fn create_instance(name: &str, flavor_id: &str) -> CreateInstance {
+    CreateInstance {
+        name: name.into(),
+        flavor_id: flavor_id.into(),
+        image_id: "image".into(),
+        network_id: None,
+        key_name: None,
+    }
+}
+
+#[tokio::test]
+async fn user_lifecycle() {
+    let provider = FakeCloudProvider::default();
+    let info = provider.create_user("alice".into()).await.unwrap();
+    assert!(provider.is_user_exist("alice".into()).await.unwrap());
+
+    let token = provider
+        .get_user_token("alice".into(), info.provider_pass.clone())
+        .await
+        .unwrap();
+    let again = provider
+        .get_user_token("alice".into(), info.provider_pass)
+        .await
+        .unwrap();
+    assert_eq!(token, again);
+    assert!(matches!(
+        provider
+            .get_user_token("alice".into(), "wrong".into())
+            .await,
+        Err(CloudError::NotFound(_))
+    ));
+
+    provider.delete_user("alice".into()).await.unwrap();
+    assert!(!provider.is_user_exist("alice".into()).await.unwrap());
+}
+
+#[tokio::test]
+async fn instances_count_towards_usage_and_quota() {
+    let provider = FakeCloudProvider::default();
+    let info = provider.create_user("bob".into()).await.unwrap();
+    let (id, pass) = (info.provider_id, info.provider_pass);
+    provider
+        .set_quota(
+            id.clone(),
+            Resources {
+                vcpus: 2,
+                ram_mb: 4096,
+                instances: 2,
+                volumes: 1,
+                floating_ips: 0,
+            },
+        )
+        .await
+        .unwrap();
+
+    let instance = provider
+        .create_instance(id.clone(), pass.clone(), create_instance("vm", "medium"))
+        .await
+        .unwrap();
+    let usage = provider.get_usage(id.clone(), pass.clone()).await.unwrap();
+    assert_eq!((usage.vcpus, usage.ram_mb, usage.instances), (2, 4096, 1));
+
+    assert!(provider
+        .create_instance(id.clone(), pass.clone(), create_instance("vm2", "small"))
+        .await
+        .is_err());
+
+    provider
+        .stop_instance(id.clone(), pass.clone(), instance.id.clone())
+        .await
+        .unwrap();
+    let instances = provider
+        .list_instances(id.clone(), pass.clone())
+        .await
+        .unwrap();
+    assert_eq!(instances[0].status, "SHUTOFF");
+
+    provider
+        .delete_instance(id.clone(), pass.clone(), instance.id)
+        .await
+        .unwrap();
+    assert!(provider.list_instances(id, pass).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn console_requires_ownership() {
+    let provider = FakeCloudProvider::default();
+    let alice = provider.create_user("alice".into()).await.unwrap();
+    let bob = provider.create_user("bob".into()).await.unwrap();
+    let instance = provider
+        .create_instance(
+            alice.provider_id.clone(),
+            alice.provider_pass.clone(),
+            create_instance("vm", "small"),
+        )
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        provider
+            .get_console(
+                bob.provider_id,
+                bob.provider_pass,
+                instance.id.clone(),
+                pikacloud_backend::models::ConsoleType::Serial,
+            )
+            .await,
+        Err(CloudError::NotFound(_))
+    ));
+    assert!(provider
+        .get_console(
+            alice.provider_id,
+            alice.provider_pass,
+            instance.id,
+            pikacloud_backend::models::ConsoleType::Serial,
+        )
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn injected_failures_and_latency() {
+    let provider =
+        FakeCloudProvider::with_config(pikacloud_backend::clouds::fake::FakeConfig {
+            latency: Duration::from_millis(20),
+            failure_rate: 1.0,
+        });
+    let started = std::time::Instant::now();
+    assert!(matches!(
+        provider.create_user("carol".into()).await,
+        Err(CloudError::SendRequest(_))
+    ));
+    assert!(started.elapsed() >= Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn calls_on_a_shared_provider_overlap() {
+    let provider =
+        FakeCloudProvider::with_config(pikacloud_backend::clouds::fake::FakeConfig {
+            latency: Duration::from_millis(200),
+            failure_rate: 0.0,
+        });
+    let started = std::time::Instant::now();
+    let (dave, erin) = tokio::join!(
+        provider.create_user("dave".into()),
+        provider.create_user("erin".into())
+    );
+    dave.unwrap();
+    erin.unwrap();
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert_eq!(provider.list_accounts().await.unwrap().len(), 2);
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestDatabase;
+use diesel::prelude::*;
+use pikacloud_backend::{
+    clouds::CloudError,
+    db::DBError,
+    jobs::{backoff, reset_abandoned, JobError},
+    models::{CloudProvider, JobKind, JobStatus, LoginProvider, NewJob, NewUser},
+    repository::{PgRepository, UserRepository},
+    schema,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// The error of a cloud request answered with `status`, or of one that got
+/// no answer in time when `delay` is longer than the client waits
+async fn provider_error(status: u16, delay: Duration) -> JobError {
+    let server = MockServer::start().await;
+    Mock::given(wiremock::matchers::any())
+        .respond_with(ResponseTemplate::new(status).set_delay(delay))
+        .mount(&server)
+        .await;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    let err = match client.post(server.uri()).send().await {
+        Ok(response) => response.error_for_status().unwrap_err(),
+        Err(err) => err,
+    };
+    JobError::Cloud(CloudError::Provider(err))
+}
+
+#[test]
+fn backoff_doubles_up_to_a_cap() {
+    let base = Duration::from_secs(5);
+    assert_eq!(backoff(base, 1), Duration::from_secs(5));
+    assert_eq!(backoff(base, 2), Duration::from_secs(10));
+    assert_eq!(backoff(base, 4), Duration::from_secs(40));
+    assert_eq!(backoff(base, 50), Duration::from_secs(600));
+    assert_eq!(backoff(base, 0), base);
+}
+
+#[tokio::test]
+async fn only_transient_errors_are_retried() {
+    assert!(JobError::Cloud(CloudError::SendRequest("timeout".into())).is_retryable());
+    assert!(JobError::Database(diesel::result::Error::BrokenTransactionManager).is_retryable());
+    assert!(!JobError::Cloud(CloudError::NotFound("instance".into())).is_retryable());
+    assert!(!JobError::Cloud(CloudError::Unsupported("instances".into())).is_retryable());
+    assert!(!JobError::Rejected("Quota exceeded: vcpus".into()).is_retryable());
+
+    // A bad flavor, an exceeded cloud quota or a conflict stays that way
+    assert!(provider_error(503, Duration::ZERO).await.is_retryable());
+    for status in [400, 403, 404, 409] {
+        let err = provider_error(status, Duration::ZERO).await;
+        assert!(!err.is_retryable(), "{status}");
+    }
+
+    // Nova may have accepted a create that timed out, retrying it would
+    // create a second server
+    let unanswered = [
+        JobError::Cloud(CloudError::SendRequest("connection reset".into())),
+        provider_error(200, Duration::from_secs(5)).await,
+    ];
+    for err in unanswered {
+        assert!(err.is_ambiguous(), "{err}");
+        assert!(!err.is_retryable_for(JobKind::CREATE_INSTANCE), "{err}");
+        assert!(err.is_retryable_for(JobKind::DELETE_INSTANCE), "{err}");
+        assert!(err.is_retryable_for(JobKind::PROVISION_ACCOUNT), "{err}");
+    }
+    let refused = provider_error(500, Duration::ZERO).await;
+    assert!(!refused.is_ambiguous());
+    assert!(refused.is_retryable_for(JobKind::CREATE_INSTANCE));
+}
+
+#[test]
+fn query_errors_from_the_pool_stay_database_errors() {
+    let err = JobError::from(DBError::Query(diesel::result::Error::NotFound));
+    assert!(matches!(err, JobError::Database(_)));
+    let err = JobError::from(DBError::FetchConn("timed out".into()));
+    assert!(matches!(err, JobError::Connection(_)));
+    assert!(err.is_retryable());
+}
+
+#[tokio::test]
+async fn only_jobs_without_recent_heartbeats_are_taken_over() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let user_id = PgRepository::new(test.db.clone())
+        .create_user(NewUser {
+            username: "alice".into(),
+            loginProvider: LoginProvider::PASSWORD,
+            name: None,
+            password: None,
+        })
+        .await
+        .unwrap()
+        .id;
+    let conn = &mut test.db.get_conn().unwrap();
+    let mut running = |kind: JobKind, attempts: i32, heartbeat: Option<chrono::Duration>| {
+        let id: String = diesel::insert_into(schema::Job::table)
+            .values(NewJob {
+                userId: user_id.clone(),
+                kind,
+                cloudProvider: CloudProvider::OPENSTACK,
+                payload: "{}".into(),
+                maxAttempts: 3,
+            })
+            .returning(schema::Job::id)
+            .get_result(conn)
+            .unwrap();
+        let heartbeat_at = heartbeat.map(|age| chrono::Utc::now().naive_utc() - age);
+        diesel::update(schema::Job::dsl::Job.find(&id))
+            .set((
+                schema::Job::status.eq(JobStatus::RUNNING),
+                schema::Job::attempts.eq(attempts),
+                schema::Job::workerId.eq("other-replica:1"),
+                schema::Job::heartbeatAt.eq(heartbeat_at),
+            ))
+            .execute(conn)
+            .unwrap();
+        id
+    };
+    let stale = Some(chrono::Duration::minutes(10));
+    let alive = running(
+        JobKind::DELETE_INSTANCE,
+        1,
+        Some(chrono::Duration::seconds(10)),
+    );
+    let stopped = running(JobKind::DELETE_INSTANCE, 1, stale);
+    let never_beat = running(JobKind::DELETE_INSTANCE, 1, None);
+    let creation = running(JobKind::CREATE_INSTANCE, 1, stale);
+    let last_attempt = running(JobKind::DELETE_INSTANCE, 3, stale);
+
+    let mut reset: Vec<String> = reset_abandoned(conn)
+        .unwrap()
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    reset.sort();
+    let mut expected = vec![stopped, never_beat];
+    expected.sort();
+    assert_eq!(reset, expected);
+    let mut status = |id: &str| -> JobStatus {
+        schema::Job::dsl::Job
+            .find(id)
+            .select(schema::Job::status)
+            .first(conn)
+            .unwrap()
+    };
+    assert_eq!(status(&alive), JobStatus::RUNNING);
+    // Nova may have created the instance, a second attempt could create another
+    assert_eq!(status(&creation), JobStatus::FAILED);
+    assert_eq!(status(&last_attempt), JobStatus::FAILED);
+}
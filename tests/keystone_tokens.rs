@@ -0,0 +1,146 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{fixture, mock_keystone, openstack, token_response, MockRedis, ADMIN_TOKEN};
+use pikacloud_backend::clouds::{
+    keystone::{
+        CachedToken, Credentials, KeystoneAuth, PasswordCredentials, ProjectScope, EXPIRY_MARGIN,
+    },
+    BaseCloudProvider,
+};
+use wiremock::{
+    matchers::{header, method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const ADMIN_KEY: &str = "openstack:admin-token";
+
+fn admin() -> Credentials {
+    Credentials::Password(PasswordCredentials {
+        username: "admin".into(),
+        password: "secret".into(),
+        project: Some(ProjectScope::Name("admin".into())),
+    })
+}
+
+fn seed(redis: &MockRedis, key: &str, token: &str, expires_in: i64) {
+    let cached = CachedToken {
+        token: token.into(),
+        expires_at: chrono::Utc::now().timestamp() + expires_in,
+        project_id: None,
+        catalog: Vec::new(),
+    };
+    redis.set(key, &serde_json::to_string(&cached).unwrap());
+}
+
+fn cached(redis: &MockRedis, key: &str) -> Option<CachedToken> {
+    serde_json::from_str(&redis.get(key)?).ok()
+}
+
+async fn keystone_issuing(token: &str, delay: Duration) -> MockServer {
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(token_response(token).set_delay(delay))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    keystone
+}
+
+#[tokio::test]
+async fn token_ttl_is_relative_to_now() {
+    let redis = MockRedis::start().await;
+    let keystone = keystone_issuing(ADMIN_TOKEN, Duration::ZERO).await;
+
+    let provider = openstack(&redis, &keystone).await;
+    provider.get_admin_token().await.unwrap();
+
+    let cached = cached(&redis, ADMIN_KEY).unwrap();
+    let lifetime = cached.expires_at - chrono::Utc::now().timestamp();
+    let ttl = redis.ttl(ADMIN_KEY).unwrap() as i64;
+    assert!(
+        ttl <= lifetime - EXPIRY_MARGIN + 1,
+        "ttl {ttl} outlives the token"
+    );
+    assert!(
+        ttl >= lifetime - EXPIRY_MARGIN - 5,
+        "ttl {ttl} is too short"
+    );
+}
+
+#[tokio::test]
+async fn tokens_close_to_expiry_are_not_used() {
+    let redis = MockRedis::start().await;
+    let keystone = keystone_issuing(ADMIN_TOKEN, Duration::ZERO).await;
+    seed(&redis, ADMIN_KEY, "expiring", EXPIRY_MARGIN - 10);
+
+    let provider = openstack(&redis, &keystone).await;
+    assert_eq!(provider.get_admin_token().await.unwrap(), ADMIN_TOKEN);
+}
+
+#[tokio::test]
+async fn concurrent_misses_share_one_request() {
+    let redis = MockRedis::start().await;
+    let keystone = keystone_issuing(ADMIN_TOKEN, Duration::from_millis(200)).await;
+
+    let auth = KeystoneAuth::new(redis.client().await, reqwest::Client::new(), keystone.uri());
+    let credentials = admin();
+    let (first, second, third) = (auth.clone(), auth.clone(), auth);
+    let tokens = tokio::join!(
+        first.token(ADMIN_KEY, &credentials),
+        second.token(ADMIN_KEY, &credentials),
+        third.token(ADMIN_KEY, &credentials),
+    );
+    assert_eq!(tokens.0.unwrap(), ADMIN_TOKEN);
+    assert_eq!(tokens.1.unwrap(), ADMIN_TOKEN);
+    assert_eq!(tokens.2.unwrap(), ADMIN_TOKEN);
+    // The per-key lock is gone once nobody waits for it
+    assert_eq!(first.inflight_count(), 0);
+}
+
+#[tokio::test]
+async fn tokens_are_refreshed_ahead_of_expiry() {
+    let redis = MockRedis::start().await;
+    let keystone = keystone_issuing(ADMIN_TOKEN, Duration::ZERO).await;
+    seed(&redis, ADMIN_KEY, "expiring", EXPIRY_MARGIN + 300);
+
+    let provider = openstack(&redis, &keystone).await;
+    // The old token is still good enough to hand out while the new one is fetched
+    assert_eq!(provider.get_admin_token().await.unwrap(), "expiring");
+
+    for _ in 0..50 {
+        if cached(&redis, ADMIN_KEY).is_some_and(|c| c.token == ADMIN_TOKEN) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the token was not refreshed");
+}
+
+#[tokio::test]
+async fn revoked_tokens_are_replaced_and_the_request_retried() {
+    let redis = MockRedis::start().await;
+    let keystone = keystone_issuing(ADMIN_TOKEN, Duration::ZERO).await;
+    seed(&redis, ADMIN_KEY, "revoked", 3600);
+    Mock::given(method("GET"))
+        .and(path("/projects"))
+        .and(header("X-Auth-Token", "revoked"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(fixture("keystone/unauthorized")))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/projects"))
+        .and(query_param("tags", "pikacloud"))
+        .and(header("X-Auth-Token", ADMIN_TOKEN))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/projects_list")))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert_eq!(provider.list_accounts().await.unwrap(), vec!["alice"]);
+    assert_eq!(cached(&redis, ADMIN_KEY).unwrap().token, ADMIN_TOKEN);
+}
@@ -0,0 +1,264 @@
+mod common;
+
+use common::{fixture, MockRedis};
+use pikacloud_backend::{
+    clouds::{
+        kubernetes::{KubernetesCloudProvider, KubernetesConfig},
+        BaseCloudProvider, CloudError,
+    },
+    quota::Resources,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_partial_json, header, method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const ADMIN_TOKEN: &str = "admin-service-account-token";
+const NAMESPACE: &str = "pika-alice";
+
+fn config(api: &MockServer) -> KubernetesConfig {
+    KubernetesConfig {
+        name: "kubernetes".into(),
+        api: api.uri(),
+        public_api: None,
+        token: ADMIN_TOKEN.into(),
+        ca_file: None,
+        namespace_prefix: "pika-".into(),
+        cluster_role: "edit".into(),
+        token_seconds: 3600,
+        quota: Resources {
+            vcpus: 2,
+            ram_mb: 4096,
+            instances: 10,
+            volumes: 5,
+            floating_ips: 0,
+        },
+    }
+}
+
+async fn kubernetes(redis: &MockRedis, api: &MockServer) -> KubernetesCloudProvider {
+    KubernetesCloudProvider::with_config(redis.client().await, config(api))
+}
+
+fn created() -> ResponseTemplate {
+    ResponseTemplate::new(201).set_body_json(json!({}))
+}
+
+#[tokio::test]
+async fn namespace_names_are_valid_labels() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    let provider = kubernetes(&redis, &api).await;
+    assert_eq!(provider.namespace_for("alice"), "pika-alice");
+    assert_eq!(provider.namespace_for("Bob.Smith_2"), "pika-bob-smith-2");
+    assert_eq!(provider.namespace_for(&"x".repeat(100)).len(), 63);
+}
+
+#[tokio::test]
+async fn create_user_provisions_the_namespace() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/v1/namespaces"))
+        .and(header("Authorization", format!("Bearer {ADMIN_TOKEN}")))
+        .and(body_partial_json(json!({
+            "metadata": {
+                "name": NAMESPACE,
+                "labels": { "app.kubernetes.io/managed-by": "pikacloud" }
+            }
+        })))
+        .respond_with(
+            ResponseTemplate::new(201).set_body_json(fixture("kubernetes/namespace_created")),
+        )
+        .expect(1)
+        .mount(&api)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!(
+            "/api/v1/namespaces/{NAMESPACE}/serviceaccounts"
+        )))
+        .and(body_partial_json(
+            json!({ "metadata": { "name": "pikacloud-user" } }),
+        ))
+        .respond_with(created())
+        .expect(1)
+        .mount(&api)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!(
+            "/apis/rbac.authorization.k8s.io/v1/namespaces/{NAMESPACE}/rolebindings"
+        )))
+        .and(body_partial_json(json!({
+            "roleRef": { "kind": "ClusterRole", "name": "edit" },
+            "subjects": [{ "kind": "ServiceAccount", "name": "pikacloud-user", "namespace": NAMESPACE }]
+        })))
+        .respond_with(created())
+        .expect(1)
+        .mount(&api)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!(
+            "/api/v1/namespaces/{NAMESPACE}/resourcequotas"
+        )))
+        .and(body_partial_json(json!({
+            "spec": { "hard": { "limits.cpu": "2", "limits.memory": "4096Mi", "pods": "10" } }
+        })))
+        .respond_with(created())
+        .expect(1)
+        .mount(&api)
+        .await;
+
+    let provider = kubernetes(&redis, &api).await;
+    let info = provider.create_user("alice".into()).await.unwrap();
+    assert_eq!(info.provider_id, NAMESPACE);
+    assert_eq!(info.provider_pass, "");
+}
+
+#[tokio::test]
+async fn failed_create_deletes_the_namespace() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(format!(
+            "/api/v1/namespaces/{NAMESPACE}/resourcequotas"
+        )))
+        .respond_with(ResponseTemplate::new(403).set_body_json(fixture("kubernetes/forbidden")))
+        .mount(&api)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(created())
+        .mount(&api)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("/api/v1/namespaces/{NAMESPACE}")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&api)
+        .await;
+
+    let provider = kubernetes(&redis, &api).await;
+    match provider.create_user("alice".into()).await {
+        Err(CloudError::Provider(err)) => {
+            assert_eq!(err.status(), Some(reqwest::StatusCode::FORBIDDEN))
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn user_tokens_come_from_the_token_request_api() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(format!(
+            "/api/v1/namespaces/{NAMESPACE}/serviceaccounts/pikacloud-user/token"
+        )))
+        .and(header("Authorization", format!("Bearer {ADMIN_TOKEN}")))
+        .and(body_partial_json(json!({
+            "kind": "TokenRequest",
+            "spec": { "expirationSeconds": 3600 }
+        })))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("kubernetes/token_request")))
+        .expect(1)
+        .mount(&api)
+        .await;
+
+    let provider = kubernetes(&redis, &api).await;
+    for _ in 0..2 {
+        let token = provider
+            .get_user_token(NAMESPACE.into(), String::new())
+            .await
+            .unwrap();
+        assert_eq!(token, "eyJhbGciOiJSUzI1NiJ9.alice");
+    }
+    assert!(redis.get("kubernetes:user-token-pika-alice").is_some());
+}
+
+#[tokio::test]
+async fn delete_user_deletes_the_namespace_and_token() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("/api/v1/namespaces/{NAMESPACE}")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .up_to_n_times(1)
+        .mount(&api)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("/api/v1/namespaces/{NAMESPACE}")))
+        .respond_with(ResponseTemplate::new(404).set_body_json(fixture("kubernetes/not_found")))
+        .mount(&api)
+        .await;
+    redis.set("kubernetes:user-token-pika-alice", "token");
+
+    let provider = kubernetes(&redis, &api).await;
+    provider.delete_user(NAMESPACE.into()).await.unwrap();
+    assert!(redis.get("kubernetes:user-token-pika-alice").is_none());
+    assert!(matches!(
+        provider.delete_user(NAMESPACE.into()).await,
+        Err(CloudError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn accounts_are_listed_by_label() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/namespaces"))
+        .and(query_param(
+            "labelSelector",
+            "app.kubernetes.io/managed-by=pikacloud,pikacloud.io/instance=kubernetes",
+        ))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(fixture("kubernetes/namespaces_list")),
+        )
+        .mount(&api)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/api/v1/namespaces/{NAMESPACE}")))
+        .respond_with(ResponseTemplate::new(404).set_body_json(fixture("kubernetes/not_found")))
+        .mount(&api)
+        .await;
+
+    let provider = kubernetes(&redis, &api).await;
+    assert_eq!(
+        provider.list_accounts().await.unwrap(),
+        vec!["pika-alice", "pika-bob"]
+    );
+    assert!(!provider.is_user_exist(NAMESPACE.into()).await.unwrap());
+}
+
+#[tokio::test]
+async fn set_quota_patches_the_resource_quota() {
+    let redis = MockRedis::start().await;
+    let api = MockServer::start().await;
+    Mock::given(method("PATCH"))
+        .and(path(format!(
+            "/api/v1/namespaces/{NAMESPACE}/resourcequotas/pikacloud-user"
+        )))
+        .and(header("Content-Type", "application/merge-patch+json"))
+        .and(body_partial_json(json!({
+            "spec": { "hard": { "requests.cpu": "8", "requests.memory": "16384Mi", "persistentvolumeclaims": "3" } }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&api)
+        .await;
+
+    let provider = kubernetes(&redis, &api).await;
+    provider
+        .set_quota(
+            NAMESPACE.into(),
+            Resources {
+                vcpus: 8,
+                ram_mb: 16384,
+                instances: 20,
+                volumes: 3,
+                floating_ips: 0,
+            },
+        )
+        .await
+        .unwrap();
+}
@@ -0,0 +1,31 @@
+use std::fs;
+
+use pikacloud_backend::migrations::embedded;
+
+#[test]
+fn every_migration_directory_is_embedded() {
+    let mut directories: Vec<String> = fs::read_dir("migrations")
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().unwrap().is_dir())
+        .map(|entry| entry.file_name().into_string().unwrap())
+        .collect();
+    directories.sort();
+    assert_eq!(embedded(), directories);
+}
+
+#[test]
+fn every_updated_at_column_has_a_trigger() {
+    let schema = fs::read_to_string("src/schema.rs").unwrap();
+    let triggers =
+        fs::read_to_string("migrations/2024-09-12-093015_maintain_updated_at/up.sql").unwrap();
+    let mut table = "";
+    for line in schema.lines().map(str::trim) {
+        if let Some(name) = line.strip_suffix(" (id) {") {
+            table = name;
+        } else if line.starts_with("updatedAt ->") {
+            let trigger = format!("BEFORE UPDATE ON \"{table}\"");
+            assert!(triggers.contains(&trigger), "no trigger on {table}");
+        }
+    }
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use common::{config, fixture, mock_keystone, token_response, MockRedis};
+use pikacloud_backend::clouds::{
+    keystone::{CachedToken, Service},
+    openstack::OpenStackCloudProvider,
+    BaseCloudProvider, CloudError,
+};
+use serde_json::{json, Value};
+use wiremock::{
+    matchers::{body_partial_json, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const SCOPED_TOKEN: &str = "gAAAAABmScopedAdminToken";
+
+/// The scoped token fixture with its endpoints pointing at `server`
+fn scoped_token(server: &MockServer) -> Value {
+    let body = fixture("keystone/auth_token_scoped").to_string();
+    serde_json::from_str(&body.replace("http://keystone.test", &server.uri())).unwrap()
+}
+
+fn catalog_token() -> CachedToken {
+    let token = fixture("keystone/auth_token_scoped")["token"].clone();
+    CachedToken {
+        token: SCOPED_TOKEN.into(),
+        expires_at: i64::MAX,
+        project_id: Some(token["project"]["id"].as_str().unwrap().into()),
+        catalog: serde_json::from_value(token["catalog"].clone()).unwrap(),
+    }
+}
+
+#[test]
+fn endpoints_are_picked_by_interface_and_region() {
+    let token = catalog_token();
+    assert_eq!(
+        token.endpoint(Service::Compute, "public", None).as_deref(),
+        Some("http://keystone.test/compute/v2.1")
+    );
+    assert_eq!(
+        token
+            .endpoint(Service::Compute, "internal", None)
+            .as_deref(),
+        Some("http://keystone.test/internal/compute/v2.1")
+    );
+    assert_eq!(
+        token
+            .endpoint(Service::Compute, "public", Some("RegionTwo"))
+            .as_deref(),
+        Some("http://keystone.test/two/compute/v2.1")
+    );
+    assert_eq!(
+        token.endpoint(Service::Network, "internal", None),
+        None,
+        "there is no internal Neutron endpoint"
+    );
+    assert_eq!(
+        token.endpoint(Service::Network, "public", Some("RegionThree")),
+        None
+    );
+}
+
+#[test]
+fn project_ids_and_trailing_slashes_are_stripped() {
+    let token = catalog_token();
+    assert_eq!(
+        token
+            .endpoint(Service::BlockStorage, "public", None)
+            .as_deref(),
+        Some("http://keystone.test/volume/v3")
+    );
+    assert_eq!(
+        token.endpoint(Service::Network, "public", None).as_deref(),
+        Some("http://keystone.test/network")
+    );
+}
+
+#[tokio::test]
+async fn services_are_discovered_from_the_admin_catalog() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": {
+                "identity": { "password": { "user": { "name": "admin" } } },
+                "scope": { "project": { "name": "admin", "domain": { "name": "Default" } } }
+            }
+        })))
+        .respond_with(
+            ResponseTemplate::new(201)
+                .insert_header("X-Subject-Token", SCOPED_TOKEN)
+                .set_body_json(scoped_token(&keystone)),
+        )
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": { "identity": { "password": { "user": { "name": "alice" } } } }
+        })))
+        .respond_with(token_response("token-alice"))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/compute/v2.1/servers/detail"))
+        .and(header("X-Auth-Token", "token-alice"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "servers": [] })))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let mut config = config(&keystone);
+    (config.nova, config.cinder, config.neutron) = (None, None, None);
+    let provider = OpenStackCloudProvider::with_config(redis.client().await, config);
+    let instances = provider
+        .list_instances("alice".into(), "pass".into())
+        .await
+        .unwrap();
+    assert!(instances.is_empty());
+}
+
+#[tokio::test]
+async fn missing_services_are_not_found() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(
+            ResponseTemplate::new(201)
+                .insert_header("X-Subject-Token", SCOPED_TOKEN)
+                .set_body_json(scoped_token(&keystone)),
+        )
+        .mount(&keystone)
+        .await;
+
+    let mut config = config(&keystone);
+    config.nova = None;
+    config.region = Some("RegionThree".into());
+    let provider = OpenStackCloudProvider::with_config(redis.client().await, config);
+    let result = provider.list_instances("alice".into(), "pass".into()).await;
+    assert!(matches!(result, Err(CloudError::NotFound(_))), "{result:?}");
+}
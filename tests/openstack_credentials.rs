@@ -0,0 +1,161 @@
+mod common;
+
+use common::{
+    fixture, mock_keystone, mount_admin_token, mount_application_credential, openstack,
+    token_response, MockRedis, APP_CREDENTIAL_ID, APP_CREDENTIAL_SECRET, MEMBER_ROLE_ID, USER_ID,
+    USER_TOKEN,
+};
+use pikacloud_backend::clouds::{keystone::ApplicationCredential, BaseCloudProvider};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_partial_json, header, method, path, query_param},
+    Mock, ResponseTemplate,
+};
+
+fn stored_credential() -> String {
+    ApplicationCredential {
+        id: APP_CREDENTIAL_ID.into(),
+        secret: APP_CREDENTIAL_SECRET.into(),
+    }
+    .to_stored()
+}
+
+#[test]
+fn stored_credentials_round_trip() {
+    let parsed = ApplicationCredential::from_stored(&stored_credential()).unwrap();
+    assert_eq!(parsed.id, APP_CREDENTIAL_ID);
+    assert_eq!(parsed.secret, APP_CREDENTIAL_SECRET);
+    assert!(ApplicationCredential::from_stored("a-plain-password").is_none());
+}
+
+#[tokio::test]
+async fn create_user_stores_an_application_credential() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("POST"))
+        .and(path("/projects"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/project_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/users"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/user_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&keystone)
+        .await;
+    // alice logs in to her own project to mint the credential
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": {
+                "identity": { "password": { "user": { "name": "alice" } } },
+                "scope": { "project": { "name": "alice" } }
+            }
+        })))
+        .respond_with(token_response(USER_TOKEN))
+        .with_priority(1)
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/users/{USER_ID}/application_credentials")))
+        .and(header("X-Auth-Token", USER_TOKEN))
+        .and(body_partial_json(json!({
+            "application_credential": {
+                "roles": [{ "id": MEMBER_ROLE_ID }],
+                "unrestricted": false
+            }
+        })))
+        .respond_with(
+            ResponseTemplate::new(201)
+                .set_body_json(fixture("keystone/application_credential_created")),
+        )
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    let info = provider.create_user("alice".into()).await.unwrap();
+    assert_eq!(info.provider_pass, stored_credential());
+
+    let requests = keystone.received_requests().await.unwrap();
+    let minted = requests
+        .iter()
+        .find(|r| r.url.path().ends_with("/application_credentials"))
+        .unwrap();
+    let body: serde_json::Value = minted.body_json().unwrap();
+    assert!(body["application_credential"]["expires_at"].is_string());
+}
+
+#[tokio::test]
+async fn user_tokens_come_from_the_application_credential() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": { "identity": {
+                "methods": ["application_credential"],
+                "application_credential": { "id": APP_CREDENTIAL_ID, "secret": APP_CREDENTIAL_SECRET }
+            } }
+        })))
+        .respond_with(token_response(USER_TOKEN))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    for _ in 0..2 {
+        let token = provider
+            .get_user_token("alice".into(), stored_credential())
+            .await
+            .unwrap();
+        assert_eq!(token, USER_TOKEN);
+    }
+}
+
+#[tokio::test]
+async fn rotation_replaces_the_credential() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    mount_application_credential(&keystone).await;
+    Mock::given(method("GET"))
+        .and(path("/users"))
+        .and(query_param("name", "alice"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/users_list")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("/users/{USER_ID}")))
+        .and(header("X-Auth-Token", common::ADMIN_TOKEN))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/user_created")))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path(format!(
+            "/users/{USER_ID}/application_credentials/old-credential"
+        )))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    let old = ApplicationCredential {
+        id: "old-credential".into(),
+        secret: "old-secret".into(),
+    };
+    redis.set("openstack:user-token-alice:alice", "{}");
+
+    let provider = openstack(&redis, &keystone).await;
+    let rotated = provider
+        .rotate_credentials("alice".into(), old.to_stored())
+        .await
+        .unwrap();
+    assert_eq!(rotated, stored_credential());
+    assert!(redis.get("openstack:user-token-alice:alice").is_none());
+}
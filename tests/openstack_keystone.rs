@@ -0,0 +1,506 @@
+mod common;
+
+use common::{
+    config, fixture, mock_keystone, mount_admin_token, mount_application_credential, openstack,
+    token_response, MockRedis, ADMIN_TOKEN, MEMBER_ROLE_ID, PROJECT_ID, USER_ID,
+};
+use pikacloud_backend::clouds::{
+    keystone::CachedToken, openstack::OpenStackCloudProvider, BaseCloudProvider, CloudError,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_partial_json, header, method, path, query_param},
+    Mock, ResponseTemplate,
+};
+
+#[tokio::test]
+async fn admin_token_is_cached() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": { "identity": { "password": { "user": { "name": "admin" } } } }
+        })))
+        .respond_with(token_response(ADMIN_TOKEN))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert_eq!(provider.get_admin_token().await.unwrap(), ADMIN_TOKEN);
+    assert_eq!(provider.get_admin_token().await.unwrap(), ADMIN_TOKEN);
+    let cached: CachedToken =
+        serde_json::from_str(&redis.get("openstack:admin-token").unwrap()).unwrap();
+    assert_eq!(cached.token, ADMIN_TOKEN);
+    // Expiry of the `token` object in the response, 2099-01-01T12:00:00Z
+    assert_eq!(cached.expires_at, 4070952000);
+}
+
+#[tokio::test]
+async fn user_tokens_are_cached_per_user() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    for user in ["alice", "bob"] {
+        Mock::given(method("POST"))
+            .and(path("/auth/tokens"))
+            .and(body_partial_json(json!({
+                "auth": { "identity": { "password": { "user": { "name": user } } } }
+            })))
+            .respond_with(token_response(&format!("token-{user}")))
+            .expect(1)
+            .mount(&keystone)
+            .await;
+    }
+
+    let provider = openstack(&redis, &keystone).await;
+    for _ in 0..2 {
+        let alice = provider
+            .get_user_token("alice".into(), "pass".into())
+            .await
+            .unwrap();
+        let bob = provider
+            .get_user_token("bob".into(), "pass".into())
+            .await
+            .unwrap();
+        assert_eq!((alice.as_str(), bob.as_str()), ("token-alice", "token-bob"));
+    }
+}
+
+#[tokio::test]
+async fn instances_keep_separate_token_caches() {
+    let redis = MockRedis::start().await;
+    let (main, gpu) = (mock_keystone().await, mock_keystone().await);
+    for (keystone, token) in [(&main, "token-main"), (&gpu, "token-gpu")] {
+        Mock::given(method("POST"))
+            .and(path("/auth/tokens"))
+            .respond_with(token_response(token))
+            .expect(1)
+            .mount(keystone)
+            .await;
+    }
+
+    let mut providers = Vec::new();
+    for (name, keystone) in [("openstack-main", &main), ("openstack-gpu", &gpu)] {
+        let mut config = config(keystone);
+        config.name = name.into();
+        providers.push(OpenStackCloudProvider::with_config(
+            redis.client().await,
+            config,
+        ));
+    }
+    for _ in 0..2 {
+        assert_eq!(providers[0].get_admin_token().await.unwrap(), "token-main");
+        assert_eq!(providers[1].get_admin_token().await.unwrap(), "token-gpu");
+    }
+    assert_eq!(providers[1].name(), "openstack-gpu");
+    assert!(redis.get("openstack-gpu:admin-token").is_some());
+}
+
+#[tokio::test]
+async fn rejected_credentials_are_reported() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(fixture("keystone/unauthorized")))
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    match provider
+        .get_user_token("alice".into(), "wrong".into())
+        .await
+    {
+        Err(CloudError::Provider(err)) => {
+            assert_eq!(err.status(), Some(reqwest::StatusCode::UNAUTHORIZED))
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+    assert!(redis.get("openstack:user-token-alice:alice").is_none());
+}
+
+#[tokio::test]
+async fn missing_subject_token_is_an_error() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/auth_token")))
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert!(matches!(
+        provider.get_admin_token().await,
+        Err(CloudError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn create_user_creates_project_user_and_role() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("POST"))
+        .and(path("/projects"))
+        .and(header("X-Auth-Token", ADMIN_TOKEN))
+        .and(body_partial_json(
+            json!({ "project": { "name": "alice", "domain_id": "default", "tags": ["pikacloud"] } }),
+        ))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/project_created")))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/users"))
+        .and(body_partial_json(
+            json!({ "user": { "name": "alice", "default_project_id": PROJECT_ID } }),
+        ))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/user_created")))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path(format!(
+            "/projects/{PROJECT_ID}/users/{USER_ID}/roles/{MEMBER_ROLE_ID}"
+        )))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    mount_application_credential(&keystone).await;
+
+    let provider = openstack(&redis, &keystone).await;
+    let info = provider.create_user("alice".into()).await.unwrap();
+    assert_eq!(info.provider_id, "alice");
+    assert!(!info.provider_pass.is_empty());
+}
+
+#[tokio::test]
+async fn create_user_removes_project_when_user_creation_fails() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("POST"))
+        .and(path("/projects"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/project_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/users"))
+        .respond_with(ResponseTemplate::new(409).set_body_json(fixture("keystone/conflict")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(0)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("/projects/{PROJECT_ID}")))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    match provider.create_user("alice".into()).await {
+        Err(CloudError::Provider(err)) => {
+            assert_eq!(err.status(), Some(reqwest::StatusCode::CONFLICT))
+        }
+        other => panic!("unexpected result {other:?}"),
+    }
+
+    // Only the project was created, so only the project is deleted
+    let requests = keystone.received_requests().await.unwrap();
+    let deletes: Vec<&str> = requests
+        .iter()
+        .filter(|r| r.method == wiremock::http::Method::DELETE)
+        .map(|r| r.url.path())
+        .collect();
+    assert_eq!(deletes, [format!("/projects/{PROJECT_ID}")]);
+}
+
+#[tokio::test]
+async fn create_user_removes_user_and_project_when_role_assignment_fails() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("POST"))
+        .and(path("/projects"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/project_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/users"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/user_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&keystone)
+        .await;
+    for deleted in [
+        format!("/users/{USER_ID}"),
+        format!("/projects/{PROJECT_ID}"),
+    ] {
+        Mock::given(method("DELETE"))
+            .and(path(deleted))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&keystone)
+            .await;
+    }
+
+    let provider = openstack(&redis, &keystone).await;
+    assert!(provider.create_user("alice".into()).await.is_err());
+
+    let requests = keystone.received_requests().await.unwrap();
+    let deletes: Vec<&str> = requests
+        .iter()
+        .filter(|r| r.method == wiremock::http::Method::DELETE)
+        .map(|r| r.url.path())
+        .collect();
+    assert_eq!(
+        deletes,
+        [
+            format!("/users/{USER_ID}"),
+            format!("/projects/{PROJECT_ID}")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn delete_user_tears_down_project_contents() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    let lists = [
+        ("/users", "keystone/users_list"),
+        ("/projects", "keystone/projects_list"),
+        ("/compute/v2.1/os-keypairs", "openstack/keypairs_list"),
+        ("/network/v2.0/floatingips", "openstack/floatingips_list"),
+        ("/network/v2.0/ports", "openstack/ports_list"),
+        ("/network/v2.0/routers", "openstack/routers_list"),
+        ("/network/v2.0/networks", "openstack/networks_list"),
+        (
+            "/network/v2.0/security-groups",
+            "openstack/security_groups_list",
+        ),
+        ("/volume/v3/snapshots", "openstack/snapshots_list"),
+        ("/volume/v3/volumes", "openstack/volumes_list"),
+    ];
+    for (list, body) in lists {
+        Mock::given(method("GET"))
+            .and(path(list))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture(body)))
+            .mount(&keystone)
+            .await;
+    }
+    // The server is listed until its deletion has finished
+    Mock::given(method("GET"))
+        .and(path("/compute/v2.1/servers"))
+        .and(query_param("project_id", PROJECT_ID))
+        .and(query_param("all_tenants", "1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("openstack/servers_list")))
+        .up_to_n_times(1)
+        .expect(1)
+        .with_priority(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/compute/v2.1/servers"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "servers": [] })))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path(
+            "/network/v2.0/routers/5c1f3b8e-2b0d-4e9a-8f4c-7d2a6e9b1c33/remove_router_interface",
+        ))
+        .and(body_partial_json(
+            json!({ "port_id": "b2a7e0e8-8d3a-4a0f-9b0c-1f6d5c7f3a10" }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    let deleted = [
+        "/compute/v2.1/servers/22c91117-08de-4894-9aa9-6ef382400985".to_string(),
+        "/network/v2.0/routers/5c1f3b8e-2b0d-4e9a-8f4c-7d2a6e9b1c33".to_string(),
+        "/network/v2.0/ports/d6e9f3a2-1b4c-4d7e-9f0a-3b2c4d5e6f72".to_string(),
+        "/network/v2.0/networks/3f0e1a8c-6b2d-4c5e-9a7f-0d1b2c3e4f50".to_string(),
+        "/network/v2.0/security-groups/85cc3048-abc3-43cc-89b3-377341426ac5".to_string(),
+        "/volume/v3/snapshots/2bb856e1-b3d8-4432-a858-09e4ce939389".to_string(),
+        "/volume/v3/volumes/45baf976-c20a-4894-a7c3-c94b7376bf55".to_string(),
+        "/compute/v2.1/os-keypairs/laptop".to_string(),
+        format!("/users/{USER_ID}"),
+        format!("/projects/{PROJECT_ID}"),
+    ];
+    for deleted in &deleted {
+        Mock::given(method("DELETE"))
+            .and(path(deleted.as_str()))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&keystone)
+            .await;
+    }
+
+    let provider = openstack(&redis, &keystone).await;
+    provider.delete_user("alice".into()).await.unwrap();
+
+    let requests = keystone.received_requests().await.unwrap();
+    let order: Vec<&str> = requests
+        .iter()
+        .filter(|r| r.method == wiremock::http::Method::DELETE)
+        .map(|r| r.url.path())
+        .collect();
+    assert_eq!(order, deleted);
+    // Volumes are only deleted once the server is gone
+    let listed_servers = requests
+        .iter()
+        .rposition(|r| r.url.path() == "/compute/v2.1/servers")
+        .unwrap();
+    let deleted_volume = requests
+        .iter()
+        .position(|r| r.url.path().starts_with("/volume/v3/volumes/"))
+        .unwrap();
+    assert!(listed_servers < deleted_volume);
+}
+
+#[tokio::test]
+async fn delete_user_of_unknown_account_is_not_found() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    for list in ["/users", "/projects"] {
+        Mock::given(method("GET"))
+            .and(path(list))
+            .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/empty_list")))
+            .mount(&keystone)
+            .await;
+    }
+    Mock::given(method("DELETE"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(0)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert!(matches!(
+        provider.delete_user("alice".into()).await,
+        Err(CloudError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn delete_user_leaves_unmanaged_projects_alone() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("GET"))
+        .and(path("/users"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/empty_list")))
+        .mount(&keystone)
+        .await;
+    // A Keystone ignoring the tags filter still returns the project
+    Mock::given(method("GET"))
+        .and(path("/projects"))
+        .and(query_param("name", "alice"))
+        .and(query_param("tags", "pikacloud"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "projects": [{ "id": PROJECT_ID, "name": "alice", "tags": [] }]
+        })))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("DELETE"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(0)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert!(matches!(
+        provider.delete_user("alice".into()).await,
+        Err(CloudError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn user_existence_is_looked_up_by_name() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("GET"))
+        .and(path("/users"))
+        .and(query_param("name", "alice"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/users_list")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/users"))
+        .and(query_param("name", "bob"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/empty_list")))
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert!(provider.is_user_exist("alice".into()).await.unwrap());
+    assert!(!provider.is_user_exist("bob".into()).await.unwrap());
+}
+
+#[tokio::test]
+async fn managed_projects_are_listed_by_tag() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("GET"))
+        .and(path("/projects"))
+        .and(query_param("tags", "pikacloud"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(fixture("keystone/projects_list")))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    assert_eq!(provider.list_accounts().await.unwrap(), ["alice"]);
+}
+
+#[tokio::test]
+async fn domain_and_role_lookups_are_cached() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    mount_admin_token(&keystone).await;
+    Mock::given(method("POST"))
+        .and(path("/projects"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/project_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/users"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(fixture("keystone/user_created")))
+        .mount(&keystone)
+        .await;
+    Mock::given(method("PUT"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&keystone)
+        .await;
+    mount_application_credential(&keystone).await;
+
+    let provider = openstack(&redis, &keystone).await;
+    provider.create_user("alice".into()).await.unwrap();
+    provider.create_user("alice".into()).await.unwrap();
+
+    let requests = keystone.received_requests().await.unwrap();
+    let lookups = |p: &str| requests.iter().filter(|r| r.url.path() == p).count();
+    assert_eq!(lookups("/domains"), 1);
+    assert_eq!(lookups("/roles"), 1);
+    // The admin token once, then alice's own login for each credential
+    assert_eq!(lookups("/auth/tokens"), 3);
+    assert_eq!(
+        redis.get("openstack_member_role_id").as_deref(),
+        Some(MEMBER_ROLE_ID)
+    );
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::{mock_keystone, openstack, token_response, MockRedis, PROJECT_ID};
+use pikacloud_backend::clouds::{keystone::ApplicationCredential, BaseCloudProvider, CloudError};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_partial_json, method, path},
+    Mock, ResponseTemplate,
+};
+
+const TEAM_PROJECT_ID: &str = "5f1c2e7a9b3d4c6e8a0b1c2d3e4f5a6b";
+
+#[tokio::test]
+async fn user_tokens_are_scoped_to_the_users_project() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": {
+                "identity": { "password": { "user": { "name": "alice" } } },
+                "scope": { "project": { "name": "alice", "domain": { "name": "Default" } } }
+            }
+        })))
+        .respond_with(token_response("token-alice"))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    for _ in 0..2 {
+        let token = provider
+            .get_user_token("alice".into(), "pass".into())
+            .await
+            .unwrap();
+        assert_eq!(token, "token-alice");
+    }
+    assert!(redis.get("openstack:user-token-alice:alice").is_some());
+}
+
+#[tokio::test]
+async fn project_tokens_are_cached_per_project() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": { "scope": { "project": { "name": "alice" } } }
+        })))
+        .respond_with(token_response("token-own"))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .and(body_partial_json(json!({
+            "auth": { "scope": { "project": { "id": TEAM_PROJECT_ID } } }
+        })))
+        .respond_with(token_response("token-team"))
+        .expect(1)
+        .mount(&keystone)
+        .await;
+
+    let provider = openstack(&redis, &keystone).await;
+    for _ in 0..2 {
+        let own = provider
+            .get_user_token("alice".into(), "pass".into())
+            .await
+            .unwrap();
+        let team = provider
+            .get_project_token("alice".into(), "pass".into(), TEAM_PROJECT_ID.into())
+            .await
+            .unwrap();
+        assert_eq!((own.as_str(), team.as_str()), ("token-own", "token-team"));
+    }
+
+    let requests = keystone.received_requests().await.unwrap();
+    let body: serde_json::Value = requests
+        .iter()
+        .find(|r| {
+            r.body_json::<serde_json::Value>().unwrap()["auth"]["scope"]["project"]["id"]
+                == TEAM_PROJECT_ID
+        })
+        .unwrap()
+        .body_json()
+        .unwrap();
+    // Project ids are unique across domains
+    assert!(body["auth"]["scope"]["project"].get("domain").is_none());
+}
+
+#[tokio::test]
+async fn application_credentials_stay_in_their_project() {
+    let redis = MockRedis::start().await;
+    let keystone = mock_keystone().await;
+    Mock::given(method("POST"))
+        .and(path("/auth/tokens"))
+        .respond_with(
+            ResponseTemplate::new(201)
+                .insert_header("X-Subject-Token", "token-credential")
+                .set_body_json(json!({
+                    "token": {
+                        "methods": ["application_credential"],
+                        "expires_at": "2099-01-01T12:00:00.000000Z",
+                        "project": { "id": PROJECT_ID, "name": "alice" }
+                    }
+                })),
+        )
+        .expect(1)
+        .mount(&keystone)
+        .await;
+    let stored = ApplicationCredential {
+        id: "credential".into(),
+        secret: "secret".into(),
+    }
+    .to_stored();
+
+    let provider = openstack(&redis, &keystone).await;
+    let own = provider
+        .get_project_token("alice".into(), stored.clone(), PROJECT_ID.into())
+        .await
+        .unwrap();
+    assert_eq!(own, "token-credential");
+    assert_eq!(
+        provider
+            .get_user_token("alice".into(), stored.clone())
+            .await
+            .unwrap(),
+        own
+    );
+    match provider
+        .get_project_token("alice".into(), stored, TEAM_PROJECT_ID.into())
+        .await
+    {
+        Err(CloudError::Unsupported(_)) => {}
+        other => panic!("unexpected result {other:?}"),
+    }
+}
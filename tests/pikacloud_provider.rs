@@ -0,0 +1,199 @@
+mod common;
+
+use common::MockRedis;
+use pikacloud_backend::{
+    clouds::{
+        pikacloud::{PikaCloudConfig, PikaCloudProvider},
+        BaseCloudProvider, CloudError,
+    },
+    models::CreateInstance,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_json, body_partial_json, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const AGENT_TOKEN: &str = "agent-admin-token";
+const USER_TOKEN: &str = "agent-user-token";
+const TOKEN_KEY: &str = "pikacloud:user-token-alice";
+
+/// The agent is served under a prefix to check that paths are appended to it
+async fn pikacloud(redis: &MockRedis, agent: &MockServer) -> PikaCloudProvider {
+    PikaCloudProvider::with_config(
+        redis.client().await,
+        PikaCloudConfig {
+            name: "pikacloud".into(),
+            agent: format!("{}/agent/", agent.uri()),
+            agent_token: AGENT_TOKEN.into(),
+        },
+    )
+}
+
+#[tokio::test]
+async fn create_user_registers_the_account() {
+    let redis = MockRedis::start().await;
+    let agent = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/agent/users"))
+        .and(header("Authorization", format!("Bearer {AGENT_TOKEN}")))
+        .and(body_partial_json(json!({ "name": "alice" })))
+        .respond_with(ResponseTemplate::new(201))
+        .expect(1)
+        .mount(&agent)
+        .await;
+
+    let provider = pikacloud(&redis, &agent).await;
+    let info = provider.create_user("alice".into()).await.unwrap();
+    assert_eq!(info.provider_id, "alice");
+    assert!(uuid::Uuid::parse_str(&info.provider_pass).is_ok());
+}
+
+#[tokio::test]
+async fn delete_user_reports_missing_accounts() {
+    let redis = MockRedis::start().await;
+    let agent = MockServer::start().await;
+    Mock::given(method("DELETE"))
+        .and(path("/agent/users/alice"))
+        .and(header("Authorization", format!("Bearer {AGENT_TOKEN}")))
+        .respond_with(ResponseTemplate::new(204))
+        .up_to_n_times(1)
+        .mount(&agent)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/agent/users/alice"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&agent)
+        .await;
+
+    let provider = pikacloud(&redis, &agent).await;
+    provider.delete_user("alice".into()).await.unwrap();
+    assert!(matches!(
+        provider.delete_user("alice".into()).await,
+        Err(CloudError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn user_tokens_are_cached_until_shortly_before_they_expire() {
+    let redis = MockRedis::start().await;
+    let agent = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/agent/tokens"))
+        .and(body_json(json!({ "name": "alice", "password": "secret" })))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "token": USER_TOKEN, "expiresIn": 3600 })),
+        )
+        .expect(1)
+        .mount(&agent)
+        .await;
+
+    let provider = pikacloud(&redis, &agent).await;
+    for _ in 0..2 {
+        let token = provider
+            .get_user_token("alice".into(), "secret".into())
+            .await
+            .unwrap();
+        assert_eq!(token, USER_TOKEN);
+    }
+    assert_eq!(redis.ttl(TOKEN_KEY), Some(3540));
+}
+
+#[tokio::test]
+async fn key_names_are_escaped_in_paths() {
+    let redis = MockRedis::start().await;
+    let agent = MockServer::start().await;
+    redis.set(TOKEN_KEY, USER_TOKEN);
+    Mock::given(method("PUT"))
+        .and(path("/agent/keys/my%20key%2F..%3Fx"))
+        .and(header("Authorization", format!("Bearer {USER_TOKEN}")))
+        .and(body_json(json!({ "publicKey": "ssh-ed25519 AAAA" })))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&agent)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/agent/keys/my%20key%2F..%3Fx"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&agent)
+        .await;
+
+    let provider = pikacloud(&redis, &agent).await;
+    provider
+        .import_keypair(
+            "alice".into(),
+            "secret".into(),
+            "my key/..?x".into(),
+            "ssh-ed25519 AAAA".into(),
+        )
+        .await
+        .unwrap();
+    // Keys that are already gone count as deleted
+    provider
+        .delete_keypair("alice".into(), "secret".into(), "my key/..?x".into())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn instances_are_created_and_deleted() {
+    let redis = MockRedis::start().await;
+    let agent = MockServer::start().await;
+    redis.set(TOKEN_KEY, USER_TOKEN);
+    Mock::given(method("POST"))
+        .and(path("/agent/instances"))
+        .and(header("Authorization", format!("Bearer {USER_TOKEN}")))
+        .and(body_partial_json(
+            json!({ "name": "vm", "flavorId": "small", "imageId": "debian" }),
+        ))
+        .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+            "id": "vm/1",
+            "name": "vm",
+            "status": "BUILD",
+            "flavorId": "small",
+            "createdAt": null
+        })))
+        .expect(1)
+        .mount(&agent)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/agent/instances/vm%2F1"))
+        .respond_with(ResponseTemplate::new(204))
+        .up_to_n_times(1)
+        .mount(&agent)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/agent/instances/vm%2F1"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&agent)
+        .await;
+
+    let provider = pikacloud(&redis, &agent).await;
+    let instance = provider
+        .create_instance(
+            "alice".into(),
+            "secret".into(),
+            CreateInstance {
+                name: "vm".into(),
+                flavor_id: "small".into(),
+                image_id: "debian".into(),
+                network_id: None,
+                key_name: None,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(instance.status, "BUILD");
+    provider
+        .delete_instance("alice".into(), "secret".into(), instance.id.clone())
+        .await
+        .unwrap();
+    assert!(matches!(
+        provider
+            .delete_instance("alice".into(), "secret".into(), instance.id)
+            .await,
+        Err(CloudError::NotFound(_))
+    ));
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use chrono::NaiveDateTime;
+use common::TestDatabase;
+use diesel::{
+    prelude::*,
+    result::{DatabaseErrorKind, Error as DieselError},
+};
+use pikacloud_backend::{
+    models::{CloudPlacement, CloudProvider, LoginProvider, NewCloudUser, NewUser},
+    placement::choose,
+    repository::{PgRepository, UserRepository},
+    schema,
+};
+
+const INSTANCES: [&str; 3] = ["openstack-main", "openstack-gpu", "openstack-eu"];
+
+fn rule(
+    instance: &str,
+    user_id: Option<&str>,
+    role_id: Option<&str>,
+    priority: i32,
+) -> CloudPlacement {
+    CloudPlacement {
+        id: uuid::Uuid::new_v4().to_string(),
+        cloudProvider: CloudProvider::OPENSTACK,
+        cloudInstance: instance.into(),
+        userId: user_id.map(String::from),
+        roleId: role_id.map(String::from),
+        priority,
+        createdAt: NaiveDateTime::default(),
+    }
+}
+
+fn chosen(rules: &[CloudPlacement], user_id: &str, role_ids: &[&str]) -> Option<String> {
+    let role_ids: Vec<String> = role_ids.iter().map(|id| id.to_string()).collect();
+    choose(rules, user_id, &role_ids, &INSTANCES)
+}
+
+#[test]
+fn user_rules_win_over_role_rules_over_global_rules() {
+    let rules = [
+        rule("openstack-eu", None, None, 100),
+        rule("openstack-gpu", None, Some("gpu"), 0),
+        rule("openstack-main", Some("alice"), None, -5),
+    ];
+    assert_eq!(
+        chosen(&rules, "alice", &["gpu"]).as_deref(),
+        Some("openstack-main")
+    );
+    assert_eq!(
+        chosen(&rules, "bob", &["gpu"]).as_deref(),
+        Some("openstack-gpu")
+    );
+    assert_eq!(
+        chosen(&rules, "carol", &["staff"]).as_deref(),
+        Some("openstack-eu")
+    );
+}
+
+#[test]
+fn highest_priority_wins_within_a_group() {
+    let rules = [
+        rule("openstack-main", None, Some("staff"), 1),
+        rule("openstack-gpu", None, Some("gpu"), 10),
+        rule("openstack-eu", None, Some("staff"), 5),
+    ];
+    assert_eq!(
+        chosen(&rules, "alice", &["staff", "gpu"]).as_deref(),
+        Some("openstack-gpu")
+    );
+    assert_eq!(
+        chosen(&rules, "alice", &["staff"]).as_deref(),
+        Some("openstack-eu")
+    );
+}
+
+#[test]
+fn rules_for_other_users_and_unknown_instances_are_ignored() {
+    let rules = [
+        rule("openstack-gpu", Some("bob"), None, 0),
+        rule("openstack-old", None, None, 100),
+    ];
+    // Falls back to the first configured instance
+    assert_eq!(
+        chosen(&rules, "alice", &[]).as_deref(),
+        Some("openstack-main")
+    );
+    assert_eq!(choose(&rules, "alice", &[], &[]), None);
+}
+
+#[tokio::test]
+async fn accounts_are_unique_per_provider() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let user_id = PgRepository::new(test.db.clone())
+        .create_user(NewUser {
+            username: "alice".into(),
+            loginProvider: LoginProvider::PASSWORD,
+            name: None,
+            password: None,
+        })
+        .await
+        .unwrap()
+        .id;
+    let account = |instance: &str| NewCloudUser {
+        userId: user_id.clone(),
+        cloudProvider: CloudProvider::OPENSTACK,
+        cloudInstance: instance.into(),
+        cloudUsername: "alice".into(),
+        cloudPassword: String::new(),
+    };
+    let conn = &mut test.db.get_conn().unwrap();
+    let mut insert = |instance| {
+        diesel::insert_into(schema::CloudUser::table)
+            .values(account(instance))
+            .execute(conn)
+    };
+
+    insert("openstack-main").unwrap();
+    assert!(matches!(
+        insert("openstack-gpu"),
+        Err(DieselError::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            _
+        ))
+    ));
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestDatabase;
+use pikacloud_backend::quota::{check_quota, lock_quota, MergeStrategy, QuotaError, Resources};
+
+fn resources(vcpus: i32, ram_mb: i32, instances: i32) -> Resources {
+    Resources {
+        vcpus,
+        ram_mb,
+        instances,
+        volumes: 2,
+        floating_ips: 1,
+    }
+}
+
+#[test]
+fn max_takes_the_largest_value_of_each_resource() {
+    let merged = MergeStrategy::Max.merge([resources(4, 2048, 1), resources(2, 8192, 3)]);
+    assert_eq!(merged, Some(resources(4, 8192, 3)));
+}
+
+#[test]
+fn sum_adds_profiles_up() {
+    let merged = MergeStrategy::Sum.merge([resources(4, 2048, 1), resources(2, 8192, 3)]);
+    assert_eq!(
+        merged,
+        Some(Resources {
+            vcpus: 6,
+            ram_mb: 10240,
+            instances: 4,
+            volumes: 4,
+            floating_ips: 2,
+        })
+    );
+    let huge = MergeStrategy::Sum.merge([resources(i32::MAX, 0, 0), resources(1, 0, 0)]);
+    assert_eq!(huge.unwrap().vcpus, i32::MAX);
+}
+
+#[test]
+fn no_profiles_leave_the_cloud_defaults() {
+    assert_eq!(MergeStrategy::Max.merge([]), None);
+    assert_eq!(
+        MergeStrategy::Sum.merge([resources(1, 512, 1)]),
+        Some(resources(1, 512, 1))
+    );
+}
+
+#[test]
+fn strategies_are_parsed() {
+    assert_eq!("max".parse(), Ok(MergeStrategy::Max));
+    assert_eq!("sum".parse(), Ok(MergeStrategy::Sum));
+    assert!("min".parse::<MergeStrategy>().is_err());
+    assert_eq!(MergeStrategy::default(), MergeStrategy::Max);
+}
+
+#[test]
+fn requests_within_the_quota_pass() {
+    let quota = resources(4, 4096, 2);
+    let usage = resources(2, 2048, 1);
+    let request = Resources {
+        vcpus: 2,
+        ram_mb: 2048,
+        instances: 1,
+        ..Default::default()
+    };
+    assert!(check_quota(Some(&quota), &usage, &request).is_ok());
+    // Without a profile there is nothing to check against
+    assert!(check_quota(None, &resources(64, 65536, 10), &request).is_ok());
+}
+
+#[test]
+fn every_exceeded_resource_is_reported() {
+    let quota = resources(4, 4096, 2);
+    let usage = resources(3, 2048, 2);
+    let request = Resources {
+        vcpus: 2,
+        ram_mb: 2048,
+        instances: 1,
+        ..Default::default()
+    };
+    match check_quota(Some(&quota), &usage, &request) {
+        Err(QuotaError::Exceeded(exceeded)) => assert_eq!(exceeded, vec!["vcpus", "instances"]),
+        other => panic!("unexpected {other:?}"),
+    }
+    assert_eq!(
+        resources(4, 4097, 2).exceeding(&quota),
+        vec!["ramMb"],
+        "limits are inclusive"
+    );
+}
+
+#[tokio::test]
+async fn quota_checks_of_a_user_take_turns() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let lock = lock_quota(&test.db, "alice", Duration::ZERO).await.unwrap();
+    assert!(matches!(
+        lock_quota(&test.db, "alice", Duration::ZERO).await,
+        Err(QuotaError::Busy)
+    ));
+    // Other users are not held up
+    let other = lock_quota(&test.db, "bob", Duration::ZERO).await.unwrap();
+    other.release().await.unwrap();
+
+    let released = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        lock.release().await.unwrap();
+    });
+    lock_quota(&test.db, "alice", Duration::from_secs(5))
+        .await
+        .unwrap();
+    released.await.unwrap();
+}
+
+#[test]
+fn locks_dropped_outside_a_runtime_are_released() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let lock = runtime
+        .block_on(lock_quota(&test.db, "alice", Duration::ZERO))
+        .unwrap();
+    drop(lock);
+    runtime
+        .block_on(lock_quota(&test.db, "alice", Duration::ZERO))
+        .unwrap();
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::MockRedis;
+use pikacloud_backend::{
+    cache::RedisClient,
+    clouds::fake::FakeConfig,
+    config::CloudConfig,
+    reload::{reload_cloud_providers, Reloadable},
+    server::load_cloud_providers,
+};
+
+fn fake(name: &str, failure_rate: f64) -> CloudConfig {
+    CloudConfig::Fake {
+        name: name.to_string(),
+        config: FakeConfig {
+            latency: Duration::ZERO,
+            failure_rate,
+        },
+    }
+}
+
+#[tokio::test]
+async fn unchanged_cloud_instances_keep_their_provider() {
+    let redis = MockRedis::start().await;
+    let cache = RedisClient::new(&redis.url).await.unwrap();
+    let previous = vec![
+        fake("kept", 0.0),
+        fake("changed", 0.0),
+        fake("removed", 0.0),
+    ];
+    let registry = load_cloud_providers(&previous, cache.clone());
+    for name in ["kept", "changed"] {
+        let provider = registry.get(name).unwrap();
+        provider.create_user("alice".into()).await.unwrap();
+    }
+
+    let clouds = vec![fake("added", 0.0), fake("changed", 0.5), fake("kept", 0.0)];
+    let (reloaded, reconfigured) = reload_cloud_providers(&clouds, &previous, &registry, cache);
+    assert_eq!(reloaded.names(), vec!["added", "changed", "kept"]);
+    assert_eq!(reconfigured, vec!["added", "changed"]);
+    assert!(Arc::ptr_eq(
+        &reloaded.get_shared("kept").unwrap(),
+        &registry.get_shared("kept").unwrap()
+    ));
+    let kept = reloaded.get("kept").unwrap();
+    assert!(kept.is_user_exist("alice".into()).await.unwrap());
+    assert!(reloaded.get("removed").is_none());
+}
+
+#[test]
+fn readers_keep_the_value_they_loaded() {
+    let config = Reloadable::new(1);
+    let shared = config.clone();
+    let before = config.load();
+    shared.store(2);
+    assert_eq!(*before, 1);
+    assert_eq!(*config.load(), 2);
+}
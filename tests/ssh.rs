@@ -0,0 +1,123 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pikacloud_backend::ssh::{validate_key_name, PublicKey, SshKeyError};
+
+// Generated with ssh-keygen, fingerprints from `ssh-keygen -lf`
+const ED25519: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFC4HzefxV2cY4ef8I4/UxFgaESKBkrFC1YykfCop+5w alice@laptop";
+const RSA_2048: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDzO8OW2CaGrXk8Sho3vLQQtJupVr3ldxfozyf/yadLLnbURG5dTp1EDpWRtXKRBqqYbuhd1dTpaEVD1HNQ/vhZ/GmdqzwNqET+i0K8D62MsSxx1PnJAbTo77GYji7KS7RDrQ85dLYQru71vGGPep7MvOttq/39fmIu0+zdgWVraWn2qRjqjTVMxqPAMHpkMLUBixI2ok4flkz/J0P99dX7Jh5MZnTI+8QRJNxNOFZ23EeHlI/szT+zpwfGBMfcwpYMPk1/wTNknruRgD6ictozbY13CqoNtSlAyJHSF4dXR4cCme8vIXLLmZRNuKpvi9sGiGxJD8HzBIAv+y2XWtdd alice@laptop";
+const RSA_1024: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQDJ4pZIlD5CW8sWQweyg5D/CDVeNTIqrkUJcOp1R76qXFrr3D0qSTAnBb/V5Pe3TTZaKT9pStLYfSIEJ713+iQSL2hFGq8TEN+auoXQBH8ma+ep/phK7DwvYWcaDu+KeWr1bA4CAVBVOo7/6j4HhWOlAb86oXRRMrhuBww/WA01cQ== alice@laptop";
+const ECDSA_256: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBJu0x7DOqXZ0PbFpuTorXz1hDeaueYNd3aNRlxeFihTI9NRTkQUv8YoGNIvCQo7iLWZb+/7I5lNxWNflnwzDWic= alice@laptop";
+const ECDSA_384: &str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBN37o2OzePmmU3vThDuI6dMQ/ihkyK8y5X2yQhOA3TDyUC+tuk6il4PK8JtCDuThj/pGZDPR32TpjEUx+GYwOdSjgEbBf/kddZa1/eLlfLWyEGI/G2hnrJfV+yM5LsyOKg== alice@laptop";
+
+/// Key data of `line`, with the blob changed by `edit`
+fn edited(line: &str, edit: impl FnOnce(&mut Vec<u8>)) -> String {
+    let mut parts = line.split(' ');
+    let key_type = parts.next().unwrap();
+    let mut blob = STANDARD.decode(parts.next().unwrap()).unwrap();
+    edit(&mut blob);
+    format!("{key_type} {}", STANDARD.encode(blob))
+}
+
+/// A blob in the SSH wire format made of `strings`
+fn wire(strings: &[&[u8]]) -> String {
+    let mut blob = Vec::new();
+    for string in strings {
+        blob.extend_from_slice(&(string.len() as u32).to_be_bytes());
+        blob.extend_from_slice(string);
+    }
+    STANDARD.encode(blob)
+}
+
+fn malformed(line: &str) -> bool {
+    matches!(PublicKey::parse(line), Err(SshKeyError::Malformed(_)))
+}
+
+#[test]
+fn ed25519_keys_are_parsed() {
+    let key = PublicKey::parse(ED25519).unwrap();
+    assert_eq!(key.key_type, "ssh-ed25519");
+    assert_eq!(key.comment.as_deref(), Some("alice@laptop"));
+    assert_eq!(
+        key.fingerprint,
+        "SHA256:nGTNRtcmbSe/9lle2nQ4ANOAEDwGn4XbfAIfhv7YhX4"
+    );
+    assert_eq!(key.to_openssh(), ED25519.trim_end_matches(" alice@laptop"));
+}
+
+#[test]
+fn ecdsa_keys_are_parsed() {
+    let key = PublicKey::parse(ECDSA_256).unwrap();
+    assert_eq!(key.key_type, "ecdsa-sha2-nistp256");
+    assert_eq!(
+        key.fingerprint,
+        "SHA256:INPjz7OELOpK6p2YVZTzM3pUvZPOVgalcRldvng4I4k"
+    );
+    let key = PublicKey::parse(ECDSA_384).unwrap();
+    assert_eq!(
+        key.fingerprint,
+        "SHA256:6jA0sZaoZ2SsDs0BZGvnrVs8nP9XmfX1typ27aXPAVU"
+    );
+}
+
+#[test]
+fn rsa_keys_need_2048_bits() {
+    let key = PublicKey::parse(RSA_2048).unwrap();
+    assert_eq!(
+        key.fingerprint,
+        "SHA256:9WtXyJDoxUUwXn+mxN35RlpFX8GnOcfpeAa/W9hq3Qc"
+    );
+    assert!(matches!(
+        PublicKey::parse(RSA_1024),
+        Err(SshKeyError::WeakRsa(1024))
+    ));
+}
+
+#[test]
+fn comments_are_optional_and_may_contain_spaces() {
+    let line = ED25519.replace("alice@laptop", "alice on  laptop");
+    let key = PublicKey::parse(&format!("  {line}\n")).unwrap();
+    assert_eq!(key.comment.as_deref(), Some("alice on laptop"));
+    let key = PublicKey::parse(&PublicKey::parse(ED25519).unwrap().to_openssh()).unwrap();
+    assert_eq!(key.comment, None);
+}
+
+#[test]
+fn malformed_keys_are_rejected() {
+    assert!(malformed(""));
+    assert!(malformed("ssh-ed25519"));
+    assert!(malformed("ssh-ed25519 not-base64!"));
+    // Type on the line differs from the one in the key data
+    assert!(malformed(&ED25519.replacen("ssh-ed25519", "ssh-rsa", 1)));
+    assert!(malformed(&edited(ED25519, |blob| blob.truncate(40))));
+    assert!(malformed(&edited(ED25519, |blob| blob.push(0))));
+    assert!(malformed(&edited(ECDSA_256, |blob| {
+        let point = blob.len() - 65;
+        blob[point] = 0x02;
+    })));
+    let wrong_curve = wire(&[b"ecdsa-sha2-nistp256", b"nistp384", &[4; 65]]);
+    assert!(malformed(&format!("ecdsa-sha2-nistp256 {wrong_curve}")));
+    let short = wire(&[b"ssh-ed25519", &[0; 31]]);
+    assert!(malformed(&format!("ssh-ed25519 {short}")));
+}
+
+#[test]
+fn unsupported_key_types_are_rejected() {
+    let dss = wire(&[b"ssh-dss", &[1; 128]]);
+    assert!(matches!(
+        PublicKey::parse(&format!("ssh-dss {dss}")),
+        Err(SshKeyError::Unsupported(key_type)) if key_type == "ssh-dss"
+    ));
+}
+
+#[test]
+fn key_names_are_limited_to_nova_characters() {
+    for name in ["laptop", "alice@work", "key-1_backup.old", "my key"] {
+        assert!(validate_key_name(name).is_ok(), "{name}");
+    }
+    for name in ["", "a/b", "key?", "ключ", &"a".repeat(65)] {
+        assert!(
+            matches!(validate_key_name(name), Err(SshKeyError::InvalidName(_))),
+            "{name}"
+        );
+    }
+}
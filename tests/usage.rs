@@ -0,0 +1,224 @@
+mod common;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use common::TestDatabase;
+use diesel::prelude::*;
+use pikacloud_backend::{
+    db::DBConn,
+    models::{CloudProvider, LoginProvider, NewUsageSample, NewUser},
+    repository::{PgRepository, UserRepository},
+    schema,
+    usage::{aggregate_usage, period_start, usage_csv, UsagePeriod, UsageQuery, UsageRow},
+};
+
+fn at(date: &str, time: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y-%m-%d %H:%M:%S").unwrap()
+}
+
+fn row(username: &str, period: NaiveDateTime, hours: f64) -> UsageRow {
+    UsageRow {
+        user_id: format!("id-{username}"),
+        username: username.into(),
+        period,
+        instance_hours: hours,
+        vcpu_hours: hours * 2.0,
+        ram_gb_hours: hours / 3.0,
+        storage_gb_hours: 0.0,
+    }
+}
+
+#[test]
+fn csv_has_one_line_per_row() {
+    let rows = [
+        row("alice", at("2024-07-01", "00:00:00"), 1.5),
+        row("bob", at("2024-07-02", "00:00:00"), 24.0),
+    ];
+    assert_eq!(
+        usage_csv(&rows, UsagePeriod::Day),
+        "userId,username,period,instanceHours,vcpuHours,ramGbHours,storageGbHours\n\
+         id-alice,alice,2024-07-01,1.500,3.000,0.500,0.000\n\
+         id-bob,bob,2024-07-02,24.000,48.000,8.000,0.000\n"
+    );
+    let months = usage_csv(&rows[..1], UsagePeriod::Month);
+    assert!(months.ends_with("\nid-alice,alice,2024-07,1.500,3.000,0.500,0.000\n"));
+}
+
+#[test]
+fn csv_fields_are_quoted_when_needed() {
+    let mut quoted = row("smith, \"j\"", at("2024-07-01", "00:00:00"), 1.0);
+    quoted.user_id = "u1".into();
+    let csv = usage_csv(&[quoted], UsagePeriod::Day);
+    assert!(csv.contains("\nu1,\"smith, \"\"j\"\"\",2024-07-01,"));
+    assert_eq!(
+        usage_csv(&[], UsagePeriod::Day).lines().count(),
+        1,
+        "only the header"
+    );
+}
+
+#[test]
+fn collection_periods_are_aligned_on_the_epoch() {
+    assert_eq!(
+        period_start(at("2024-07-01", "10:59:59"), 3600),
+        at("2024-07-01", "10:00:00")
+    );
+    assert_eq!(
+        period_start(at("2024-07-01", "11:00:00"), 3600),
+        at("2024-07-01", "11:00:00")
+    );
+    assert_eq!(
+        period_start(at("2024-07-01", "10:47:12"), 900),
+        at("2024-07-01", "10:45:00")
+    );
+}
+
+#[tokio::test]
+async fn passes_are_skipped_while_another_replica_runs_them() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let lock = test.db.try_advisory_lock("usage:collect").await.unwrap();
+    let skipped = test.db.exclusive("usage:collect", || async { 1 }).await;
+    assert_eq!(skipped.unwrap(), None);
+
+    lock.unwrap().release().await.unwrap();
+    let ran = test.db.exclusive("usage:collect", || async { 1 }).await;
+    assert_eq!(ran.unwrap(), Some(1));
+    // The lock is released after each pass
+    assert!(test
+        .db
+        .try_advisory_lock("usage:collect")
+        .await
+        .unwrap()
+        .is_some());
+}
+
+async fn create_user(test: &TestDatabase, username: &str) -> String {
+    let repository = PgRepository::new(test.db.clone());
+    let new_user = NewUser {
+        username: username.into(),
+        loginProvider: LoginProvider::PASSWORD,
+        name: None,
+        password: None,
+    };
+    repository.create_user(new_user).await.unwrap().id
+}
+
+/// Half an hour of an instance with `vcpus` and `ram_mb`
+fn instance(user_id: &str, status: &str, vcpus: i32, ram_mb: i32) -> NewUsageSample {
+    NewUsageSample {
+        userId: user_id.into(),
+        cloudProvider: CloudProvider::OPENSTACK,
+        resourceType: "instance".into(),
+        resourceId: uuid::Uuid::new_v4().to_string(),
+        resourceName: "vm".into(),
+        status: status.into(),
+        flavorId: None,
+        vcpus,
+        ramMb: ram_mb,
+        storageGb: 20,
+        intervalSeconds: 1800,
+    }
+}
+
+/// Half an hour of a volume of `size_gb`
+fn volume(user_id: &str, size_gb: i32) -> NewUsageSample {
+    NewUsageSample {
+        resourceType: "volume".into(),
+        status: "in-use".into(),
+        vcpus: 0,
+        ramMb: 0,
+        storageGb: size_gb,
+        ..instance(user_id, "", 0, 0)
+    }
+}
+
+fn record(conn: &mut DBConn, sample: NewUsageSample, sampled_at: NaiveDateTime) {
+    let resource_id = sample.resourceId.clone();
+    diesel::insert_into(schema::UsageSample::table)
+        .values(sample)
+        .execute(conn)
+        .unwrap();
+    let inserted =
+        schema::UsageSample::table.filter(schema::UsageSample::resourceId.eq(resource_id));
+    diesel::update(inserted)
+        .set(schema::UsageSample::sampledAt.eq(sampled_at))
+        .execute(conn)
+        .unwrap();
+}
+
+fn query(period: UsagePeriod, from: &str, to: &str) -> UsageQuery {
+    UsageQuery {
+        period,
+        from: Some(from.parse::<NaiveDate>().unwrap()),
+        to: Some(to.parse::<NaiveDate>().unwrap()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn samples_add_up_per_user_and_period() {
+    let Some(test) = TestDatabase::create() else {
+        return;
+    };
+    let alice = create_user(&test, "alice").await;
+    let bob = create_user(&test, "bob").await;
+    let conn = &mut test.db.get_conn().unwrap();
+    let july = |time| at("2024-07-01", time);
+    record(conn, instance(&alice, "ACTIVE", 2, 4096), july("10:00:00"));
+    record(conn, instance(&alice, "ACTIVE", 2, 4096), july("10:30:00"));
+    record(conn, instance(&alice, "SHUTOFF", 2, 4096), july("11:00:00"));
+    record(conn, volume(&alice, 10), july("10:00:00"));
+    record(conn, instance(&bob, "ACTIVE", 8, 16384), july("12:00:00"));
+    let next_day = at("2024-07-02", "09:00:00");
+    record(conn, instance(&alice, "ACTIVE", 2, 4096), next_day);
+    let next_month = at("2024-08-01", "09:00:00");
+    record(conn, instance(&alice, "ACTIVE", 2, 4096), next_month);
+
+    let days = aggregate_usage(
+        conn,
+        None,
+        &query(UsagePeriod::Day, "2024-07-01", "2024-07-03"),
+    )
+    .unwrap();
+    let summary: Vec<(&str, String, f64, f64, f64, f64)> = days
+        .iter()
+        .map(|r| {
+            (
+                r.username.as_str(),
+                r.period.format("%Y-%m-%d").to_string(),
+                r.instance_hours,
+                r.vcpu_hours,
+                r.ram_gb_hours,
+                r.storage_gb_hours,
+            )
+        })
+        .collect();
+    // Stopped instances and root disks are not counted
+    assert_eq!(
+        summary,
+        vec![
+            ("alice", "2024-07-01".into(), 1.0, 2.0, 4.0, 5.0),
+            ("bob", "2024-07-01".into(), 0.5, 4.0, 8.0, 0.0),
+            ("alice", "2024-07-02".into(), 0.5, 1.0, 2.0, 0.0),
+        ]
+    );
+
+    let months = aggregate_usage(
+        conn,
+        Some(&alice),
+        &query(UsagePeriod::Month, "2024-07-01", "2024-09-01"),
+    )
+    .unwrap();
+    let summary: Vec<(NaiveDateTime, f64)> = months
+        .iter()
+        .map(|r| (r.period, r.instance_hours))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (at("2024-07-01", "00:00:00"), 1.5),
+            (at("2024-08-01", "00:00:00"), 0.5),
+        ]
+    );
+}